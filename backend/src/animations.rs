@@ -0,0 +1,513 @@
+//! Animations for the panel: animated GIFs and PNGs, uploaded, converted frame by frame, kept
+//! in the library (see [`db`]), and played by streaming each frame in turn (see [`stream`]) for as long as it should show.
+//!
+//! `POST /api/animations` takes a GIF or an APNG as the request body, and the query parameters
+//! `POST /api/images` does (see [`images`](crate::images)), converting each frame the same way.
+//! Each frame is composited as a browser would show it, honouring GIF disposal methods and
+//! transparency, and keeps its own delay. It answers with the stored animation's
+//! [`AnimationInfo`], and plays it straight away on the `device` or `group` if given. Then:
+//!
+//! - `GET /api/animations` lists them
+//! - `GET /api/animations/{id}` is its [`AnimationInfo`]
+//! - `POST /api/animations/{id}/play?device=..` (or `group=..`) plays it
+//! - `POST /api/animations/stop?device=..` (or `group=..`) stops what's playing there
+//! - `DELETE /api/animations/{id}` stops it wherever it's playing, and forgets it
+//!
+//! Aseprite sprite sheets are uploaded to `POST /api/animations/aseprite` instead (see
+//! [`aseprite`](crate::aseprite)).
+//!
+//! It plays as many times as the file says, which for a GIF without a loop count is once. A
+//! target plays one animation at a time: playing another, or showing an image on it, stops
+//! the one before. Targets are matched as given, so playing on a group doesn't stop an
+//! animation playing on one of its devices.
+
+use std::io::Cursor;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::body::Bytes;
+use axum::extract::{DefaultBodyLimit, Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use common::LED_BUFFER_SIZE;
+use image::codecs::png::PngDecoder;
+use image::metadata::LoopCount;
+use image::{AnimationDecoder, DynamicImage, ImageDecoder, ImageFormat};
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::{info, warn};
+use web_common::Target;
+
+use crate::db::{self, Content, Database, Kind, Search};
+use crate::gif::{self, GifError};
+use crate::images::{Conversion, MAX_UPLOAD_LEN, TargetQuery, UploadQuery, to_frame};
+use crate::{AppState, homeassistant, stream};
+
+/// Most frames kept of an animation
+pub const MAX_FRAMES: usize = 1024;
+/// Frames are shown for at least this long, whatever the file says
+pub const MIN_DELAY: Duration = Duration::from_millis(10);
+
+#[derive(Debug)]
+pub enum AnimationError {
+    /// Not a GIF or PNG
+    UnsupportedFormat,
+    /// A PNG without an animation - upload it to `/api/images` instead
+    NotAnimated,
+    TooLarge {
+        width: u32,
+        height: u32,
+    },
+    TooManyFrames,
+    Gif(GifError),
+    Png(image::ImageError),
+}
+
+impl std::fmt::Display for AnimationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AnimationError::UnsupportedFormat => write!(f, "not a GIF or PNG"),
+            AnimationError::NotAnimated => write!(f, "a still PNG, upload it as an image"),
+            AnimationError::TooLarge { width, height } => write!(
+                f,
+                "{width}x{height} is larger than {0}x{0}",
+                gif::MAX_DIMENSION
+            ),
+            AnimationError::TooManyFrames => write!(f, "more than {MAX_FRAMES} frames"),
+            AnimationError::Gif(e) => write!(f, "invalid GIF: {e}"),
+            AnimationError::Png(e) => write!(f, "invalid APNG: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for AnimationError {}
+
+/// A frame of an animation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnimationFrame {
+    /// [`common::LED_BUFFER_SIZE`] bytes
+    pub frame: Vec<u8>,
+    pub delay: Duration,
+}
+
+/// An animation, converted for the panel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Decoded {
+    /// Of the upload, before conversion
+    pub width: u32,
+    pub height: u32,
+    /// How many times to play it: `None` for forever
+    pub plays: Option<u32>,
+    pub frames: Vec<AnimationFrame>,
+}
+
+/// Decode a GIF or APNG, and convert each of its frames.
+pub fn decode(data: &[u8], conversion: &Conversion) -> Result<Decoded, AnimationError> {
+    let mut frames = Vec::new();
+    let mut add_frame = |image: DynamicImage, delay: Duration| {
+        if frames.len() == MAX_FRAMES {
+            return Err(AnimationError::TooManyFrames);
+        }
+        frames.push(AnimationFrame {
+            frame: to_frame(&image, conversion),
+            delay: delay.max(MIN_DELAY),
+        });
+        Ok(())
+    };
+
+    if data.starts_with(b"GIF8") {
+        let mut result = Ok(());
+        let header = gif::decode(data, |canvas, delay| {
+            if result.is_ok() {
+                result = add_frame(DynamicImage::ImageRgba8(canvas.clone()), delay);
+            }
+        })
+        .map_err(AnimationError::Gif)?;
+        result?;
+        return Ok(Decoded {
+            width: header.width.into(),
+            height: header.height.into(),
+            plays: header.plays,
+            frames,
+        });
+    }
+
+    if image::guess_format(data).ok() != Some(ImageFormat::Png) {
+        return Err(AnimationError::UnsupportedFormat);
+    }
+    let decoder = PngDecoder::new(Cursor::new(data)).map_err(AnimationError::Png)?;
+    if !decoder.is_apng().map_err(AnimationError::Png)? {
+        return Err(AnimationError::NotAnimated);
+    }
+    let (width, height) = decoder.dimensions();
+    if width > gif::MAX_DIMENSION.into() || height > gif::MAX_DIMENSION.into() {
+        return Err(AnimationError::TooLarge { width, height });
+    }
+    let decoder = decoder.apng().map_err(AnimationError::Png)?;
+    let plays = match decoder.loop_count() {
+        LoopCount::Infinite => None,
+        LoopCount::Finite(plays) => Some(plays.get()),
+    };
+    for frame in decoder.into_frames() {
+        let frame = frame.map_err(AnimationError::Png)?;
+        let (numer, denom) = frame.delay().numer_denom_ms();
+        let delay = Duration::from_micros(numer as u64 * 1000 / denom.max(1) as u64);
+        add_frame(DynamicImage::ImageRgba8(frame.into_buffer()), delay)?;
+    }
+    if frames.is_empty() {
+        return Err(AnimationError::NotAnimated);
+    }
+    Ok(Decoded {
+        width,
+        height,
+        plays,
+        frames,
+    })
+}
+
+/// What's known about a stored animation.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AnimationInfo {
+    pub id: String,
+    pub name: Option<String>,
+    /// Of the upload, before conversion
+    pub width: u32,
+    pub height: u32,
+    /// How many times it plays: `None` for forever
+    pub plays: Option<u32>,
+    /// How long each frame shows for
+    pub delays_ms: Vec<u64>,
+}
+
+#[derive(Debug)]
+pub struct Animation {
+    pub info: AnimationInfo,
+    pub frames: Vec<AnimationFrame>,
+}
+
+/// An animation's metadata in the library. Its data is the frames, one after the other.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct Meta {
+    width: u32,
+    height: u32,
+    plays: Option<u32>,
+    delays_ms: Vec<u64>,
+}
+
+impl From<&Content> for AnimationInfo {
+    fn from(content: &Content) -> Self {
+        // Written by `store`
+        let meta: Meta = serde_json::from_value(content.meta.clone()).unwrap_or_default();
+        Self {
+            id: content.id.clone(),
+            name: content.name.clone(),
+            width: meta.width,
+            height: meta.height,
+            plays: meta.plays,
+            delays_ms: meta.delays_ms,
+        }
+    }
+}
+
+/// Animation `id`, with its frames.
+pub fn load(db: &Database, id: &str) -> rusqlite::Result<Option<Animation>> {
+    let Some((content, data)) = db.get_with_data(Kind::Animation, id)? else {
+        return Ok(None);
+    };
+    let info = AnimationInfo::from(&content);
+    let frames = data
+        .as_chunks::<LED_BUFFER_SIZE>()
+        .0
+        .iter()
+        .zip(&info.delays_ms)
+        .map(|(frame, &delay)| AnimationFrame {
+            frame: frame.to_vec(),
+            delay: Duration::from_millis(delay),
+        })
+        .collect();
+    Ok(Some(Animation { info, frames }))
+}
+
+#[derive(Debug)]
+struct Playing {
+    target: Target,
+    /// The animation's, or the playlist's
+    id: String,
+    task: JoinHandle<()>,
+}
+
+/// The animations and playlists playing, at most one per target.
+#[derive(Debug, Default)]
+pub struct Players {
+    playing: Mutex<Vec<Playing>>,
+}
+
+impl Players {
+    /// Play `animation` on `target`, instead of whatever was playing there.
+    pub fn play(&self, state: &AppState, target: Target, animation: Arc<Animation>) {
+        info!("Playing animation {} on {target:?}", animation.info.id);
+        let id = animation.info.id.clone();
+        self.start(target.clone(), id, play(state.clone(), target, animation));
+    }
+
+    /// Run `task`, which plays `id` on `target`, instead of whatever was playing there.
+    pub(crate) fn start(
+        &self,
+        target: Target,
+        id: String,
+        task: impl Future<Output = ()> + Send + 'static,
+    ) {
+        let mut playing = self.playing.lock().unwrap();
+        playing.retain(|p| {
+            let keep = p.target != target && !p.task.is_finished();
+            if !keep {
+                p.task.abort();
+            }
+            keep
+        });
+        playing.push(Playing {
+            target,
+            id,
+            task: tokio::spawn(task),
+        });
+    }
+
+    /// The id of the animation or playlist playing on `target`.
+    pub fn playing(&self, target: &Target) -> Option<String> {
+        let playing = self.playing.lock().unwrap();
+        playing
+            .iter()
+            .find(|p| p.target == *target && !p.task.is_finished())
+            .map(|p| p.id.clone())
+    }
+
+    /// The targets playing animation or playlist `id`.
+    pub fn targets(&self, id: &str) -> Vec<Target> {
+        let playing = self.playing.lock().unwrap();
+        playing
+            .iter()
+            .filter(|p| p.id == id && !p.task.is_finished())
+            .map(|p| p.target.clone())
+            .collect()
+    }
+
+    /// Stop what's playing on `target`, returning whether there was anything.
+    pub fn stop(&self, target: &Target) -> bool {
+        self.stop_where(|p| p.target == *target)
+    }
+
+    /// Stop animation or playlist `id` wherever it's playing.
+    pub fn stop_content(&self, id: &str) {
+        self.stop_where(|p| p.id == id);
+    }
+
+    fn stop_where(&self, matches: impl Fn(&Playing) -> bool) -> bool {
+        let mut stopped = false;
+        self.playing.lock().unwrap().retain(|p| {
+            if !matches(p) {
+                return !p.task.is_finished();
+            }
+            stopped |= !p.task.is_finished();
+            p.task.abort();
+            false
+        });
+        stopped
+    }
+}
+
+async fn play(state: AppState, target: Target, animation: Arc<Animation>) {
+    let mut plays = 0;
+    let mut next = Instant::now();
+    loop {
+        for frame in &animation.frames {
+            if let Err(e) = stream::send_to(&state, &target, &frame.frame).await {
+                warn!("Animation {} stopped: {e}", animation.info.id);
+                return;
+            }
+            // Keeping to the file's timing, unless we've fallen behind
+            next = (next + frame.delay).max(Instant::now());
+            tokio::time::sleep_until(next).await;
+        }
+        plays += 1;
+        if animation.info.plays.is_some_and(|n| plays >= n) {
+            return;
+        }
+    }
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route(
+            "/api/animations",
+            get(get_animations)
+                .post(post_animation)
+                .layer(DefaultBodyLimit::max(MAX_UPLOAD_LEN)),
+        )
+        .route(
+            "/api/animations/{id}",
+            get(get_animation).delete(delete_animation),
+        )
+        .route("/api/animations/{id}/play", post(post_play))
+        .route("/api/animations/stop", post(post_stop))
+}
+
+async fn post_animation(
+    State(state): State<AppState>,
+    Query(query): Query<UploadQuery>,
+    data: Bytes,
+) -> Response {
+    let (conversion, target) = match query.parse() {
+        Ok(parsed) => parsed,
+        Err(e) => return e.into_response(),
+    };
+
+    // Every frame is resampled, which takes a while
+    let decoded = tokio::task::spawn_blocking(move || decode(&data, &conversion))
+        .await
+        .expect("animation conversion panicked");
+    let decoded = match decoded {
+        Ok(decoded) => decoded,
+        Err(e @ AnimationError::UnsupportedFormat) => {
+            return (StatusCode::UNSUPPORTED_MEDIA_TYPE, e.to_string()).into_response();
+        }
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+
+    match store(&state, query.name, decoded, target) {
+        Ok(info) => (StatusCode::CREATED, Json(info)).into_response(),
+        Err(e) => db::error_response(e),
+    }
+}
+
+/// Keep a converted animation in the library, playing it on `target` if given.
+pub(crate) fn store(
+    state: &AppState,
+    name: Option<String>,
+    decoded: Decoded,
+    target: Option<Target>,
+) -> rusqlite::Result<AnimationInfo> {
+    let animation = Arc::new(Animation {
+        info: AnimationInfo {
+            id: uuid::Uuid::new_v4().to_string(),
+            name,
+            width: decoded.width,
+            height: decoded.height,
+            plays: decoded.plays,
+            delays_ms: decoded
+                .frames
+                .iter()
+                .map(|f| f.delay.as_millis() as u64)
+                .collect(),
+        },
+        frames: decoded.frames,
+    });
+    let info = animation.info.clone();
+    info!(
+        "Stored animation {} ({}x{}, {} frames)",
+        info.id,
+        info.width,
+        info.height,
+        info.delays_ms.len()
+    );
+    let meta = Meta {
+        width: info.width,
+        height: info.height,
+        plays: info.plays,
+        delays_ms: info.delays_ms.clone(),
+    };
+    // Can't fail, it's numbers
+    let meta = serde_json::to_value(meta).unwrap();
+    let data: Vec<u8> = animation
+        .frames
+        .iter()
+        .flat_map(|f| f.frame.iter().copied())
+        .collect();
+    state.db.insert(
+        &info.id,
+        Kind::Animation,
+        info.name.as_deref(),
+        &meta,
+        &data,
+    )?;
+
+    homeassistant::republish_discovery(state);
+    if let Some(target) = target {
+        state.players.play(state, target, animation);
+    }
+    Ok(info)
+}
+
+async fn get_animations(State(state): State<AppState>) -> Response {
+    let search = Search {
+        kind: Some(Kind::Animation),
+        ..Default::default()
+    };
+    match state.db.search(&search) {
+        Ok(found) => {
+            Json(found.iter().map(AnimationInfo::from).collect::<Vec<_>>()).into_response()
+        }
+        Err(e) => db::error_response(e),
+    }
+}
+
+async fn get_animation(State(state): State<AppState>, Path(id): Path<String>) -> Response {
+    match state.db.get(Some(Kind::Animation), &id) {
+        Ok(Some(content)) => Json(AnimationInfo::from(&content)).into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => db::error_response(e),
+    }
+}
+
+pub(crate) fn required_target(query: TargetQuery) -> Result<Target, (StatusCode, &'static str)> {
+    query
+        .target()?
+        .ok_or((StatusCode::BAD_REQUEST, "give a device or a group"))
+}
+
+async fn post_play(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(query): Query<TargetQuery>,
+) -> Response {
+    let target = match required_target(query) {
+        Ok(target) => target,
+        Err(e) => return e.into_response(),
+    };
+    let animation = match load(&state.db, &id) {
+        Ok(Some(animation)) => animation,
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(e) => return db::error_response(e),
+    };
+    state.players.play(&state, target, Arc::new(animation));
+    StatusCode::ACCEPTED.into_response()
+}
+
+pub(crate) async fn post_stop(
+    State(state): State<AppState>,
+    Query(query): Query<TargetQuery>,
+) -> Response {
+    let target = match required_target(query) {
+        Ok(target) => target,
+        Err(e) => return e.into_response(),
+    };
+    if state.players.stop(&target) {
+        StatusCode::NO_CONTENT.into_response()
+    } else {
+        (StatusCode::NOT_FOUND, "nothing playing").into_response()
+    }
+}
+
+async fn delete_animation(State(state): State<AppState>, Path(id): Path<String>) -> Response {
+    match state.db.delete(Some(Kind::Animation), &id) {
+        Ok(true) => {
+            state.players.stop_content(&id);
+            homeassistant::republish_discovery(&state);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Ok(false) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => db::error_response(e),
+    }
+}
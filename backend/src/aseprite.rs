@@ -0,0 +1,335 @@
+//! Animations from Aseprite sprite sheets: the sheet's PNG and the JSON Aseprite exports with
+//! it (`File > Export Sprite Sheet`, with "JSON Data" and "Tags" checked).
+//!
+//! `POST /api/animations/aseprite` takes a JSON body:
+//!
+//! ```json
+//! {"image": "<the sheet PNG, base64>", "sheet": {"frames": .., "meta": {"frameTags": ..}}}
+//! ```
+//!
+//! and the query parameters `POST /api/animations` does (see [`animations`](crate::animations)),
+//! plus `tag` to play just that tag's frames. Both of Aseprite's layouts of `frames` are
+//! accepted - the array, and the hash in the order the frames are exported. Each frame is cut
+//! from its rect, put back where it was trimmed from, and shows for its own duration. A tag's
+//! frames play in its `direction` (`forward`, `reverse`, `pingpong` or `pingpong_reverse`),
+//! as many times as its `repeat` says, and forever otherwise.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::time::Duration;
+
+use axum::extract::{DefaultBodyLimit, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::{Json, Router};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use image::{DynamicImage, GenericImageView, RgbaImage, imageops};
+use serde::de::{MapAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+
+use crate::animations::{self, AnimationFrame, Decoded, MAX_FRAMES, MIN_DELAY};
+use crate::images::{self, Conversion, ConvertError, MAX_UPLOAD_LEN, UploadQuery, to_frame};
+use crate::{AppState, db, gif};
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    #[default]
+    Forward,
+    Reverse,
+    Pingpong,
+    PingpongReverse,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub w: u32,
+    pub h: u32,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Size {
+    pub w: u32,
+    pub h: u32,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Frame {
+    /// Where it is on the sheet
+    pub frame: Rect,
+    #[serde(default)]
+    pub rotated: bool,
+    #[serde(default)]
+    pub trimmed: bool,
+    /// Where `frame` goes in the untrimmed sprite
+    pub sprite_source_size: Option<Rect>,
+    /// The untrimmed sprite
+    pub source_size: Option<Size>,
+    /// In ms
+    pub duration: u32,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Tag {
+    pub name: String,
+    pub from: usize,
+    pub to: usize,
+    #[serde(default)]
+    pub direction: Direction,
+    /// How many times to play it, e.g. `"3"`. Absent or `"0"` for forever.
+    pub repeat: Option<String>,
+}
+
+impl Tag {
+    /// The frames it plays, in order.
+    pub fn sequence(&self) -> Vec<usize> {
+        let forward = self.from..=self.to;
+        // Ping-pong doesn't show the end frames twice in a row
+        let inner = (self.from + 1)..self.to;
+        match self.direction {
+            Direction::Forward => forward.collect(),
+            Direction::Reverse => forward.rev().collect(),
+            Direction::Pingpong => forward.chain(inner.rev()).collect(),
+            Direction::PingpongReverse => forward.rev().chain(inner).collect(),
+        }
+    }
+
+    pub fn plays(&self) -> Option<u32> {
+        self.repeat
+            .as_deref()
+            .and_then(|repeat| repeat.parse().ok())
+            .filter(|&plays| plays > 0)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Meta {
+    #[serde(default)]
+    pub frame_tags: Vec<Tag>,
+}
+
+/// Aseprite's JSON export.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Sheet {
+    #[serde(deserialize_with = "frames_in_order")]
+    pub frames: Vec<Frame>,
+    #[serde(default)]
+    pub meta: Meta,
+}
+
+/// The frames as an array, or as a hash in document order - frame names sort wrongly past 9.
+fn frames_in_order<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Frame>, D::Error> {
+    struct FramesVisitor;
+
+    impl<'de> Visitor<'de> for FramesVisitor {
+        type Value = Vec<Frame>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "an array or map of frames")
+        }
+
+        fn visit_seq<A: serde::de::SeqAccess<'de>>(
+            self,
+            mut seq: A,
+        ) -> Result<Vec<Frame>, A::Error> {
+            let mut frames = Vec::new();
+            while let Some(frame) = seq.next_element()? {
+                frames.push(frame);
+            }
+            Ok(frames)
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Vec<Frame>, A::Error> {
+            let mut frames = Vec::new();
+            while let Some((_name, frame)) = map.next_entry::<String, Frame>()? {
+                frames.push(frame);
+            }
+            Ok(frames)
+        }
+    }
+
+    deserializer.deserialize_any(FramesVisitor)
+}
+
+#[derive(Debug)]
+pub enum SheetError {
+    Image(ConvertError),
+    NoFrames,
+    TooManyFrames,
+    /// Aseprite doesn't rotate frames, but other packers do
+    Rotated(usize),
+    /// A frame's rect isn't on the sheet
+    OffSheet(usize),
+    /// A trimmed frame that doesn't fit back in its sprite
+    BadTrim(usize),
+    UnknownTag {
+        tag: String,
+        tags: Vec<String>,
+    },
+    /// A tag's frames aren't all in the sheet
+    BadTag(String),
+}
+
+impl fmt::Display for SheetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SheetError::Image(e) => write!(f, "sheet image: {e}"),
+            SheetError::NoFrames => write!(f, "no frames"),
+            SheetError::TooManyFrames => write!(f, "more than {MAX_FRAMES} frames"),
+            SheetError::Rotated(i) => write!(f, "frame {i} is rotated"),
+            SheetError::OffSheet(i) => write!(f, "frame {i} is off the sheet"),
+            SheetError::BadTrim(i) => write!(f, "frame {i} doesn't fit in its sprite"),
+            SheetError::UnknownTag { tag, tags } => {
+                write!(f, "no tag {tag:?}, the tags are {tags:?}")
+            }
+            SheetError::BadTag(tag) => write!(f, "tag {tag:?} has frames not in the sheet"),
+        }
+    }
+}
+
+impl std::error::Error for SheetError {}
+
+/// Cut frame `index` from the sheet, as the untrimmed sprite.
+fn sprite(sheet: &DynamicImage, frame: &Frame, index: usize) -> Result<RgbaImage, SheetError> {
+    if frame.rotated {
+        return Err(SheetError::Rotated(index));
+    }
+    let Rect { x, y, w, h } = frame.frame;
+    let fits =
+        |start: u32, len: u32, limit: u32| start.checked_add(len).is_some_and(|end| end <= limit);
+    if w == 0 || h == 0 || !fits(x, w, sheet.width()) || !fits(y, h, sheet.height()) {
+        return Err(SheetError::OffSheet(index));
+    }
+    let cut = sheet.view(x, y, w, h).to_image();
+    let (Some(source), Some(size), true) =
+        (frame.sprite_source_size, frame.source_size, frame.trimmed)
+    else {
+        return Ok(cut);
+    };
+    let too_large =
+        |size: Size| size.w > gif::MAX_DIMENSION.into() || size.h > gif::MAX_DIMENSION.into();
+    if too_large(size) || !fits(source.x, w, size.w) || !fits(source.y, h, size.h) {
+        return Err(SheetError::BadTrim(index));
+    }
+    let mut sprite = RgbaImage::new(size.w, size.h);
+    imageops::replace(&mut sprite, &cut, source.x.into(), source.y.into());
+    Ok(sprite)
+}
+
+/// Decode the sheet `png` and convert the frames of `tag`, or of the whole sheet.
+pub fn decode(
+    png: &[u8],
+    sheet: &Sheet,
+    tag: Option<&str>,
+    conversion: &Conversion,
+) -> Result<Decoded, SheetError> {
+    if sheet.frames.is_empty() {
+        return Err(SheetError::NoFrames);
+    }
+    let (sequence, plays) = match tag {
+        None => ((0..sheet.frames.len()).collect(), None),
+        Some(name) => {
+            let tags = &sheet.meta.frame_tags;
+            let Some(tag) = tags.iter().find(|t| t.name == name) else {
+                return Err(SheetError::UnknownTag {
+                    tag: name.to_string(),
+                    tags: tags.iter().map(|t| t.name.clone()).collect(),
+                });
+            };
+            if tag.from > tag.to || tag.to >= sheet.frames.len() {
+                return Err(SheetError::BadTag(tag.name.clone()));
+            }
+            (tag.sequence(), tag.plays())
+        }
+    };
+    if sequence.len() > MAX_FRAMES {
+        return Err(SheetError::TooManyFrames);
+    }
+
+    let image = images::decode(png).map_err(SheetError::Image)?;
+    // Each frame converted once, however often it plays
+    let used: HashSet<usize> = sequence.iter().copied().collect();
+    let mut converted = vec![None; sheet.frames.len()];
+    for &index in &used {
+        let sprite = sprite(&image, &sheet.frames[index], index)?;
+        converted[index] = Some(to_frame(&DynamicImage::ImageRgba8(sprite), conversion));
+    }
+
+    let first = &sheet.frames[sequence[0]];
+    let size = first.source_size.unwrap_or(Size {
+        w: first.frame.w,
+        h: first.frame.h,
+    });
+    Ok(Decoded {
+        width: size.w,
+        height: size.h,
+        plays,
+        frames: sequence
+            .into_iter()
+            .map(|index| AnimationFrame {
+                // Converted above
+                frame: converted[index].clone().unwrap(),
+                delay: Duration::from_millis(sheet.frames[index].duration.into()).max(MIN_DELAY),
+            })
+            .collect(),
+    })
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AsepriteUpload {
+    /// The sheet PNG, base64
+    pub image: String,
+    pub sheet: Sheet,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct TagQuery {
+    pub tag: Option<String>,
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new().route(
+        "/api/animations/aseprite",
+        // Base64 is a third larger
+        post(post_aseprite).layer(DefaultBodyLimit::max(MAX_UPLOAD_LEN * 2)),
+    )
+}
+
+async fn post_aseprite(
+    State(state): State<AppState>,
+    Query(query): Query<UploadQuery>,
+    Query(TagQuery { tag }): Query<TagQuery>,
+    Json(upload): Json<AsepriteUpload>,
+) -> Response {
+    let (conversion, target) = match query.parse() {
+        Ok(parsed) => parsed,
+        Err(e) => return e.into_response(),
+    };
+    let Ok(png) = BASE64.decode(upload.image.as_bytes()) else {
+        return (StatusCode::BAD_REQUEST, "image isn't base64").into_response();
+    };
+
+    let decoded = tokio::task::spawn_blocking(move || {
+        decode(&png, &upload.sheet, tag.as_deref(), &conversion)
+    })
+    .await
+    .expect("sprite sheet conversion panicked");
+    let decoded = match decoded {
+        Ok(decoded) => decoded,
+        Err(e @ SheetError::Image(ConvertError::UnsupportedFormat)) => {
+            return (StatusCode::UNSUPPORTED_MEDIA_TYPE, e.to_string()).into_response();
+        }
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+
+    match animations::store(&state, query.name, decoded, target) {
+        Ok(info) => (StatusCode::CREATED, Json(info)).into_response(),
+        Err(e) => db::error_response(e),
+    }
+}
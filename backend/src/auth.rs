@@ -0,0 +1,598 @@
+//! User accounts, API tokens, and what each [`Role`] may do.
+//!
+//! Until the first user is added the backend is open, as it was before there were accounts:
+//! anyone who can reach it may do anything. The first user is always an admin. From then on
+//! every route (but logging in and out) needs a [`Caller`], who is either:
+//!
+//! - logged in, with the `session` cookie `POST /api/login` (a [`Login`]) sets, until
+//!   `POST /api/logout` or [`SESSION_TTL`] - as the frontend is
+//! - a script, with `Authorization: Bearer <token>`, an API token from `POST /api/tokens`
+//!
+//! What a caller may do depends on their role:
+//!
+//! - a viewer may look: `GET` routes, and the WebSocket's device updates and guest previews
+//! - a painter may also change what devices show: every other route and WebSocket message
+//! - an admin may also manage users
+//!
+//! Admins manage users with `GET`/`POST /api/users` (`{"name", "password", "role"}`),
+//! `PATCH /api/users/{name}` (`{"password"}` and/or `{"role"}`) and `DELETE /api/users/{name}`,
+//! but can't remove or demote the last admin. A new password ends the user's sessions.
+//! `GET /api/me` is the caller.
+//!
+//! Users make their own tokens with `POST /api/tokens` (`{"name", "role"}`), list them with
+//! `GET /api/tokens` and revoke them with `DELETE /api/tokens/{id}`; admins see everyone's. A
+//! token is scoped to a role, at most its user's, and acts with the lower of that and its user's
+//! current role. It's only shown when it's made.
+//!
+//! Stock WLED apps can't log in or give a token, so the `wled.role` setting gives callers without
+//! either that role on the WLED JSON API (`/json/...`, see [`wled`](crate::wled)). Without it,
+//! the WLED API is checked like any other route. WLED realtime UDP isn't covered at all.
+//!
+//! An open WebSocket's caller is checked again before each message from it, and every
+//! [`RECHECK_INTERVAL`], so a logout, a revoked token or a lower role soon applies to it too;
+//! a socket whose caller is gone is closed.
+//!
+//! Passwords are kept as PBKDF2-HMAC-SHA256 hashes, and session ids and tokens as SHA-256
+//! hashes. The cookie isn't `Secure`, as the backend serves plain HTTP.
+
+use std::num::NonZeroU32;
+use std::str::FromStr;
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use axum::extract::{Path, Request, State};
+use axum::http::{HeaderMap, Method, StatusCode, header};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, patch, post};
+use axum::{Extension, Json, Router};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::{digest, pbkdf2};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+use web_common::Login;
+
+use crate::db::{self, Database};
+use crate::{AppState, now_ms, wled};
+
+pub const SESSION_COOKIE: &str = "session";
+pub const SESSION_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Of users and tokens
+pub const MAX_NAME_LEN: usize = 64;
+pub const MIN_PASSWORD_LEN: usize = 8;
+
+/// As OWASP recommends for PBKDF2-HMAC-SHA256
+pub const PBKDF2_ITERATIONS: u32 = 600_000;
+const HASH_SCHEME: &str = "pbkdf2-sha256";
+
+/// So tokens are easy to spot, e.g. in a script checked in by mistake
+pub const TOKEN_PREFIX: &str = "elt_";
+
+/// How often an open WebSocket's caller is checked again, if it sends nothing
+pub const RECHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// What a caller may do. Each role may do everything the ones before it may.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Viewer,
+    Painter,
+    Admin,
+}
+
+impl Role {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Painter => "painter",
+            Role::Admin => "admin",
+        }
+    }
+
+    pub(crate) fn parse(role: &str) -> Option<Self> {
+        match role {
+            "viewer" => Some(Role::Viewer),
+            "painter" => Some(Role::Painter),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = ();
+
+    fn from_str(role: &str) -> Result<Self, ()> {
+        Role::parse(role).ok_or(())
+    }
+}
+
+/// Who's making a request.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Caller {
+    /// `None` while there are no users
+    pub user: Option<String>,
+    pub role: Role,
+}
+
+impl Caller {
+    pub fn may(&self, role: Role) -> bool {
+        self.role >= role
+    }
+
+    /// Whose tokens they may see and revoke: `None` for everyone's.
+    fn token_owner(&self) -> Option<&str> {
+        if self.may(Role::Admin) {
+            None
+        } else {
+            self.user.as_deref()
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct User {
+    pub name: String,
+    pub role: Role,
+    pub created_ms: u64,
+}
+
+/// An API token, without the token itself.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    pub id: String,
+    pub name: String,
+    pub user: String,
+    /// The most it may do
+    pub role: Role,
+    pub created_ms: u64,
+}
+
+/// A token just made, the only time it's shown.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NewToken {
+    #[serde(flatten)]
+    pub info: Token,
+    pub token: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NewUser {
+    pub name: String,
+    pub password: String,
+    pub role: Role,
+}
+
+/// Changes to a user. What isn't given is left as it is.
+#[derive(Deserialize, Debug, Default)]
+pub struct UserUpdate {
+    pub password: Option<String>,
+    pub role: Option<Role>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TokenRequest {
+    pub name: String,
+    /// The caller's own, if not given
+    pub role: Option<Role>,
+}
+
+/// A hash of `password` with a random salt, as `pbkdf2-sha256$iterations$salt$hash`. Slow, on
+/// purpose.
+pub fn hash_password(password: &str) -> String {
+    let salt = random::<16>();
+    let mut hash = [0; digest::SHA256_OUTPUT_LEN];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        NonZeroU32::new(PBKDF2_ITERATIONS).unwrap(),
+        &salt,
+        password.as_bytes(),
+        &mut hash,
+    );
+    format!(
+        "{HASH_SCHEME}${PBKDF2_ITERATIONS}${}${}",
+        hex::encode(salt),
+        hex::encode(hash)
+    )
+}
+
+/// Whether `password` is the one `hash` was made from by [`hash_password`].
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    let mut parts = hash.split('$');
+    let (Some(HASH_SCHEME), Some(iterations), Some(salt), Some(hash), None) = (
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+    ) else {
+        return false;
+    };
+    let (Some(iterations), Ok(salt), Ok(hash)) = (
+        iterations.parse().ok().and_then(NonZeroU32::new),
+        hex::decode(salt),
+        hex::decode(hash),
+    ) else {
+        return false;
+    };
+    pbkdf2::verify(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        &salt,
+        password.as_bytes(),
+        &hash,
+    )
+    .is_ok()
+}
+
+async fn hash_password_blocking(password: String) -> String {
+    tokio::task::spawn_blocking(move || hash_password(&password))
+        .await
+        .expect("password hashing panicked")
+}
+
+/// How a session id or token is kept, so the database alone can't be used to log in.
+pub fn secret_hash(secret: &str) -> String {
+    hex::encode(digest::digest(&digest::SHA256, secret.as_bytes()))
+}
+
+fn random<const N: usize>() -> [u8; N] {
+    let mut bytes = [0; N];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("no randomness from the OS");
+    bytes
+}
+
+/// The `Authorization: Bearer` token in `headers`.
+fn bearer(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+/// Cookie `name` in `headers`.
+fn cookie<'h>(headers: &'h HeaderMap, name: &str) -> Option<&'h str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .find_map(|cookie| cookie.trim().strip_prefix(name)?.strip_prefix('='))
+}
+
+/// The caller making a request with `headers`, or `None` if they haven't logged in or given a
+/// token that's still good.
+pub fn caller(db: &Database, headers: &HeaderMap) -> rusqlite::Result<Option<Caller>> {
+    if !db.has_users()? {
+        return Ok(Some(Caller {
+            user: None,
+            role: Role::Admin,
+        }));
+    }
+    if let Some(token) = bearer(headers) {
+        return Ok(db
+            .token_user(&secret_hash(token))?
+            .map(|(token, user_role)| Caller {
+                user: Some(token.user),
+                role: token.role.min(user_role),
+            }));
+    }
+    if let Some(session) = cookie(headers, SESSION_COOKIE) {
+        return Ok(db
+            .session_user(&secret_hash(session), now_ms())?
+            .map(|(user, role)| Caller {
+                user: Some(user),
+                role,
+            }));
+    }
+    Ok(None)
+}
+
+/// The role a request needs, or `None` for logging in and out.
+pub fn required_role(method: &Method, path: &str) -> Option<Role> {
+    let under = |prefix: &str| {
+        path.strip_prefix(prefix)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    };
+    if path == "/api/login" || path == "/api/logout" {
+        None
+    } else if under("/api/users") {
+        Some(Role::Admin)
+    } else if path == "/api/me"
+        || under("/api/tokens")
+        || method == Method::GET
+        || method == Method::HEAD
+    {
+        // Anyone may see who they are and manage their own tokens
+        Some(Role::Viewer)
+    } else {
+        Some(Role::Painter)
+    }
+}
+
+/// Middleware: refuse a request whose caller's role isn't enough for it, and give the rest
+/// their [`Caller`] as an extension.
+pub async fn authorise(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let caller = match caller(&state.db, request.headers()) {
+        Ok(None) if wled::is_api(request.uri().path()) => {
+            state.wled_role.map(|role| Caller { user: None, role })
+        }
+        Ok(caller) => caller,
+        Err(e) => return db::error_response(e),
+    };
+    if let Some(role) = required_role(request.method(), request.uri().path()) {
+        match &caller {
+            None => {
+                return (
+                    StatusCode::UNAUTHORIZED,
+                    [(header::WWW_AUTHENTICATE, "Bearer")],
+                    "log in or give an API token",
+                )
+                    .into_response();
+            }
+            Some(caller) if !caller.may(role) => {
+                return (
+                    StatusCode::FORBIDDEN,
+                    format!("needs the {} role", role.as_str()),
+                )
+                    .into_response();
+            }
+            Some(_) => {}
+        }
+    }
+    if let Some(caller) = caller {
+        request.extensions_mut().insert(caller);
+    }
+    next.run(request).await
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/api/login", post(post_login))
+        .route("/api/logout", post(post_logout))
+        .route("/api/me", get(get_me))
+        .route("/api/users", get(get_users).post(post_user))
+        .route("/api/users/{name}", patch(patch_user).delete(delete_user))
+        .route("/api/tokens", get(get_tokens).post(post_token))
+        .route("/api/tokens/{id}", delete(delete_token))
+}
+
+fn check_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        Err(format!("name must be 1 to {MAX_NAME_LEN} bytes"))
+    } else {
+        Ok(())
+    }
+}
+
+fn check_password(password: &str) -> Result<(), String> {
+    if password.chars().count() < MIN_PASSWORD_LEN {
+        Err(format!(
+            "password must be at least {MIN_PASSWORD_LEN} characters"
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+fn session_cookie(session: &str, max_age: Duration) -> String {
+    format!(
+        "{SESSION_COOKIE}={session}; Path=/; Max-Age={}; HttpOnly; SameSite=Strict",
+        max_age.as_secs()
+    )
+}
+
+/// Checked against for names that aren't users, so a failed login takes as long either way
+static NO_USER_HASH: LazyLock<String> = LazyLock::new(|| hash_password(""));
+
+async fn post_login(State(state): State<AppState>, Json(login): Json<Login>) -> Response {
+    let found = match state.db.user_password(&login.name) {
+        Ok(found) => found,
+        Err(e) => return db::error_response(e),
+    };
+    let hash = found.as_ref().map(|(_, hash)| hash.clone());
+    let password = login.password;
+    // The first use of `NO_USER_HASH` makes it, which is as slow as checking it
+    let verified = tokio::task::spawn_blocking(move || {
+        verify_password(&password, hash.as_deref().unwrap_or(&NO_USER_HASH))
+    })
+    .await
+    .expect("password check panicked");
+    let Some((role, _)) = found.filter(|_| verified) else {
+        warn!("Failed login as {}", login.name);
+        return (StatusCode::UNAUTHORIZED, "wrong name or password").into_response();
+    };
+
+    let session = hex::encode(random::<32>());
+    let expires_ms = now_ms() + SESSION_TTL.as_millis() as u64;
+    if let Err(e) = state
+        .db
+        .insert_session(&secret_hash(&session), &login.name, expires_ms)
+    {
+        return db::error_response(e);
+    }
+    info!("{} logged in", login.name);
+    (
+        [(header::SET_COOKIE, session_cookie(&session, SESSION_TTL))],
+        Json(Caller {
+            user: Some(login.name),
+            role,
+        }),
+    )
+        .into_response()
+}
+
+async fn post_logout(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if let Some(session) = cookie(&headers, SESSION_COOKIE)
+        && let Err(e) = state.db.delete_session(&secret_hash(session))
+    {
+        return db::error_response(e);
+    }
+    (
+        StatusCode::NO_CONTENT,
+        [(header::SET_COOKIE, session_cookie("", Duration::ZERO))],
+    )
+        .into_response()
+}
+
+async fn get_me(Extension(caller): Extension<Caller>) -> Json<Caller> {
+    Json(caller)
+}
+
+async fn get_users(State(state): State<AppState>) -> Response {
+    match state.db.users() {
+        Ok(users) => Json(users).into_response(),
+        Err(e) => db::error_response(e),
+    }
+}
+
+/// Whether `name` is the only admin, who mustn't be removed or demoted.
+fn last_admin(db: &Database, name: &str) -> rusqlite::Result<bool> {
+    let admins: Vec<_> = db
+        .users()?
+        .into_iter()
+        .filter(|user| user.role == Role::Admin)
+        .collect();
+    Ok(matches!(admins.as_slice(), [admin] if admin.name == name))
+}
+
+async fn post_user(State(state): State<AppState>, Json(user): Json<NewUser>) -> Response {
+    if let Err(e) = check_name(&user.name).and(check_password(&user.password)) {
+        return (StatusCode::BAD_REQUEST, e).into_response();
+    }
+    let role = match state.db.has_users() {
+        Ok(true) => user.role,
+        // Or nobody could manage users
+        Ok(false) => Role::Admin,
+        Err(e) => return db::error_response(e),
+    };
+    let password_hash = hash_password_blocking(user.password).await;
+    match state.db.insert_user(&user.name, role, &password_hash) {
+        Ok(Some(created)) => {
+            info!("Added {} user {}", role.as_str(), created.name);
+            (StatusCode::CREATED, Json(created)).into_response()
+        }
+        Ok(None) => (StatusCode::CONFLICT, "name is taken").into_response(),
+        Err(e) => db::error_response(e),
+    }
+}
+
+async fn patch_user(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Json(update): Json<UserUpdate>,
+) -> Response {
+    if let Some(password) = &update.password
+        && let Err(e) = check_password(password)
+    {
+        return (StatusCode::BAD_REQUEST, e).into_response();
+    }
+    if update.role.is_some_and(|role| role != Role::Admin) {
+        match last_admin(&state.db, &name) {
+            Ok(true) => return (StatusCode::CONFLICT, "the last admin").into_response(),
+            Ok(false) => {}
+            Err(e) => return db::error_response(e),
+        }
+    }
+    let password_hash = match update.password {
+        Some(password) => Some(hash_password_blocking(password).await),
+        None => None,
+    };
+    match state
+        .db
+        .update_user(&name, update.role, password_hash.as_deref())
+    {
+        Ok(Some(user)) => Json(user).into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => db::error_response(e),
+    }
+}
+
+async fn delete_user(State(state): State<AppState>, Path(name): Path<String>) -> Response {
+    match last_admin(&state.db, &name) {
+        Ok(true) => return (StatusCode::CONFLICT, "the last admin").into_response(),
+        Ok(false) => {}
+        Err(e) => return db::error_response(e),
+    }
+    match state.db.delete_user(&name) {
+        Ok(true) => {
+            info!("Removed user {name}");
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Ok(false) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => db::error_response(e),
+    }
+}
+
+async fn get_tokens(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+) -> Response {
+    match state.db.tokens(caller.token_owner()) {
+        Ok(tokens) => Json(tokens).into_response(),
+        Err(e) => db::error_response(e),
+    }
+}
+
+async fn post_token(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    Json(request): Json<TokenRequest>,
+) -> Response {
+    let Some(user) = caller.user.clone() else {
+        return (
+            StatusCode::CONFLICT,
+            "there are no users to make tokens for",
+        )
+            .into_response();
+    };
+    if let Err(e) = check_name(&request.name) {
+        return (StatusCode::BAD_REQUEST, e).into_response();
+    }
+    let role = request.role.unwrap_or(caller.role);
+    if !caller.may(role) {
+        return (
+            StatusCode::FORBIDDEN,
+            format!(
+                "a {} can't make {} tokens",
+                caller.role.as_str(),
+                role.as_str()
+            ),
+        )
+            .into_response();
+    }
+
+    let token = format!("{TOKEN_PREFIX}{}", hex::encode(random::<32>()));
+    let info = Token {
+        id: uuid::Uuid::new_v4().to_string(),
+        name: request.name,
+        user,
+        role,
+        created_ms: now_ms(),
+    };
+    if let Err(e) = state.db.insert_token(&info, &secret_hash(&token)) {
+        return db::error_response(e);
+    }
+    info!("{} made {} token {}", info.user, role.as_str(), info.name);
+    (StatusCode::CREATED, Json(NewToken { info, token })).into_response()
+}
+
+async fn delete_token(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<String>,
+) -> Response {
+    match state.db.delete_token(&id, caller.token_owner()) {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => db::error_response(e),
+    }
+}
@@ -0,0 +1,71 @@
+//! Where the MQTT broker is and how to authenticate to it, as [`config`](crate::config)
+//! gives it: a host and port, optionally a username and password, and optionally a PEM CA
+//! certificate to connect with TLS, trusting only it.
+//!
+//! Trusting only the given CA pins the broker to certificates it issued, such as those made
+//! by `mosquitto/setup.sh`.
+
+use std::fmt;
+
+use rumqttc::{MqttOptions, TlsConfiguration, Transport};
+
+use crate::config::{Config, ConfigError};
+
+pub const PORT: u16 = 1883;
+pub const TLS_PORT: u16 = 8883;
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct Broker {
+    pub host: String,
+    pub port: u16,
+    /// Username and password
+    pub credentials: Option<(String, String)>,
+    /// PEM CA certificate the broker's certificate must be issued by. Connects with TLS if set.
+    pub ca: Option<Vec<u8>>,
+}
+
+impl Default for Broker {
+    fn default() -> Self {
+        Self {
+            host: "localhost".to_string(),
+            port: PORT,
+            credentials: None,
+            ca: None,
+        }
+    }
+}
+
+// Without the password
+impl fmt::Debug for Broker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Broker")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("username", &self.credentials.as_ref().map(|(user, _)| user))
+            .field("tls", &self.ca.is_some())
+            .finish()
+    }
+}
+
+impl Broker {
+    /// The broker the `MQTT_*` environment variables describe.
+    pub fn from_env() -> Result<Self, ConfigError> {
+        Config::from_env().map(|config| config.broker)
+    }
+
+    /// Client options connecting to this broker, with its credentials and TLS.
+    pub fn options(&self, client_id: &str) -> MqttOptions {
+        let mut opts = MqttOptions::new(client_id, &self.host, self.port);
+        if let Some((username, password)) = &self.credentials {
+            opts.set_credentials(username, password);
+        }
+        if let Some(ca) = &self.ca {
+            opts.set_transport(Transport::tls_with_config(TlsConfiguration::Simple {
+                ca: ca.clone(),
+                alpn: None,
+                client_auth: None,
+            }));
+        }
+        opts
+    }
+}
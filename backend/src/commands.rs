@@ -0,0 +1,267 @@
+//! Acknowledged device commands.
+//!
+//! Commands are published with QoS 1 on a device's command topic in a [`CommandEnvelope`] with
+//! a fresh id, unique across devices, and a `sender` picked at random when the backend starts,
+//! so that devices don't take a restarted backend's commands for redeliveries. The device's
+//! [`CommandAck`]s arrive on its ack topic and are broadcast to WebSocket clients, and also
+//! handed to whoever is waiting on that id (the REST API).
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::{Json, Router, routing::post};
+use protocol::topics::suffix;
+use protocol::{Command, CommandAck, CommandEnvelope};
+use rumqttc::QoS;
+use serde::Serialize;
+use tokio::sync::oneshot;
+use tokio::task::JoinSet;
+use tracing::{info, warn};
+use web_common::{ServerMsg, Target};
+
+use crate::AppState;
+
+/// How long REST callers wait for the device to acknowledge a command
+pub const ACK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Command ids, and the callers waiting on their acks.
+#[derive(Debug)]
+pub struct PendingCommands {
+    /// Never 0, which is what devices see from senders that don't set one
+    sender: u32,
+    next_id: AtomicU32,
+    waiting: Mutex<HashMap<u32, oneshot::Sender<CommandAck>>>,
+}
+
+impl Default for PendingCommands {
+    /// Ids start at a random point too, so that acks for another backend's commands are
+    /// unlikely to be taken for ours.
+    fn default() -> Self {
+        let random = uuid::Uuid::new_v4().as_u128();
+        Self {
+            sender: (random as u32).max(1),
+            next_id: AtomicU32::new((random >> 32) as u32),
+            waiting: Mutex::default(),
+        }
+    }
+}
+
+impl PendingCommands {
+    fn next_id(&self) -> u32 {
+        // Never 0, so that a default-initialised id is never mistaken for a real one
+        loop {
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed).wrapping_add(1);
+            if id != 0 {
+                return id;
+            }
+        }
+    }
+
+    fn wait_for(&self, id: u32) -> oneshot::Receiver<CommandAck> {
+        let (tx, rx) = oneshot::channel();
+        self.waiting.lock().unwrap().insert(id, tx);
+        rx
+    }
+
+    fn forget(&self, id: u32) {
+        self.waiting.lock().unwrap().remove(&id);
+    }
+
+    fn resolve(&self, ack: CommandAck) {
+        if let Some(tx) = self.waiting.lock().unwrap().remove(&ack.id) {
+            let _ = tx.send(ack);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CommandSendError {
+    Mqtt(rumqttc::ClientError),
+    /// No ack within [`ACK_TIMEOUT`]
+    Timeout {
+        id: u32,
+    },
+}
+
+impl fmt::Display for CommandSendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandSendError::Mqtt(e) => write!(f, "MQTT publish failed: {e}"),
+            CommandSendError::Timeout { id } => write!(f, "no ack for command #{id}"),
+        }
+    }
+}
+
+impl std::error::Error for CommandSendError {}
+
+/// Publish `command` to a device in an envelope, returning its id. The ack is only broadcast.
+pub async fn send(
+    state: &AppState,
+    device_id: &str,
+    command: Command,
+) -> Result<u32, CommandSendError> {
+    let id = state.commands.next_id();
+    publish(state, device_id, id, command).await?;
+    Ok(id)
+}
+
+/// [`send`] `command` to every device `target` refers to, returning each device's result.
+pub async fn send_to(
+    state: &AppState,
+    target: &Target,
+    command: Command,
+) -> Vec<(String, Result<u32, CommandSendError>)> {
+    let device_ids = state.devices.read().unwrap().resolve(target);
+    let mut results = Vec::with_capacity(device_ids.len());
+    for device_id in device_ids {
+        let result = send(state, &device_id, command.clone()).await;
+        results.push((device_id, result));
+    }
+    results
+}
+
+/// Publish `command` to a device in an envelope and wait for it to acknowledge it.
+pub async fn send_and_wait(
+    state: &AppState,
+    device_id: &str,
+    command: Command,
+    timeout: Duration,
+) -> Result<CommandAck, CommandSendError> {
+    let id = state.commands.next_id();
+    let ack = state.commands.wait_for(id);
+
+    let result = match publish(state, device_id, id, command).await {
+        Ok(()) => tokio::time::timeout(timeout, ack)
+            .await
+            .ok()
+            .and_then(Result::ok)
+            .ok_or(CommandSendError::Timeout { id }),
+        Err(e) => Err(e),
+    };
+    if result.is_err() {
+        state.commands.forget(id);
+    }
+    result
+}
+
+async fn publish(
+    state: &AppState,
+    device_id: &str,
+    id: u32,
+    command: Command,
+) -> Result<(), CommandSendError> {
+    info!("Sending command #{id} to {device_id}: {command:?}");
+    let envelope = CommandEnvelope {
+        sender: state.commands.sender,
+        id,
+        reply_to: None,
+        command,
+    };
+    let payload = serde_json::to_vec(&envelope).unwrap();
+    state
+        .mqtt_client
+        .publish(
+            state.topics.device(device_id, suffix::COMMAND),
+            QoS::AtLeastOnce,
+            false,
+            payload,
+        )
+        .await
+        .map_err(CommandSendError::Mqtt)
+}
+
+/// Handle a payload on a device's ack topic.
+pub fn handle_ack(state: &AppState, device_id: &str, payload: &[u8]) {
+    let Ok(ack) = serde_json::from_slice::<CommandAck>(payload) else {
+        warn!(
+            "Invalid command ack: {:?}",
+            String::from_utf8_lossy(payload)
+        );
+        return;
+    };
+    if let Some(error) = ack.error {
+        warn!("{device_id} rejected command #{}: {error:?}", ack.id);
+    }
+    state.commands.resolve(ack);
+    // No WebSocket clients is fine
+    let _ = state.tx.send(ServerMsg::CommandAck {
+        device_id: device_id.to_string(),
+        id: ack.id,
+        error: ack.error,
+    });
+}
+
+/// `POST /api/devices/{device_id}/command` - send a [`Command`] and wait for the device's ack.
+///
+/// `POST /api/groups/{group}/command` - send a [`Command`] to every device in the group, and
+/// wait for all of their acks. Always 200, with a [`GroupResult`] per device.
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route(
+            "/api/devices/{device_id}/command",
+            post(post_device_command),
+        )
+        .route("/api/groups/{group}/command", post(post_group_command))
+}
+
+/// One device's outcome of a group command: `ack` if it answered, otherwise `error`.
+#[derive(Debug, Serialize)]
+pub struct GroupResult {
+    pub device_id: String,
+    pub ack: Option<CommandAck>,
+    pub error: Option<String>,
+}
+
+async fn post_device_command(
+    State(state): State<AppState>,
+    Path(device_id): Path<String>,
+    Json(command): Json<Command>,
+) -> Response {
+    ack_response(send_and_wait(&state, &device_id, command, ACK_TIMEOUT).await)
+}
+
+/// The HTTP response for the outcome of [`send_and_wait`]: 200 with the ack, 422 with the ack
+/// if the device rejected the command, 504 if it didn't answer and 502 if it wasn't sent.
+pub fn ack_response(result: Result<CommandAck, CommandSendError>) -> Response {
+    match result {
+        Ok(ack) if ack.is_ok() => (StatusCode::OK, Json(ack)).into_response(),
+        Ok(ack) => (StatusCode::UNPROCESSABLE_ENTITY, Json(ack)).into_response(),
+        Err(e @ CommandSendError::Timeout { .. }) => {
+            (StatusCode::GATEWAY_TIMEOUT, e.to_string()).into_response()
+        }
+        Err(e) => (StatusCode::BAD_GATEWAY, e.to_string()).into_response(),
+    }
+}
+
+async fn post_group_command(
+    State(state): State<AppState>,
+    Path(group): Path<String>,
+    Json(command): Json<Command>,
+) -> impl IntoResponse {
+    let device_ids = state.devices.read().unwrap().resolve(&Target::Group(group));
+
+    // Wait for the acks concurrently, so a missing device costs one timeout rather than one each
+    let mut pending = JoinSet::new();
+    for device_id in device_ids {
+        let state = state.clone();
+        let command = command.clone();
+        pending.spawn(async move {
+            let result = send_and_wait(&state, &device_id, command, ACK_TIMEOUT).await;
+            GroupResult {
+                device_id,
+                error: result.as_ref().err().map(ToString::to_string),
+                ack: result.ok(),
+            }
+        });
+    }
+
+    let mut results = pending.join_all().await;
+    results.sort_by(|a, b| a.device_id.cmp(&b.device_id));
+    Json(results)
+}
@@ -0,0 +1,491 @@
+//! The backend's settings, in layers: the defaults, then a TOML file, then the environment,
+//! then command-line flags, each overriding the ones before. All are checked at startup, and
+//! a bad one is reported by where it was given, e.g. `MQTT_PORT "x" is not a port number`.
+//!
+//! The file is the one `--config` or `CONFIG_FILE` names, or else [`DEFAULT_FILE`] if there is
+//! one. Empty environment variables count as unset.
+//!
+//! | file key            | variable         | flag               | default                  |
+//! |---------------------|------------------|--------------------|--------------------------|
+//! | `mqtt.host`         | `MQTT_HOST`      | `--mqtt-host`      | `localhost`              |
+//! | `mqtt.port`         | `MQTT_PORT`      | `--mqtt-port`      | 1883, or 8883 with TLS   |
+//! | `mqtt.username`     | `MQTT_USERNAME`  | `--mqtt-username`  | none                     |
+//! | `mqtt.password`     | `MQTT_PASSWORD`  |                    | none                     |
+//! | `mqtt.ca_file`      | `MQTT_CA_FILE`   | `--mqtt-ca-file`   | none                     |
+//! | `mqtt.client_id`    | `MQTT_CLIENT_ID` | `--mqtt-client-id` | `egui-axum-mqtt-backend` |
+//! | `mqtt.topic_prefix` | `TOPIC_PREFIX`   | `--topic-prefix`   | `esp32-wasmi-led`        |
+//! | `http.bind`         | `BIND_ADDR`      | `--bind`           | `0.0.0.0:3000`           |
+//! | `http.static_dir`   | `STATIC_DIR`     | `--static-dir`     | `dist`                   |
+//! | `wled.port`         | `WLED_PORT`      | `--wled-port`      | 21324                    |
+//! | `wled.role`         | `WLED_ROLE`      | `--wled-role`      | none                     |
+//! | `database.path`     | `DATABASE_PATH`  | `--database`       | `esp32-wasmi-led.db`     |
+//! | `log.level`         | `LOG_LEVEL`      | `--log-level`      | `info`                   |
+//!
+//! `wled.role` is the role callers without credentials get on the WLED JSON API, once there are
+//! users (see [`auth`](crate::auth)). The username and password are given together. The password has no flag, as other users can
+//! see a process's command line. With a CA file, the backend connects with TLS, trusting only
+//! that CA (see [`broker`](crate::broker)).
+//!
+//! For example:
+//!
+//! ```toml
+//! [mqtt]
+//! host = "broker.local"
+//! username = "backend"
+//! password = "secret"
+//!
+//! [http]
+//! bind = "127.0.0.1:3000"
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use toml_edit::{DocumentMut, Item, TableLike, Value};
+use tracing::level_filters::LevelFilter;
+
+use crate::auth::Role;
+use crate::broker::{self, Broker};
+use crate::wled::realtime;
+use crate::{DEFAULT_PREFIX, db};
+
+/// Read, if it exists, when no other file is given
+pub const DEFAULT_FILE: &str = "esp32-wasmi-led.toml";
+pub const DEFAULT_CLIENT_ID: &str = "egui-axum-mqtt-backend";
+pub const DEFAULT_BIND: &str = "0.0.0.0:3000";
+/// Where the frontend's build (trunk's output) is served from
+pub const DEFAULT_STATIC_DIR: &str = "dist";
+
+/// A setting, and the ways to give it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Setting {
+    /// `table.key` in the file
+    pub key: &'static str,
+    pub env: &'static str,
+    /// `None` for secrets, which other users could see on the command line
+    pub flag: Option<&'static str>,
+    pub help: &'static str,
+}
+
+pub const SETTINGS: &[Setting] = &[
+    Setting {
+        key: "mqtt.host",
+        env: "MQTT_HOST",
+        flag: Some("--mqtt-host"),
+        help: "MQTT broker host",
+    },
+    Setting {
+        key: "mqtt.port",
+        env: "MQTT_PORT",
+        flag: Some("--mqtt-port"),
+        help: "MQTT broker port",
+    },
+    Setting {
+        key: "mqtt.username",
+        env: "MQTT_USERNAME",
+        flag: Some("--mqtt-username"),
+        help: "MQTT username",
+    },
+    Setting {
+        key: "mqtt.password",
+        env: "MQTT_PASSWORD",
+        flag: None,
+        help: "MQTT password",
+    },
+    Setting {
+        key: "mqtt.ca_file",
+        env: "MQTT_CA_FILE",
+        flag: Some("--mqtt-ca-file"),
+        help: "PEM CA certificate to connect with TLS, trusting only it",
+    },
+    Setting {
+        key: "mqtt.client_id",
+        env: "MQTT_CLIENT_ID",
+        flag: Some("--mqtt-client-id"),
+        help: "MQTT client id",
+    },
+    Setting {
+        key: "mqtt.topic_prefix",
+        env: "TOPIC_PREFIX",
+        flag: Some("--topic-prefix"),
+        help: "MQTT topic prefix; devices use the default",
+    },
+    Setting {
+        key: "http.bind",
+        env: "BIND_ADDR",
+        flag: Some("--bind"),
+        help: "address to serve the web UI and API on",
+    },
+    Setting {
+        key: "http.static_dir",
+        env: "STATIC_DIR",
+        flag: Some("--static-dir"),
+        help: "directory of the built frontend",
+    },
+    Setting {
+        key: "wled.port",
+        env: "WLED_PORT",
+        flag: Some("--wled-port"),
+        help: "UDP port for WLED realtime frames",
+    },
+    Setting {
+        key: "wled.role",
+        env: "WLED_ROLE",
+        flag: Some("--wled-role"),
+        help: "role for WLED JSON API callers without credentials",
+    },
+    Setting {
+        key: "database.path",
+        env: "DATABASE_PATH",
+        flag: Some("--database"),
+        help: "SQLite database of the content library",
+    },
+    Setting {
+        key: "log.level",
+        env: "LOG_LEVEL",
+        flag: Some("--log-level"),
+        help: "off, error, warn, info, debug or trace",
+    },
+];
+
+/// Where a setting was given.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    File { path: PathBuf, key: &'static str },
+    Env(&'static str),
+    Flag(&'static str),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::File { path, key } => write!(f, "{key} in {}", path.display()),
+            Source::Env(name) => write!(f, "{name}"),
+            Source::Flag(flag) => write!(f, "{flag}"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The file couldn't be read
+    Read(PathBuf, std::io::Error),
+    Toml(PathBuf, toml_edit::TomlError),
+    UnknownKey(PathBuf, String),
+    UnknownFlag(String),
+    /// A flag at the end of the command line
+    MissingValue(&'static str),
+    BadValue {
+        source: Source,
+        value: String,
+        expected: &'static str,
+    },
+    /// A username without a password, or the other way round
+    IncompleteCredentials,
+    CaFile(Source, std::io::Error),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Read(path, e) => write!(f, "can't read {}: {e}", path.display()),
+            Self::Toml(path, e) => write!(f, "{}: {e}", path.display()),
+            Self::UnknownKey(path, key) => {
+                write!(f, "{}: unknown setting {key}", path.display())
+            }
+            Self::UnknownFlag(flag) => write!(f, "unknown option {flag}, see --help"),
+            Self::MissingValue(flag) => write!(f, "{flag} needs a value"),
+            Self::BadValue {
+                source,
+                value,
+                expected,
+            } => write!(f, "{source} {value:?} is not {expected}"),
+            Self::IncompleteCredentials => {
+                write!(f, "the MQTT username and password must be given together")
+            }
+            Self::CaFile(source, e) => write!(f, "{source}: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// The settings given so far, as text, and where each was given.
+#[derive(Debug, Clone, Default)]
+pub struct Layers {
+    values: BTreeMap<&'static str, (String, Source)>,
+}
+
+impl Layers {
+    /// Add the settings in the TOML file at `path`.
+    pub fn file(&mut self, path: &Path) -> Result<(), ConfigError> {
+        let text =
+            std::fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_path_buf(), e))?;
+        let document: DocumentMut = text
+            .parse()
+            .map_err(|e| ConfigError::Toml(path.to_path_buf(), e))?;
+        self.table(path, "", document.as_table())
+    }
+
+    fn table(
+        &mut self,
+        path: &Path,
+        prefix: &str,
+        table: &dyn TableLike,
+    ) -> Result<(), ConfigError> {
+        for (key, item) in table.iter() {
+            let key = format!("{prefix}{key}");
+            if let Some(table) = item.as_table_like() {
+                self.table(path, &format!("{key}."), table)?;
+                continue;
+            }
+            let Some(setting) = SETTINGS.iter().find(|s| s.key == key) else {
+                return Err(ConfigError::UnknownKey(path.to_path_buf(), key));
+            };
+            let source = Source::File {
+                path: path.to_path_buf(),
+                key: setting.key,
+            };
+            let value = match item {
+                Item::Value(Value::String(s)) => s.value().clone(),
+                Item::Value(Value::Integer(i)) => i.value().to_string(),
+                other => {
+                    return Err(ConfigError::BadValue {
+                        source,
+                        value: other.type_name().to_string(),
+                        expected: "a string or a number",
+                    });
+                }
+            };
+            self.values.insert(setting.key, (value, source));
+        }
+        Ok(())
+    }
+
+    /// Add the settings in the environment, as `var` gives them.
+    pub fn env(&mut self, var: impl Fn(&str) -> Option<String>) {
+        for setting in SETTINGS {
+            if let Some(value) = var(setting.env).filter(|v| !v.is_empty()) {
+                self.values
+                    .insert(setting.key, (value, Source::Env(setting.env)));
+            }
+        }
+    }
+
+    /// Add the settings given as flags.
+    pub fn flags(&mut self, flags: Vec<(&'static Setting, String)>) {
+        for (setting, value) in flags {
+            // Only settings with a flag are parsed
+            let source = Source::Flag(setting.flag.unwrap());
+            self.values.insert(setting.key, (value, source));
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<&(String, Source)> {
+        self.values.get(key)
+    }
+
+    fn parse<T: FromStr>(
+        &self,
+        key: &str,
+        expected: &'static str,
+    ) -> Result<Option<T>, ConfigError> {
+        self.get(key)
+            .map(|(value, source)| {
+                value.parse().map_err(|_| ConfigError::BadValue {
+                    source: source.clone(),
+                    value: value.clone(),
+                    expected,
+                })
+            })
+            .transpose()
+    }
+
+    fn string(&self, key: &str, default: &str) -> String {
+        self.get(key)
+            .map_or_else(|| default.to_string(), |(value, _)| value.clone())
+    }
+
+    /// Check the settings, and fill in the defaults of those not given.
+    pub fn build(&self) -> Result<Config, ConfigError> {
+        let ca = match self.get("mqtt.ca_file") {
+            Some((path, source)) => {
+                Some(std::fs::read(path).map_err(|e| ConfigError::CaFile(source.clone(), e))?)
+            }
+            None => None,
+        };
+        let port = match self.parse("mqtt.port", "a port number")? {
+            Some(port) => port,
+            None if ca.is_some() => broker::TLS_PORT,
+            None => broker::PORT,
+        };
+        let credentials = match (self.get("mqtt.username"), self.get("mqtt.password")) {
+            (Some((username, _)), Some((password, _))) => {
+                Some((username.clone(), password.clone()))
+            }
+            (None, None) => None,
+            _ => return Err(ConfigError::IncompleteCredentials),
+        };
+        let broker = Broker {
+            host: self.string("mqtt.host", &Broker::default().host),
+            port,
+            credentials,
+            ca,
+        };
+
+        let topic_prefix = self.string("mqtt.topic_prefix", DEFAULT_PREFIX);
+        if topic_prefix.is_empty()
+            || topic_prefix.ends_with('/')
+            || topic_prefix.contains(['+', '#'])
+        {
+            // The default is fine, so it was given
+            let (_, source) = self.get("mqtt.topic_prefix").unwrap();
+            return Err(ConfigError::BadValue {
+                source: source.clone(),
+                value: topic_prefix,
+                expected: "a topic prefix, without wildcards or a trailing /",
+            });
+        }
+
+        let static_dir = self.string("http.static_dir", DEFAULT_STATIC_DIR);
+        // The default needn't exist, as the frontend may be served by trunk
+        if let Some((dir, source)) = self.get("http.static_dir")
+            && !Path::new(dir).is_dir()
+        {
+            return Err(ConfigError::BadValue {
+                source: source.clone(),
+                value: dir.clone(),
+                expected: "a directory",
+            });
+        }
+
+        Ok(Config {
+            broker,
+            client_id: self.string("mqtt.client_id", DEFAULT_CLIENT_ID),
+            topic_prefix,
+            bind: self
+                .parse("http.bind", "an address and port")?
+                // Can't fail, it's a constant
+                .unwrap_or_else(|| DEFAULT_BIND.parse().unwrap()),
+            static_dir: static_dir.into(),
+            wled_port: self
+                .parse("wled.port", "a port number")?
+                .unwrap_or(realtime::DEFAULT_PORT),
+            wled_role: self.parse("wled.role", "viewer, painter or admin")?,
+            database_path: self.string("database.path", db::DEFAULT_PATH).into(),
+            log_level: self
+                .parse("log.level", "a log level")?
+                .unwrap_or(LevelFilter::INFO),
+        })
+    }
+}
+
+/// What's given on the command line.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Args {
+    /// `--config`
+    pub config: Option<PathBuf>,
+    pub flags: Vec<(&'static Setting, String)>,
+}
+
+impl Args {
+    /// Parse `args`, without the program's name.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, ConfigError> {
+        let mut parsed = Args::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            // `--flag value` or `--flag=value`
+            let (flag, value) = match arg.split_once('=') {
+                Some((flag, value)) => (flag.to_string(), Some(value.to_string())),
+                None => (arg, None),
+            };
+            let (name, setting) = if flag == "--config" {
+                ("--config", None)
+            } else {
+                let setting = SETTINGS
+                    .iter()
+                    .find(|s| s.flag == Some(flag.as_str()))
+                    .ok_or(ConfigError::UnknownFlag(flag))?;
+                (setting.flag.unwrap(), Some(setting))
+            };
+            let value = value
+                .or_else(|| args.next())
+                .ok_or(ConfigError::MissingValue(name))?;
+            match setting {
+                Some(setting) => parsed.flags.push((setting, value)),
+                None => parsed.config = Some(PathBuf::from(value)),
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+/// What `--help` prints.
+pub fn usage() -> String {
+    let mut usage = format!(
+        "Usage: backend [--config FILE] [OPTIONS]\n\n\
+         Settings are read from FILE (or CONFIG_FILE, or {DEFAULT_FILE} if it exists),\n\
+         then the environment, then these options, given as --option VALUE or\n\
+         --option=VALUE:\n\n"
+    );
+    for setting in SETTINGS {
+        let flag = setting.flag.unwrap_or("(no option)");
+        usage += &format!(
+            "  {flag:<18} {:<16} {:<18} {}\n",
+            setting.env, setting.key, setting.help
+        );
+    }
+    usage
+}
+
+/// The backend's settings.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub broker: Broker,
+    pub client_id: String,
+    pub topic_prefix: String,
+    pub bind: SocketAddr,
+    pub static_dir: PathBuf,
+    /// For WLED realtime UDP
+    pub wled_port: u16,
+    /// For WLED JSON API callers without credentials
+    pub wled_role: Option<Role>,
+    pub database_path: PathBuf,
+    pub log_level: LevelFilter,
+}
+
+impl Config {
+    /// The settings from the command line `args` (without the program's name), the
+    /// environment, as `var` gives it, and the file either of them names.
+    pub fn load(
+        args: impl IntoIterator<Item = String>,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, ConfigError> {
+        let Args { config, flags } = Args::parse(args)?;
+        let file = config
+            .or_else(|| {
+                var("CONFIG_FILE")
+                    .filter(|v| !v.is_empty())
+                    .map(PathBuf::from)
+            })
+            .or_else(|| Some(PathBuf::from(DEFAULT_FILE)).filter(|path| path.exists()));
+
+        let mut layers = Layers::default();
+        if let Some(file) = file {
+            layers.file(&file)?;
+        }
+        layers.env(var);
+        layers.flags(flags);
+        layers.build()
+    }
+
+    /// The settings from the environment alone.
+    pub fn from_env() -> Result<Self, ConfigError> {
+        let mut layers = Layers::default();
+        layers.env(|name| std::env::var(name).ok());
+        layers.build()
+    }
+}
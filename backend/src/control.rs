@@ -0,0 +1,171 @@
+//! Typed REST endpoints for driving a device without speaking MQTT.
+//!
+//! Each request is checked here and sent as the equivalent [`Command`], waiting for the
+//! device's ack as `POST /api/devices/{device_id}/command` does (see [`commands`]), except
+//! `POST /frame`, which is streamed:
+//!
+//! | endpoint                                  | body                                            |
+//! |-------------------------------------------|-------------------------------------------------|
+//! | `PUT /api/devices/{device_id}/mode`       | `{"mode":"Direct"}`                             |
+//! | `PUT /api/devices/{device_id}/brightness` | `{"brightness":128}`                            |
+//! | `POST /api/devices/{device_id}/pixels`    | `{"pixels":[{"point":{..},"color":{..}},..]}`   |
+//! | `POST /api/devices/{device_id}/fill`      | `{"color":{..}}`, and `origin`, `width`, `height` for a rectangle |
+//! | `POST /api/devices/{device_id}/frame`     | [`LED_BUFFER_SIZE`] bytes of row-major RGB      |
+//!
+//! Bad input is a 400 and nothing is sent. Pixels and fills need the device to be in
+//! [`Mode::Direct`]; otherwise the device rejects them with a 422. A frame switches the device
+//! to [`Mode::Stream`] by itself, and is answered with a 202 as there's no ack.
+
+use axum::body::Bytes;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{post, put};
+use axum::{Json, Router};
+use common::{LED_BUFFER_SIZE, LED_PANEL_HEIGHT, LED_PANEL_WIDTH};
+use protocol::{Command, DirectCommand, MAX_PIXELS_PER_BATCH, Mode, Pixel, Point, Rgb};
+use serde::Deserialize;
+use web_common::Target;
+
+use crate::commands::{self, ACK_TIMEOUT, send_and_wait};
+use crate::{AppState, stream};
+
+#[derive(Debug, Deserialize)]
+pub struct ModeRequest {
+    pub mode: Mode,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BrightnessRequest {
+    pub brightness: u8,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PixelsRequest {
+    pub pixels: Vec<Pixel>,
+}
+
+/// The whole panel, or the rectangle at `origin` if given.
+#[derive(Debug, Deserialize)]
+pub struct FillRequest {
+    pub color: Rgb,
+    pub origin: Option<Point>,
+    pub width: Option<u8>,
+    pub height: Option<u8>,
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/api/devices/{device_id}/mode", put(put_mode))
+        .route("/api/devices/{device_id}/brightness", put(put_brightness))
+        .route("/api/devices/{device_id}/pixels", post(post_pixels))
+        .route("/api/devices/{device_id}/fill", post(post_fill))
+        .route("/api/devices/{device_id}/frame", post(post_frame))
+}
+
+fn bad_request(reason: impl Into<String>) -> Response {
+    (StatusCode::BAD_REQUEST, reason.into()).into_response()
+}
+
+fn on_panel(point: &Point) -> bool {
+    (point.x as usize) < LED_PANEL_WIDTH && (point.y as usize) < LED_PANEL_HEIGHT
+}
+
+async fn put_mode(
+    State(state): State<AppState>,
+    Path(device_id): Path<String>,
+    Json(request): Json<ModeRequest>,
+) -> Response {
+    let command = Command::SetMode(request.mode);
+    commands::ack_response(send_and_wait(&state, &device_id, command, ACK_TIMEOUT).await)
+}
+
+async fn put_brightness(
+    State(state): State<AppState>,
+    Path(device_id): Path<String>,
+    Json(request): Json<BrightnessRequest>,
+) -> Response {
+    let command = Command::SetBrightness(request.brightness);
+    commands::ack_response(send_and_wait(&state, &device_id, command, ACK_TIMEOUT).await)
+}
+
+/// Any number of pixels, sent in as many `SetPixels` batches as it takes. Stops at the first
+/// batch the device rejects.
+async fn post_pixels(
+    State(state): State<AppState>,
+    Path(device_id): Path<String>,
+    Json(request): Json<PixelsRequest>,
+) -> Response {
+    if request.pixels.is_empty() {
+        return bad_request("no pixels");
+    }
+    if let Some(pixel) = request.pixels.iter().find(|p| !on_panel(&p.point)) {
+        return bad_request(format!("pixel {:?} is off the panel", pixel.point));
+    }
+
+    let mut result = None;
+    for batch in request.pixels.chunks(MAX_PIXELS_PER_BATCH) {
+        let command = Command::DirectCommand(DirectCommand::SetPixels {
+            // Fits, by the size of the batch
+            pixels: batch.iter().copied().collect(),
+        });
+        let batch_result = send_and_wait(&state, &device_id, command, ACK_TIMEOUT).await;
+        let failed = !matches!(&batch_result, Ok(ack) if ack.is_ok());
+        result = Some(batch_result);
+        if failed {
+            break;
+        }
+    }
+    // Can't be `None`, there's at least one batch
+    commands::ack_response(result.unwrap())
+}
+
+async fn post_fill(
+    State(state): State<AppState>,
+    Path(device_id): Path<String>,
+    Json(request): Json<FillRequest>,
+) -> Response {
+    let FillRequest {
+        color,
+        origin,
+        width,
+        height,
+    } = request;
+    let command = match (origin, width, height) {
+        (None, None, None) => DirectCommand::SetAll { color },
+        (Some(origin), Some(width), Some(height)) => {
+            if !on_panel(&origin) {
+                return bad_request(format!("origin {origin:?} is off the panel"));
+            }
+            if width == 0 || height == 0 {
+                return bad_request("empty rectangle");
+            }
+            DirectCommand::FillRect {
+                origin,
+                width,
+                height,
+                color,
+            }
+        }
+        _ => return bad_request("a rectangle needs all of origin, width and height"),
+    };
+    let command = Command::DirectCommand(command);
+    commands::ack_response(send_and_wait(&state, &device_id, command, ACK_TIMEOUT).await)
+}
+
+async fn post_frame(
+    State(state): State<AppState>,
+    Path(device_id): Path<String>,
+    frame: Bytes,
+) -> Response {
+    if frame.len() != LED_BUFFER_SIZE {
+        return bad_request(format!(
+            "a frame is {LED_BUFFER_SIZE} bytes of RGB, not {}",
+            frame.len()
+        ));
+    }
+    match stream::send_to(&state, &Target::Device(device_id), &frame).await {
+        Ok(()) => StatusCode::ACCEPTED.into_response(),
+        Err(e) => (StatusCode::BAD_GATEWAY, e.to_string()).into_response(),
+    }
+}
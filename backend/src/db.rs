@@ -0,0 +1,815 @@
+//! The content library and device settings, kept in SQLite so they outlive the backend.
+//!
+//! Content - images, animations and the like - is one table: each item has a kind, an optional
+//! name, tags, its kind's metadata as JSON and its data as a blob, which the kind's module
+//! interprets (see [`images`](crate::images), [`animations`](crate::animations) and
+//! [`guests`](crate::guests)). The library as a whole is searched and labelled through:
+//!
+//! - `GET /api/library?kind=..&tag=..&q=..`, all optional: the items of that kind, with that
+//!   tag, whose name or a tag contains `q`, oldest first
+//! - `GET /api/library/{id}`
+//! - `PATCH /api/library/{id}` with `{"name": ..}` and/or `{"tags": [..]}`
+//! - `DELETE /api/library/{id}`
+//!
+//! Playlists and schedules are kept whole, as JSON: see [`Records`]. Users, their sessions and
+//! their API tokens are kept for [`auth`](crate::auth).
+//!
+//! The schema is changed only by adding to [`MIGRATIONS`], which are applied in turn to bring
+//! an existing database up to date, recording how far it got in `PRAGMA user_version`.
+
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::sync::Mutex;
+
+use axum::extract::{Path as UrlPath, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use rusqlite::{Connection, OptionalExtension, Row, params};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::auth::{Role, Token, User};
+use crate::{AppState, homeassistant, now_ms};
+
+/// Where the database is kept, unless [`config`](crate::config) says otherwise
+pub const DEFAULT_PATH: &str = "esp32-wasmi-led.db";
+
+/// Each brings the schema from the version before it to the next.
+pub const MIGRATIONS: &[&str] = &[
+    // 1: content and device settings
+    "CREATE TABLE content (
+        id TEXT PRIMARY KEY,
+        kind TEXT NOT NULL,
+        name TEXT,
+        meta TEXT NOT NULL,
+        data BLOB NOT NULL,
+        created_ms INTEGER NOT NULL,
+        updated_ms INTEGER NOT NULL
+    );
+    CREATE INDEX content_kind ON content (kind);
+    CREATE TABLE content_tags (
+        content_id TEXT NOT NULL REFERENCES content (id) ON DELETE CASCADE,
+        tag TEXT NOT NULL,
+        PRIMARY KEY (content_id, tag)
+    );
+    CREATE INDEX content_tags_tag ON content_tags (tag);
+    CREATE TABLE device_settings (
+        device_id TEXT PRIMARY KEY,
+        groups TEXT NOT NULL
+    );",
+    // 2: playlists and schedules
+    "CREATE TABLE playlists (
+        id TEXT PRIMARY KEY,
+        body TEXT NOT NULL,
+        created_ms INTEGER NOT NULL,
+        updated_ms INTEGER NOT NULL
+    );
+    CREATE TABLE schedules (
+        id TEXT PRIMARY KEY,
+        body TEXT NOT NULL,
+        created_ms INTEGER NOT NULL,
+        updated_ms INTEGER NOT NULL
+    );",
+    // 3: users, sessions and API tokens
+    "CREATE TABLE users (
+        name TEXT PRIMARY KEY,
+        role TEXT NOT NULL,
+        password_hash TEXT NOT NULL,
+        created_ms INTEGER NOT NULL
+    );
+    CREATE TABLE sessions (
+        id_hash TEXT PRIMARY KEY,
+        user_name TEXT NOT NULL REFERENCES users (name) ON DELETE CASCADE,
+        expires_ms INTEGER NOT NULL
+    );
+    CREATE TABLE tokens (
+        id TEXT PRIMARY KEY,
+        hash TEXT NOT NULL UNIQUE,
+        user_name TEXT NOT NULL REFERENCES users (name) ON DELETE CASCADE,
+        name TEXT NOT NULL,
+        role TEXT NOT NULL,
+        created_ms INTEGER NOT NULL
+    );",
+];
+
+/// The tables of records kept whole, as the JSON of the module's type for them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Records {
+    /// [`Playlist`](crate::playlists::Playlist)s
+    Playlists,
+    /// [`Schedule`](crate::schedules::Schedule)s
+    Schedules,
+}
+
+impl Records {
+    fn table(self) -> &'static str {
+        match self {
+            Records::Playlists => "playlists",
+            Records::Schedules => "schedules",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Image,
+    Animation,
+    Guest,
+}
+
+impl Kind {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Kind::Image => "image",
+            Kind::Animation => "animation",
+            Kind::Guest => "guest",
+        }
+    }
+
+    fn parse(kind: &str) -> Option<Self> {
+        match kind {
+            "image" => Some(Kind::Image),
+            "animation" => Some(Kind::Animation),
+            "guest" => Some(Kind::Guest),
+            _ => None,
+        }
+    }
+}
+
+/// An item in the library, without its data.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Content {
+    pub id: String,
+    pub kind: Kind,
+    pub name: Option<String>,
+    pub tags: BTreeSet<String>,
+    /// As its kind describes it, e.g. an [`ImageInfo`](crate::images::ImageInfo)'s size
+    pub meta: serde_json::Value,
+    pub created_ms: u64,
+    pub updated_ms: u64,
+}
+
+/// What to look for in the library. Everything matches what isn't given.
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct Search {
+    pub kind: Option<Kind>,
+    pub tag: Option<String>,
+    /// Part of the name or of a tag
+    pub q: Option<String>,
+}
+
+/// Changes to an item. What isn't given is left as it is.
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct Update {
+    /// `null` to remove the name
+    #[serde(default, with = "double_option")]
+    pub name: Option<Option<String>>,
+    pub tags: Option<BTreeSet<String>>,
+}
+
+// Tells a `null` name apart from a missing one
+mod double_option {
+    use serde::{Deserialize, Deserializer};
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Option<String>>, D::Error> {
+        Option::<String>::deserialize(deserializer).map(Some)
+    }
+}
+
+#[derive(Debug)]
+pub struct Database {
+    connection: Mutex<Connection>,
+}
+
+impl Database {
+    /// Open the database at `path`, creating it if need be, and bring its schema up to date.
+    pub fn open(path: impl AsRef<Path>) -> rusqlite::Result<Self> {
+        Self::new(Connection::open(path)?)
+    }
+
+    /// A database that lasts as long as it does, for tests.
+    pub fn open_in_memory() -> rusqlite::Result<Self> {
+        Self::new(Connection::open_in_memory()?)
+    }
+
+    fn new(mut connection: Connection) -> rusqlite::Result<Self> {
+        connection.pragma_update(None, "foreign_keys", true)?;
+        migrate(&mut connection)?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// The schema version, the number of [`MIGRATIONS`] applied.
+    pub fn version(&self) -> rusqlite::Result<usize> {
+        let connection = self.connection.lock().unwrap();
+        version(&connection)
+    }
+
+    pub fn insert(
+        &self,
+        id: &str,
+        kind: Kind,
+        name: Option<&str>,
+        meta: &serde_json::Value,
+        data: &[u8],
+    ) -> rusqlite::Result<()> {
+        let now = now_ms() as i64;
+        self.connection.lock().unwrap().execute(
+            "INSERT INTO content (id, kind, name, meta, data, created_ms, updated_ms)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?6)",
+            params![id, kind.as_str(), name, meta.to_string(), data, now],
+        )?;
+        Ok(())
+    }
+
+    /// Insert item `id` as the next version of the items of `kind` named `name`, 1 for the
+    /// first, returning its version. The version is added to `meta`, an object, as `version`.
+    pub fn insert_version(
+        &self,
+        id: &str,
+        kind: Kind,
+        name: &str,
+        meta: &serde_json::Value,
+        data: &[u8],
+    ) -> rusqlite::Result<u32> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        let latest: u32 = transaction.query_row(
+            "SELECT COALESCE(MAX(json_extract(meta, '$.version')), 0) FROM content
+             WHERE kind = ?1 AND name = ?2",
+            params![kind.as_str(), name],
+            |row| row.get(0),
+        )?;
+        let version = latest + 1;
+        let mut meta = meta.clone();
+        meta["version"] = version.into();
+        let now = now_ms() as i64;
+        transaction.execute(
+            "INSERT INTO content (id, kind, name, meta, data, created_ms, updated_ms)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?6)",
+            params![id, kind.as_str(), name, meta.to_string(), data, now],
+        )?;
+        transaction.commit()?;
+        Ok(version)
+    }
+
+    /// Item `id`, if it's of `kind`.
+    pub fn get(&self, kind: Option<Kind>, id: &str) -> rusqlite::Result<Option<Content>> {
+        let connection = self.connection.lock().unwrap();
+        let content = connection
+            .query_row(
+                "SELECT id, kind, name, meta, created_ms, updated_ms FROM content WHERE id = ?1",
+                [id],
+                content_from_row,
+            )
+            .optional()?
+            .filter(|content| kind.is_none_or(|kind| kind == content.kind));
+        content.map(|c| with_tags(&connection, c)).transpose()
+    }
+
+    /// Item `id` and its data, if it's of `kind`.
+    pub fn get_with_data(
+        &self,
+        kind: Kind,
+        id: &str,
+    ) -> rusqlite::Result<Option<(Content, Vec<u8>)>> {
+        let connection = self.connection.lock().unwrap();
+        let found = connection
+            .query_row(
+                "SELECT id, kind, name, meta, created_ms, updated_ms, data FROM content
+                 WHERE id = ?1 AND kind = ?2",
+                params![id, kind.as_str()],
+                |row| Ok((content_from_row(row)?, row.get(6)?)),
+            )
+            .optional()?;
+        found
+            .map(|(content, data)| Ok((with_tags(&connection, content)?, data)))
+            .transpose()
+    }
+
+    /// The items matching `search`, oldest first.
+    pub fn search(&self, search: &Search) -> rusqlite::Result<Vec<Content>> {
+        // `q` is matched literally, not as a pattern
+        let pattern = search.q.as_ref().map(|q| {
+            let escaped = q
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_");
+            format!("%{escaped}%")
+        });
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT id, kind, name, meta, created_ms, updated_ms FROM content
+             WHERE (?1 IS NULL OR kind = ?1)
+               AND (?2 IS NULL OR id IN (SELECT content_id FROM content_tags WHERE tag = ?2))
+               AND (?3 IS NULL OR name LIKE ?3 ESCAPE '\\'
+                    OR id IN (SELECT content_id FROM content_tags WHERE tag LIKE ?3 ESCAPE '\\'))
+             ORDER BY created_ms, rowid",
+        )?;
+        let found = statement
+            .query_map(
+                params![search.kind.map(Kind::as_str), search.tag, pattern],
+                content_from_row,
+            )?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        found
+            .into_iter()
+            .map(|content| with_tags(&connection, content))
+            .collect()
+    }
+
+    /// Apply `update` to item `id`, returning it as it now is.
+    pub fn update(&self, id: &str, update: &Update) -> rusqlite::Result<Option<Content>> {
+        {
+            let mut connection = self.connection.lock().unwrap();
+            let transaction = connection.transaction()?;
+            let changed = transaction.execute(
+                "UPDATE content SET updated_ms = ?2 WHERE id = ?1",
+                params![id, now_ms() as i64],
+            )?;
+            if changed == 0 {
+                return Ok(None);
+            }
+            if let Some(name) = &update.name {
+                transaction.execute(
+                    "UPDATE content SET name = ?2 WHERE id = ?1",
+                    params![id, name],
+                )?;
+            }
+            if let Some(tags) = &update.tags {
+                transaction.execute("DELETE FROM content_tags WHERE content_id = ?1", [id])?;
+                for tag in tags.iter().filter(|tag| !tag.is_empty()) {
+                    transaction.execute(
+                        "INSERT INTO content_tags (content_id, tag) VALUES (?1, ?2)",
+                        params![id, tag],
+                    )?;
+                }
+            }
+            transaction.commit()?;
+        }
+        self.get(None, id)
+    }
+
+    /// Delete item `id` if it's of `kind`, returning whether it was there.
+    pub fn delete(&self, kind: Option<Kind>, id: &str) -> rusqlite::Result<bool> {
+        let deleted = self.connection.lock().unwrap().execute(
+            "DELETE FROM content WHERE id = ?1 AND (?2 IS NULL OR kind = ?2)",
+            params![id, kind.map(Kind::as_str)],
+        )?;
+        Ok(deleted > 0)
+    }
+
+    /// Every device's groups, as last set.
+    pub fn device_groups(&self) -> rusqlite::Result<Vec<(String, BTreeSet<String>)>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare("SELECT device_id, groups FROM device_settings")?;
+        statement
+            .query_map([], |row| {
+                let groups: String = row.get(1)?;
+                // Written by `set_device_groups`
+                Ok((
+                    row.get(0)?,
+                    serde_json::from_str(&groups).unwrap_or_default(),
+                ))
+            })?
+            .collect()
+    }
+
+    pub fn set_device_groups(
+        &self,
+        device_id: &str,
+        groups: &BTreeSet<String>,
+    ) -> rusqlite::Result<()> {
+        // Can't fail, it's a set of strings
+        let groups = serde_json::to_string(groups).unwrap();
+        self.connection.lock().unwrap().execute(
+            "INSERT INTO device_settings (device_id, groups) VALUES (?1, ?2)
+             ON CONFLICT (device_id) DO UPDATE SET groups = excluded.groups",
+            params![device_id, groups],
+        )?;
+        Ok(())
+    }
+}
+
+impl Database {
+    /// Every record in `records`, oldest first. Any that no longer parse are skipped.
+    pub fn records<T: DeserializeOwned>(&self, records: Records) -> rusqlite::Result<Vec<T>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(&format!(
+            "SELECT id, body FROM {} ORDER BY created_ms, rowid",
+            records.table()
+        ))?;
+        let bodies = statement
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(bodies
+            .into_iter()
+            .filter_map(|(id, body)| match serde_json::from_str(&body) {
+                Ok(record) => Some(record),
+                Err(e) => {
+                    warn!("Skipping {} {id}: {e}", records.table());
+                    None
+                }
+            })
+            .collect())
+    }
+
+    /// Record `id` in `records`.
+    pub fn record<T: DeserializeOwned>(
+        &self,
+        records: Records,
+        id: &str,
+    ) -> rusqlite::Result<Option<T>> {
+        let body: Option<String> = self
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                &format!("SELECT body FROM {} WHERE id = ?1", records.table()),
+                [id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(body.and_then(|body| serde_json::from_str(&body).ok()))
+    }
+
+    /// Add or replace record `id` in `records`.
+    pub fn put_record<T: Serialize>(
+        &self,
+        records: Records,
+        id: &str,
+        record: &T,
+    ) -> rusqlite::Result<()> {
+        let body = serde_json::to_string(record)
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(e.into()))?;
+        self.connection.lock().unwrap().execute(
+            &format!(
+                "INSERT INTO {} (id, body, created_ms, updated_ms) VALUES (?1, ?2, ?3, ?3)
+                 ON CONFLICT (id) DO UPDATE SET body = excluded.body, updated_ms = excluded.updated_ms",
+                records.table()
+            ),
+            params![id, body, now_ms() as i64],
+        )?;
+        Ok(())
+    }
+
+    /// Delete record `id` from `records`, returning whether it was there.
+    pub fn delete_record(&self, records: Records, id: &str) -> rusqlite::Result<bool> {
+        let deleted = self.connection.lock().unwrap().execute(
+            &format!("DELETE FROM {} WHERE id = ?1", records.table()),
+            [id],
+        )?;
+        Ok(deleted > 0)
+    }
+}
+
+impl Database {
+    /// Whether any users have been added.
+    pub fn has_users(&self) -> rusqlite::Result<bool> {
+        self.connection.lock().unwrap().query_row(
+            "SELECT EXISTS (SELECT 1 FROM users)",
+            [],
+            |row| row.get(0),
+        )
+    }
+
+    /// Every user, oldest first.
+    pub fn users(&self) -> rusqlite::Result<Vec<User>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection
+            .prepare("SELECT name, role, created_ms FROM users ORDER BY created_ms, rowid")?;
+        statement.query_map([], user_from_row)?.collect()
+    }
+
+    /// User `name`'s role and password hash.
+    pub fn user_password(&self, name: &str) -> rusqlite::Result<Option<(Role, String)>> {
+        self.connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT role, password_hash FROM users WHERE name = ?1",
+                [name],
+                |row| Ok((role_from_row(row, 0)?, row.get(1)?)),
+            )
+            .optional()
+    }
+
+    /// Add user `name`, returning them, or `None` if the name is taken.
+    pub fn insert_user(
+        &self,
+        name: &str,
+        role: Role,
+        password_hash: &str,
+    ) -> rusqlite::Result<Option<User>> {
+        let created_ms = now_ms();
+        let inserted = self.connection.lock().unwrap().execute(
+            "INSERT INTO users (name, role, password_hash, created_ms) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (name) DO NOTHING",
+            params![name, role.as_str(), password_hash, created_ms as i64],
+        )?;
+        Ok((inserted > 0).then(|| User {
+            name: name.to_string(),
+            role,
+            created_ms,
+        }))
+    }
+
+    /// Change user `name`'s role and/or password, returning them as they now are. A new
+    /// password ends their sessions.
+    pub fn update_user(
+        &self,
+        name: &str,
+        role: Option<Role>,
+        password_hash: Option<&str>,
+    ) -> rusqlite::Result<Option<User>> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        let changed = transaction.execute(
+            "UPDATE users SET role = COALESCE(?2, role), password_hash = COALESCE(?3, password_hash)
+             WHERE name = ?1",
+            params![name, role.map(Role::as_str), password_hash],
+        )?;
+        if changed == 0 {
+            return Ok(None);
+        }
+        if password_hash.is_some() {
+            transaction.execute("DELETE FROM sessions WHERE user_name = ?1", [name])?;
+        }
+        let user = transaction.query_row(
+            "SELECT name, role, created_ms FROM users WHERE name = ?1",
+            [name],
+            user_from_row,
+        )?;
+        transaction.commit()?;
+        Ok(Some(user))
+    }
+
+    /// Delete user `name`, with their sessions and tokens, returning whether they were there.
+    pub fn delete_user(&self, name: &str) -> rusqlite::Result<bool> {
+        let deleted = self
+            .connection
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM users WHERE name = ?1", [name])?;
+        Ok(deleted > 0)
+    }
+
+    /// Start a session for `user`, forgetting any that have expired.
+    pub fn insert_session(
+        &self,
+        id_hash: &str,
+        user: &str,
+        expires_ms: u64,
+    ) -> rusqlite::Result<()> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute(
+            "DELETE FROM sessions WHERE expires_ms <= ?1",
+            [now_ms() as i64],
+        )?;
+        connection.execute(
+            "INSERT INTO sessions (id_hash, user_name, expires_ms) VALUES (?1, ?2, ?3)",
+            params![id_hash, user, expires_ms as i64],
+        )?;
+        Ok(())
+    }
+
+    /// The user of a session that hasn't expired by `now_ms`, and their role.
+    pub fn session_user(
+        &self,
+        id_hash: &str,
+        now_ms: u64,
+    ) -> rusqlite::Result<Option<(String, Role)>> {
+        self.connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT users.name, users.role FROM sessions
+                 JOIN users ON users.name = sessions.user_name
+                 WHERE sessions.id_hash = ?1 AND sessions.expires_ms > ?2",
+                params![id_hash, now_ms as i64],
+                |row| Ok((row.get(0)?, role_from_row(row, 1)?)),
+            )
+            .optional()
+    }
+
+    pub fn delete_session(&self, id_hash: &str) -> rusqlite::Result<()> {
+        self.connection
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM sessions WHERE id_hash = ?1", [id_hash])?;
+        Ok(())
+    }
+
+    pub fn insert_token(&self, token: &Token, hash: &str) -> rusqlite::Result<()> {
+        self.connection.lock().unwrap().execute(
+            "INSERT INTO tokens (id, hash, user_name, name, role, created_ms)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                token.id,
+                hash,
+                token.user,
+                token.name,
+                token.role.as_str(),
+                token.created_ms as i64
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// The tokens of `user`, or everyone's, oldest first.
+    pub fn tokens(&self, user: Option<&str>) -> rusqlite::Result<Vec<Token>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT id, name, user_name, role, created_ms FROM tokens
+             WHERE ?1 IS NULL OR user_name = ?1
+             ORDER BY created_ms, rowid",
+        )?;
+        statement.query_map([user], token_from_row)?.collect()
+    }
+
+    /// The token with `hash`, and its user's role.
+    pub fn token_user(&self, hash: &str) -> rusqlite::Result<Option<(Token, Role)>> {
+        self.connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT tokens.id, tokens.name, tokens.user_name, tokens.role, tokens.created_ms,
+                        users.role
+                 FROM tokens JOIN users ON users.name = tokens.user_name
+                 WHERE tokens.hash = ?1",
+                [hash],
+                |row| Ok((token_from_row(row)?, role_from_row(row, 5)?)),
+            )
+            .optional()
+    }
+
+    /// Delete token `id` if it's `user`'s (or anyone's, if `None`), returning whether it was
+    /// there.
+    pub fn delete_token(&self, id: &str, user: Option<&str>) -> rusqlite::Result<bool> {
+        let deleted = self.connection.lock().unwrap().execute(
+            "DELETE FROM tokens WHERE id = ?1 AND (?2 IS NULL OR user_name = ?2)",
+            params![id, user],
+        )?;
+        Ok(deleted > 0)
+    }
+}
+
+fn version(connection: &Connection) -> rusqlite::Result<usize> {
+    connection.pragma_query_value(None, "user_version", |row| row.get(0))
+}
+
+fn migrate(connection: &mut Connection) -> rusqlite::Result<()> {
+    let from = version(connection)?;
+    for (applied, migration) in MIGRATIONS.iter().enumerate().skip(from) {
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(migration)?;
+        transaction.pragma_update(None, "user_version", applied + 1)?;
+        transaction.commit()?;
+    }
+    if from < MIGRATIONS.len() {
+        info!(
+            "Migrated the database from version {from} to {}",
+            MIGRATIONS.len()
+        );
+    }
+    Ok(())
+}
+
+fn content_from_row(row: &Row) -> rusqlite::Result<Content> {
+    let kind: String = row.get(1)?;
+    let meta: String = row.get(3)?;
+    let created_ms: i64 = row.get(4)?;
+    let updated_ms: i64 = row.get(5)?;
+    Ok(Content {
+        id: row.get(0)?,
+        // Only ever written from a `Kind`
+        kind: Kind::parse(&kind).ok_or(rusqlite::Error::InvalidColumnType(
+            1,
+            "kind".to_string(),
+            rusqlite::types::Type::Text,
+        ))?,
+        name: row.get(2)?,
+        tags: BTreeSet::new(),
+        meta: serde_json::from_str(&meta).unwrap_or_default(),
+        created_ms: created_ms as u64,
+        updated_ms: updated_ms as u64,
+    })
+}
+
+fn role_from_row(row: &Row, index: usize) -> rusqlite::Result<Role> {
+    let role: String = row.get(index)?;
+    // Only ever written from a `Role`
+    Role::parse(&role).ok_or(rusqlite::Error::InvalidColumnType(
+        index,
+        "role".to_string(),
+        rusqlite::types::Type::Text,
+    ))
+}
+
+fn user_from_row(row: &Row) -> rusqlite::Result<User> {
+    let created_ms: i64 = row.get(2)?;
+    Ok(User {
+        name: row.get(0)?,
+        role: role_from_row(row, 1)?,
+        created_ms: created_ms as u64,
+    })
+}
+
+fn token_from_row(row: &Row) -> rusqlite::Result<Token> {
+    let created_ms: i64 = row.get(4)?;
+    Ok(Token {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        user: row.get(2)?,
+        role: role_from_row(row, 3)?,
+        created_ms: created_ms as u64,
+    })
+}
+
+fn with_tags(connection: &Connection, mut content: Content) -> rusqlite::Result<Content> {
+    let mut statement =
+        connection.prepare_cached("SELECT tag FROM content_tags WHERE content_id = ?1")?;
+    content.tags = statement
+        .query_map([&content.id], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(content)
+}
+
+/// A database failure, as a response.
+pub fn error_response(e: rusqlite::Error) -> Response {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("database error: {e}"),
+    )
+        .into_response()
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new().route("/api/library", get(get_library)).route(
+        "/api/library/{id}",
+        get(get_content).patch(patch_content).delete(delete_content),
+    )
+}
+
+async fn get_library(State(state): State<AppState>, Query(search): Query<Search>) -> Response {
+    match state.db.search(&search) {
+        Ok(found) => Json(found).into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+async fn get_content(State(state): State<AppState>, UrlPath(id): UrlPath<String>) -> Response {
+    match state.db.get(None, &id) {
+        Ok(Some(content)) => Json(content).into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+async fn patch_content(
+    State(state): State<AppState>,
+    UrlPath(id): UrlPath<String>,
+    Json(update): Json<Update>,
+) -> Response {
+    match state.db.update(&id, &update) {
+        Ok(Some(content)) => {
+            // Animations are offered to Home Assistant by name
+            if content.kind == Kind::Animation {
+                homeassistant::republish_discovery(&state);
+            }
+            Json(content).into_response()
+        }
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+async fn delete_content(State(state): State<AppState>, UrlPath(id): UrlPath<String>) -> Response {
+    let kind = match state.db.get(None, &id) {
+        Ok(Some(content)) => content.kind,
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(e) => return error_response(e),
+    };
+    match state.db.delete(Some(kind), &id) {
+        Ok(true) => {
+            // In case it's playing
+            state.players.stop_content(&id);
+            // Animations are offered to Home Assistant
+            if kind == Kind::Animation {
+                homeassistant::republish_discovery(&state);
+            }
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Ok(false) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => error_response(e),
+    }
+}
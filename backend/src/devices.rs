@@ -0,0 +1,243 @@
+//! Device discovery and registry.
+//!
+//! Devices are discovered from their retained status, info and telemetry topics, so the
+//! registry fills up as soon as the backend subscribes. Groups are assigned here, not on the
+//! device, and every device is in [`ALL_DEVICES`]. Groups are kept in the database (see [`db`]),
+//! so devices that were given groups are known from startup, as offline until they report in.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::routing::{get, put};
+use axum::{Json, Router};
+use protocol::DeviceConfig;
+use protocol::ping::PingResponse;
+use protocol::presence::{self, DeviceInfo};
+use protocol::telemetry::Telemetry;
+use protocol::topics::suffix;
+use serde::Serialize;
+use tracing::{info, warn};
+use web_common::{ALL_DEVICES, ServerMsg, Target};
+
+use crate::{AppState, commands, db, homeassistant};
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Device {
+    pub device_id: String,
+    pub online: bool,
+    pub info: Option<DeviceInfo>,
+    pub telemetry: Option<Telemetry>,
+    /// As last reported in reply to `GetConfig`, without passwords
+    pub config: Option<DeviceConfig>,
+    /// Not including [`ALL_DEVICES`]
+    pub groups: BTreeSet<String>,
+    /// As reported to Home Assistant
+    #[serde(skip)]
+    pub light: homeassistant::LightState,
+}
+
+#[derive(Debug, Default)]
+pub struct Registry {
+    devices: BTreeMap<String, Device>,
+}
+
+impl Registry {
+    pub fn get(&self, device_id: &str) -> Option<&Device> {
+        self.devices.get(device_id)
+    }
+
+    /// The device, added if it's new.
+    pub fn entry(&mut self, device_id: &str) -> &mut Device {
+        self.devices
+            .entry(device_id.to_string())
+            .or_insert_with(|| Device {
+                device_id: device_id.to_string(),
+                ..Default::default()
+            })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Device> {
+        self.devices.values()
+    }
+
+    /// The ids of the devices `target` refers to. A device id is taken as given, whether or not
+    /// the device has been seen yet.
+    pub fn resolve(&self, target: &Target) -> Vec<String> {
+        match target {
+            Target::Device(device_id) => vec![device_id.clone()],
+            Target::Group(group) if group == ALL_DEVICES => self.devices.keys().cloned().collect(),
+            Target::Group(group) => self
+                .devices
+                .values()
+                .filter(|d| d.groups.contains(group))
+                .map(|d| d.device_id.clone())
+                .collect(),
+        }
+    }
+}
+
+/// Messages that bring a newly connected WebSocket client up to date.
+pub fn snapshot(state: &AppState) -> Vec<ServerMsg> {
+    let registry = state.devices.read().unwrap();
+    let mut msgs = Vec::new();
+    for device in registry.iter() {
+        if let Some(info) = &device.info {
+            msgs.push(ServerMsg::DeviceInfo { info: info.clone() });
+        }
+        msgs.push(ServerMsg::Presence {
+            device_id: device.device_id.clone(),
+            online: device.online,
+        });
+        if let Some(telemetry) = &device.telemetry {
+            msgs.push(ServerMsg::Telemetry {
+                device_id: device.device_id.clone(),
+                telemetry: telemetry.clone(),
+            });
+        }
+    }
+    msgs
+}
+
+/// Handle a publish on one of a device's topics.
+pub fn handle_publish(state: &AppState, device_id: &str, topic_suffix: &str, payload: &[u8]) {
+    match topic_suffix {
+        suffix::STATUS => match presence::parse_status(payload) {
+            Some(online) => update_presence(state, device_id, online),
+            None => warn!("Invalid status for {device_id}: {payload:?}"),
+        },
+        suffix::INFO => match serde_json::from_slice::<DeviceInfo>(payload) {
+            Ok(info) => {
+                info!("Discovered device {device_id}: {info:?}");
+                state.devices.write().unwrap().entry(device_id).info = Some(info.clone());
+                let _ = state.tx.send(ServerMsg::DeviceInfo { info });
+                spawn_discovery(state, device_id);
+            }
+            Err(e) => warn!("Invalid device info from {device_id}: {e}"),
+        },
+        suffix::TELEMETRY => match serde_json::from_slice::<Telemetry>(payload) {
+            Ok(telemetry) => {
+                // Only the guest comes from telemetry: the library's animations are left out
+                let effects_changed = {
+                    let mut registry = state.devices.write().unwrap();
+                    let device = registry.entry(device_id);
+                    let effects = homeassistant::effects(device, &[]);
+                    device.telemetry = Some(telemetry.clone());
+                    homeassistant::effects(device, &[]) != effects
+                };
+                if effects_changed {
+                    spawn_discovery(state, device_id);
+                }
+                let _ = state.tx.send(ServerMsg::Telemetry {
+                    device_id: device_id.to_string(),
+                    telemetry,
+                });
+            }
+            Err(e) => warn!("Invalid telemetry from {device_id}: {e}"),
+        },
+        suffix::CONFIG => match serde_json::from_slice::<DeviceConfig>(payload) {
+            Ok(config) => {
+                state.devices.write().unwrap().entry(device_id).config = Some(config.clone());
+                let _ = state.tx.send(ServerMsg::DeviceConfig {
+                    device_id: device_id.to_string(),
+                    config: Box::new(config),
+                });
+            }
+            Err(e) => warn!("Invalid config from {device_id}: {e}"),
+        },
+        suffix::COMMAND_ACK => commands::handle_ack(state, device_id, payload),
+        suffix::PING_RESPONSE => match serde_json::from_slice::<PingResponse>(payload) {
+            Ok(resp) => {
+                let _ = state.tx.send(ServerMsg::PingResponse {
+                    device_id: device_id.to_string(),
+                    correlation_id: resp.correlation_id.to_string(),
+                    device_reply: resp.message.to_string(),
+                });
+            }
+            Err(e) => warn!("Invalid ping response from {device_id}: {e}"),
+        },
+        homeassistant::LIGHT_SET => {
+            let state = state.clone();
+            let device_id = device_id.to_string();
+            let payload = payload.to_vec();
+            tokio::spawn(async move {
+                homeassistant::handle_light_command(&state, &device_id, &payload).await;
+            });
+        }
+        other => warn!("Unexpected topic for {device_id}: {other}"),
+    }
+}
+
+// Publishing from the MQTT loop itself could deadlock if the request queue is full
+fn spawn_discovery(state: &AppState, device_id: &str) {
+    let state = state.clone();
+    let device_id = device_id.to_string();
+    tokio::spawn(async move {
+        homeassistant::publish_discovery(&state, &device_id).await;
+    });
+}
+
+// Record a device's presence, telling WebSocket clients if it changed
+fn update_presence(state: &AppState, device_id: &str, online: bool) {
+    let previous = {
+        let mut registry = state.devices.write().unwrap();
+        let previous = registry.get(device_id).map(|d| d.online);
+        registry.entry(device_id).online = online;
+        previous
+    };
+    if previous != Some(online) {
+        info!(
+            "Device {device_id} is {}",
+            if online { "online" } else { "offline" }
+        );
+        let _ = state.tx.send(ServerMsg::Presence {
+            device_id: device_id.to_string(),
+            online,
+        });
+    }
+}
+
+/// `GET /api/devices`, `GET /api/devices/{device_id}` and
+/// `PUT /api/devices/{device_id}/groups` (a JSON list of group names).
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/api/devices", get(get_devices))
+        .route("/api/devices/{device_id}", get(get_device))
+        .route("/api/devices/{device_id}/groups", put(put_groups))
+}
+
+async fn get_devices(State(state): State<AppState>) -> impl IntoResponse {
+    let devices: Vec<Device> = state.devices.read().unwrap().iter().cloned().collect();
+    Json(devices)
+}
+
+async fn get_device(
+    State(state): State<AppState>,
+    Path(device_id): Path<String>,
+) -> impl IntoResponse {
+    match state.devices.read().unwrap().get(&device_id) {
+        Some(device) => Json(device.clone()).into_response(),
+        None => (StatusCode::NOT_FOUND, "Unknown device").into_response(),
+    }
+}
+
+async fn put_groups(
+    State(state): State<AppState>,
+    Path(device_id): Path<String>,
+    Json(groups): Json<BTreeSet<String>>,
+) -> impl IntoResponse {
+    let mut registry = state.devices.write().unwrap();
+    let Some(device) = registry.devices.get_mut(&device_id) else {
+        return (StatusCode::NOT_FOUND, "Unknown device").into_response();
+    };
+    let groups = groups
+        .into_iter()
+        .filter(|g| !g.is_empty() && g != ALL_DEVICES)
+        .collect();
+    if let Err(e) = state.db.set_device_groups(&device_id, &groups) {
+        return db::error_response(e);
+    }
+    device.groups = groups;
+    Json(device.clone()).into_response()
+}
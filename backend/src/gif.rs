@@ -0,0 +1,335 @@
+//! A GIF decoder for animations: each frame is composited onto the canvas, honouring the
+//! frame's disposal method and transparency, as browsers show it.
+//!
+//! Follows GIF89a (<https://www.w3.org/Graphics/GIF/spec-gif89a.txt>), plus the Netscape
+//! application extension for the loop count.
+
+use std::time::Duration;
+
+use image::{Rgba, RgbaImage};
+
+/// Largest canvas decoded, in either dimension
+pub const MAX_DIMENSION: u16 = 2048;
+/// Browsers show frames with no delay, or one of 0 or 10 ms, for this long instead
+pub const DEFAULT_DELAY: Duration = Duration::from_millis(100);
+
+const EXTENSION: u8 = 0x21;
+const IMAGE: u8 = 0x2c;
+const TRAILER: u8 = 0x3b;
+const GRAPHIC_CONTROL: u8 = 0xf9;
+const APPLICATION: u8 = 0xff;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GifError {
+    NotGif,
+    /// The data ends mid-block
+    Truncated,
+    TooLarge {
+        width: u16,
+        height: u16,
+    },
+    /// An image without a colour table to look its pixels up in
+    NoColorTable,
+    BadImageData,
+    NoFrames,
+}
+
+impl std::fmt::Display for GifError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GifError::NotGif => write!(f, "not a GIF"),
+            GifError::Truncated => write!(f, "truncated GIF"),
+            GifError::TooLarge { width, height } => write!(
+                f,
+                "{width}x{height} is larger than {MAX_DIMENSION}x{MAX_DIMENSION}"
+            ),
+            GifError::NoColorTable => write!(f, "GIF image without a colour table"),
+            GifError::BadImageData => write!(f, "invalid GIF image data"),
+            GifError::NoFrames => write!(f, "GIF without any images"),
+        }
+    }
+}
+
+impl std::error::Error for GifError {}
+
+/// What to do with a frame's area once it has been shown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Disposal {
+    /// Leave it, to draw the next frame over
+    Keep,
+    /// Clear it to transparent
+    Background,
+    /// Put back what was there before
+    Previous,
+}
+
+/// From the graphic control extension, for the next image.
+#[derive(Debug, Clone, Copy)]
+struct Control {
+    disposal: Disposal,
+    delay: Duration,
+    transparent: Option<u8>,
+}
+
+// For an image without a graphic control extension
+impl Default for Control {
+    fn default() -> Self {
+        Self {
+            disposal: Disposal::Keep,
+            delay: DEFAULT_DELAY,
+            transparent: None,
+        }
+    }
+}
+
+/// The file's global properties.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub width: u16,
+    pub height: u16,
+    /// How many times to play it: `None` for forever
+    pub plays: Option<u32>,
+}
+
+/// Reads a GIF a block at a time.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], GifError> {
+        if self.data.len() < n {
+            return Err(GifError::Truncated);
+        }
+        let (bytes, rest) = self.data.split_at(n);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, GifError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, GifError> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    /// A colour table of `2^(size + 1)` entries, if `flags` (with the size in the low bits)
+    /// says there is one.
+    fn color_table(&mut self, flags: u8) -> Result<Option<Vec<[u8; 3]>>, GifError> {
+        if flags & 0x80 == 0 {
+            return Ok(None);
+        }
+        let entries = 2usize << (flags & 0x07);
+        let table = self.bytes(entries * 3)?;
+        Ok(Some(table.as_chunks::<3>().0.to_vec()))
+    }
+
+    /// The sub-blocks that follow, joined.
+    fn sub_blocks(&mut self) -> Result<Vec<u8>, GifError> {
+        let mut joined = Vec::new();
+        loop {
+            let len = self.u8()? as usize;
+            if len == 0 {
+                return Ok(joined);
+            }
+            joined.extend_from_slice(self.bytes(len)?);
+        }
+    }
+}
+
+/// Decode `data`, calling `on_frame` with the canvas and delay of each frame in turn.
+pub fn decode(
+    data: &[u8],
+    mut on_frame: impl FnMut(&RgbaImage, Duration),
+) -> Result<Header, GifError> {
+    if !(data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a")) {
+        return Err(GifError::NotGif);
+    }
+    let mut reader = Reader { data: &data[6..] };
+    let width = reader.u16()?;
+    let height = reader.u16()?;
+    if width > MAX_DIMENSION || height > MAX_DIMENSION {
+        return Err(GifError::TooLarge { width, height });
+    }
+    let flags = reader.u8()?;
+    // Background colour and aspect ratio: browsers ignore both
+    reader.bytes(2)?;
+    let global_table = reader.color_table(flags)?;
+
+    // Without a Netscape extension, once
+    let mut plays = Some(1);
+    let mut canvas = RgbaImage::new(width.into(), height.into());
+    let mut control = Control::default();
+    let mut frames = 0;
+    loop {
+        // Some encoders leave the trailer off
+        let block = match reader.u8() {
+            Ok(block) => block,
+            Err(_) if frames > 0 => break,
+            Err(e) => return Err(e),
+        };
+        match block {
+            EXTENSION => {
+                let label = reader.u8()?;
+                let body = reader.sub_blocks()?;
+                match label {
+                    GRAPHIC_CONTROL if body.len() >= 4 => {
+                        let delay_cs = u16::from_le_bytes([body[1], body[2]]);
+                        control = Control {
+                            disposal: match (body[0] >> 2) & 0x07 {
+                                2 => Disposal::Background,
+                                3 => Disposal::Previous,
+                                _ => Disposal::Keep,
+                            },
+                            delay: match delay_cs {
+                                0 | 1 => DEFAULT_DELAY,
+                                cs => Duration::from_millis(cs as u64 * 10),
+                            },
+                            transparent: (body[0] & 0x01 != 0).then_some(body[3]),
+                        };
+                    }
+                    // The Netscape extension's body is its identifier, then a loop count
+                    // sub-block: 1 and the number of times to repeat, 0 for forever
+                    APPLICATION
+                        if body.len() >= 14
+                            && (body.starts_with(b"NETSCAPE2.0")
+                                || body.starts_with(b"ANIMEXTS1.0"))
+                            && body[11] == 1 =>
+                    {
+                        plays = match u16::from_le_bytes([body[12], body[13]]) {
+                            0 => None,
+                            repeats => Some(repeats as u32 + 1),
+                        };
+                    }
+                    _ => {}
+                }
+            }
+            IMAGE => {
+                let left = reader.u16()?;
+                let top = reader.u16()?;
+                let frame_width = reader.u16()?;
+                let frame_height = reader.u16()?;
+                // Checked before decompressing, as the frame's size says how much to allocate
+                if u32::from(left) + u32::from(frame_width) > width.into()
+                    || u32::from(top) + u32::from(frame_height) > height.into()
+                {
+                    return Err(GifError::BadImageData);
+                }
+                let flags = reader.u8()?;
+                let local_table = reader.color_table(flags)?;
+                let table = local_table
+                    .as_ref()
+                    .or(global_table.as_ref())
+                    .ok_or(GifError::NoColorTable)?;
+                let min_code_size = reader.u8()?;
+                let compressed = reader.sub_blocks()?;
+                let indices = decompress(
+                    &compressed,
+                    min_code_size,
+                    frame_width as usize * frame_height as usize,
+                )?;
+
+                let previous = (control.disposal == Disposal::Previous).then(|| canvas.clone());
+                let rows = row_order(frame_height, flags & 0x40 != 0);
+                let area = Area {
+                    left: left.into(),
+                    top: top.into(),
+                    width: frame_width.into(),
+                    height: frame_height.into(),
+                };
+                for (row, y) in indices.chunks(frame_width.max(1) as usize).zip(rows) {
+                    for (x, &index) in row.iter().enumerate() {
+                        if Some(index) == control.transparent {
+                            continue;
+                        }
+                        let (x, y) = (area.left + x as u32, area.top + y as u32);
+                        if let (Some(&[r, g, b]), true) = (
+                            table.get(index as usize),
+                            x < canvas.width() && y < canvas.height(),
+                        ) {
+                            canvas.put_pixel(x, y, Rgba([r, g, b, 255]));
+                        }
+                    }
+                }
+
+                on_frame(&canvas, control.delay);
+                frames += 1;
+
+                match control.disposal {
+                    Disposal::Keep => {}
+                    Disposal::Background => area.clear(&mut canvas),
+                    Disposal::Previous => {
+                        if let Some(previous) = previous {
+                            canvas = previous;
+                        }
+                    }
+                }
+                control = Control::default();
+            }
+            TRAILER => break,
+            _ => return Err(GifError::BadImageData),
+        }
+    }
+
+    if frames == 0 {
+        return Err(GifError::NoFrames);
+    }
+    Ok(Header {
+        width,
+        height,
+        plays,
+    })
+}
+
+/// A frame's rectangle on the canvas.
+struct Area {
+    left: u32,
+    top: u32,
+    width: u32,
+    height: u32,
+}
+
+impl Area {
+    fn clear(&self, canvas: &mut RgbaImage) {
+        for y in self.top..(self.top + self.height).min(canvas.height()) {
+            for x in self.left..(self.left + self.width).min(canvas.width()) {
+                canvas.put_pixel(x, y, Rgba([0, 0, 0, 0]));
+            }
+        }
+    }
+}
+
+/// The canvas row each row of image data is for: interlaced images come in four passes.
+fn row_order(height: u16, interlaced: bool) -> Vec<u16> {
+    if !interlaced {
+        return (0..height).collect();
+    }
+    [(0, 8), (4, 8), (2, 4), (1, 2)]
+        .into_iter()
+        .flat_map(|(start, step)| (start..height).step_by(step))
+        .collect()
+}
+
+/// Up to `len` colour indices from LZW-compressed image data. Missing pixels are left as 0.
+fn decompress(data: &[u8], min_code_size: u8, len: usize) -> Result<Vec<u8>, GifError> {
+    if !(1..=11).contains(&min_code_size) {
+        return Err(GifError::BadImageData);
+    }
+    let mut decoder = weezl::decode::Decoder::new(weezl::BitOrder::Lsb, min_code_size);
+    let mut indices = vec![0u8; len];
+    let (mut read, mut written) = (0, 0);
+    // Stops at the pixels needed, however much more the data would expand to
+    while written < len {
+        let result = decoder.decode_bytes(&data[read..], &mut indices[written..]);
+        read += result.consumed_in;
+        written += result.consumed_out;
+        match result.status {
+            Ok(weezl::LzwStatus::Ok) => {}
+            Ok(weezl::LzwStatus::Done | weezl::LzwStatus::NoProgress) => break,
+            Err(_) => return Err(GifError::BadImageData),
+        }
+    }
+    Ok(indices)
+}
@@ -0,0 +1,411 @@
+//! WASM guest modules: uploaded, checked against what a device can run, kept in versions, and
+//! deployed to devices (see `protocol::guest` for the wire format).
+//!
+//! `POST /api/guests` takes a `.wasm` module as the request body, and optionally `name`, which
+//! otherwise comes from the module's manifest. The module is checked with Wasmi, as the device
+//! runs it: it must parse, export its `memory` and `init: () -> ()` and
+//! `update: (u64, u64, u32) -> u32` functions, import only [`HOST_IMPORTS`], and fit within
+//! [`MAX_GUEST_LEN`] and [`MAX_GUEST_MEMORY_PAGES`]. A module with the same name as one already
+//! kept is its next version. It answers with the stored guest's [`GuestInfo`], or 422 saying
+//! why the module was refused. Then:
+//!
+//! - `GET /api/guests?name=..` lists them, all versions, optionally of one name only
+//! - `GET /api/guests/{id}` is its [`GuestInfo`]
+//! - `GET /api/guests/{id}/wasm` is the module
+//! - `POST /api/guests/{id}/deploy?device=..` (or `group=..`) sends it to each device, in
+//!   chunks, answering with the transfer id and the devices
+//! - `DELETE /api/guests/{id}` forgets it
+//!
+//! A module may describe itself in a [`MANIFEST_SECTION`] custom section, as a JSON
+//! [`Manifest`]. A device runs a deployed guest until it restarts, or the guest fails, and then
+//! goes back to the one it was built with.
+
+use axum::body::Bytes;
+use axum::extract::{DefaultBodyLimit, Path, Query, State};
+use axum::http::{StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use protocol::guest::{
+    GuestChunk, HOST_IMPORTS, MANIFEST_SECTION, MAX_GUEST_CHUNK_DATA_LEN, MAX_GUEST_LEN,
+    MAX_GUEST_MEMORY_PAGES,
+};
+use protocol::telemetry::MAX_GUEST_NAME_LEN;
+use protocol::topics::suffix;
+use rumqttc::QoS;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use wasmi::{Engine, ExternType, FuncType, Module, ValType};
+
+use crate::AppState;
+use crate::animations::required_target;
+use crate::db::{self, Content, Database, Kind, Search};
+use crate::images::TargetQuery;
+
+/// How a guest describes itself, in its [`MANIFEST_SECTION`]. All optional.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    pub name: Option<String>,
+    /// As its author numbers it, unrelated to the version it's kept as
+    pub version: Option<String>,
+    pub description: Option<String>,
+    pub author: Option<String>,
+}
+
+/// Why a module was refused.
+#[derive(Debug)]
+pub enum GuestError {
+    TooLarge(usize),
+    Invalid(wasmi::Error),
+    MissingExport(&'static str),
+    /// The export isn't what the host expects, e.g. a function of the wrong type
+    WrongExport(&'static str),
+    /// The pages it starts with, the host's added
+    TooMuchMemory(u64),
+    UnknownImport {
+        module: String,
+        name: String,
+    },
+    BadManifest(serde_json::Error),
+}
+
+impl std::fmt::Display for GuestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GuestError::TooLarge(len) => {
+                write!(f, "module is {len} bytes, more than {MAX_GUEST_LEN}")
+            }
+            GuestError::Invalid(e) => write!(f, "invalid module: {e}"),
+            GuestError::MissingExport(name) => write!(f, "no `{name}` export"),
+            GuestError::WrongExport(name) => write!(f, "`{name}` export has the wrong type"),
+            GuestError::TooMuchMemory(pages) => write!(
+                f,
+                "memory needs {pages} pages with the host's, more than {MAX_GUEST_MEMORY_PAGES}"
+            ),
+            GuestError::UnknownImport { module, name } => {
+                write!(
+                    f,
+                    "imports `{module}.{name}`, which the host doesn't provide"
+                )
+            }
+            GuestError::BadManifest(e) => write!(f, "invalid manifest: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for GuestError {}
+
+/// What's learnt from checking a module.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Validated {
+    /// Linear memory it starts with, in pages, before the host adds its own
+    pub memory_pages: u64,
+    pub manifest: Manifest,
+}
+
+/// Check that `wasm` is a module a device can run.
+pub fn validate(wasm: &[u8]) -> Result<Validated, GuestError> {
+    if wasm.len() > MAX_GUEST_LEN {
+        return Err(GuestError::TooLarge(wasm.len()));
+    }
+    let module = Module::new(&Engine::default(), wasm).map_err(GuestError::Invalid)?;
+
+    let export = |name: &'static str| {
+        module
+            .exports()
+            .find(|export| export.name() == name)
+            .map(|export| export.ty().clone())
+            .ok_or(GuestError::MissingExport(name))
+    };
+    let memory = *export("memory")?
+        .memory()
+        .ok_or(GuestError::WrongExport("memory"))?;
+    // The host grows it by a page for its pixel buffer
+    let pages = memory.minimum() + 1;
+    if pages > MAX_GUEST_MEMORY_PAGES {
+        return Err(GuestError::TooMuchMemory(pages));
+    }
+    if memory.maximum().is_some_and(|maximum| maximum < pages) {
+        return Err(GuestError::WrongExport("memory"));
+    }
+    let expect_func = |name: &'static str, params: &[ValType], results: &[ValType]| {
+        let ty = export(name)?;
+        let expected = FuncType::new(params.iter().copied(), results.iter().copied());
+        match ty {
+            ExternType::Func(ty) if ty == expected => Ok(()),
+            _ => Err(GuestError::WrongExport(name)),
+        }
+    };
+    expect_func("init", &[], &[])?;
+    expect_func(
+        "update",
+        &[ValType::I64, ValType::I64, ValType::I32],
+        &[ValType::I32],
+    )?;
+
+    if let Some(import) = module
+        .imports()
+        .find(|import| !HOST_IMPORTS.contains(&(import.module(), import.name())))
+    {
+        return Err(GuestError::UnknownImport {
+            module: import.module().to_string(),
+            name: import.name().to_string(),
+        });
+    }
+
+    let manifest = match module
+        .custom_sections()
+        .find(|section| section.name() == MANIFEST_SECTION)
+    {
+        Some(section) => serde_json::from_slice(section.data()).map_err(GuestError::BadManifest)?,
+        None => Manifest::default(),
+    };
+
+    Ok(Validated {
+        memory_pages: memory.minimum(),
+        manifest,
+    })
+}
+
+/// What's known about a stored guest.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct GuestInfo {
+    pub id: String,
+    pub name: String,
+    /// 1 for the first guest kept with its name, and so on
+    pub version: u32,
+    /// Of the module
+    pub len: usize,
+    pub memory_pages: u64,
+    pub manifest: Manifest,
+}
+
+/// A guest's metadata in the library
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct Meta {
+    #[serde(default)]
+    version: u32,
+    len: usize,
+    memory_pages: u64,
+    manifest: Manifest,
+}
+
+impl From<&Content> for GuestInfo {
+    fn from(content: &Content) -> Self {
+        // Written by `store`
+        let meta: Meta = serde_json::from_value(content.meta.clone()).unwrap_or_default();
+        Self {
+            id: content.id.clone(),
+            name: content.name.clone().unwrap_or_default(),
+            version: meta.version,
+            len: meta.len,
+            memory_pages: meta.memory_pages,
+            manifest: meta.manifest,
+        }
+    }
+}
+
+/// Keep a checked module as the next version of the guests named `name`.
+pub fn store(
+    db: &Database,
+    id: &str,
+    name: &str,
+    validated: Validated,
+    wasm: &[u8],
+) -> rusqlite::Result<GuestInfo> {
+    let meta = Meta {
+        version: 0,
+        len: wasm.len(),
+        memory_pages: validated.memory_pages,
+        manifest: validated.manifest,
+    };
+    // Can't fail, it's numbers and strings
+    let meta_value = serde_json::to_value(&meta).unwrap();
+    let version = db.insert_version(id, Kind::Guest, name, &meta_value, wasm)?;
+    Ok(GuestInfo {
+        id: id.to_string(),
+        name: name.to_string(),
+        version,
+        len: meta.len,
+        memory_pages: meta.memory_pages,
+        manifest: meta.manifest,
+    })
+}
+
+/// Guest `id` and its module.
+pub fn load(db: &Database, id: &str) -> rusqlite::Result<Option<(GuestInfo, Vec<u8>)>> {
+    let loaded = db.get_with_data(Kind::Guest, id)?;
+    Ok(loaded.map(|(content, wasm)| (GuestInfo::from(&content), wasm)))
+}
+
+/// The MQTT payloads that send `wasm` to a device as transfer `transfer`.
+pub fn chunks(transfer: u32, wasm: &[u8]) -> Vec<Vec<u8>> {
+    let total = wasm.len() as u32;
+    wasm.chunks(MAX_GUEST_CHUNK_DATA_LEN)
+        .enumerate()
+        .map(|(i, data)| {
+            let offset = (i * MAX_GUEST_CHUNK_DATA_LEN) as u32;
+            let mut payload = GuestChunk::header(transfer, offset, total).to_vec();
+            payload.extend_from_slice(data);
+            payload
+        })
+        .collect()
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route(
+            "/api/guests",
+            get(get_guests)
+                .post(post_guest)
+                .layer(DefaultBodyLimit::max(MAX_GUEST_LEN)),
+        )
+        .route("/api/guests/{id}", get(get_guest).delete(delete_guest))
+        .route("/api/guests/{id}/wasm", get(get_wasm))
+        .route("/api/guests/{id}/deploy", post(post_deploy))
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct NameQuery {
+    pub name: Option<String>,
+}
+
+async fn post_guest(
+    State(state): State<AppState>,
+    Query(query): Query<NameQuery>,
+    wasm: Bytes,
+) -> Response {
+    // Compiling a module takes a while
+    let checked = {
+        let wasm = wasm.clone();
+        tokio::task::spawn_blocking(move || validate(&wasm))
+            .await
+            .expect("guest validation panicked")
+    };
+    let validated = match checked {
+        Ok(validated) => validated,
+        Err(e) => return (StatusCode::UNPROCESSABLE_ENTITY, e.to_string()).into_response(),
+    };
+
+    let Some(name) = query.name.or_else(|| validated.manifest.name.clone()) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            "give a name, or a module with one in its manifest",
+        )
+            .into_response();
+    };
+    if name.is_empty() || name.len() > MAX_GUEST_NAME_LEN {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("name must be 1 to {MAX_GUEST_NAME_LEN} bytes"),
+        )
+            .into_response();
+    }
+
+    let id = uuid::Uuid::new_v4().to_string();
+    match store(&state.db, &id, &name, validated, &wasm) {
+        Ok(info) => {
+            info!(
+                "Stored guest {id} ({name} version {}, {} bytes)",
+                info.version, info.len
+            );
+            (StatusCode::CREATED, Json(info)).into_response()
+        }
+        Err(e) => db::error_response(e),
+    }
+}
+
+async fn get_guests(State(state): State<AppState>, Query(query): Query<NameQuery>) -> Response {
+    let search = Search {
+        kind: Some(Kind::Guest),
+        ..Default::default()
+    };
+    match state.db.search(&search) {
+        Ok(found) => Json(
+            found
+                .iter()
+                .map(GuestInfo::from)
+                .filter(|info| query.name.as_ref().is_none_or(|name| *name == info.name))
+                .collect::<Vec<_>>(),
+        )
+        .into_response(),
+        Err(e) => db::error_response(e),
+    }
+}
+
+async fn get_guest(State(state): State<AppState>, Path(id): Path<String>) -> Response {
+    match state.db.get(Some(Kind::Guest), &id) {
+        Ok(Some(content)) => Json(GuestInfo::from(&content)).into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => db::error_response(e),
+    }
+}
+
+async fn get_wasm(State(state): State<AppState>, Path(id): Path<String>) -> Response {
+    match load(&state.db, &id) {
+        Ok(Some((_, wasm))) => ([(header::CONTENT_TYPE, "application/wasm")], wasm).into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => db::error_response(e),
+    }
+}
+
+/// The devices a guest was sent to, as transfer `transfer`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Deployment {
+    pub transfer: u32,
+    pub device_ids: Vec<String>,
+}
+
+async fn post_deploy(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(query): Query<TargetQuery>,
+) -> Response {
+    let target = match required_target(query) {
+        Ok(target) => target,
+        Err(e) => return e.into_response(),
+    };
+    let (info, wasm) = match load(&state.db, &id) {
+        Ok(Some(loaded)) => loaded,
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(e) => return db::error_response(e),
+    };
+
+    // Random, so that a device doesn't take a new transfer for the rest of an old one
+    let transfer = uuid::Uuid::new_v4().as_u128() as u32;
+    let chunks = chunks(transfer, &wasm);
+    let device_ids = state.devices.read().unwrap().resolve(&target);
+    for device_id in &device_ids {
+        let topic = state.topics.device(device_id, suffix::GUEST);
+        for chunk in &chunks {
+            if let Err(e) = state
+                .mqtt_client
+                .publish(&topic, QoS::AtLeastOnce, false, chunk.clone())
+                .await
+            {
+                return (StatusCode::BAD_GATEWAY, format!("MQTT publish failed: {e}"))
+                    .into_response();
+            }
+        }
+    }
+    info!(
+        "Deploying guest {id} ({} version {}) to {device_ids:?} as transfer {transfer}",
+        info.name, info.version
+    );
+    (
+        StatusCode::ACCEPTED,
+        Json(Deployment {
+            transfer,
+            device_ids,
+        }),
+    )
+        .into_response()
+}
+
+async fn delete_guest(State(state): State<AppState>, Path(id): Path<String>) -> Response {
+    match state.db.delete(Some(Kind::Guest), &id) {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => db::error_response(e),
+    }
+}
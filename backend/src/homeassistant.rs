@@ -0,0 +1,388 @@
+//! Home Assistant MQTT discovery, published by the backend on behalf of each discovered device.
+//!
+//! Each device appears in Home Assistant as a JSON schema `light`, plus sensors for FPS, the
+//! power estimate and RSSI read straight from its telemetry topic. Light commands are
+//! translated into device commands much like the [`wled`](crate::wled) API: a colour fills the
+//! panel in `Mode::Direct`, an effect runs a WASM guest, plays one of the library's animations
+//! or switches to DMX, and on/off and brightness set the device brightness.
+
+use std::sync::Arc;
+
+use protocol::presence::{self, DeviceInfo};
+use protocol::topics::suffix;
+use protocol::{Command, DirectCommand, Mode, Rgb};
+use rumqttc::QoS;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{info, warn};
+use web_common::Target;
+
+use crate::AppState;
+use crate::animations;
+use crate::db::{Content, Kind, Search};
+use crate::devices::Device;
+
+/// Home Assistant's default discovery prefix
+pub const DEFAULT_DISCOVERY_PREFIX: &str = "homeassistant";
+
+/// Device topic suffix Home Assistant publishes light commands on
+pub const LIGHT_SET: &str = "ha/light/set";
+/// Device topic suffix the backend publishes the light state on, retained
+pub const LIGHT_STATE: &str = "ha/light/state";
+
+/// Effect that switches the device to `Mode::Dmx`
+pub const EFFECT_DMX: &str = "DMX";
+
+/// What selecting an effect does.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Effect {
+    /// Run the device's WASM guest
+    Guest,
+    /// Switch to `Mode::Dmx`
+    Dmx,
+    /// Play the library's animation with this id
+    Animation(String),
+}
+
+/// The light state reported to Home Assistant, updated by light commands.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LightState {
+    pub on: bool,
+    pub brightness: u8,
+    pub color: Rgb,
+    /// `None` while showing `color`
+    pub effect: Option<String>,
+}
+
+impl Default for LightState {
+    fn default() -> Self {
+        Self {
+            on: true,
+            brightness: 255,
+            color: Rgb::new(255, 255, 255),
+            effect: None,
+        }
+    }
+}
+
+/// A JSON schema light command - everything is optional.
+#[derive(Deserialize, Debug, Default)]
+pub struct LightCommand {
+    /// `"ON"` or `"OFF"`
+    pub state: Option<String>,
+    pub brightness: Option<u8>,
+    pub color: Option<Rgb>,
+    pub effect: Option<String>,
+}
+
+#[derive(Serialize)]
+struct LightStatePayload<'a> {
+    state: &'static str,
+    brightness: u8,
+    color_mode: &'static str,
+    color: Rgb,
+    effect: Option<&'a str>,
+}
+
+impl LightState {
+    fn to_json(&self) -> Vec<u8> {
+        serde_json::to_vec(&LightStatePayload {
+            state: if self.on { "ON" } else { "OFF" },
+            brightness: self.brightness,
+            color_mode: "rgb",
+            color: self.color,
+            effect: self.effect.as_deref(),
+        })
+        .unwrap()
+    }
+
+    /// Apply a light command, returning the device commands that bring the device in line
+    /// with the new state. Unknown effects are ignored, and animations are left to the caller
+    /// to play.
+    pub fn apply(&mut self, command: LightCommand, effects: &[(String, Effect)]) -> Vec<Command> {
+        let mut commands = Vec::new();
+        let before = self.clone();
+
+        match command.state.as_deref() {
+            Some("ON") => self.on = true,
+            Some("OFF") => self.on = false,
+            Some(other) => warn!("Ignoring light state {other:?}"),
+            None => {}
+        }
+        if let Some(brightness) = command.brightness {
+            self.brightness = brightness;
+        }
+        if let Some(color) = command.color {
+            self.color = color;
+            self.effect = None;
+        }
+        if let Some(effect) = command.effect {
+            if effects.iter().any(|(name, _)| *name == effect) {
+                self.effect = Some(effect);
+            } else {
+                warn!("Ignoring unknown effect {effect:?}");
+            }
+        }
+
+        if (self.on, self.brightness) != (before.on, before.brightness) {
+            let level = if self.on { self.brightness } else { 0 };
+            commands.push(Command::SetBrightness(level));
+        }
+        match &self.effect {
+            // Setting the mode is harmless if the device is already in it, and the device may
+            // have been switched by something else since
+            None if self.effect != before.effect || self.color != before.color => {
+                commands.push(Command::SetMode(Mode::Direct));
+                commands.push(Command::DirectCommand(DirectCommand::SetAll {
+                    color: self.color,
+                }));
+            }
+            Some(effect) if self.effect != before.effect => {
+                match effects.iter().find(|(name, _)| name == effect) {
+                    Some((_, Effect::Guest)) => commands.push(Command::SetMode(Mode::Wasm)),
+                    Some((_, Effect::Dmx)) => commands.push(Command::SetMode(Mode::Dmx)),
+                    // The device switches to `Mode::Stream` when the frames arrive
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+
+        commands
+    }
+}
+
+/// The effects a device offers, by name: its WASM guest, the library's `animations`, and DMX.
+pub fn effects(device: &Device, animations: &[Content]) -> Vec<(String, Effect)> {
+    let mut effects: Vec<(String, Effect)> = device
+        .telemetry
+        .as_ref()
+        .and_then(|t| t.guest.as_ref())
+        .map(|guest| (guest.to_string(), Effect::Guest))
+        .into_iter()
+        .collect();
+    for animation in animations {
+        let mut name = animation
+            .name
+            .clone()
+            .unwrap_or_else(|| animation.id.clone());
+        // Effects are selected by name, so each needs its own
+        if name == EFFECT_DMX || effects.iter().any(|(n, _)| *n == name) {
+            name = format!("{name} ({})", animation.id);
+        }
+        effects.push((name, Effect::Animation(animation.id.clone())));
+    }
+    effects.push((EFFECT_DMX.to_string(), Effect::Dmx));
+    effects
+}
+
+/// The library's animations, to offer as effects.
+fn stored_animations(state: &AppState) -> Vec<Content> {
+    let search = Search {
+        kind: Some(Kind::Animation),
+        ..Default::default()
+    };
+    state.db.search(&search).unwrap_or_else(|e| {
+        warn!("Animations not offered as effects: {e}");
+        Vec::new()
+    })
+}
+
+/// Discovery config topics and payloads for a device.
+fn discovery_configs(
+    state: &AppState,
+    info: &DeviceInfo,
+    effects: &[(String, Effect)],
+) -> Vec<(String, serde_json::Value)> {
+    let id = info.device_id.as_str();
+    let topics = &state.topics;
+    let config_topic = |component: &str, object: &str| {
+        format!("{}/{component}/{id}/{object}/config", topics.discovery)
+    };
+    let telemetry_topic = topics.device(id, suffix::TELEMETRY);
+
+    let device = json!({
+        "identifiers": [id],
+        "name": format!("{} {id}", presence::CLIENT_ID_PREFIX.trim_end_matches('-')),
+        "model": format!("{}x{} LED panel", info.width, info.height),
+        "sw_version": info.firmware_version.as_str(),
+    });
+    let availability = json!({
+        "availability_topic": topics.device(id, suffix::STATUS),
+        "payload_available": presence::ONLINE,
+        "payload_not_available": presence::OFFLINE,
+    });
+    let with_common = |mut config: serde_json::Value| {
+        config["device"] = device.clone();
+        for (k, v) in availability.as_object().unwrap() {
+            config[k] = v.clone();
+        }
+        config
+    };
+
+    let sensor = |object: &str, name: &str, value_template: &str, unit: &str| {
+        with_common(json!({
+            "name": name,
+            "unique_id": format!("{id}_{object}"),
+            "state_topic": telemetry_topic,
+            "value_template": value_template,
+            "unit_of_measurement": unit,
+            "state_class": "measurement",
+        }))
+    };
+
+    let mut power = sensor(
+        "power",
+        "Power estimate",
+        "{{ value_json.power_mw / 1000 }}",
+        "W",
+    );
+    power["device_class"] = json!("power");
+    let mut rssi = sensor("rssi", "RSSI", "{{ value_json.rssi_dbm }}", "dBm");
+    rssi["device_class"] = json!("signal_strength");
+    rssi["entity_category"] = json!("diagnostic");
+
+    vec![
+        (
+            config_topic("light", "panel"),
+            with_common(json!({
+                "name": null,
+                "unique_id": format!("{id}_panel"),
+                "schema": "json",
+                "command_topic": topics.device(id, LIGHT_SET),
+                "state_topic": topics.device(id, LIGHT_STATE),
+                "brightness": true,
+                "brightness_scale": 255,
+                "supported_color_modes": ["rgb"],
+                "effect": true,
+                "effect_list": effects.iter().map(|(name, _)| name).collect::<Vec<_>>(),
+            })),
+        ),
+        (
+            config_topic("sensor", "fps"),
+            sensor("fps", "FPS", "{{ value_json.frames.fps }}", "fps"),
+        ),
+        (config_topic("sensor", "power"), power),
+        (config_topic("sensor", "rssi"), rssi),
+    ]
+}
+
+/// Publish (retained) the discovery configs for a device, if it has announced itself.
+pub async fn publish_discovery(state: &AppState, device_id: &str) {
+    let animations = stored_animations(state);
+    let (info, effects) = {
+        let registry = state.devices.read().unwrap();
+        let Some(device) = registry.get(device_id) else {
+            return;
+        };
+        let Some(info) = device.info.clone() else {
+            return;
+        };
+        (info, effects(device, &animations))
+    };
+
+    info!(
+        "Publishing Home Assistant discovery for {device_id}, {} effects",
+        effects.len()
+    );
+    for (topic, config) in discovery_configs(state, &info, &effects) {
+        let payload = serde_json::to_vec(&config).unwrap();
+        if let Err(e) = state
+            .mqtt_client
+            .publish(topic, QoS::AtLeastOnce, true, payload)
+            .await
+        {
+            warn!("Home Assistant discovery not published: {e}");
+        }
+    }
+}
+
+/// Publish the discovery configs for every device again, as the library's animations changed.
+pub fn republish_discovery(state: &AppState) {
+    let device_ids: Vec<String> = state
+        .devices
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|device| device.info.is_some())
+        .map(|device| device.device_id.clone())
+        .collect();
+    let state = state.clone();
+    tokio::spawn(async move {
+        for device_id in device_ids {
+            publish_discovery(&state, &device_id).await;
+        }
+    });
+}
+
+/// Handle a light command from Home Assistant.
+pub async fn handle_light_command(state: &AppState, device_id: &str, payload: &[u8]) {
+    let command = match serde_json::from_slice::<LightCommand>(payload) {
+        Ok(command) => command,
+        Err(e) => {
+            warn!("Invalid light command for {device_id}: {e}");
+            return;
+        }
+    };
+
+    let animations = stored_animations(state);
+    let (commands, light, before, effects) = {
+        let mut registry = state.devices.write().unwrap();
+        let device = registry.entry(device_id);
+        let effects = effects(device, &animations);
+        let before = device.light.clone();
+        let commands = device.light.apply(command, &effects);
+        (commands, device.light.clone(), before, effects)
+    };
+
+    // Animations are played from here, as there's no device command for them: when one is
+    // picked, or the light is turned back on showing one
+    let target = Target::Device(device_id.to_string());
+    let animation = light
+        .effect
+        .as_ref()
+        .filter(|_| light.on && (light.effect != before.effect || !before.on))
+        .and_then(|name| {
+            effects
+                .into_iter()
+                .find_map(|(effect_name, effect)| match effect {
+                    Effect::Animation(id) if effect_name == *name => Some(id),
+                    _ => None,
+                })
+        });
+    if let Some(id) = animation {
+        match animations::load(&state.db, &id) {
+            Ok(Some(animation)) => state.players.play(state, target, Arc::new(animation)),
+            Ok(None) => warn!("Animation {id} is no longer in the library"),
+            Err(e) => warn!("Animation {id} not loaded: {e}"),
+        }
+    } else if !light.on
+        || commands
+            .iter()
+            .any(|command| matches!(command, Command::SetMode(_)))
+    {
+        // An animation would go on streaming to a light that's off, or over whatever the
+        // device was switched to
+        state.players.stop(&target);
+    }
+
+    for command in commands {
+        info!("Home Assistant -> {device_id}: {command:?}");
+        if let Err(e) = crate::commands::send(state, device_id, command).await {
+            warn!("{e}");
+        }
+    }
+
+    if let Err(e) = state
+        .mqtt_client
+        .publish(
+            state.topics.device(device_id, LIGHT_STATE),
+            QoS::AtLeastOnce,
+            true,
+            light.to_json(),
+        )
+        .await
+    {
+        warn!("Light state not published: {e}");
+    }
+}
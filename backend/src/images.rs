@@ -0,0 +1,451 @@
+//! Still images for the panel: uploaded, converted to a frame, kept, and shown by streaming
+//! the frame (see [`stream`]).
+//!
+//! `POST /api/images` takes a PNG, JPEG, BMP or WebP as the request body, and these query
+//! parameters, all optional:
+//!
+//! - `fit`: `crop` (the default) fills the panel, cropping the longer side; `contain` fits the
+//!   whole image, with black bars; `stretch` ignores the aspect ratio
+//! - `filter`: `nearest` for pixel art, `triangle`, or `lanczos` (the default) for photos
+//! - `bits`: colour depth per channel, 1 to 8 (the default, no quantisation)
+//! - `dither`: `true` to diffuse the quantisation error (Floyd-Steinberg)
+//! - `name`: to recognise it by
+//! - `device` or `group`: to show it straight away, instead of any animation playing there
+//!   (see [`animations`](crate::animations))
+//!
+//! It answers with the stored image's [`ImageInfo`]. Then:
+//!
+//! - `GET /api/images` lists them
+//! - `GET /api/images/{id}` is the converted image, as a panel-sized PNG
+//! - `POST /api/images/{id}/show?device=..` (or `group=..`) shows it again
+//! - `DELETE /api/images/{id}` forgets it
+//!
+//! Images are kept in the library (see [`db`]), where they can be named and tagged.
+
+use std::io::Cursor;
+
+use axum::body::Bytes;
+use axum::extract::{DefaultBodyLimit, Path, Query, State};
+use axum::http::{StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use common::{BYTES_PER_LED, LED_BUFFER_SIZE, LED_PANEL_HEIGHT, LED_PANEL_WIDTH};
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, ImageFormat, ImageReader, Limits, RgbImage, RgbaImage};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use web_common::Target;
+
+use crate::db::{self, Content, Database, Kind, Search};
+use crate::{AppState, stream};
+
+/// Largest upload accepted
+pub const MAX_UPLOAD_LEN: usize = 16 * 1024 * 1024;
+/// Largest image decoded, in either dimension
+pub const MAX_DIMENSION: u32 = 8192;
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Fit {
+    #[default]
+    Crop,
+    Contain,
+    Stretch,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Filter {
+    Nearest,
+    Triangle,
+    #[default]
+    Lanczos,
+}
+
+impl From<Filter> for FilterType {
+    fn from(filter: Filter) -> Self {
+        match filter {
+            Filter::Nearest => FilterType::Nearest,
+            Filter::Triangle => FilterType::Triangle,
+            Filter::Lanczos => FilterType::Lanczos3,
+        }
+    }
+}
+
+/// How an image is made into a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Conversion {
+    pub fit: Fit,
+    pub filter: Filter,
+    /// Bits per channel, 1 to 8
+    pub bits: u8,
+    pub dither: bool,
+}
+
+impl Default for Conversion {
+    fn default() -> Self {
+        Self {
+            fit: Fit::default(),
+            filter: Filter::default(),
+            bits: 8,
+            dither: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConvertError {
+    /// Not a PNG, JPEG, BMP or WebP
+    UnsupportedFormat,
+    Decode(image::ImageError),
+    BadBits(u8),
+}
+
+impl std::fmt::Display for ConvertError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConvertError::UnsupportedFormat => write!(f, "not a PNG, JPEG, BMP or WebP image"),
+            ConvertError::Decode(e) => write!(f, "invalid image: {e}"),
+            ConvertError::BadBits(bits) => write!(f, "bits must be 1 to 8, not {bits}"),
+        }
+    }
+}
+
+impl std::error::Error for ConvertError {}
+
+pub fn decode(data: &[u8]) -> Result<DynamicImage, ConvertError> {
+    let mut reader = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .map_err(|_| ConvertError::UnsupportedFormat)?;
+    match reader.format() {
+        Some(ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::Bmp | ImageFormat::WebP) => {}
+        _ => return Err(ConvertError::UnsupportedFormat),
+    }
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+    reader.limits(limits);
+    reader.decode().map_err(ConvertError::Decode)
+}
+
+/// Decode `data` and convert it into a frame: [`LED_BUFFER_SIZE`] bytes of row-major RGB.
+pub fn convert(data: &[u8], conversion: &Conversion) -> Result<Vec<u8>, ConvertError> {
+    if !(1..=8).contains(&conversion.bits) {
+        return Err(ConvertError::BadBits(conversion.bits));
+    }
+    Ok(to_frame(&decode(data)?, conversion))
+}
+
+/// Convert a decoded image into a frame.
+pub fn to_frame(image: &DynamicImage, conversion: &Conversion) -> Vec<u8> {
+    let (width, height) = (LED_PANEL_WIDTH as u32, LED_PANEL_HEIGHT as u32);
+    let filter = conversion.filter.into();
+    let resized = match conversion.fit {
+        Fit::Crop => image.resize_to_fill(width, height, filter),
+        Fit::Stretch => image.resize_exact(width, height, filter),
+        Fit::Contain => {
+            let fitted = image.resize(width, height, filter).to_rgba8();
+            let mut canvas = RgbaImage::new(width, height);
+            let x = (width - fitted.width()) / 2;
+            let y = (height - fitted.height()) / 2;
+            imageops::overlay(&mut canvas, &fitted, x.into(), y.into());
+            DynamicImage::ImageRgba8(canvas)
+        }
+    };
+
+    // Transparency shows as black, as unlit LEDs do
+    let mut frame: Vec<u8> = resized
+        .to_rgba8()
+        .pixels()
+        .flat_map(|p| {
+            let [r, g, b, a] = p.0;
+            let over_black = |c: u8| ((c as u16 * a as u16 + 127) / 255) as u8;
+            [over_black(r), over_black(g), over_black(b)]
+        })
+        .collect();
+    quantise(
+        &mut frame,
+        LED_PANEL_WIDTH,
+        conversion.bits,
+        conversion.dither,
+    );
+    frame
+}
+
+/// Reduce each channel of a row-major RGB `frame` to `bits` bits, spread back over 0..=255,
+/// optionally diffusing the error to the neighbouring pixels (Floyd-Steinberg).
+pub fn quantise(frame: &mut [u8], width: usize, bits: u8, dither: bool) {
+    if bits >= 8 {
+        return;
+    }
+    let steps = ((1u16 << bits) - 1) as f32;
+    let nearest = |v: f32| ((v.clamp(0.0, 255.0) / 255.0 * steps).round() / steps * 255.0).round();
+
+    if !dither {
+        for c in frame.iter_mut() {
+            *c = nearest(*c as f32) as u8;
+        }
+        return;
+    }
+
+    let height = frame.len() / (width * BYTES_PER_LED);
+    let mut values: Vec<f32> = frame.iter().map(|&c| c as f32).collect();
+    let at = |x: usize, y: usize, channel: usize| (y * width + x) * BYTES_PER_LED + channel;
+    for y in 0..height {
+        for x in 0..width {
+            for channel in 0..BYTES_PER_LED {
+                let i = at(x, y, channel);
+                let old = values[i];
+                let new = nearest(old);
+                values[i] = new;
+                let error = old - new;
+
+                let mut spread = |x: Option<usize>, y: usize, weight: f32| {
+                    if let Some(x) = x.filter(|&x| x < width)
+                        && y < height
+                    {
+                        values[at(x, y, channel)] += error * weight;
+                    }
+                };
+                spread(Some(x + 1), y, 7.0 / 16.0);
+                spread(x.checked_sub(1), y + 1, 3.0 / 16.0);
+                spread(Some(x), y + 1, 5.0 / 16.0);
+                spread(Some(x + 1), y + 1, 1.0 / 16.0);
+            }
+        }
+    }
+    for (c, v) in frame.iter_mut().zip(values) {
+        *c = v as u8;
+    }
+}
+
+/// What's known about a stored image.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ImageInfo {
+    pub id: String,
+    pub name: Option<String>,
+    /// Of the upload, before conversion
+    pub width: u32,
+    pub height: u32,
+}
+
+/// An image's metadata in the library
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+struct Meta {
+    width: u32,
+    height: u32,
+}
+
+impl From<&Content> for ImageInfo {
+    fn from(content: &Content) -> Self {
+        // Written by `store`
+        let meta: Meta = serde_json::from_value(content.meta.clone()).unwrap_or_default();
+        Self {
+            id: content.id.clone(),
+            name: content.name.clone(),
+            width: meta.width,
+            height: meta.height,
+        }
+    }
+}
+
+/// Keep a converted image (its [`LED_BUFFER_SIZE`] byte `frame`) in the library.
+pub fn store(db: &Database, info: &ImageInfo, frame: &[u8]) -> rusqlite::Result<()> {
+    let meta = Meta {
+        width: info.width,
+        height: info.height,
+    };
+    // Can't fail, it's two numbers
+    let meta = serde_json::to_value(meta).unwrap();
+    db.insert(&info.id, Kind::Image, info.name.as_deref(), &meta, frame)
+}
+
+/// Image `id` and its frame.
+pub fn load(db: &Database, id: &str) -> rusqlite::Result<Option<(ImageInfo, Vec<u8>)>> {
+    let loaded = db.get_with_data(Kind::Image, id)?;
+    Ok(loaded.map(|(content, frame)| (ImageInfo::from(&content), frame)))
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route(
+            "/api/images",
+            get(get_images)
+                .post(post_image)
+                .layer(DefaultBodyLimit::max(MAX_UPLOAD_LEN)),
+        )
+        .route("/api/images/{id}", get(get_image).delete(delete_image))
+        .route("/api/images/{id}/show", post(post_show))
+}
+
+/// Where to show an image: `device=..` or `group=..`.
+#[derive(Deserialize, Debug, Default)]
+pub struct TargetQuery {
+    pub device: Option<String>,
+    pub group: Option<String>,
+}
+
+impl TargetQuery {
+    pub(crate) fn target(self) -> Result<Option<Target>, (StatusCode, &'static str)> {
+        match (self.device, self.group) {
+            (None, None) => Ok(None),
+            (Some(device), None) => Ok(Some(Target::Device(device))),
+            (None, Some(group)) => Ok(Some(Target::Group(group))),
+            (Some(_), Some(_)) => Err((
+                StatusCode::BAD_REQUEST,
+                "give a device or a group, not both",
+            )),
+        }
+    }
+}
+
+// Flat, as `Query` can't parse numbers and flags in flattened structs
+#[derive(Deserialize, Debug)]
+pub struct UploadQuery {
+    #[serde(default)]
+    pub fit: Fit,
+    #[serde(default)]
+    pub filter: Filter,
+    pub bits: Option<u8>,
+    #[serde(default)]
+    pub dither: bool,
+    pub name: Option<String>,
+    pub device: Option<String>,
+    pub group: Option<String>,
+}
+
+impl UploadQuery {
+    /// The conversion asked for, and where to show the result.
+    pub(crate) fn parse(&self) -> Result<(Conversion, Option<Target>), (StatusCode, String)> {
+        let target = TargetQuery {
+            device: self.device.clone(),
+            group: self.group.clone(),
+        }
+        .target()
+        .map_err(|(status, reason)| (status, reason.to_string()))?;
+        let conversion = Conversion {
+            fit: self.fit,
+            filter: self.filter,
+            bits: self.bits.unwrap_or(8),
+            dither: self.dither,
+        };
+        if !(1..=8).contains(&conversion.bits) {
+            let e = ConvertError::BadBits(conversion.bits);
+            return Err((StatusCode::BAD_REQUEST, e.to_string()));
+        }
+        Ok((conversion, target))
+    }
+}
+
+async fn show(state: &AppState, target: &Target, frame: &[u8]) -> Result<(), (StatusCode, String)> {
+    state.players.stop(target);
+    stream::send_to(state, target, frame)
+        .await
+        .map_err(|e| (StatusCode::BAD_GATEWAY, e.to_string()))
+}
+
+async fn post_image(
+    State(state): State<AppState>,
+    Query(query): Query<UploadQuery>,
+    data: Bytes,
+) -> Response {
+    let (conversion, target) = match query.parse() {
+        Ok(parsed) => parsed,
+        Err(e) => return e.into_response(),
+    };
+
+    // Decoding and resampling a large photo takes a while
+    let converted = tokio::task::spawn_blocking(move || {
+        decode(&data).map(|image| {
+            let frame = to_frame(&image, &conversion);
+            (image.width(), image.height(), frame)
+        })
+    })
+    .await
+    .expect("image conversion panicked");
+    let (width, height, frame) = match converted {
+        Ok(converted) => converted,
+        Err(e @ ConvertError::UnsupportedFormat) => {
+            return (StatusCode::UNSUPPORTED_MEDIA_TYPE, e.to_string()).into_response();
+        }
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+    debug_assert_eq!(frame.len(), LED_BUFFER_SIZE);
+
+    let info = ImageInfo {
+        id: uuid::Uuid::new_v4().to_string(),
+        name: query.name,
+        width,
+        height,
+    };
+    info!(
+        "Stored image {} ({width}x{height}, {conversion:?})",
+        info.id
+    );
+    if let Err(e) = store(&state.db, &info, &frame) {
+        return db::error_response(e);
+    }
+
+    if let Some(target) = target
+        && let Err(e) = show(&state, &target, &frame).await
+    {
+        return e.into_response();
+    }
+    (StatusCode::CREATED, Json(info)).into_response()
+}
+
+async fn get_images(State(state): State<AppState>) -> Response {
+    let search = Search {
+        kind: Some(Kind::Image),
+        ..Default::default()
+    };
+    match state.db.search(&search) {
+        Ok(found) => Json(found.iter().map(ImageInfo::from).collect::<Vec<_>>()).into_response(),
+        Err(e) => db::error_response(e),
+    }
+}
+
+async fn get_image(State(state): State<AppState>, Path(id): Path<String>) -> Response {
+    let frame = match load(&state.db, &id) {
+        Ok(Some((_, frame))) => frame,
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(e) => return db::error_response(e),
+    };
+    // Can't fail, the frame is the panel's size
+    let image = RgbImage::from_raw(LED_PANEL_WIDTH as u32, LED_PANEL_HEIGHT as u32, frame).unwrap();
+    let mut png = Vec::new();
+    if let Err(e) = image.write_to(&mut Cursor::new(&mut png), ImageFormat::Png) {
+        return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+    }
+    ([(header::CONTENT_TYPE, "image/png")], png).into_response()
+}
+
+async fn post_show(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(query): Query<TargetQuery>,
+) -> Response {
+    let target = match query.target() {
+        Ok(Some(target)) => target,
+        Ok(None) => return (StatusCode::BAD_REQUEST, "give a device or a group").into_response(),
+        Err(e) => return e.into_response(),
+    };
+    let frame = match load(&state.db, &id) {
+        Ok(Some((_, frame))) => frame,
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(e) => return db::error_response(e),
+    };
+    match show(&state, &target, &frame).await {
+        Ok(()) => StatusCode::ACCEPTED.into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+async fn delete_image(State(state): State<AppState>, Path(id): Path<String>) -> Response {
+    match state.db.delete(Some(Kind::Image), &id) {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => db::error_response(e),
+    }
+}
@@ -1,39 +1,91 @@
-use axum::extract::ws::{Message, WebSocket};
+use axum::extract::ws::{CloseFrame, Message, WebSocket, close_code};
 use axum::extract::{State, WebSocketUpgrade};
+use axum::http::HeaderMap;
+use axum::middleware;
 use axum::response::IntoResponse;
 use axum::routing::get;
-use axum::{Json, Router};
-use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Packet, QoS};
+use axum::{Extension, Json, Router};
+use protocol::ping::PingRequest;
+use protocol::topics::{parse_device_topic, suffix};
+use rumqttc::{AsyncClient, Event, EventLoop, Packet, QoS};
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
 use std::sync::{Arc, RwLock};
 use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::sync::broadcast;
 use tower_http::services::ServeDir;
 use tracing::{error, info, warn};
-use web_common::{ClientMsg, LastMessage, ServerMsg};
+use web_common::{ClientMsg, LastMessage, ServerMsg, Target};
 
-// Default MQTT topic prefix (production):
-pub const DEFAULT_PREFIX: &str = "esp32-wasmi-led";
+pub mod animations;
+pub mod aseprite;
+pub mod auth;
+pub mod broker;
+pub mod commands;
+pub mod config;
+pub mod control;
+pub mod db;
+pub mod devices;
+pub mod gif;
+pub mod guests;
+pub mod homeassistant;
+pub mod images;
+pub mod mdns;
+pub mod playlists;
+pub mod schedules;
+pub mod simulator;
+pub mod stream;
+pub mod wled;
+
+use auth::{Caller, Role};
+use broker::Broker;
+use stream::Streamers;
+
+// Default MQTT topic prefix (production), as used by the device:
+pub use protocol::topics::DEFAULT_PREFIX;
 
 /// MQTT topic configuration, to support concurrent testing with unique prefixes
 #[derive(Debug, Clone)]
 pub struct Topics {
+    pub prefix: String,
     pub send: String,
     pub poll: String,
     pub live: String,
-    pub ping_req: String,
-    pub ping_resp: String,
+    /// Home Assistant discovery prefix
+    pub discovery: String,
 }
 
 impl Topics {
     pub fn new(prefix: &str) -> Self {
+        let topic = |suffix: &str| format!("{prefix}/{suffix}");
         Self {
-            send: format!("{prefix}/send"),
-            poll: format!("{prefix}/poll"),
-            live: format!("{prefix}/live"),
-            ping_req: format!("{prefix}/ping/request"),
-            ping_resp: format!("{prefix}/ping/response"),
+            prefix: prefix.to_string(),
+            send: topic(suffix::SEND),
+            poll: topic(suffix::POLL),
+            live: topic(suffix::LIVE),
+            discovery: homeassistant::DEFAULT_DISCOVERY_PREFIX.to_string(),
         }
     }
+
+    pub fn with_discovery_prefix(mut self, discovery: &str) -> Self {
+        self.discovery = discovery.to_string();
+        self
+    }
+
+    /// `{prefix}/{device_id}/{suffix}`
+    pub fn device(&self, device_id: &str, suffix: &str) -> String {
+        format!("{}/{device_id}/{suffix}", self.prefix)
+    }
+
+    /// Wildcard matching `suffix` for every device
+    pub fn all_devices(&self, suffix: &str) -> String {
+        self.device("+", suffix)
+    }
+
+    /// The device id and suffix, if `topic` is a device topic.
+    pub fn parse_device<'t>(&self, topic: &'t str) -> Option<(&'t str, &'t str)> {
+        parse_device_topic(&self.prefix, topic)
+    }
 }
 
 impl Default for Topics {
@@ -49,22 +101,33 @@ pub struct AppState {
     /// Broadcast channel: backend + all WebSocket clients
     pub tx: broadcast::Sender<ServerMsg>,
     pub topics: Topics,
-}
-
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
-pub struct PingPayload {
-    pub correlation_id: String,
-    pub message: String,
+    /// Shared by all WebSocket clients, so each device's sequence numbers stay consistent
+    pub streamers: Arc<Streamers>,
+    /// State reported by the WLED-compatible API
+    pub wled: Arc<RwLock<wled::WledState>>,
+    /// Set while WLED realtime UDP data is being shown
+    pub wled_live: Arc<AtomicBool>,
+    /// Shared by all clients, so command ids are unique
+    pub commands: Arc<commands::PendingCommands>,
+    /// Every device seen on its device topics
+    pub devices: Arc<RwLock<devices::Registry>>,
+    /// The content library and device settings
+    pub db: Arc<db::Database>,
+    /// The animations and playlists playing
+    pub players: Arc<animations::Players>,
+    /// The local time, for schedules and clocks
+    pub clock: Arc<dyn schedules::Clock>,
+    /// Given to callers of the WLED JSON API without credentials, see [`auth`]
+    pub wled_role: Option<Role>,
 }
 
 /// Create MQTT client and event loop, and subscribe to relevant topics
 pub async fn create_mqtt(
     client_id: &str,
-    host: &str,
-    port: u16,
+    broker: &Broker,
     topics: &Topics,
 ) -> (AsyncClient, EventLoop) {
-    let mut opts = MqttOptions::new(client_id, host, port);
+    let mut opts = broker.options(client_id);
     opts.set_keep_alive(std::time::Duration::from_secs(30));
 
     let (client, eventloop) = AsyncClient::new(opts, 50);
@@ -77,23 +140,55 @@ pub async fn create_mqtt(
         .subscribe(&topics.live, QoS::AtLeastOnce)
         .await
         .unwrap();
-    client
-        .subscribe(&topics.ping_resp, QoS::AtLeastOnce)
-        .await
-        .unwrap();
+    // Everything the devices publish, see `devices::handle_publish`
+    for device_suffix in [
+        suffix::STATUS,
+        suffix::INFO,
+        suffix::TELEMETRY,
+        suffix::COMMAND_ACK,
+        suffix::PING_RESPONSE,
+        suffix::CONFIG,
+        homeassistant::LIGHT_SET,
+    ] {
+        client
+            .subscribe(topics.all_devices(device_suffix), QoS::AtLeastOnce)
+            .await
+            .unwrap();
+    }
 
     (client, eventloop)
 }
 
-/// Build the shared application state
-pub fn create_state(mqtt_client: AsyncClient, topics: Topics) -> AppState {
+/// Build the shared application state, with the devices' settings from `db`
+pub fn create_state(mqtt_client: AsyncClient, topics: Topics, db: db::Database) -> AppState {
     let (tx, _rx) = broadcast::channel::<ServerMsg>(100);
 
+    let streamers = Streamers::new(mqtt_client.clone(), topics.clone());
+
+    let mut devices = devices::Registry::default();
+    match db.device_groups() {
+        Ok(device_groups) => {
+            for (device_id, groups) in device_groups {
+                devices.entry(&device_id).groups = groups;
+            }
+        }
+        Err(e) => warn!("Couldn't load the device settings: {e}"),
+    }
+
     AppState {
         mqtt_client,
         last_poll_msg: Arc::new(RwLock::new(None)),
         tx,
         topics,
+        streamers: Arc::new(streamers),
+        wled: Arc::new(RwLock::new(wled::WledState::default())),
+        wled_live: Arc::new(AtomicBool::new(false)),
+        commands: Arc::default(),
+        devices: Arc::new(RwLock::new(devices)),
+        db: Arc::new(db),
+        players: Arc::default(),
+        clock: Arc::new(schedules::LocalClock),
+        wled_role: None,
     }
 }
 
@@ -124,20 +219,15 @@ pub fn spawn_mqtt_loop(mut eventloop: EventLoop, state: AppState) -> tokio::task
                                 }
                             }
                         }
-                        t if t == state.topics.ping_resp => {
-                            if let Ok(resp) = serde_json::from_str::<PingPayload>(&payload) {
-                                match state.tx.send(ServerMsg::PingResponse {
-                                    correlation_id: resp.correlation_id,
-                                    device_reply: resp.message,
-                                }) {
-                                    Ok(_) => {}
-                                    Err(e) => {
-                                        error!("MQTT send error: {e}")
-                                    }
-                                }
-                            } else {
-                                warn!("Invalid JSON: {payload:?}");
-                            }
+                        t if let Some((device_id, device_suffix)) =
+                            state.topics.parse_device(t) =>
+                        {
+                            devices::handle_publish(
+                                &state,
+                                device_id,
+                                device_suffix,
+                                &publish.payload,
+                            );
                         }
                         _ => {
                             warn!("Received message on unexpected topic: {topic}");
@@ -154,18 +244,35 @@ pub fn spawn_mqtt_loop(mut eventloop: EventLoop, state: AppState) -> tokio::task
     })
 }
 
-/// Build the Axum router (without fallback, for testing)
+/// Build the Axum router (without fallback, for testing). Every route is checked by
+/// [`auth::authorise`].
 pub fn build_router(state: AppState) -> Router {
     Router::new()
         .route("/api/ws", get(ws_handler))
         .route("/api/last-message", get(get_last_message))
+        .merge(auth::router())
+        .merge(devices::router())
+        .merge(commands::router())
+        .merge(control::router())
+        .merge(images::router())
+        .merge(animations::router())
+        .merge(aseprite::router())
+        .merge(guests::router())
+        .merge(playlists::router())
+        .merge(schedules::router())
+        .merge(db::router())
+        .merge(wled::router())
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::authorise,
+        ))
         .with_state(state)
 }
 
 /// Build the full router including static file serving
-pub fn build_app(state: AppState) -> Router {
-    // Serve the frontend Wasm app from ./dist (trunk output)
-    build_router(state).fallback_service(ServeDir::new("dist"))
+pub fn build_app(state: AppState, static_dir: &Path) -> Router {
+    // Serve the frontend Wasm app (trunk output)
+    build_router(state).fallback_service(ServeDir::new(static_dir))
 }
 
 // HTTP handler: user-initiated poll
@@ -178,44 +285,172 @@ async fn get_last_message(State(state): State<AppState>) -> impl IntoResponse {
 }
 
 // WebSocket handler
-async fn ws_handler(ws: WebSocketUpgrade, State(state): State<AppState>) -> impl IntoResponse {
-    ws.on_upgrade(|socket| handle_socket(socket, state))
+async fn ws_handler(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Extension(caller): Extension<Caller>,
+) -> impl IntoResponse {
+    ws.on_upgrade(|socket| handle_socket(socket, state, headers, caller))
+}
+
+/// The caller who opened a socket with `headers`, as they are now, or `None` if they've logged
+/// out or their token or user is gone.
+fn recheck_caller(state: &AppState, headers: &HeaderMap) -> Option<Caller> {
+    auth::caller(&state.db, headers).unwrap_or_else(|e| {
+        warn!("WebSocket caller not checked: {e}");
+        None
+    })
+}
+
+/// Close a socket whose caller is gone.
+async fn close_unauthorised(mut socket: WebSocket) {
+    let close = CloseFrame {
+        code: close_code::POLICY,
+        reason: "logged out".into(),
+    };
+    let _ = socket.send(Message::Close(Some(close))).await;
+}
+
+/// Tell a client its role doesn't allow `action`, returning whether it's still there.
+async fn forbidden(socket: &mut WebSocket, action: &str) -> bool {
+    let json = serde_json::to_string(&ServerMsg::Forbidden {
+        action: action.to_string(),
+    })
+    .unwrap();
+    socket.send(Message::Text(json.into())).await.is_ok()
 }
 
-async fn handle_socket(mut socket: WebSocket, state: AppState) {
+async fn handle_socket(
+    mut socket: WebSocket,
+    state: AppState,
+    headers: HeaderMap,
+    mut caller: Caller,
+) {
     let mut rx = state.tx.subscribe();
 
+    // Device changes are only pushed, so start the client off with what we know
+    for msg in devices::snapshot(&state) {
+        let json = serde_json::to_string(&msg).unwrap();
+        if socket.send(Message::Text(json.into())).await.is_err() {
+            return;
+        }
+    }
+    // Where this client's binary frames go
+    let mut stream_target = Target::default();
+    let mut simulator = simulator::Session::default();
+    let mut recheck = tokio::time::interval(auth::RECHECK_INTERVAL);
+
     loop {
         tokio::select! {
             // Messages from the frontend:
             msg = socket.recv() => {
+                // Acted on as the caller is now, not as they were when the socket was opened
+                if matches!(msg, Some(Ok(Message::Text(_) | Message::Binary(_)))) {
+                    match recheck_caller(&state, &headers) {
+                        Some(now) => caller = now,
+                        None => return close_unauthorised(socket).await,
+                    }
+                }
+                // Viewers only watch
+                let may_paint = caller.may(Role::Painter);
                 match msg {
                     Some(Ok(Message::Text(text))) => {
                         match serde_json::from_str::<ClientMsg>(&text) {
+                            Ok(ClientMsg::Publish { .. } | ClientMsg::PingDevice { .. } | ClientMsg::Command { .. })
+                                if !may_paint =>
+                            {
+                                if !forbidden(&mut socket, "sending to devices").await {
+                                    return;
+                                }
+                            }
                             Ok(ClientMsg::Publish { payload }) => {
                                 info!("Publishing to MQTT: {payload}");
                                 let _ = state.mqtt_client
                                     .publish(&state.topics.send, QoS::AtLeastOnce, false, payload.as_bytes())
                                     .await;
                             }
-                            Ok(ClientMsg::PingDevice { correlation_id }) => {
-                                info!("Pinging Device: {correlation_id}");
-                                let ping = serde_json::to_string(&PingPayload {
+                            Ok(ClientMsg::PingDevice { correlation_id, target }) => {
+                                info!("Pinging {target:?}: {correlation_id}");
+                                let Ok(correlation_id) = correlation_id.as_str().try_into() else {
+                                    warn!("Correlation ID too long: {correlation_id}");
+                                    continue;
+                                };
+                                let ping = serde_json::to_string(&PingRequest {
                                     correlation_id,
-                                    message: "ping".into(),
+                                    message: "ping".try_into().unwrap(),
                                 }).unwrap();
-                                let _ = state.mqtt_client
-                                    .publish(&state.topics.ping_req, QoS::AtLeastOnce, false, ping.as_bytes())
-                                    .await;
+                                let device_ids = state.devices.read().unwrap().resolve(&target);
+                                for device_id in device_ids {
+                                    let topic = state.topics.device(&device_id, suffix::PING_REQUEST);
+                                    let _ = state.mqtt_client
+                                        .publish(topic, QoS::AtLeastOnce, false, ping.as_bytes())
+                                        .await;
+                                }
+                            }
+                            Ok(ClientMsg::Command { target, command }) => {
+                                // Acks are broadcast to all clients; tell this one which ids to expect
+                                for (device_id, result) in commands::send_to(&state, &target, *command).await {
+                                    let id = match result {
+                                        Ok(id) => id,
+                                        Err(e) => {
+                                            warn!("Command not sent to {device_id}: {e}");
+                                            continue;
+                                        }
+                                    };
+                                    let json = serde_json::to_string(&ServerMsg::CommandSent { device_id, id }).unwrap();
+                                    if socket.send(Message::Text(json.into())).await.is_err() {
+                                        return;
+                                    }
+                                }
+                            }
+                            Ok(ClientMsg::SetStreamTarget { target }) => {
+                                info!("Streaming to {target:?}");
+                                stream_target = target;
+                            }
+                            Ok(ClientMsg::Simulate { control }) => {
+                                if let Some(msg) = simulator.control(&state.db, control).await {
+                                    let json = serde_json::to_string(&msg).unwrap();
+                                    if socket.send(Message::Text(json.into())).await.is_err() {
+                                        return;
+                                    }
+                                }
                             }
                             Err(e) => warn!("Bad client message: {e}"),
                         }
                     }
+                    Some(Ok(Message::Binary(_))) if !may_paint => {
+                        if !forbidden(&mut socket, "streaming to devices").await {
+                            return;
+                        }
+                    }
+                    // Binary frames are full LED frames to stream to the device(s)
+                    Some(Ok(Message::Binary(frame))) => {
+                        if let Err(e) = stream::send_to(&state, &stream_target, &frame).await {
+                            warn!("Stream frame not sent: {e}");
+                        }
+                    }
                     Some(Ok(Message::Close(_))) | None => break,
                     _ => {}
                 }
             }
 
+            // Or so that one who only watches stops seeing updates
+            _ = recheck.tick() => {
+                match recheck_caller(&state, &headers) {
+                    Some(now) => caller = now,
+                    None => return close_unauthorised(socket).await,
+                }
+            }
+
+            // This client's guest preview:
+            msg = simulator.next_frame() => {
+                let json = serde_json::to_string(&msg).unwrap();
+                if socket.send(Message::Text(json.into())).await.is_err() {
+                    break;
+                }
+            }
+
             // Messages from MQTT → push to frontend:
             Ok(server_msg) = rx.recv() => {
                 let json = serde_json::to_string(&server_msg).unwrap();
@@ -1,19 +1,103 @@
+use backend::broker;
+use backend::config::{self, Config};
+use backend::db::Database;
+use backend::mdns::{self, Advertisement, Service};
+use backend::schedules::spawn_scheduler;
+use backend::wled::realtime;
 use backend::{Topics, build_app, create_mqtt, create_state, spawn_mqtt_loop};
-use tracing::info;
+use protocol::mdns::{HTTP_SERVICE, MQTT_SERVICE};
+use tracing::{error, info, warn};
 
 #[tokio::main]
 async fn main() {
-    tracing_subscriber::fmt::init();
+    let args = std::env::args().skip(1);
+    if std::env::args().any(|arg| arg == "--help" || arg == "-h") {
+        print!("{}", config::usage());
+        return;
+    }
+    // Before logging is set up, as it's configured here
+    let config = match Config::load(args, |name| std::env::var(name).ok()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Bad configuration: {e}");
+            std::process::exit(1);
+        }
+    };
 
-    let topics = Topics::default();
-    let (mqtt_client, eventloop) =
-        create_mqtt("egui-axum-mqtt-backend", "localhost", 1883, &topics).await;
-    let state = create_state(mqtt_client, topics);
+    tracing_subscriber::fmt()
+        .with_max_level(config.log_level)
+        .init();
+    // The broker's password isn't shown
+    info!("{config:?}");
+    let broker = &config.broker;
+
+    let db = match Database::open(&config.database_path) {
+        Ok(db) => db,
+        Err(e) => {
+            error!(
+                "Can't open the database {}: {e}",
+                config.database_path.display()
+            );
+            std::process::exit(1);
+        }
+    };
+
+    if let Ok(false) = db.has_users() {
+        warn!("No users yet, so anyone can use the backend: add an admin with POST /api/users");
+    }
+
+    let topics = Topics::new(&config.topic_prefix);
+    let (mqtt_client, eventloop) = create_mqtt(&config.client_id, broker, &topics).await;
+    let mut state = create_state(mqtt_client, topics, db);
+    state.wled_role = config.wled_role;
     let _mqtt_handle = spawn_mqtt_loop(eventloop, state.clone());
+    let _scheduler_handle = spawn_scheduler(state.clone());
+
+    let wled_socket = match tokio::net::UdpSocket::bind(("0.0.0.0", config.wled_port)).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            error!(
+                "Can't listen for WLED realtime on UDP port {}: {e}",
+                config.wled_port
+            );
+            std::process::exit(1);
+        }
+    };
+    let _wled_handle = realtime::spawn_bridge(wled_socket, state.clone());
+
+    // So that devices can find the broker (assumed to be on this host) and the web UI
+    match mdns::local_address().and_then(|address| Ok((address, mdns::bind()?))) {
+        Ok((address, socket)) => {
+            let services = vec![
+                Service {
+                    service: MQTT_SERVICE,
+                    // `_mqtt._tcp` is plaintext, which is also all the device speaks
+                    port: if broker.ca.is_some() {
+                        broker::PORT
+                    } else {
+                        broker.port
+                    },
+                },
+                Service {
+                    service: HTTP_SERVICE,
+                    port: config.bind.port(),
+                },
+            ];
+            let _mdns_handle =
+                mdns::spawn_responder(socket, Advertisement::for_this_host(address, services));
+        }
+        Err(e) => warn!("Not advertising over mDNS: {e}"),
+    }
 
-    let app = build_app(state);
+    let app = build_app(state, &config.static_dir);
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    info!("Listening on http://localhost:3000");
+    let listener = match tokio::net::TcpListener::bind(config.bind).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Can't listen on {}: {e}", config.bind);
+            std::process::exit(1);
+        }
+    };
+    info!("Listening on http://{}", config.bind);
     axum::serve(listener, app).await.unwrap();
 }
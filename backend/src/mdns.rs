@@ -0,0 +1,328 @@
+//! An mDNS / DNS-SD responder, advertising the MQTT broker and web UI on the local network so
+//! that devices on a fresh network can find them (see [`protocol::mdns`]).
+//!
+//! Answers queries for the advertised services, their instances and the host's address.
+//! Legacy unicast queries (from a port other than 5353) and queries asking for a unicast
+//! response are answered directly; everything else is answered to the group.
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+
+use protocol::mdns::{
+    FLAGS_RESPONSE, MDNS_ADDR, MDNS_PORT, Message, RData, Reader, Record, TYPE_A, TYPE_ANY,
+    TYPE_PTR, TYPE_SRV, TYPE_TXT, name_eq,
+};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use tracing::{debug, info, warn};
+
+/// TTL of host and service records, as RFC 6762 recommends
+const HOST_TTL: u32 = 120;
+/// TTL of `PTR` records, as RFC 6762 recommends
+const PTR_TTL: u32 = 4500;
+/// Legacy unicast responses must not be cached for longer than this
+const LEGACY_TTL: u32 = 10;
+
+const ANNOUNCEMENTS: usize = 2;
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A service to advertise, e.g. [`protocol::mdns::MQTT_SERVICE`] on port 1883.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Service {
+    pub service: &'static str,
+    pub port: u16,
+}
+
+/// What the responder answers for: `services`, each as an instance named `instance`, on
+/// `host` at `address`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Advertisement {
+    /// Instance label, e.g. `myhost` for `myhost._mqtt._tcp.local`
+    pub instance: String,
+    /// e.g. `myhost.local`
+    pub host: String,
+    pub address: Ipv4Addr,
+    pub services: Vec<Service>,
+}
+
+/// Whether to answer to the group or straight back to the querier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Destination {
+    Group,
+    Querier,
+}
+
+impl Advertisement {
+    /// Advertise `services` under this machine's hostname.
+    pub fn for_this_host(address: Ipv4Addr, services: Vec<Service>) -> Self {
+        let hostname = gethostname::gethostname().to_string_lossy().into_owned();
+        // Just the first label - the domain is `.local`
+        let label = hostname.split('.').next().unwrap_or_default();
+        let label = if label.is_empty() {
+            "esp32-wasmi-led"
+        } else {
+            label
+        };
+        Self {
+            instance: label.to_string(),
+            host: format!("{label}.local"),
+            address,
+            services,
+        }
+    }
+
+    fn instance_name(&self, service: &Service) -> String {
+        format!("{}.{}", self.instance, service.service)
+    }
+
+    /// The records answering a question, and the additional records that go with them.
+    fn records_for<'a>(
+        &'a self,
+        names: &'a [String],
+        qname: &str,
+        qtype: u16,
+        answers: &mut Vec<Record<'a>>,
+        additionals: &mut Vec<Record<'a>>,
+    ) {
+        let matches = |rtype: u16| qtype == rtype || qtype == TYPE_ANY;
+        let address = Record {
+            name: &self.host,
+            ttl: HOST_TTL,
+            cache_flush: true,
+            data: RData::A(self.address.octets()),
+        };
+
+        for (service, name) in self.services.iter().zip(names) {
+            let srv = Record {
+                name,
+                ttl: HOST_TTL,
+                cache_flush: true,
+                data: RData::Srv {
+                    port: service.port,
+                    target: &self.host,
+                },
+            };
+            let txt = Record {
+                name,
+                ttl: PTR_TTL,
+                cache_flush: true,
+                data: RData::EmptyTxt,
+            };
+            if name_eq(qname, service.service) && matches(TYPE_PTR) {
+                answers.push(Record {
+                    name: service.service,
+                    ttl: PTR_TTL,
+                    cache_flush: false,
+                    data: RData::Ptr(name),
+                });
+                additionals.extend([srv, txt, address]);
+            } else if name_eq(qname, name) {
+                if matches(TYPE_SRV) {
+                    answers.push(srv);
+                    additionals.push(address);
+                }
+                if matches(TYPE_TXT) {
+                    answers.push(txt);
+                }
+            }
+        }
+        if name_eq(qname, &self.host) && matches(TYPE_A) {
+            answers.push(address);
+        }
+    }
+
+    /// The response to a query, written to `buf`, and where to send it - or `None` if the
+    /// query isn't for anything advertised. `legacy` is for queries not from port 5353.
+    pub fn respond(
+        &self,
+        query: &[u8],
+        legacy: bool,
+        buf: &mut [u8],
+    ) -> Option<(usize, Destination)> {
+        let mut reader = Reader::new(query).ok()?;
+        if reader.is_response() {
+            return None;
+        }
+        let names: Vec<String> = self
+            .services
+            .iter()
+            .map(|s| self.instance_name(s))
+            .collect();
+        let mut questions = Vec::new();
+        let mut answers = Vec::new();
+        let mut additionals = Vec::new();
+        let mut unicast = legacy;
+        while let Some(Ok(question)) = reader.question() {
+            let before = answers.len();
+            self.records_for(
+                &names,
+                &question.name,
+                question.qtype,
+                &mut answers,
+                &mut additionals,
+            );
+            if answers.len() > before {
+                unicast |= question.unicast_response;
+                questions.push(question);
+            }
+        }
+        if answers.is_empty() {
+            return None;
+        }
+        let additionals = unique(additionals, &answers);
+
+        // Legacy unicast responses echo the query, and must not look like mDNS to caches
+        let id = if legacy { reader.id() } else { 0 };
+        let mut message = Message::new(buf, id, FLAGS_RESPONSE).ok()?;
+        if legacy {
+            for question in &questions {
+                message
+                    .question(&question.name, question.qtype, false)
+                    .ok()?;
+            }
+        }
+        for record in answers {
+            message.answer(&for_querier(record, legacy)).ok()?;
+        }
+        for record in additionals {
+            message.additional(&for_querier(record, legacy)).ok()?;
+        }
+        let destination = if unicast {
+            Destination::Querier
+        } else {
+            Destination::Group
+        };
+        Some((message.finish(), destination))
+    }
+
+    /// An unsolicited response announcing every service.
+    pub fn announcement(&self, buf: &mut [u8]) -> Option<usize> {
+        let names: Vec<String> = self
+            .services
+            .iter()
+            .map(|s| self.instance_name(s))
+            .collect();
+        let mut answers = Vec::new();
+        let mut additionals = Vec::new();
+        for service in &self.services {
+            self.records_for(
+                &names,
+                service.service,
+                TYPE_PTR,
+                &mut answers,
+                &mut additionals,
+            );
+        }
+        let additionals = unique(additionals, &answers);
+        let mut message = Message::new(buf, 0, FLAGS_RESPONSE).ok()?;
+        for record in answers.iter().chain(&additionals) {
+            message.answer(record).ok()?;
+        }
+        Some(message.finish())
+    }
+}
+
+fn for_querier(mut record: Record, legacy: bool) -> Record {
+    if legacy {
+        record.ttl = record.ttl.min(LEGACY_TTL);
+        record.cache_flush = false;
+    }
+    record
+}
+
+/// `records` without duplicates, or any already in `answers`.
+fn unique<'a>(records: Vec<Record<'a>>, answers: &[Record<'a>]) -> Vec<Record<'a>> {
+    let mut unique = Vec::new();
+    for record in records {
+        if !answers.contains(&record) && !unique.contains(&record) {
+            unique.push(record);
+        }
+    }
+    unique
+}
+
+fn group() -> SocketAddr {
+    SocketAddrV4::new(Ipv4Addr::from(MDNS_ADDR), MDNS_PORT).into()
+}
+
+/// A socket on the mDNS port, in the mDNS group, shared with any other responder on the host.
+pub fn bind() -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, MDNS_PORT)).into())?;
+    socket.join_multicast_v4(&Ipv4Addr::from(MDNS_ADDR), &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_multicast_ttl_v4(255)?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// The address this host reaches the local network from. Nothing is sent.
+pub fn local_address() -> io::Result<Ipv4Addr> {
+    let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect(group())?;
+    match socket.local_addr()?.ip() {
+        std::net::IpAddr::V4(address) if !address.is_unspecified() => Ok(address),
+        _ => Err(io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            "no IPv4 address",
+        )),
+    }
+}
+
+/// Announce `advertisement`, then answer queries for it on `socket` (from [`bind`]).
+pub fn spawn_responder(
+    socket: UdpSocket,
+    advertisement: Advertisement,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut buf = [0u8; 1500];
+        let mut reply = [0u8; 1500];
+        info!(
+            "Advertising {} at {} over mDNS",
+            advertisement.host, advertisement.address
+        );
+
+        let mut announcements = 0;
+        let mut announce = tokio::time::interval(ANNOUNCE_INTERVAL);
+
+        loop {
+            let recv = tokio::select! {
+                recv = socket.recv_from(&mut buf) => recv,
+                _ = announce.tick(), if announcements < ANNOUNCEMENTS => {
+                    announcements += 1;
+                    let Some(len) = advertisement.announcement(&mut reply) else {
+                        continue;
+                    };
+                    if let Err(e) = socket.send_to(&reply[..len], group()).await {
+                        warn!("mDNS announcement failed: {e}");
+                        announcements = ANNOUNCEMENTS;
+                    }
+                    continue;
+                }
+            };
+            let (n, from) = match recv {
+                Ok(r) => r,
+                Err(e) => {
+                    warn!("mDNS receive failed: {e}");
+                    continue;
+                }
+            };
+            let legacy = from.port() != MDNS_PORT;
+            let Some((len, destination)) = advertisement.respond(&buf[..n], legacy, &mut reply)
+            else {
+                continue;
+            };
+            let to = match destination {
+                Destination::Group => group(),
+                Destination::Querier => from,
+            };
+            debug!("Answering mDNS query from {from}");
+            if let Err(e) = socket.send_to(&reply[..len], to).await {
+                warn!("mDNS response to {to} failed: {e}");
+            }
+        }
+    })
+}
@@ -0,0 +1,422 @@
+//! Playlists: content shown one item after another, each for as long as it says, looping until
+//! something else is shown on the target. Every item is drawn here and streamed (see
+//! [`stream`]), except [`Source::Guest`], which hands the panel back to the device's WASM guest.
+//!
+//! A playlist is JSON like:
+//!
+//! ```json
+//! {"name": "evening", "items": [
+//!     {"type": "animation", "id": "..", "duration_ms": 30000},
+//!     {"type": "clock", "color": {"r": 255, "g": 128, "b": 0}, "duration_ms": 10000,
+//!      "transition": {"type": "fade", "ms": 1000}},
+//!     {"type": "guest", "duration_ms": 60000}
+//! ]}
+//! ```
+//!
+//! An item's `transition` is how it replaces the item before: a `cut` (the default), a `fade`,
+//! or a `wipe` in from the left, taking `ms`. There's nothing to blend with on the guest's side,
+//! so to and from [`Source::Guest`] it's always a cut.
+//!
+//! - `GET /api/playlists` lists them, and `POST` adds one, answering with it and its `id`
+//! - `GET`, `PUT` and `DELETE /api/playlists/{id}`
+//! - `POST /api/playlists/{id}/play?device=..` (or `group=..`) plays it
+//! - `POST /api/playlists/stop?device=..` (or `group=..`) stops what's playing there
+//!
+//! A playlist plays on its target as an animation does, until something else is shown there
+//! (see [`animations`]); changing it restarts it wherever it's playing. Items whose content has
+//! been deleted since are skipped. [`schedules`](crate::schedules) play them by the clock.
+
+use std::time::Duration;
+
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use chrono::{NaiveTime, Timelike};
+use common::{BYTES_PER_LED, LED_BUFFER_SIZE, LED_PANEL_HEIGHT, LED_PANEL_WIDTH};
+use host_common::draw::Canvas;
+use protocol::{Command, Mode, Rgb};
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+use tracing::{info, warn};
+use web_common::Target;
+
+use crate::animations::{self, AnimationFrame};
+use crate::db::{self, Database, Kind, Records};
+use crate::images::{self, TargetQuery};
+use crate::{AppState, commands, stream};
+
+/// Shortest an item shows for
+pub const MIN_DURATION: Duration = Duration::from_millis(100);
+/// A frame is sent at least this often, so a device that missed one catches up
+const REFRESH: Duration = Duration::from_secs(1);
+/// Between the frames of a transition
+const TRANSITION_INTERVAL: Duration = Duration::from_millis(33);
+
+/// What an item shows.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Source {
+    /// From the library
+    Image { id: String },
+    /// From the library, looping for as long as the item shows
+    Animation { id: String },
+    /// The local time, hours over minutes
+    Clock {
+        #[serde(default = "white")]
+        color: Rgb,
+    },
+    /// The whole panel one colour
+    Color { color: Rgb },
+    /// Whatever the device's WASM guest draws
+    Guest,
+}
+
+fn white() -> Rgb {
+    Rgb {
+        r: 255,
+        g: 255,
+        b: 255,
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Transition {
+    #[default]
+    Cut,
+    Fade {
+        ms: u64,
+    },
+    /// The new item covers the old one column by column, from the left
+    Wipe {
+        ms: u64,
+    },
+}
+
+impl Transition {
+    fn duration(self) -> Duration {
+        match self {
+            Transition::Cut => Duration::ZERO,
+            Transition::Fade { ms } | Transition::Wipe { ms } => Duration::from_millis(ms),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Item {
+    #[serde(flatten)]
+    pub source: Source,
+    pub duration_ms: u64,
+    /// From the item before into this one
+    #[serde(default)]
+    pub transition: Transition,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Playlist {
+    /// Given when it's added
+    #[serde(default)]
+    pub id: String,
+    pub name: Option<String>,
+    pub items: Vec<Item>,
+}
+
+impl Playlist {
+    /// Whether it can be played, with the content it shows all in `db`.
+    pub(crate) fn check(&self, db: &Database) -> Result<(), (StatusCode, String)> {
+        let bad = |reason: String| Err((StatusCode::BAD_REQUEST, reason));
+        if self.items.is_empty() {
+            return bad("no items".to_string());
+        }
+        for (i, item) in self.items.iter().enumerate() {
+            let duration = Duration::from_millis(item.duration_ms);
+            if duration < MIN_DURATION {
+                return bad(format!(
+                    "item {i} is shorter than {}ms",
+                    MIN_DURATION.as_millis()
+                ));
+            }
+            if item.transition.duration() > duration {
+                return bad(format!("item {i}'s transition is longer than the item"));
+            }
+            let (kind, id) = match &item.source {
+                Source::Image { id } => (Kind::Image, id),
+                Source::Animation { id } => (Kind::Animation, id),
+                _ => continue,
+            };
+            match db.get(Some(kind), id) {
+                Ok(Some(_)) => {}
+                Ok(None) => return bad(format!("item {i}: no {} {id}", kind.as_str())),
+                Err(e) => {
+                    return Err((
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        format!("database error: {e}"),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Part of the way from `from` to `to`, `progress` being from 0 to 1.
+pub fn blend(transition: Transition, from: &[u8], to: &[u8], progress: f32) -> Vec<u8> {
+    let progress = progress.clamp(0.0, 1.0);
+    match transition {
+        Transition::Cut => to.to_vec(),
+        Transition::Fade { .. } => from
+            .iter()
+            .zip(to)
+            .map(|(&a, &b)| (a as f32 + (b as f32 - a as f32) * progress).round() as u8)
+            .collect(),
+        Transition::Wipe { .. } => {
+            let columns = (progress * LED_PANEL_WIDTH as f32).round() as usize;
+            let from = from.as_chunks::<BYTES_PER_LED>().0;
+            let to = to.as_chunks::<BYTES_PER_LED>().0;
+            from.iter()
+                .zip(to)
+                .enumerate()
+                .flat_map(|(i, (a, b))| if i % LED_PANEL_WIDTH < columns { b } else { a })
+                .copied()
+                .collect()
+        }
+    }
+}
+
+/// `HH` over `MM`, in the middle of the panel.
+pub fn clock_frame(time: NaiveTime, color: Rgb) -> Vec<u8> {
+    // Two digits of the 3x5 font a column apart, on two lines a row apart
+    const WIDTH: usize = 7;
+    const HEIGHT: usize = 11;
+    let mut frame = vec![0; LED_BUFFER_SIZE];
+    let mut canvas = Canvas::new(&mut frame, LED_PANEL_WIDTH, LED_PANEL_HEIGHT);
+    let origin = (
+        ((LED_PANEL_WIDTH - WIDTH) / 2) as i32,
+        ((LED_PANEL_HEIGHT - HEIGHT) / 2) as i32,
+    );
+    let text = format!("{:02}\n{:02}", time.hour(), time.minute());
+    canvas.draw_text(origin, &text, (color.r, color.g, color.b));
+    frame
+}
+
+/// An item's content, loaded to be shown.
+enum Render {
+    Still(Vec<u8>),
+    Frames(Vec<AnimationFrame>),
+    Clock(Rgb),
+    Guest,
+}
+
+impl Render {
+    /// `None` if its content has gone from the library.
+    fn load(db: &Database, source: &Source) -> rusqlite::Result<Option<Self>> {
+        Ok(match source {
+            Source::Image { id } => images::load(db, id)?.map(|(_, frame)| Render::Still(frame)),
+            Source::Animation { id } => {
+                animations::load(db, id)?.map(|animation| Render::Frames(animation.frames))
+            }
+            Source::Clock { color } => Some(Render::Clock(*color)),
+            Source::Color { color } => Some(Render::Still(
+                [color.r, color.g, color.b].repeat(LED_BUFFER_SIZE / BYTES_PER_LED),
+            )),
+            Source::Guest => Some(Render::Guest),
+        })
+    }
+
+    /// The frame `elapsed` into the item, at local time `now`, and how long until it changes.
+    fn frame(&self, elapsed: Duration, now: NaiveTime) -> (Vec<u8>, Duration) {
+        match self {
+            Render::Still(frame) => (frame.clone(), REFRESH),
+            Render::Frames(frames) => {
+                let total: Duration = frames.iter().map(|f| f.delay).sum();
+                let mut into =
+                    Duration::from_nanos((elapsed.as_nanos() % total.as_nanos().max(1)) as u64);
+                for frame in frames {
+                    if into < frame.delay {
+                        return (frame.frame.clone(), frame.delay - into);
+                    }
+                    into -= frame.delay;
+                }
+                // Only by rounding
+                (frames[0].frame.clone(), frames[0].delay)
+            }
+            Render::Clock(color) => (clock_frame(now, *color), REFRESH),
+            // Not drawn here, see `run`
+            Render::Guest => (vec![0; LED_BUFFER_SIZE], REFRESH),
+        }
+    }
+}
+
+/// Play `playlist` on `target`, instead of whatever was playing there.
+pub fn play(state: &AppState, target: Target, playlist: Playlist) {
+    info!("Playing playlist {} on {target:?}", playlist.id);
+    let id = playlist.id.clone();
+    state
+        .players
+        .start(target.clone(), id, run(state.clone(), target, playlist));
+}
+
+async fn run(state: AppState, target: Target, playlist: Playlist) {
+    let mut items = Vec::new();
+    for item in &playlist.items {
+        match Render::load(&state.db, &item.source) {
+            Ok(Some(render)) => items.push((item, render)),
+            Ok(None) => warn!("Playlist {}: skipping {:?}", playlist.id, item.source),
+            Err(e) => warn!(
+                "Playlist {}: couldn't load {:?}: {e}",
+                playlist.id, item.source
+            ),
+        }
+    }
+    if items.is_empty() {
+        warn!("Playlist {} has nothing to show", playlist.id);
+        return;
+    }
+
+    // What was on the panel as the item before finished, to transition from
+    let mut shown: Option<Vec<u8>> = None;
+    for (item, render) in items.iter().cycle() {
+        let start = Instant::now();
+        let end = start + Duration::from_millis(item.duration_ms);
+        if let Render::Guest = render {
+            let command = Command::SetMode(Mode::Wasm);
+            for (device_id, result) in commands::send_to(&state, &target, command).await {
+                if let Err(e) = result {
+                    warn!(
+                        "Playlist {}: guest not shown on {device_id}: {e}",
+                        playlist.id
+                    );
+                }
+            }
+            shown = None;
+            tokio::time::sleep_until(end).await;
+            continue;
+        }
+
+        let from = shown.take();
+        let transition = item.transition.duration();
+        loop {
+            let now = Instant::now();
+            if now >= end {
+                break;
+            }
+            let elapsed = now - start;
+            let (frame, until_change) = render.frame(elapsed, state.clock.now().time());
+            let (frame, wait) = match &from {
+                Some(from) if elapsed < transition => {
+                    let progress = elapsed.as_secs_f32() / transition.as_secs_f32();
+                    let frame = blend(item.transition, from, &frame, progress);
+                    (frame, TRANSITION_INTERVAL.min(transition - elapsed))
+                }
+                _ => (frame, until_change.min(REFRESH)),
+            };
+            if let Err(e) = stream::send_to(&state, &target, &frame).await {
+                warn!("Playlist {} stopped: {e}", playlist.id);
+                return;
+            }
+            shown = Some(frame);
+            tokio::time::sleep_until((now + wait).min(end)).await;
+        }
+    }
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/api/playlists", get(get_playlists).post(post_playlist))
+        .route(
+            "/api/playlists/{id}",
+            get(get_playlist).put(put_playlist).delete(delete_playlist),
+        )
+        .route("/api/playlists/{id}/play", post(post_play))
+        .route("/api/playlists/stop", post(animations::post_stop))
+}
+
+async fn get_playlists(State(state): State<AppState>) -> Response {
+    match state.db.records::<Playlist>(Records::Playlists) {
+        Ok(playlists) => Json(playlists).into_response(),
+        Err(e) => db::error_response(e),
+    }
+}
+
+async fn post_playlist(
+    State(state): State<AppState>,
+    Json(mut playlist): Json<Playlist>,
+) -> Response {
+    if let Err(e) = playlist.check(&state.db) {
+        return e.into_response();
+    }
+    playlist.id = uuid::Uuid::new_v4().to_string();
+    match state
+        .db
+        .put_record(Records::Playlists, &playlist.id, &playlist)
+    {
+        Ok(()) => (StatusCode::CREATED, Json(playlist)).into_response(),
+        Err(e) => db::error_response(e),
+    }
+}
+
+async fn get_playlist(State(state): State<AppState>, Path(id): Path<String>) -> Response {
+    match state.db.record::<Playlist>(Records::Playlists, &id) {
+        Ok(Some(playlist)) => Json(playlist).into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => db::error_response(e),
+    }
+}
+
+async fn put_playlist(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(mut playlist): Json<Playlist>,
+) -> Response {
+    match state.db.record::<Playlist>(Records::Playlists, &id) {
+        Ok(Some(_)) => {}
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(e) => return db::error_response(e),
+    }
+    if let Err(e) = playlist.check(&state.db) {
+        return e.into_response();
+    }
+    playlist.id = id;
+    if let Err(e) = state
+        .db
+        .put_record(Records::Playlists, &playlist.id, &playlist)
+    {
+        return db::error_response(e);
+    }
+    for target in state.players.targets(&playlist.id) {
+        play(&state, target, playlist.clone());
+    }
+    Json(playlist).into_response()
+}
+
+async fn delete_playlist(State(state): State<AppState>, Path(id): Path<String>) -> Response {
+    match state.db.delete_record(Records::Playlists, &id) {
+        Ok(true) => {
+            state.players.stop_content(&id);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Ok(false) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => db::error_response(e),
+    }
+}
+
+async fn post_play(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(query): Query<TargetQuery>,
+) -> Response {
+    let target = match animations::required_target(query) {
+        Ok(target) => target,
+        Err(e) => return e.into_response(),
+    };
+    match state.db.record::<Playlist>(Records::Playlists, &id) {
+        Ok(Some(playlist)) => {
+            play(&state, target, playlist);
+            StatusCode::ACCEPTED.into_response()
+        }
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => db::error_response(e),
+    }
+}
@@ -0,0 +1,493 @@
+//! Schedules: what each target shows when, so the panel runs without anyone at it.
+//!
+//! A schedule is a target and a list of entries, each a [`Cron`] expression and what to show
+//! from then on - a playlist (see [`playlists`]) or nothing:
+//!
+//! ```json
+//! {"name": "kitchen", "target": {"Device": "404cca01abff"}, "entries": [
+//!     {"cron": "0 7 * * *", "show": {"type": "playlist", "id": "<the clock>"}},
+//!     {"cron": "0 9 * * *", "show": {"type": "playlist", "id": "<the animations>"}},
+//!     {"cron": "30 22 * * *", "show": {"type": "off"}}
+//! ]}
+//! ```
+//!
+//! shows the clock from 07:00 to 09:00, the animations until 22:30, and nothing overnight.
+//! At any time a schedule shows the entry that fired last - a backend started at noon starts
+//! the animations - or the first of those listed if several fired at that minute. Times are
+//! the backend's local time, from its [`Clock`].
+//!
+//! The scheduler acts only when what a schedule shows changes, so something shown on the
+//! target by hand stays until the next entry fires. `off` stops what's playing and blanks the
+//! panel.
+//!
+//! - `GET /api/schedules` lists them, and `POST` adds one, answering with it and its `id`
+//! - `GET`, `PUT` and `DELETE /api/schedules/{id}`
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use chrono::{Datelike, NaiveDate, NaiveDateTime, Timelike};
+use common::LED_BUFFER_SIZE;
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+use web_common::Target;
+
+use crate::db::{self, Records};
+use crate::playlists::{self, Playlist};
+use crate::{AppState, stream};
+
+/// How often the scheduler looks at the clock
+pub const SCHEDULER_INTERVAL: Duration = Duration::from_secs(1);
+/// How far back to look for when an entry last fired: long enough for the 29th of February
+const LOOKBACK_DAYS: u32 = 8 * 366;
+
+/// The local time, for the scheduler and the clock playlists draw. Tests set their own.
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> NaiveDateTime;
+}
+
+/// The system's local time.
+#[derive(Debug, Default)]
+pub struct LocalClock;
+
+impl Clock for LocalClock {
+    fn now(&self) -> NaiveDateTime {
+        chrono::Local::now().naive_local()
+    }
+}
+
+/// A field of a cron expression: its name, its range, and the names its values may go by,
+/// counting from the start of the range.
+struct Field {
+    name: &'static str,
+    min: u32,
+    max: u32,
+    names: &'static [&'static str],
+}
+
+const MINUTE: Field = Field {
+    name: "minute",
+    min: 0,
+    max: 59,
+    names: &[],
+};
+const HOUR: Field = Field {
+    name: "hour",
+    min: 0,
+    max: 23,
+    names: &[],
+};
+const DAY: Field = Field {
+    name: "day of the month",
+    min: 1,
+    max: 31,
+    names: &[],
+};
+const MONTH: Field = Field {
+    name: "month",
+    min: 1,
+    max: 12,
+    names: &[
+        "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+    ],
+};
+const WEEKDAY: Field = Field {
+    name: "day of the week",
+    min: 0,
+    // Sunday is both 0 and 7
+    max: 7,
+    names: &["sun", "mon", "tue", "wed", "thu", "fri", "sat"],
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CronError {
+    /// Not the five fields
+    Fields(usize),
+    Bad {
+        field: &'static str,
+        value: String,
+    },
+}
+
+impl fmt::Display for CronError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CronError::Fields(n) => write!(f, "{n} fields, not minute hour day month weekday"),
+            CronError::Bad { field, value } => write!(f, "bad {field} {value:?}"),
+        }
+    }
+}
+
+impl std::error::Error for CronError {}
+
+/// When something happens, as in a crontab: `minute hour day-of-month month day-of-week`.
+///
+/// Each field is `*`, a value, a range `a-b`, or a list of them `a,b-c`; `*` and ranges may
+/// have a step, `*/15`. Months and days of the week may be given by their first three
+/// letters, `mon-fri`. As in cron, when both days are restricted either one matching will do.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cron {
+    expression: String,
+    /// A bit for each value
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Cron {
+    fn matches_date(&self, date: NaiveDate) -> bool {
+        let day = self.days & (1 << date.day()) != 0;
+        let weekday = self.weekdays & (1 << date.weekday().num_days_from_sunday()) != 0;
+        let month = self.months & (1 << date.month()) != 0;
+        month
+            && if self.any_day || self.any_weekday {
+                day && weekday
+            } else {
+                day || weekday
+            }
+    }
+
+    pub fn matches(&self, at: NaiveDateTime) -> bool {
+        self.matches_date(at.date())
+            && self.hours & (1 << at.hour()) != 0
+            && self.minutes & (1 << at.minute()) != 0
+    }
+
+    /// The last minute it fired, at or before `at`.
+    pub fn latest(&self, at: NaiveDateTime) -> Option<NaiveDateTime> {
+        let mut date = at.date();
+        for _ in 0..LOOKBACK_DAYS {
+            if self.matches_date(date) {
+                let (hours, minutes) = if date == at.date() {
+                    (at.hour(), at.minute())
+                } else {
+                    (23, 59)
+                };
+                for hour in (0..=hours).rev().filter(|h| self.hours & (1 << h) != 0) {
+                    let last = if hour == hours { minutes } else { 59 };
+                    if let Some(minute) = (0..=last).rev().find(|m| self.minutes & (1 << m) != 0) {
+                        return date.and_hms_opt(hour, minute, 0);
+                    }
+                }
+            }
+            date = date.pred_opt()?;
+        }
+        None
+    }
+}
+
+fn parse_field(text: &str, field: &Field) -> Result<u64, CronError> {
+    let bad = || CronError::Bad {
+        field: field.name,
+        value: text.to_string(),
+    };
+    let value = |value: &str| {
+        let lower = value.to_ascii_lowercase();
+        let n = match field.names.iter().position(|name| *name == lower) {
+            Some(i) => i as u32 + field.min,
+            None => value.parse().map_err(|_| bad())?,
+        };
+        if (field.min..=field.max).contains(&n) {
+            Ok(n)
+        } else {
+            Err(bad())
+        }
+    };
+
+    let mut bits = 0;
+    for part in text.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse().ok().filter(|&s| s > 0).ok_or_else(bad)?),
+            None => (part, 1),
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (field.min, field.max),
+            // `fri-sun`: Sunday is 7 too, at the end of the week
+            Some((start, end)) if field.name == WEEKDAY.name && value(end)? == 0 => {
+                (value(start)?, WEEKDAY.max)
+            }
+            Some((start, end)) => (value(start)?, value(end)?),
+            // `5/15` is from 5 on
+            None if step > 1 => (value(range)?, field.max),
+            None => {
+                let n = value(range)?;
+                (n, n)
+            }
+        };
+        if start > end {
+            return Err(bad());
+        }
+        for n in (start..=end).step_by(step as usize) {
+            bits |= 1 << n;
+        }
+    }
+    Ok(bits)
+}
+
+impl FromStr for Cron {
+    type Err = CronError;
+
+    fn from_str(expression: &str) -> Result<Self, CronError> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(CronError::Fields(fields.len()));
+        };
+        let mut weekday_bits = parse_field(weekdays, &WEEKDAY)?;
+        if weekday_bits & (1 << 7) != 0 {
+            weekday_bits = (weekday_bits | 1) & !(1 << 7);
+        }
+        Ok(Self {
+            expression: expression.to_string(),
+            minutes: parse_field(minutes, &MINUTE)?,
+            hours: parse_field(hours, &HOUR)?,
+            days: parse_field(days, &DAY)?,
+            months: parse_field(months, &MONTH)?,
+            weekdays: weekday_bits,
+            any_day: days.starts_with('*'),
+            any_weekday: weekdays.starts_with('*'),
+        })
+    }
+}
+
+impl TryFrom<String> for Cron {
+    type Error = CronError;
+
+    fn try_from(expression: String) -> Result<Self, CronError> {
+        expression.parse()
+    }
+}
+
+impl From<Cron> for String {
+    fn from(cron: Cron) -> Self {
+        cron.expression
+    }
+}
+
+impl fmt::Display for Cron {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.expression)
+    }
+}
+
+/// What a schedule shows.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Show {
+    Playlist { id: String },
+    Off,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub cron: Cron,
+    pub show: Show,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    /// Given when it's added
+    #[serde(default)]
+    pub id: String,
+    pub name: Option<String>,
+    pub target: Target,
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+    pub entries: Vec<Entry>,
+}
+
+impl Schedule {
+    /// The entry showing at `now`, and when it fired.
+    pub fn active(&self, now: NaiveDateTime) -> Option<(&Entry, NaiveDateTime)> {
+        let mut active: Option<(&Entry, NaiveDateTime)> = None;
+        for entry in &self.entries {
+            let Some(fired) = entry.cron.latest(now) else {
+                continue;
+            };
+            // The first listed wins a tie
+            if active.is_none_or(|(_, latest)| fired > latest) {
+                active = Some((entry, fired));
+            }
+        }
+        active
+    }
+
+    /// Whether it can be run, with the playlists it shows all in the database.
+    fn check(&self, state: &AppState) -> Result<(), (StatusCode, String)> {
+        for (i, entry) in self.entries.iter().enumerate() {
+            let Show::Playlist { id } = &entry.show else {
+                continue;
+            };
+            match state.db.record::<Playlist>(Records::Playlists, id) {
+                Ok(Some(_)) => {}
+                Ok(None) => {
+                    return Err((
+                        StatusCode::BAD_REQUEST,
+                        format!("entry {i}: no playlist {id}"),
+                    ));
+                }
+                Err(e) => {
+                    return Err((
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        format!("database error: {e}"),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What each schedule shows, so that it's only acted on when that changes.
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    showing: HashMap<String, (Target, Show)>,
+}
+
+impl Scheduler {
+    /// What to show where, for the enabled `schedules` showing something different at `now`
+    /// than when last asked.
+    pub fn due(&mut self, schedules: &[Schedule], now: NaiveDateTime) -> Vec<(Target, Show)> {
+        let mut due = Vec::new();
+        // Forgetting schedules since deleted or disabled, so they start afresh if they're back
+        let mut showing = HashMap::new();
+        for schedule in schedules.iter().filter(|s| s.enabled) {
+            let Some((entry, _)) = schedule.active(now) else {
+                continue;
+            };
+            let show = (schedule.target.clone(), entry.show.clone());
+            if self.showing.get(&schedule.id) != Some(&show) {
+                due.push(show.clone());
+            }
+            showing.insert(schedule.id.clone(), show);
+        }
+        self.showing = showing;
+        due
+    }
+}
+
+/// Run the schedules in the database by `state.clock`, until aborted.
+pub fn spawn_scheduler(state: AppState) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut scheduler = Scheduler::default();
+        let mut interval = tokio::time::interval(SCHEDULER_INTERVAL);
+        loop {
+            interval.tick().await;
+            let schedules = match state.db.records::<Schedule>(Records::Schedules) {
+                Ok(schedules) => schedules,
+                Err(e) => {
+                    warn!("Couldn't load the schedules: {e}");
+                    continue;
+                }
+            };
+            for (target, show) in scheduler.due(&schedules, state.clock.now()) {
+                apply(&state, target, show).await;
+            }
+        }
+    })
+}
+
+async fn apply(state: &AppState, target: Target, show: Show) {
+    match show {
+        Show::Playlist { id } => match state.db.record::<Playlist>(Records::Playlists, &id) {
+            Ok(Some(playlist)) => playlists::play(state, target, playlist),
+            Ok(None) => warn!("Scheduled playlist {id} for {target:?} has been deleted"),
+            Err(e) => warn!("Couldn't load scheduled playlist {id}: {e}"),
+        },
+        Show::Off => {
+            info!("Scheduled off: {target:?}");
+            state.players.stop(&target);
+            if let Err(e) = stream::send_to(state, &target, &[0; LED_BUFFER_SIZE]).await {
+                warn!("Couldn't blank {target:?}: {e}");
+            }
+        }
+    }
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/api/schedules", get(get_schedules).post(post_schedule))
+        .route(
+            "/api/schedules/{id}",
+            get(get_schedule).put(put_schedule).delete(delete_schedule),
+        )
+}
+
+async fn get_schedules(State(state): State<AppState>) -> Response {
+    match state.db.records::<Schedule>(Records::Schedules) {
+        Ok(schedules) => Json(schedules).into_response(),
+        Err(e) => db::error_response(e),
+    }
+}
+
+async fn post_schedule(
+    State(state): State<AppState>,
+    Json(mut schedule): Json<Schedule>,
+) -> Response {
+    if let Err(e) = schedule.check(&state) {
+        return e.into_response();
+    }
+    schedule.id = uuid::Uuid::new_v4().to_string();
+    match state
+        .db
+        .put_record(Records::Schedules, &schedule.id, &schedule)
+    {
+        Ok(()) => (StatusCode::CREATED, Json(schedule)).into_response(),
+        Err(e) => db::error_response(e),
+    }
+}
+
+async fn get_schedule(State(state): State<AppState>, Path(id): Path<String>) -> Response {
+    match state.db.record::<Schedule>(Records::Schedules, &id) {
+        Ok(Some(schedule)) => Json(schedule).into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => db::error_response(e),
+    }
+}
+
+async fn put_schedule(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(mut schedule): Json<Schedule>,
+) -> Response {
+    match state.db.record::<Schedule>(Records::Schedules, &id) {
+        Ok(Some(_)) => {}
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(e) => return db::error_response(e),
+    }
+    if let Err(e) = schedule.check(&state) {
+        return e.into_response();
+    }
+    schedule.id = id;
+    match state
+        .db
+        .put_record(Records::Schedules, &schedule.id, &schedule)
+    {
+        Ok(()) => Json(schedule).into_response(),
+        Err(e) => db::error_response(e),
+    }
+}
+
+async fn delete_schedule(State(state): State<AppState>, Path(id): Path<String>) -> Response {
+    match state.db.delete_record(Records::Schedules, &id) {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => db::error_response(e),
+    }
+}
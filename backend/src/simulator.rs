@@ -0,0 +1,338 @@
+//! Guest previews: a stored guest (see [`guests`]) run by the backend as a device would run it,
+//! with the same tick contract as the firmware's `wasm_task`, its frames sent to a WebSocket
+//! client as [`ServerMsg::Frame`]s instead of to a panel.
+//!
+//! Each WebSocket client has a [`Session`], controlled with [`SimulatorControl`]s. Guest time
+//! passes at the session's speed while it's playing. A guest's state only moves forward, so
+//! seeking back replays it from the start. Each call into the guest gets [`FUEL_PER_CALL`], and
+//! a seek [`MAX_SEEK_FUEL`] in all, so a guest that never returns fails instead of holding up
+//! the backend. Guests run on blocking threads, not the runtime's.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::LED_BUFFER_SIZE;
+use protocol::guest::MAX_GUEST_MEMORY_PAGES;
+use tokio::task::JoinHandle;
+use tokio::time::{Interval, MissedTickBehavior};
+use wasmi::{
+    Config, Engine, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder, TypedFunc,
+};
+use web_common::{ServerMsg, SimulatorControl};
+
+use crate::db::Database;
+use crate::guests;
+
+/// As the device counts time for its guest
+pub const TICKS_PER_SECOND: u64 = 256;
+
+/// Frames per second, unless the client asks for another rate
+pub const DEFAULT_FPS: u32 = 30;
+pub const MAX_FPS: u32 = 60;
+pub const MAX_SPEED: f32 = 16.0;
+
+/// Furthest into a guest's run that can be sought to, as getting there means running it
+pub const MAX_SEEK_MS: u64 = 10 * 60 * 1000;
+
+/// Instructions, roughly, a guest may run in one call - far more than a device manages in a
+/// frame
+pub const FUEL_PER_CALL: u64 = 10_000_000;
+
+/// Instructions, roughly, a guest may run to get to where a seek goes - a second or two's work
+pub const MAX_SEEK_FUEL: u64 = 1_000_000_000;
+
+const PAGE_SIZE: usize = 64 * 1024;
+
+/// An instance of a guest, with the memory and calls the device gives it.
+#[derive(Debug)]
+pub struct Guest {
+    store: Store<StoreLimits>,
+    memory: Memory,
+    update: TypedFunc<(u64, u64, u32), u32>,
+    host_buffer_offset: u32,
+}
+
+impl Guest {
+    /// Instantiate `wasm`, give it the host's page of memory, and call its `init`.
+    pub fn new(wasm: &[u8]) -> Result<Self, wasmi::Error> {
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let module = Module::new(&engine, wasm)?;
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(MAX_GUEST_MEMORY_PAGES as usize * PAGE_SIZE)
+            .build();
+        let mut store = Store::new(&engine, limits);
+        store.limiter(|limits| limits);
+        store.set_fuel(FUEL_PER_CALL)?;
+        let instance = Linker::new(&engine).instantiate_and_start(&mut store, &module)?;
+
+        let memory = instance
+            .get_memory(&store, "memory")
+            .ok_or_else(|| wasmi::Error::new("no `memory` export"))?;
+        let host_buffer_offset = memory.data_size(&store) as u32;
+        memory.grow(&mut store, 1)?;
+        let update = instance.get_typed_func(&store, "update")?;
+        let init = instance.get_typed_func::<(), ()>(&store, "init")?;
+        store.set_fuel(FUEL_PER_CALL)?;
+        init.call(&mut store, ())?;
+
+        Ok(Self {
+            store,
+            memory,
+            update,
+            host_buffer_offset,
+        })
+    }
+
+    /// Call the guest's `update`, returning the frame it drew.
+    pub fn update(&mut self, ticks: u64, counter: u64) -> Result<Vec<u8>, wasmi::Error> {
+        self.update_with_fuel(ticks, counter, FUEL_PER_CALL)
+            .map(|(frame, _)| frame)
+    }
+
+    /// [`update`](Self::update) with at most `fuel`, returning the frame and the fuel used.
+    fn update_with_fuel(
+        &mut self,
+        ticks: u64,
+        counter: u64,
+        fuel: u64,
+    ) -> Result<(Vec<u8>, u64), wasmi::Error> {
+        self.store.set_fuel(fuel)?;
+        let offset = self
+            .update
+            .call(&mut self.store, (ticks, counter, self.host_buffer_offset))?
+            as usize;
+        let used = fuel - self.store.get_fuel()?;
+        self.memory
+            .data(&self.store)
+            .get(offset..offset + LED_BUFFER_SIZE)
+            .map(|frame| (frame.to_vec(), used))
+            .ok_or_else(|| wasmi::Error::new("pixel buffer out of bounds"))
+    }
+}
+
+/// A guest's run, a frame at a time.
+#[derive(Debug)]
+pub struct Simulation {
+    guest_id: String,
+    wasm: Arc<[u8]>,
+    guest: Guest,
+    /// Between frames, in guest time at normal speed
+    frame_ms: f64,
+    pub speed: f32,
+    pub playing: bool,
+    /// Guest time of the next frame
+    ms: f64,
+    counter: u64,
+}
+
+impl Simulation {
+    pub fn new(guest_id: String, wasm: Arc<[u8]>, fps: u32) -> Result<Self, wasmi::Error> {
+        Ok(Self {
+            guest: Guest::new(&wasm)?,
+            guest_id,
+            wasm,
+            frame_ms: 1000.0 / fps as f64,
+            speed: 1.0,
+            playing: true,
+            ms: 0.0,
+            counter: 0,
+        })
+    }
+
+    /// Real time between frames.
+    pub fn frame_interval(&self) -> Duration {
+        Duration::from_secs_f64(self.frame_ms / 1000.0)
+    }
+
+    /// Render the next frame, and move guest time on by a frame at the current speed.
+    pub fn step(&mut self) -> Result<ServerMsg, wasmi::Error> {
+        let ms = self.ms as u64;
+        let pixels = self
+            .guest
+            .update(ms * TICKS_PER_SECOND / 1000, self.counter)?;
+        let frame = ServerMsg::Frame {
+            guest_id: self.guest_id.clone(),
+            ms,
+            counter: self.counter,
+            pixels,
+        };
+        self.counter += 1;
+        self.ms += self.frame_ms * self.speed as f64;
+        Ok(frame)
+    }
+
+    /// Run the guest up to `ms` (at most [`MAX_SEEK_MS`]) at normal speed, and render the
+    /// frame there. Fails if getting there takes more than [`MAX_SEEK_FUEL`].
+    pub fn seek(&mut self, ms: u64) -> Result<ServerMsg, wasmi::Error> {
+        let ms = ms.min(MAX_SEEK_MS) as f64;
+        if ms < self.ms {
+            self.guest = Guest::new(&self.wasm)?;
+            self.ms = 0.0;
+            self.counter = 0;
+        }
+        let mut fuel = MAX_SEEK_FUEL;
+        while self.ms + self.frame_ms <= ms {
+            let budget = fuel.min(FUEL_PER_CALL);
+            let (_, used) = self
+                .guest
+                .update_with_fuel(
+                    self.ms as u64 * TICKS_PER_SECOND / 1000,
+                    self.counter,
+                    budget,
+                )
+                .map_err(|e| {
+                    if budget < FUEL_PER_CALL {
+                        wasmi::Error::new(format!("the guest takes too long to get to {ms} ms"))
+                    } else {
+                        e
+                    }
+                })?;
+            fuel -= used;
+            self.counter += 1;
+            self.ms += self.frame_ms;
+        }
+        self.ms = ms;
+        self.step()
+    }
+}
+
+type Rendered = (Simulation, Result<ServerMsg, wasmi::Error>);
+
+/// A WebSocket client's preview, if it has one.
+#[derive(Debug)]
+pub struct Session {
+    simulation: Option<Simulation>,
+    interval: Interval,
+    /// The next frame, with the simulation, while it's being rendered. Kept here so that
+    /// neither is lost if the caller stops waiting for the frame.
+    rendering: Option<JoinHandle<Rendered>>,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self {
+            simulation: None,
+            interval: frame_interval(Duration::from_secs(1) / DEFAULT_FPS),
+            rendering: None,
+        }
+    }
+}
+
+fn frame_interval(period: Duration) -> Interval {
+    let mut interval = tokio::time::interval(period);
+    // A slow guest gets a lower frame rate, not a burst of frames
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    interval
+}
+
+async fn start(db: &Database, guest_id: String, fps: Option<u32>) -> Result<Simulation, String> {
+    let wasm = match guests::load(db, &guest_id) {
+        Ok(Some((_, wasm))) => Arc::from(wasm),
+        Ok(None) => return Err(format!("no guest {guest_id}")),
+        Err(e) => return Err(e.to_string()),
+    };
+    let fps = fps.unwrap_or(DEFAULT_FPS).clamp(1, MAX_FPS);
+    // Compiling a module takes a while
+    tokio::task::spawn_blocking(move || Simulation::new(guest_id, wasm, fps))
+        .await
+        .expect("guest simulation panicked")
+        .map_err(|e| e.to_string())
+}
+
+fn error(error: impl ToString) -> ServerMsg {
+    ServerMsg::SimulatorError {
+        error: error.to_string(),
+    }
+}
+
+impl Session {
+    /// Wait for the frame being rendered, if any, and drop it, so the simulation can be
+    /// controlled.
+    async fn finish_rendering(&mut self) {
+        if let Some(rendering) = self.rendering.take() {
+            let (simulation, frame) = rendering.await.expect("guest simulation panicked");
+            if frame.is_ok() {
+                self.simulation = Some(simulation);
+            }
+        }
+    }
+
+    /// Act on `control`, answering with a frame for a seek, or an error.
+    pub async fn control(&mut self, db: &Database, control: SimulatorControl) -> Option<ServerMsg> {
+        self.finish_rendering().await;
+        match (control, self.simulation.as_mut()) {
+            (SimulatorControl::Start { guest_id, fps }, _) => {
+                let started = start(db, guest_id, fps).await;
+                self.simulation = None;
+                return match started {
+                    Ok(simulation) => {
+                        self.interval = frame_interval(simulation.frame_interval());
+                        self.simulation = Some(simulation);
+                        None
+                    }
+                    Err(e) => Some(error(e)),
+                };
+            }
+            (SimulatorControl::Stop, _) => self.simulation = None,
+            (_, None) => return Some(error("no preview running")),
+            (SimulatorControl::Play, Some(simulation)) => {
+                simulation.playing = true;
+                self.interval.reset_immediately();
+            }
+            (SimulatorControl::Pause, Some(simulation)) => simulation.playing = false,
+            (SimulatorControl::Speed { speed }, Some(simulation)) => {
+                if !(speed > 0.0 && speed <= MAX_SPEED) {
+                    return Some(error(format!("speed must be over 0, up to {MAX_SPEED}")));
+                }
+                simulation.speed = speed;
+            }
+            (SimulatorControl::Seek { ms }, Some(_)) => {
+                // Replaying a guest takes a while
+                let mut simulation = self.simulation.take().unwrap();
+                let (simulation, frame) = tokio::task::spawn_blocking(move || {
+                    let frame = simulation.seek(ms);
+                    (simulation, frame)
+                })
+                .await
+                .expect("guest simulation panicked");
+                return match frame {
+                    Ok(frame) => {
+                        self.simulation = Some(simulation);
+                        Some(frame)
+                    }
+                    Err(e) => Some(error(e)),
+                };
+            }
+        }
+        None
+    }
+
+    /// The next frame of a playing preview, when it's due, or an error if the guest failed and
+    /// the preview stopped. Never ready otherwise. Cancel safe.
+    pub async fn next_frame(&mut self) -> ServerMsg {
+        if self.rendering.is_none() {
+            if !self.simulation.as_ref().is_some_and(|s| s.playing) {
+                return std::future::pending().await;
+            }
+            self.interval.tick().await;
+            let mut simulation = self.simulation.take().unwrap();
+            // A frame may use all of its fuel
+            self.rendering = Some(tokio::task::spawn_blocking(move || {
+                let frame = simulation.step();
+                (simulation, frame)
+            }));
+        }
+        let rendered = self.rendering.as_mut().unwrap().await;
+        self.rendering = None;
+        let (simulation, frame) = rendered.expect("guest simulation panicked");
+        match frame {
+            Ok(frame) => {
+                self.simulation = Some(simulation);
+                frame
+            }
+            Err(e) => error(e),
+        }
+    }
+}
@@ -0,0 +1,153 @@
+//! Realtime frame streaming to devices (see `protocol::stream` for the wire format).
+
+use std::collections::HashMap;
+
+use common::LED_BUFFER_SIZE;
+use protocol::stream::{MAX_STREAM_FRAME_LEN, StreamError, encode_delta, encode_raw};
+use protocol::topics::suffix;
+use rumqttc::{AsyncClient, ClientError, QoS};
+use tokio::sync::Mutex;
+use web_common::Target;
+
+use crate::{AppState, Topics};
+
+/// Send a raw frame at least this often, so a device that lost a frame (or joined late)
+/// resynchronises within a second or so.
+pub const DEFAULT_KEYFRAME_INTERVAL: u32 = 30;
+
+#[derive(Debug)]
+pub enum StreamSendError {
+    Frame(StreamError),
+    Mqtt(ClientError),
+}
+
+impl std::fmt::Display for StreamSendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StreamSendError::Frame(e) => write!(f, "invalid frame: {e:?}"),
+            StreamSendError::Mqtt(e) => write!(f, "MQTT publish failed: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for StreamSendError {}
+
+/// Publishes full frames on the stream topic, delta-encoding them against the previous frame
+/// where that is smaller.
+///
+/// Frames are published with QoS 0 - a late frame is worse than a lost one.
+#[derive(Debug)]
+pub struct FrameStreamer {
+    client: AsyncClient,
+    topic: String,
+    seq: u32,
+    prev: Option<Vec<u8>>,
+    since_keyframe: u32,
+    keyframe_interval: u32,
+}
+
+impl FrameStreamer {
+    pub fn new(client: AsyncClient, topic: String) -> Self {
+        Self {
+            client,
+            topic,
+            seq: 0,
+            prev: None,
+            since_keyframe: 0,
+            keyframe_interval: DEFAULT_KEYFRAME_INTERVAL,
+        }
+    }
+
+    pub fn with_keyframe_interval(mut self, interval: u32) -> Self {
+        self.keyframe_interval = interval.max(1);
+        self
+    }
+
+    /// Start a new stream: the next frame is sent raw with sequence number 0, which the device
+    /// always accepts.
+    pub fn restart(&mut self) {
+        self.seq = 0;
+        self.prev = None;
+    }
+
+    /// Encode `frame` (`LED_BUFFER_SIZE` bytes of row-major RGB) as the next stream packet.
+    pub fn encode(&mut self, frame: &[u8]) -> Result<Vec<u8>, StreamError> {
+        if frame.len() != LED_BUFFER_SIZE {
+            return Err(StreamError::WrongFrameSize(frame.len()));
+        }
+
+        let mut out = vec![0u8; MAX_STREAM_FRAME_LEN];
+        let delta = match &self.prev {
+            Some(prev) if self.since_keyframe < self.keyframe_interval => {
+                encode_delta(self.seq, prev, frame, &mut out).ok()
+            }
+            _ => None,
+        };
+        let len = match delta {
+            Some(len) => {
+                self.since_keyframe += 1;
+                len
+            }
+            None => {
+                self.since_keyframe = 0;
+                encode_raw(self.seq, frame, &mut out)?
+            }
+        };
+        out.truncate(len);
+
+        self.seq = self.seq.wrapping_add(1);
+        self.prev = Some(frame.to_vec());
+        Ok(out)
+    }
+
+    /// Encode and publish the next frame.
+    pub async fn send(&mut self, frame: &[u8]) -> Result<(), StreamSendError> {
+        let packet = self.encode(frame).map_err(StreamSendError::Frame)?;
+        self.client
+            .publish(&self.topic, QoS::AtMostOnce, false, packet)
+            .await
+            .map_err(StreamSendError::Mqtt)
+    }
+}
+
+/// A [`FrameStreamer`] per device, so that each device gets its own sequence numbers and
+/// deltas - devices join and leave a group stream independently.
+#[derive(Debug)]
+pub struct Streamers {
+    client: AsyncClient,
+    topics: Topics,
+    streamers: Mutex<HashMap<String, FrameStreamer>>,
+}
+
+impl Streamers {
+    pub fn new(client: AsyncClient, topics: Topics) -> Self {
+        Self {
+            client,
+            topics,
+            streamers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Encode and publish the next frame to each device.
+    pub async fn send(&self, device_ids: &[String], frame: &[u8]) -> Result<(), StreamSendError> {
+        let mut streamers = self.streamers.lock().await;
+        for device_id in device_ids {
+            let streamer = streamers.entry(device_id.clone()).or_insert_with(|| {
+                let topic = self.topics.device(device_id, suffix::STREAM);
+                FrameStreamer::new(self.client.clone(), topic)
+            });
+            streamer.send(frame).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Stream `frame` to every device `target` refers to.
+pub async fn send_to(
+    state: &AppState,
+    target: &Target,
+    frame: &[u8],
+) -> Result<(), StreamSendError> {
+    let device_ids = state.devices.read().unwrap().resolve(target);
+    state.streamers.send(&device_ids, frame).await
+}
@@ -0,0 +1,322 @@
+//! A WLED-compatible JSON API, so that existing WLED apps and integrations can drive the panel.
+//!
+//! `/json/state` changes are translated into device commands and sent to every device with
+//! [`commands::send_to`](crate::commands::send_to). Only the parts of the API that map onto the device are implemented: power,
+//! brightness, a solid colour (effect 0) and the WASM guest (effect 1). WLED's realtime UDP
+//! protocols are handled by [`realtime`].
+
+use axum::extract::State;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::{Json, Router};
+use common::{LED_PANEL_HEIGHT, LED_PANEL_NUM_LEDS, LED_PANEL_WIDTH};
+use protocol::{Command, DirectCommand, Mode, Rgb};
+use serde::Deserialize;
+use serde_json::json;
+use tracing::{info, warn};
+use web_common::Target;
+
+use crate::AppState;
+
+pub mod realtime;
+
+/// WLED version we claim to be, for clients that check it
+const WLED_VERSION: &str = "0.14.0";
+const WLED_BUILD: u32 = 2310130;
+
+const NAME: &str = "esp32-wasmi-led";
+
+/// WLED effects, indexed by `fx`
+pub const EFFECTS: &[&str] = &["Solid", "WASM Guest"];
+const FX_SOLID: u8 = 0;
+const FX_GUEST: u8 = 1;
+
+const PALETTES: &[&str] = &["Default"];
+
+/// The WLED state we report, updated by `/json/state` requests.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WledState {
+    pub on: bool,
+    pub bri: u8,
+    pub color: Rgb,
+    pub fx: u8,
+}
+
+impl Default for WledState {
+    fn default() -> Self {
+        Self {
+            on: true,
+            bri: 100,
+            color: Rgb::new(255, 160, 0),
+            fx: FX_GUEST,
+        }
+    }
+}
+
+impl WledState {
+    fn to_json(&self) -> serde_json::Value {
+        let Rgb { r, g, b } = self.color;
+        json!({
+            "on": self.on,
+            "bri": self.bri,
+            "transition": 0,
+            "ps": -1,
+            "pl": -1,
+            "lor": 0,
+            "mainseg": 0,
+            "seg": [{
+                "id": 0,
+                "start": 0,
+                "stop": LED_PANEL_NUM_LEDS,
+                "len": LED_PANEL_NUM_LEDS,
+                "startY": 0,
+                "stopY": LED_PANEL_HEIGHT,
+                "on": self.on,
+                "bri": 255,
+                "col": [[r, g, b], [0, 0, 0], [0, 0, 0]],
+                "fx": self.fx,
+                "sx": 128,
+                "ix": 128,
+                "pal": 0,
+                "sel": true,
+            }],
+        })
+    }
+
+    /// Apply a `/json/state` update, returning the device commands that bring the device in
+    /// line with the new state.
+    pub fn apply(&mut self, update: StateUpdate) -> Vec<Command> {
+        let mut commands = Vec::new();
+        let before = self.clone();
+
+        match update.on {
+            Some(OnValue::Set(on)) => self.on = on,
+            Some(OnValue::Toggle(t)) if t == "t" => self.on = !self.on,
+            Some(OnValue::Toggle(t)) => warn!("Ignoring WLED on: {t:?}"),
+            None => {}
+        }
+        if let Some(bri) = update.bri {
+            // WLED turns off at brightness 0, but remembers the previous brightness
+            if bri == 0 {
+                self.on = false;
+            } else {
+                self.bri = bri;
+            }
+        }
+
+        let segments = match update.seg {
+            Some(Segments::One(seg)) => vec![seg],
+            Some(Segments::Many(segs)) => segs,
+            None => vec![],
+        };
+        // There is only one segment
+        for seg in segments.into_iter().filter(|s| s.id.unwrap_or(0) == 0) {
+            if let Some(on) = seg.on {
+                self.on = on;
+            }
+            if let Some(color) = seg.col.as_ref().and_then(|c| c.first()) {
+                match color.to_rgb() {
+                    Some(rgb) => self.color = rgb,
+                    None => warn!("Ignoring WLED colour: {color:?}"),
+                }
+            }
+            if let Some(fx) = seg.fx {
+                if (fx as usize) < EFFECTS.len() {
+                    self.fx = fx;
+                } else {
+                    warn!("Ignoring unknown WLED effect {fx}");
+                }
+            }
+        }
+
+        if (self.on, self.bri) != (before.on, before.bri) {
+            let level = if self.on { self.bri } else { 0 };
+            commands.push(Command::SetBrightness(level));
+        }
+        if self.fx != before.fx {
+            commands.push(Command::SetMode(match self.fx {
+                FX_SOLID => Mode::Direct,
+                _ => Mode::Wasm,
+            }));
+        }
+        if self.fx == FX_SOLID && (self.fx != before.fx || self.color != before.color) {
+            commands.push(Command::DirectCommand(DirectCommand::SetAll {
+                color: self.color,
+            }));
+        }
+
+        commands
+    }
+}
+
+/// A partial `/json/state` update - everything is optional.
+#[derive(Deserialize, Debug, Default)]
+pub struct StateUpdate {
+    pub on: Option<OnValue>,
+    pub bri: Option<u8>,
+    pub seg: Option<Segments>,
+    /// Respond with the full state
+    #[serde(default)]
+    pub v: bool,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum OnValue {
+    Set(bool),
+    /// `"t"` toggles
+    Toggle(String),
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum Segments {
+    One(SegmentUpdate),
+    Many(Vec<SegmentUpdate>),
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct SegmentUpdate {
+    pub id: Option<u8>,
+    pub on: Option<bool>,
+    pub col: Option<Vec<WledColor>>,
+    pub fx: Option<u8>,
+}
+
+/// `[r, g, b]`, `[r, g, b, w]` or `"RRGGBB"`
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum WledColor {
+    Channels(Vec<u8>),
+    Hex(String),
+}
+
+impl WledColor {
+    fn to_rgb(&self) -> Option<Rgb> {
+        match self {
+            WledColor::Channels(c) => match c.as_slice() {
+                [r, g, b] | [r, g, b, _] => Some(Rgb::new(*r, *g, *b)),
+                _ => None,
+            },
+            WledColor::Hex(hex) => {
+                let hex = hex.get(hex.len().checked_sub(6)?..)?;
+                let v = u32::from_str_radix(hex, 16).ok()?;
+                Some(Rgb::new((v >> 16) as u8, (v >> 8) as u8, v as u8))
+            }
+        }
+    }
+}
+
+fn info_json(live: bool) -> serde_json::Value {
+    json!({
+        "ver": WLED_VERSION,
+        "vid": WLED_BUILD,
+        "leds": {
+            "count": LED_PANEL_NUM_LEDS,
+            "rgbw": false,
+            "wv": 0,
+            "cct": 0,
+            "pwr": 0,
+            "fps": 0,
+            "maxpwr": 0,
+            "maxseg": 1,
+            "seglc": [1],
+            "lc": 1,
+            "matrix": { "w": LED_PANEL_WIDTH, "h": LED_PANEL_HEIGHT },
+        },
+        "str": false,
+        "name": NAME,
+        "udpport": realtime::DEFAULT_PORT,
+        "live": live,
+        "lm": if live { "UDP" } else { "" },
+        "fxcount": EFFECTS.len(),
+        "palcount": PALETTES.len(),
+        "arch": "esp32",
+        "core": "",
+        "brand": "WLED",
+        "product": NAME,
+        "mac": "",
+        "ip": "",
+    })
+}
+
+/// Whether `path` is one of the WLED JSON API's.
+pub fn is_api(path: &str) -> bool {
+    path == "/json" || path.starts_with("/json/")
+}
+
+/// Routes for the WLED JSON API, to merge into the main router.
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/json", get(get_all).post(post_state))
+        .route("/json/si", get(get_state_info).post(post_state))
+        .route("/json/state", get(get_state).post(post_state))
+        .route("/json/info", get(get_info))
+        .route("/json/effects", get(get_effects))
+        .route("/json/palettes", get(get_palettes))
+}
+
+fn state_json(state: &AppState) -> serde_json::Value {
+    state.wled.read().unwrap().to_json()
+}
+
+fn is_live(state: &AppState) -> bool {
+    state.wled_live.load(std::sync::atomic::Ordering::Relaxed)
+}
+
+async fn get_all(State(state): State<AppState>) -> impl IntoResponse {
+    Json(json!({
+        "state": state_json(&state),
+        "info": info_json(is_live(&state)),
+        "effects": EFFECTS,
+        "palettes": PALETTES,
+    }))
+}
+
+async fn get_state_info(State(state): State<AppState>) -> impl IntoResponse {
+    Json(json!({
+        "state": state_json(&state),
+        "info": info_json(is_live(&state)),
+    }))
+}
+
+async fn get_state(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state_json(&state))
+}
+
+async fn get_info(State(state): State<AppState>) -> impl IntoResponse {
+    Json(info_json(is_live(&state)))
+}
+
+async fn get_effects() -> impl IntoResponse {
+    Json(EFFECTS)
+}
+
+async fn get_palettes() -> impl IntoResponse {
+    Json(PALETTES)
+}
+
+async fn post_state(
+    State(state): State<AppState>,
+    Json(update): Json<StateUpdate>,
+) -> impl IntoResponse {
+    let verbose = update.v;
+    let commands = state.wled.write().unwrap().apply(update);
+
+    for command in commands {
+        info!("WLED -> device: {command:?}");
+        for (device_id, result) in
+            crate::commands::send_to(&state, &Target::default(), command).await
+        {
+            if let Err(e) = result {
+                warn!("Not sent to {device_id}: {e}");
+            }
+        }
+    }
+
+    if verbose {
+        Json(state_json(&state))
+    } else {
+        Json(json!({ "success": true }))
+    }
+}
@@ -0,0 +1,152 @@
+//! WLED realtime UDP protocols (WARLS, DRGB, DRGBW, DNRGB), bridged to the device's frame
+//! stream.
+//!
+//! Every packet is `[protocol][timeout][data...]`. LED indices are row-major panel pixels.
+//! `timeout` is how many seconds to keep showing realtime data after the last packet, with
+//! 255 meaning until told otherwise - so the last frame is re-sent to keep the device in
+//! `Mode::Stream` until then.
+
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use common::{BYTES_PER_LED, LED_BUFFER_SIZE, LED_PANEL_NUM_LEDS};
+use tokio::net::UdpSocket;
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+use web_common::Target;
+
+use crate::{AppState, stream};
+
+/// WLED's default realtime UDP port
+pub const DEFAULT_PORT: u16 = 21324;
+
+const WARLS: u8 = 1;
+const DRGB: u8 = 2;
+const DRGBW: u8 = 3;
+const DNRGB: u8 = 4;
+
+const TIMEOUT_FOREVER: u8 = 255;
+
+// Re-send the last frame this often while realtime is held, to beat the device's stream timeout
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RealtimeError {
+    TooShort,
+    /// Protocol byte we don't handle (including 0, WLED's sync notifier)
+    UnknownProtocol(u8),
+}
+
+/// How long a realtime packet asks to hold its frame for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hold {
+    For(Duration),
+    Forever,
+}
+
+/// Apply a realtime packet to `frame` (`LED_BUFFER_SIZE` bytes of row-major RGB), ignoring
+/// LEDs beyond the panel.
+pub fn apply_packet(packet: &[u8], frame: &mut [u8]) -> Result<Hold, RealtimeError> {
+    let [protocol, timeout, data @ ..] = packet else {
+        return Err(RealtimeError::TooShort);
+    };
+
+    let mut set = |index: usize, rgb: &[u8]| {
+        if index < LED_PANEL_NUM_LEDS && frame.len() >= LED_BUFFER_SIZE {
+            let o = index * BYTES_PER_LED;
+            frame[o..o + BYTES_PER_LED].copy_from_slice(rgb);
+        }
+    };
+
+    match *protocol {
+        WARLS => {
+            for [index, rgb @ ..] in data.as_chunks::<4>().0 {
+                set(*index as usize, rgb);
+            }
+        }
+        DRGB => {
+            for (i, rgb) in data.as_chunks::<3>().0.iter().enumerate() {
+                set(i, rgb);
+            }
+        }
+        DRGBW => {
+            for (i, rgbw) in data.as_chunks::<4>().0.iter().enumerate() {
+                set(i, &rgbw[..3]);
+            }
+        }
+        DNRGB => {
+            let [hi, lo, data @ ..] = data else {
+                return Err(RealtimeError::TooShort);
+            };
+            let start = u16::from_be_bytes([*hi, *lo]) as usize;
+            for (i, rgb) in data.as_chunks::<3>().0.iter().enumerate() {
+                set(start + i, rgb);
+            }
+        }
+        other => return Err(RealtimeError::UnknownProtocol(other)),
+    }
+
+    Ok(match *timeout {
+        TIMEOUT_FOREVER => Hold::Forever,
+        secs => Hold::For(Duration::from_secs(secs.into())),
+    })
+}
+
+/// Receive realtime packets on `socket` and stream the resulting frames to every device.
+/// `AppState::wled_live` is set while realtime data is being shown.
+pub fn spawn_bridge(socket: UdpSocket, state: AppState) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let live = &state.wled_live;
+        if let Ok(addr) = socket.local_addr() {
+            info!("WLED realtime listening on udp://{addr}");
+        }
+
+        let mut frame = vec![0u8; LED_BUFFER_SIZE];
+        let mut buf = [0u8; 1500];
+        // `None` when not held, `Some(None)` when held forever
+        let mut hold_until: Option<Option<Instant>> = None;
+        let mut keepalive = tokio::time::interval(KEEPALIVE_INTERVAL);
+
+        loop {
+            tokio::select! {
+                recv = socket.recv_from(&mut buf) => {
+                    let (n, from) = match recv {
+                        Ok(r) => r,
+                        Err(e) => {
+                            warn!("WLED realtime receive failed: {e}");
+                            continue;
+                        }
+                    };
+                    match apply_packet(&buf[..n], &mut frame) {
+                        Ok(hold) => {
+                            hold_until = Some(match hold {
+                                Hold::For(d) => Some(Instant::now() + d),
+                                Hold::Forever => None,
+                            });
+                            live.store(true, Ordering::Relaxed);
+                        }
+                        Err(e) => {
+                            debug!("Ignoring WLED realtime packet from {from}: {e:?}");
+                            continue;
+                        }
+                    }
+                }
+                _ = keepalive.tick() => {
+                    match hold_until {
+                        Some(Some(until)) if Instant::now() >= until => {
+                            hold_until = None;
+                            live.store(false, Ordering::Relaxed);
+                            continue;
+                        }
+                        Some(_) => {}
+                        None => continue,
+                    }
+                }
+            }
+
+            if let Err(e) = stream::send_to(&state, &Target::default(), &frame).await {
+                warn!("WLED realtime frame not sent: {e}");
+            }
+        }
+    })
+}
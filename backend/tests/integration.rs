@@ -1,21 +1,56 @@
 //! Integration tests for the egui-axum-mqtt backend.
 //!
-//! **Prerequisites**: a Mosquitto (or compatible) MQTT broker on localhost:1883
-//! with `allow_anonymous true`.
+//! **Prerequisites**: a Mosquitto (or compatible) MQTT broker, by default on localhost:1883
+//! with `allow_anonymous true`. The `MQTT_*` variables of [`backend::broker`] select another
+//! broker, credentials and TLS, as for the backend itself.
 //!
 //! Run with:
 //!   cargo test -p backend --test integration -- --nocapture
+//!
+//! or against the local broker from `just mosquitto`, with TLS and authentication:
+//!   just test-backend-tls
 
+use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
 use std::time::Duration;
 
+use chrono::NaiveDateTime;
 use futures_util::{SinkExt, StreamExt};
-use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use rumqttc::{AsyncClient, ConnectionError, Event, Packet, QoS};
 use tokio::time::timeout;
 use tokio_tungstenite::tungstenite;
-use web_common::{ClientMsg, LastMessage, ServerMsg};
+use tracing::level_filters::LevelFilter;
+use web_common::{ClientMsg, LastMessage, ServerMsg, SimulatorControl, Target};
+
+use common::{LED_BUFFER_SIZE, LED_PANEL_HEIGHT, LED_PANEL_NUM_LEDS, LED_PANEL_WIDTH};
+use protocol::stream::{FrameEncoding, StreamFrame};
 
-use backend::{PingPayload, Topics, build_router, create_mqtt, create_state, spawn_mqtt_loop};
+use backend::animations::AnimationInfo;
+use backend::auth::{Caller, NewToken, Role, TOKEN_PREFIX};
+use backend::broker::Broker;
+use backend::config::{self, Config};
+use backend::db::{Content, Database, Kind, Records};
+use backend::guests::{self, GuestError, GuestInfo};
+use backend::homeassistant::{LIGHT_SET, LIGHT_STATE};
+use backend::images::ImageInfo;
+use backend::playlists::{self, Playlist, Transition};
+use backend::schedules::{
+    Clock, Cron, SCHEDULER_INTERVAL, Schedule, Scheduler, Show, spawn_scheduler,
+};
+use backend::simulator::Simulation;
+use backend::wled::realtime;
+use backend::{Topics, build_router, create_mqtt, create_state, spawn_mqtt_loop};
+use protocol::guest::{GuestChunk, MAX_GUEST_CHUNK_DATA_LEN, MAX_GUEST_LEN};
+use protocol::ping::{PingRequest, PingResponse};
+use protocol::presence::DeviceInfo;
+use protocol::telemetry::{FrameTimes, HeapUsage, MAX_GUEST_NAME_LEN, Telemetry};
+use protocol::topics::suffix;
+use protocol::{
+    Command, CommandAck, CommandEnvelope, CommandError, DeviceConfig, DirectCommand, Mode, Pixel,
+    Point, Rgb,
+};
 
 /// A self-contained test environment with its own MQTT topic namespace.
 /// Starts the backend on an ephemeral port, creates a separate MQTT client for the "test side"
@@ -23,6 +58,8 @@ use backend::{PingPayload, Topics, build_router, create_mqtt, create_state, spaw
 /// Each instance gets a unique topic prefix so that parallel tests don't interfere with each other.
 struct TestHarness {
     addr: SocketAddr,
+    /// WLED realtime UDP bridge
+    wled_udp: SocketAddr,
     http: reqwest::Client,
     topics: Topics,
     /// MQTT client the *test* uses to publish/subscribe (not the backend's).
@@ -46,14 +83,24 @@ impl TestHarness {
     {
         // Each test gets a unique client-id to avoid collisions when tests run in parallel.
         let id = uuid_short();
-        let topics = Topics::new(&format!("test-{id}"));
+        let topics = Topics::new(&format!("test-{id}"))
+            .with_discovery_prefix(&format!("test-{id}/homeassistant"));
 
         // Backend side:
+        let broker = broker();
         let (mqtt_client, eventloop) =
-            create_mqtt(&format!("test-backend-{id}"), "localhost", 1883, &topics).await;
-        let state = create_state(mqtt_client, topics.clone());
+            create_mqtt(&format!("test-backend-{id}"), &broker, &topics).await;
+        let state = create_state(
+            mqtt_client,
+            topics.clone(),
+            Database::open_in_memory().unwrap(),
+        );
         let backend_mqtt_handle = spawn_mqtt_loop(eventloop, state.clone());
 
+        let wled_socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let wled_udp = wled_socket.local_addr().unwrap();
+        realtime::spawn_bridge(wled_socket, state.clone());
+
         let router = build_router(state);
         let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
         let addr = listener.local_addr().unwrap();
@@ -63,7 +110,7 @@ impl TestHarness {
         });
 
         // Test (observer) side:
-        let mut opts = MqttOptions::new(format!("test-observer-{id}"), "localhost", 1883);
+        let mut opts = broker.options(&format!("test-observer-{id}"));
         opts.set_keep_alive(Duration::from_secs(30));
         let (test_mqtt, mut test_eventloop) = AsyncClient::new(opts, 50);
 
@@ -94,6 +141,7 @@ impl TestHarness {
 
         Self {
             addr,
+            wled_udp,
             http: reqwest::Client::new(),
             topics,
             test_mqtt,
@@ -171,11 +219,91 @@ impl TestHarness {
         }
     }
 
+    /// Wait for the next frame streamed to [`DEVICE`]. Requires a subscription to its stream
+    /// topic.
+    async fn expect_frame(&mut self) -> Vec<u8> {
+        let topic = self.topics.device(DEVICE, suffix::STREAM);
+        let payload = self.expect_mqtt_on_topic(&topic, T).await;
+        let mut display = vec![0u8; LED_BUFFER_SIZE];
+        StreamFrame::parse(&payload)
+            .unwrap()
+            .apply(&mut display)
+            .unwrap();
+        display
+    }
+
+    /// Wait for a command envelope from the backend to `device_id`. Requires a subscription to
+    /// its command topic.
+    async fn expect_command(&mut self, device_id: &str, dur: Duration) -> CommandEnvelope {
+        let topic = self.topics.device(device_id, suffix::COMMAND);
+        let payload = self.expect_mqtt_on_topic(&topic, dur).await;
+        serde_json::from_slice(&payload).expect("failed to parse CommandEnvelope from MQTT")
+    }
+
+    /// Publish an ack as the device would.
+    async fn publish_ack(&self, device_id: &str, ack: CommandAck) {
+        self.test_mqtt
+            .publish(
+                self.topics.device(device_id, suffix::COMMAND_ACK),
+                QoS::AtLeastOnce,
+                false,
+                serde_json::to_vec(&ack).unwrap(),
+            )
+            .await
+            .unwrap();
+    }
+
+    /// Bring a device online, as the device would, and wait for the backend to discover it.
+    async fn announce_device(&self, device_id: &str) {
+        self.test_mqtt
+            .publish(
+                self.topics.device(device_id, suffix::STATUS),
+                QoS::AtLeastOnce,
+                false,
+                "online",
+            )
+            .await
+            .unwrap();
+
+        let path = format!("/api/devices/{device_id}");
+        timeout(T, async {
+            while self.http_get(&path).await.status() != 200 {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("device {device_id} not discovered"));
+    }
+
     /// HTTP GET helper.
     async fn http_get(&self, path: &str) -> reqwest::Response {
         let url = format!("http://{}{}", self.addr, path);
         self.http.get(&url).send().await.expect("HTTP GET failed")
     }
+
+    /// HTTP POST helper, with a JSON body.
+    async fn http_post(&self, path: &str, body: &serde_json::Value) -> reqwest::Response {
+        let url = format!("http://{}{}", self.addr, path);
+        self.http
+            .post(&url)
+            .json(body)
+            .send()
+            .await
+            .expect("HTTP POST failed")
+    }
+}
+
+/// Serve the backend's routes with `db`, for tests that don't send anything to devices.
+async fn serve_without_broker(db: Database) -> SocketAddr {
+    let (client, _eventloop) =
+        AsyncClient::new(rumqttc::MqttOptions::new("unused", "localhost", 1), 10);
+    let state = create_state(client, Topics::default(), db);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, build_router(state)).await.unwrap();
+    });
+    addr
 }
 
 /// Produce a short random-ish identifier
@@ -188,9 +316,17 @@ fn uuid_short() -> String {
     format!("{:08x}-{:x}", nanos, std::process::id())
 }
 
+/// The broker to test against, from the environment
+fn broker() -> Broker {
+    Broker::from_env().expect("bad MQTT_* settings")
+}
+
 /// Default timeout used across tests
 const T: Duration = Duration::from_secs(5);
 
+/// The simulated device
+const DEVICE: &str = "404cca01abff";
+
 // Pattern 1: Real-time send  (client → WS → backend → MQTT)
 #[tokio::test]
 async fn pattern1_realtime_send() {
@@ -277,7 +413,7 @@ async fn pattern3_realtime_receive() {
 async fn pattern4_ping_device_roundtrip() {
     // Test side subscribes to TOPIC_PING_REQ so it can see the backend's
     // outgoing ping, then publishes a reply on TOPIC_PING_RESP.
-    let mut h = TestHarness::new(|t| vec![t.ping_req.clone()]).await;
+    let mut h = TestHarness::new(|t| vec![t.device(DEVICE, suffix::PING_REQUEST)]).await;
     let mut ws = h.connect_ws().await;
 
     let corr_id = "test-corr-001".to_string();
@@ -285,24 +421,32 @@ async fn pattern4_ping_device_roundtrip() {
     // 1. Client sends PingDevice over WebSocket.
     let msg = ClientMsg::PingDevice {
         correlation_id: corr_id.clone(),
+        target: Target::Device(DEVICE.into()),
     };
     TestHarness::ws_send(&mut ws, &msg).await;
 
-    // 2. Backend should publish a PingPayload on TOPIC_PING_REQ.
-    let req_bytes = h.expect_mqtt_on_topic(&h.topics.ping_req.clone(), T).await;
-    let req: PingPayload =
-        serde_json::from_slice(&req_bytes).expect("failed to parse PingPayload from MQTT");
-    assert_eq!(req.correlation_id, corr_id);
-    assert_eq!(req.message, "ping");
+    // 2. Backend should publish a PingRequest on the device's TOPIC_PING_REQ.
+    let topic = h.topics.device(DEVICE, suffix::PING_REQUEST);
+    let req_bytes = h.expect_mqtt_on_topic(&topic, T).await;
+    let req: PingRequest =
+        serde_json::from_slice(&req_bytes).expect("failed to parse PingRequest from MQTT");
+    assert_eq!(req.correlation_id.as_str(), corr_id);
+    assert_eq!(req.message.as_str(), "ping");
 
-    // 3. Simulate the device replying on TOPIC_PING_RESP.
-    let reply = serde_json::to_vec(&PingPayload {
-        correlation_id: corr_id.clone(),
-        message: "pong from device".into(),
+    // 3. Simulate the device replying on its TOPIC_PING_RESP.
+    let reply = serde_json::to_vec(&PingResponse {
+        correlation_id: corr_id.as_str().try_into().unwrap(),
+        message: "pong from device".try_into().unwrap(),
+        protocol_version: protocol::PROTOCOL_VERSION,
     })
     .unwrap();
     h.test_mqtt
-        .publish(&h.topics.ping_resp, QoS::AtLeastOnce, false, reply)
+        .publish(
+            h.topics.device(DEVICE, suffix::PING_RESPONSE),
+            QoS::AtLeastOnce,
+            false,
+            reply,
+        )
         .await
         .unwrap();
 
@@ -310,9 +454,11 @@ async fn pattern4_ping_device_roundtrip() {
     let server_msg = TestHarness::ws_recv(&mut ws, T).await;
     match server_msg {
         ServerMsg::PingResponse {
+            device_id,
             correlation_id,
             device_reply,
         } => {
+            assert_eq!(device_id, DEVICE);
             assert_eq!(correlation_id, corr_id);
             assert_eq!(device_reply, "pong from device");
         }
@@ -330,13 +476,19 @@ async fn pattern4_ping_unmatched_correlation_id_still_arrives() {
     let mut ws = h.connect_ws().await;
 
     // Publish a PingResponse with an ID nobody asked for.
-    let reply = serde_json::to_vec(&PingPayload {
-        correlation_id: "unknown-id".into(),
-        message: "surprise".into(),
+    let reply = serde_json::to_vec(&PingResponse {
+        correlation_id: "unknown-id".try_into().unwrap(),
+        message: "surprise".try_into().unwrap(),
+        protocol_version: protocol::PROTOCOL_VERSION,
     })
     .unwrap();
     h.test_mqtt
-        .publish(&h.topics.ping_resp, QoS::AtLeastOnce, false, reply)
+        .publish(
+            h.topics.device(DEVICE, suffix::PING_RESPONSE),
+            QoS::AtLeastOnce,
+            false,
+            reply,
+        )
         .await
         .unwrap();
 
@@ -345,6 +497,7 @@ async fn pattern4_ping_unmatched_correlation_id_still_arrives() {
         ServerMsg::PingResponse {
             correlation_id,
             device_reply,
+            ..
         } => {
             assert_eq!(correlation_id, "unknown-id");
             assert_eq!(device_reply, "surprise");
@@ -410,3 +563,2242 @@ async fn bad_ws_message_does_not_crash() {
         other => panic!("expected MqttUpdate, got {other:?}"),
     }
 }
+
+// Realtime streaming  (binary WS frames → backend → MQTT stream topic)
+#[tokio::test]
+async fn binary_ws_frames_are_streamed() {
+    let mut h = TestHarness::new(|t| vec![t.device(DEVICE, suffix::STREAM)]).await;
+    let mut ws = h.connect_ws().await;
+
+    let msg = ClientMsg::SetStreamTarget {
+        target: Target::Device(DEVICE.into()),
+    };
+    TestHarness::ws_send(&mut ws, &msg).await;
+
+    // The first frame of a stream is always sent raw, with sequence number 0.
+    let mut frame = vec![0u8; LED_BUFFER_SIZE];
+    frame[..3].copy_from_slice(&[255, 0, 0]);
+    ws.send(tungstenite::Message::Binary(frame.clone().into()))
+        .await
+        .unwrap();
+
+    let (topic, payload) = h.expect_mqtt(T).await;
+    assert_eq!(topic, h.topics.device(DEVICE, suffix::STREAM));
+    let raw = StreamFrame::parse(&payload).unwrap();
+    assert_eq!(raw.encoding, FrameEncoding::Raw);
+    assert_eq!(raw.seq, 0);
+
+    let mut display = vec![0u8; LED_BUFFER_SIZE];
+    raw.apply(&mut display).unwrap();
+    assert_eq!(display, frame);
+
+    // A small change is sent as a delta against the previous frame.
+    frame[30..33].copy_from_slice(&[0, 0, 255]);
+    ws.send(tungstenite::Message::Binary(frame.clone().into()))
+        .await
+        .unwrap();
+
+    let (_, payload) = h.expect_mqtt(T).await;
+    let delta = StreamFrame::parse(&payload).unwrap();
+    assert_eq!(delta.encoding, FrameEncoding::Delta);
+    assert!(delta.follows(Some(raw.seq)));
+    assert!(payload.len() < LED_BUFFER_SIZE);
+
+    delta.apply(&mut display).unwrap();
+    assert_eq!(display, frame);
+}
+
+// WLED JSON API  (HTTP → backend → every device's MQTT command topic)
+#[tokio::test]
+async fn wled_state_maps_to_device_commands() {
+    let mut h = TestHarness::new(|t| vec![t.device(DEVICE, suffix::COMMAND)]).await;
+    h.announce_device(DEVICE).await;
+
+    let info: serde_json::Value = h.http_get("/json/info").await.json().await.unwrap();
+    assert_eq!(info["leds"]["count"], LED_PANEL_NUM_LEDS);
+    let effects: Vec<String> = h.http_get("/json/effects").await.json().await.unwrap();
+    assert_eq!(effects[0], "Solid");
+
+    // A solid colour switches to Direct mode and fills the panel.
+    let resp = h
+        .http_post(
+            "/json/state",
+            &serde_json::json!({"seg": [{"fx": 0, "col": [[255, 0, 0]]}], "v": true}),
+        )
+        .await;
+    assert_eq!(resp.status(), 200);
+    let state: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(state["seg"][0]["col"][0], serde_json::json!([255, 0, 0]));
+
+    let mode = h.expect_command(DEVICE, T).await;
+    assert_eq!(mode.command, Command::SetMode(Mode::Direct));
+    let fill = h.expect_command(DEVICE, T).await;
+    assert_eq!(
+        serde_json::to_string(&fill.command).unwrap(),
+        r#"{"DirectCommand":{"SetAll":{"color":{"r":255,"g":0,"b":0}}}}"#
+    );
+    assert_ne!(mode.id, fill.id);
+    // Both from this run of the backend
+    assert_ne!(mode.sender, 0);
+    assert_eq!(mode.sender, fill.sender);
+
+    // Turning off sets the device brightness to 0.
+    h.http_post("/json/state", &serde_json::json!({"on": false}))
+        .await;
+    let off = h.expect_command(DEVICE, T).await;
+    assert_eq!(off.command, Command::SetBrightness(0));
+}
+
+// Command acks  (WebSocket → MQTT command topic, MQTT ack topic → all WebSockets)
+#[tokio::test]
+async fn ws_command_ack_is_broadcast() {
+    let mut h = TestHarness::new(|t| vec![t.device(DEVICE, suffix::COMMAND)]).await;
+    let mut ws = h.connect_ws().await;
+    let mut other_ws = h.connect_ws().await;
+
+    let msg = ClientMsg::Command {
+        target: Target::Device(DEVICE.into()),
+        command: Box::new(Command::SetMode(Mode::Direct)),
+    };
+    TestHarness::ws_send(&mut ws, &msg).await;
+
+    let ServerMsg::CommandSent { device_id, id } = TestHarness::ws_recv(&mut ws, T).await else {
+        panic!("expected CommandSent");
+    };
+    assert_eq!(device_id, DEVICE);
+    let envelope = h.expect_command(DEVICE, T).await;
+    assert_eq!(envelope.id, id);
+    assert_eq!(envelope.command, Command::SetMode(Mode::Direct));
+
+    h.publish_ack(DEVICE, CommandAck::ok(id)).await;
+    for ws in [&mut ws, &mut other_ws] {
+        match TestHarness::ws_recv(ws, T).await {
+            ServerMsg::CommandAck {
+                device_id,
+                id: acked,
+                error,
+            } => {
+                assert_eq!(device_id, DEVICE);
+                assert_eq!(acked, id);
+                assert_eq!(error, None);
+            }
+            other => panic!("expected CommandAck, got {other:?}"),
+        }
+    }
+}
+
+// Command acks  (HTTP POST waits for the device's ack)
+#[tokio::test]
+async fn rest_command_returns_device_error() {
+    let mut h = TestHarness::new(|t| vec![t.device(DEVICE, suffix::COMMAND)]).await;
+
+    let url = format!("http://{}/api/devices/{DEVICE}/command", h.addr);
+    let request = h.http.post(&url).json(&Command::SetBrightness(10)).send();
+    let request = tokio::spawn(request);
+
+    let envelope = h.expect_command(DEVICE, T).await;
+    assert_eq!(envelope.command, Command::SetBrightness(10));
+    h.publish_ack(
+        DEVICE,
+        CommandAck::nack(envelope.id, CommandError::WrongMode),
+    )
+    .await;
+
+    let resp = request.await.unwrap().expect("HTTP POST failed");
+    assert_eq!(resp.status(), 422);
+    let ack: CommandAck = resp.json().await.unwrap();
+    assert_eq!(ack, CommandAck::nack(envelope.id, CommandError::WrongMode));
+}
+
+// Telemetry  (MQTT device telemetry topic → HTTP GET and all WebSockets)
+#[tokio::test]
+async fn telemetry_is_parsed_and_forwarded() {
+    let h = TestHarness::new(|_| vec![]).await;
+    let mut ws = h.connect_ws().await;
+
+    let path = format!("/api/devices/{DEVICE}");
+    assert_eq!(h.http_get(&path).await.status(), 404);
+
+    let telemetry = Telemetry {
+        protocol_version: protocol::PROTOCOL_VERSION,
+        firmware_version: "0.1.0".try_into().unwrap(),
+        uptime_s: 42,
+        mode: Mode::Wasm,
+        guest: Some("guest".try_into().unwrap()),
+        frames: FrameTimes {
+            fps: 60,
+            min_us: 7000,
+            avg_us: 7500,
+            max_us: 9000,
+        },
+        heap: HeapUsage {
+            used: 1000,
+            free: 2000,
+        },
+        guest_memory_bytes: 131072,
+        power_mw: 1280,
+        rssi_dbm: Some(-60),
+        ip: Some("192.168.1.242".try_into().unwrap()),
+    };
+    h.test_mqtt
+        .publish(
+            h.topics.device(DEVICE, suffix::TELEMETRY),
+            QoS::AtLeastOnce,
+            false,
+            serde_json::to_vec(&telemetry).unwrap(),
+        )
+        .await
+        .unwrap();
+
+    match TestHarness::ws_recv(&mut ws, T).await {
+        ServerMsg::Telemetry {
+            device_id,
+            telemetry: received,
+        } => {
+            assert_eq!(device_id, DEVICE);
+            assert_eq!(received, telemetry);
+        }
+        other => panic!("expected Telemetry, got {other:?}"),
+    }
+    let device: serde_json::Value = h.http_get(&path).await.json().await.unwrap();
+    let fetched: Telemetry = serde_json::from_value(device["telemetry"].clone()).unwrap();
+    assert_eq!(fetched, telemetry);
+}
+
+// Presence  (MQTT device status topic → all WebSockets, and to new WebSockets on connect)
+#[tokio::test]
+async fn device_presence_is_tracked() {
+    let h = TestHarness::new(|_| vec![]).await;
+    let mut ws = h.connect_ws().await;
+
+    let status_topic = h.topics.device(DEVICE, suffix::STATUS);
+    for (payload, online) in [("online", true), ("offline", false)] {
+        h.test_mqtt
+            .publish(&status_topic, QoS::AtLeastOnce, false, payload)
+            .await
+            .unwrap();
+        match TestHarness::ws_recv(&mut ws, T).await {
+            ServerMsg::Presence {
+                device_id,
+                online: received,
+            } => {
+                assert_eq!(device_id, DEVICE);
+                assert_eq!(received, online);
+            }
+            other => panic!("expected Presence, got {other:?}"),
+        }
+    }
+
+    // A client connecting later is told the current state
+    let mut late_ws = h.connect_ws().await;
+    match TestHarness::ws_recv(&mut late_ws, T).await {
+        ServerMsg::Presence { device_id, online } => {
+            assert_eq!(device_id, DEVICE);
+            assert!(!online);
+        }
+        other => panic!("expected Presence, got {other:?}"),
+    }
+}
+
+// WLED realtime UDP  (DRGB packet → backend → every device's MQTT stream topic)
+#[tokio::test]
+async fn wled_realtime_udp_is_streamed() {
+    let mut h = TestHarness::new(|t| vec![t.device(DEVICE, suffix::STREAM)]).await;
+    h.announce_device(DEVICE).await;
+
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    // DRGB, hold for 2 s, first two pixels red and green
+    let packet = [2, 2, 255, 0, 0, 0, 255, 0];
+    socket.send_to(&packet, h.wled_udp).await.unwrap();
+
+    let (topic, payload) = h.expect_mqtt(T).await;
+    assert_eq!(topic, h.topics.device(DEVICE, suffix::STREAM));
+    let frame = StreamFrame::parse(&payload).unwrap();
+
+    let mut display = vec![0u8; LED_BUFFER_SIZE];
+    frame.apply(&mut display).unwrap();
+    assert_eq!(&display[..7], &[255, 0, 0, 0, 255, 0, 0]);
+}
+
+// Discovery and groups  (MQTT device info → registry, group command → each device's ack)
+#[tokio::test]
+async fn devices_are_discovered_and_grouped() {
+    const OTHER: &str = "404cca01ac00";
+    let mut h = TestHarness::new(|t| vec![t.all_devices(suffix::COMMAND)]).await;
+
+    let info = DeviceInfo {
+        device_id: DEVICE.try_into().unwrap(),
+        firmware_version: "0.1.0".try_into().unwrap(),
+        protocol_version: protocol::PROTOCOL_VERSION,
+        width: 16,
+        height: 16,
+    };
+    h.test_mqtt
+        .publish(
+            h.topics.device(DEVICE, suffix::INFO),
+            QoS::AtLeastOnce,
+            true,
+            serde_json::to_vec(&info).unwrap(),
+        )
+        .await
+        .unwrap();
+    h.announce_device(DEVICE).await;
+    h.announce_device(OTHER).await;
+
+    let devices: serde_json::Value = h.http_get("/api/devices").await.json().await.unwrap();
+    assert_eq!(devices[0]["device_id"], DEVICE);
+    assert_eq!(devices[0]["online"], true);
+    assert_eq!(devices[0]["info"]["width"], 16);
+    assert_eq!(devices[1]["device_id"], OTHER);
+
+    // Only DEVICE is in the kitchen
+    let url = format!("http://{}/api/devices/{DEVICE}/groups", h.addr);
+    let resp = h.http.put(&url).json(&["kitchen"]).send().await.unwrap();
+    assert_eq!(resp.status(), 200);
+
+    let url = format!("http://{}/api/groups/kitchen/command", h.addr);
+    let request = h.http.post(&url).json(&Command::SetBrightness(10)).send();
+    let request = tokio::spawn(request);
+
+    let envelope = h.expect_command(DEVICE, T).await;
+    h.publish_ack(DEVICE, CommandAck::ok(envelope.id)).await;
+
+    let resp = request.await.unwrap().expect("HTTP POST failed");
+    assert_eq!(resp.status(), 200);
+    let results: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(
+        results,
+        serde_json::json!([{
+            "device_id": DEVICE,
+            "ack": CommandAck::ok(envelope.id),
+            "error": null,
+        }])
+    );
+
+    // A new client learns about both devices
+    let mut ws = h.connect_ws().await;
+    match TestHarness::ws_recv(&mut ws, T).await {
+        ServerMsg::DeviceInfo { info: received } => assert_eq!(received, info),
+        other => panic!("expected DeviceInfo, got {other:?}"),
+    }
+}
+
+// Home Assistant  (device info → discovery configs, light commands → device commands)
+#[tokio::test]
+async fn home_assistant_light_maps_to_device_commands() {
+    let mut h = TestHarness::new(|t| {
+        vec![
+            format!("{}/#", t.discovery),
+            t.device(DEVICE, suffix::COMMAND),
+            t.device(DEVICE, LIGHT_STATE),
+        ]
+    })
+    .await;
+
+    let info = DeviceInfo {
+        device_id: DEVICE.try_into().unwrap(),
+        firmware_version: "0.1.0".try_into().unwrap(),
+        protocol_version: protocol::PROTOCOL_VERSION,
+        width: 16,
+        height: 16,
+    };
+    h.test_mqtt
+        .publish(
+            h.topics.device(DEVICE, suffix::INFO),
+            QoS::AtLeastOnce,
+            false,
+            serde_json::to_vec(&info).unwrap(),
+        )
+        .await
+        .unwrap();
+
+    let topic = format!("{}/light/{DEVICE}/panel/config", h.topics.discovery);
+    let config: serde_json::Value =
+        serde_json::from_slice(&h.expect_mqtt_on_topic(&topic, T).await).unwrap();
+    assert_eq!(config["schema"], "json");
+    assert_eq!(config["command_topic"], h.topics.device(DEVICE, LIGHT_SET));
+    assert_eq!(config["effect_list"], serde_json::json!(["DMX"]));
+    assert_eq!(config["device"]["identifiers"][0], DEVICE);
+
+    let (mqtt, set_topic) = (h.test_mqtt.clone(), h.topics.device(DEVICE, LIGHT_SET));
+    let set = |payload: serde_json::Value| {
+        mqtt.publish(
+            set_topic.clone(),
+            QoS::AtLeastOnce,
+            false,
+            serde_json::to_vec(&payload).unwrap(),
+        )
+    };
+
+    // A colour fills the panel in Direct mode
+    set(serde_json::json!({"state": "ON", "color": {"r": 255, "g": 0, "b": 0}}))
+        .await
+        .unwrap();
+    let mode = h.expect_command(DEVICE, T).await;
+    assert_eq!(mode.command, Command::SetMode(Mode::Direct));
+    let fill = h.expect_command(DEVICE, T).await;
+    assert_eq!(
+        serde_json::to_string(&fill.command).unwrap(),
+        r#"{"DirectCommand":{"SetAll":{"color":{"r":255,"g":0,"b":0}}}}"#
+    );
+    let light: serde_json::Value = serde_json::from_slice(
+        &h.expect_mqtt_on_topic(&h.topics.device(DEVICE, LIGHT_STATE), T)
+            .await,
+    )
+    .unwrap();
+    assert_eq!(light["state"], "ON");
+    assert_eq!(light["color"]["r"], 255);
+
+    // Off sets the device brightness to 0, and an effect switches mode
+    set(serde_json::json!({"state": "OFF"})).await.unwrap();
+    let off = h.expect_command(DEVICE, T).await;
+    assert_eq!(off.command, Command::SetBrightness(0));
+    set(serde_json::json!({"effect": "DMX"})).await.unwrap();
+    let dmx = h.expect_command(DEVICE, T).await;
+    assert_eq!(dmx.command, Command::SetMode(Mode::Dmx));
+}
+
+// Device config  (GetConfig → config topic → registry and all WebSockets)
+#[tokio::test]
+async fn device_config_is_reported() {
+    let mut h = TestHarness::new(|t| vec![t.device(DEVICE, suffix::COMMAND)]).await;
+    let mut ws = h.connect_ws().await;
+
+    let url = format!("http://{}/api/devices/{DEVICE}/command", h.addr);
+    let request = h.http.post(&url).json(&Command::GetConfig).send();
+    let request = tokio::spawn(request);
+
+    let envelope = h.expect_command(DEVICE, T).await;
+    assert_eq!(envelope.command, Command::GetConfig);
+    let config: DeviceConfig = serde_json::from_value(serde_json::json!({
+        "wifi": {"ssid": "home", "password": ""},
+        "static_ip": null,
+        "mqtt": {"broker": [192, 168, 1, 201], "port": 1883, "username": "", "password": ""},
+    }))
+    .unwrap();
+    h.test_mqtt
+        .publish(
+            h.topics.device(DEVICE, suffix::CONFIG),
+            QoS::AtLeastOnce,
+            false,
+            serde_json::to_vec(&config).unwrap(),
+        )
+        .await
+        .unwrap();
+    h.publish_ack(DEVICE, CommandAck::ok(envelope.id)).await;
+
+    let resp = request.await.unwrap().expect("HTTP POST failed");
+    assert_eq!(resp.status(), 200);
+
+    match TestHarness::ws_recv(&mut ws, T).await {
+        ServerMsg::DeviceConfig {
+            device_id,
+            config: received,
+        } => {
+            assert_eq!(device_id, DEVICE);
+            assert_eq!(*received, config);
+        }
+        other => panic!("expected DeviceConfig, got {other:?}"),
+    }
+    let device: serde_json::Value = h
+        .http_get(&format!("/api/devices/{DEVICE}"))
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(device["config"]["brightness"], 100);
+}
+
+// mDNS  (device's legacy unicast query for _mqtt._tcp → responder → SRV and A records)
+#[tokio::test]
+async fn broker_is_advertised_over_mdns() {
+    use backend::mdns::{Advertisement, Service, spawn_responder};
+    use protocol::mdns::{self, HTTP_SERVICE, MQTT_SERVICE, TYPE_PTR};
+
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let responder_addr = socket.local_addr().unwrap();
+    let advertisement = Advertisement {
+        instance: "backend".to_string(),
+        host: "backend.local".to_string(),
+        address: [192, 168, 1, 201].into(),
+        services: vec![
+            Service {
+                service: MQTT_SERVICE,
+                port: 1883,
+            },
+            Service {
+                service: HTTP_SERVICE,
+                port: 3000,
+            },
+        ],
+    };
+    let _responder = spawn_responder(socket, advertisement);
+
+    let device = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut query = [0u8; 64];
+    let len = mdns::query(&mut query, 7, MQTT_SERVICE, TYPE_PTR).unwrap();
+    device.send_to(&query[..len], responder_addr).await.unwrap();
+
+    let mut packet = [0u8; 1500];
+    let (n, _) = timeout(T, device.recv_from(&mut packet))
+        .await
+        .expect("timed out waiting for the mDNS response")
+        .unwrap();
+    let reader = mdns::Reader::new(&packet[..n]).unwrap();
+    assert!(reader.is_response());
+    assert_eq!(reader.id(), 7);
+
+    let instance = mdns::find_service(&packet[..n], MQTT_SERVICE)
+        .unwrap()
+        .expect("no _mqtt._tcp instance");
+    assert_eq!(instance.host.as_str(), "backend.local");
+    assert_eq!(instance.port, 1883);
+    assert_eq!(instance.address, Some([192, 168, 1, 201]));
+
+    // Nothing is advertised for other services
+    let len = mdns::query(&mut query, 8, "_ipp._tcp.local", TYPE_PTR).unwrap();
+    device.send_to(&query[..len], responder_addr).await.unwrap();
+    assert!(
+        timeout(Duration::from_millis(200), device.recv_from(&mut packet))
+            .await
+            .is_err()
+    );
+}
+
+// Broker authentication  (wrong password → connection refused; only with credentials set)
+#[tokio::test]
+async fn wrong_password_is_refused() {
+    let mut broker = broker();
+    let Some((_, password)) = broker.credentials.as_mut() else {
+        eprintln!("MQTT_USERNAME not set, skipping");
+        return;
+    };
+    password.push_str("-wrong");
+
+    let (_client, mut eventloop) = AsyncClient::new(
+        broker.options(&format!("test-intruder-{}", uuid_short())),
+        10,
+    );
+    let result = timeout(T, eventloop.poll())
+        .await
+        .expect("timed out waiting for the broker");
+    assert!(
+        matches!(result, Err(ConnectionError::ConnectionRefused(_))),
+        "{result:?}"
+    );
+}
+
+// Typed REST control  (HTTP → validated Command → device's MQTT command topic, waiting for acks)
+#[tokio::test]
+async fn rest_control_endpoints_send_commands() {
+    let mut h = TestHarness::new(|t| vec![t.device(DEVICE, suffix::COMMAND)]).await;
+    let device_url = format!("http://{}/api/devices/{DEVICE}", h.addr);
+
+    let request = h
+        .http
+        .put(format!("{device_url}/mode"))
+        .json(&serde_json::json!({"mode": "Direct"}))
+        .send();
+    let request = tokio::spawn(request);
+    let envelope = h.expect_command(DEVICE, T).await;
+    assert_eq!(envelope.command, Command::SetMode(Mode::Direct));
+    h.publish_ack(DEVICE, CommandAck::ok(envelope.id)).await;
+    assert_eq!(request.await.unwrap().unwrap().status(), 200);
+
+    // More pixels than fit in one command are sent in batches
+    let pixels: Vec<Pixel> = (0..40)
+        .map(|i| Pixel {
+            point: Point::new(i % 16, i / 16),
+            color: Rgb::new(i, 0, 0),
+        })
+        .collect();
+    let request = h
+        .http
+        .post(format!("{device_url}/pixels"))
+        .json(&serde_json::json!({ "pixels": pixels }))
+        .send();
+    let request = tokio::spawn(request);
+    let mut sent = Vec::new();
+    for _ in 0..2 {
+        let envelope = h.expect_command(DEVICE, T).await;
+        let Command::DirectCommand(DirectCommand::SetPixels { pixels }) = envelope.command else {
+            panic!("expected SetPixels, got {:?}", envelope.command);
+        };
+        sent.extend(pixels);
+        h.publish_ack(DEVICE, CommandAck::ok(envelope.id)).await;
+    }
+    assert_eq!(sent, pixels);
+    assert_eq!(request.await.unwrap().unwrap().status(), 200);
+
+    let request = h
+        .http
+        .post(format!("{device_url}/fill"))
+        .json(&serde_json::json!({
+            "color": {"r": 0, "g": 0, "b": 64},
+            "origin": {"x": 2, "y": 3},
+            "width": 4,
+            "height": 5,
+        }))
+        .send();
+    let request = tokio::spawn(request);
+    let envelope = h.expect_command(DEVICE, T).await;
+    assert_eq!(
+        envelope.command,
+        Command::DirectCommand(DirectCommand::FillRect {
+            origin: Point::new(2, 3),
+            width: 4,
+            height: 5,
+            color: Rgb::new(0, 0, 64),
+        })
+    );
+    // The device's rejection is passed on
+    h.publish_ack(
+        DEVICE,
+        CommandAck::nack(envelope.id, CommandError::WrongMode),
+    )
+    .await;
+    assert_eq!(request.await.unwrap().unwrap().status(), 422);
+}
+
+#[tokio::test]
+async fn rest_control_rejects_bad_input() {
+    let mut h = TestHarness::new(|t| vec![t.device(DEVICE, suffix::COMMAND)]).await;
+    let device_url = format!("http://{}/api/devices/{DEVICE}", h.addr);
+
+    let off_panel = serde_json::json!({"pixels": [
+        {"point": {"x": 16, "y": 0}, "color": {"r": 1, "g": 2, "b": 3}}
+    ]});
+    let resp = h
+        .http
+        .post(format!("{device_url}/pixels"))
+        .json(&off_panel)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 400);
+
+    let partial_rect = serde_json::json!({"color": {"r": 1, "g": 2, "b": 3}, "width": 2});
+    let resp = h
+        .http
+        .post(format!("{device_url}/fill"))
+        .json(&partial_rect)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 400);
+
+    let resp = h
+        .http
+        .put(format!("{device_url}/brightness"))
+        .json(&serde_json::json!({"brightness": 300}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 422);
+
+    let resp = h
+        .http
+        .post(format!("{device_url}/frame"))
+        .body(vec![0u8; 10])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 400);
+
+    // Nothing reached the device
+    assert!(
+        timeout(Duration::from_millis(300), h.expect_mqtt(T))
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn rest_frame_is_streamed() {
+    let mut h = TestHarness::new(|t| vec![t.device(DEVICE, suffix::STREAM)]).await;
+
+    let mut frame = vec![0u8; LED_BUFFER_SIZE];
+    frame[3..6].copy_from_slice(&[0, 255, 0]);
+    let resp = h
+        .http
+        .post(format!("http://{}/api/devices/{DEVICE}/frame", h.addr))
+        .body(frame.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 202);
+
+    let payload = h
+        .expect_mqtt_on_topic(&h.topics.device(DEVICE, suffix::STREAM), T)
+        .await;
+    let mut display = vec![0u8; LED_BUFFER_SIZE];
+    StreamFrame::parse(&payload)
+        .unwrap()
+        .apply(&mut display)
+        .unwrap();
+    assert_eq!(display, frame);
+}
+
+/// A PNG twice the panel's size: red on the left, blue on the right.
+fn two_tone_png() -> Vec<u8> {
+    two_tone(image::ImageFormat::Png)
+}
+
+/// [`two_tone_png`], as `format`.
+fn two_tone(format: image::ImageFormat) -> Vec<u8> {
+    let (width, height) = (2 * LED_PANEL_WIDTH as u32, 2 * LED_PANEL_HEIGHT as u32);
+    let image = image::RgbImage::from_fn(width, height, |x, _| {
+        if x < width / 2 {
+            image::Rgb([255, 0, 0])
+        } else {
+            image::Rgb([0, 0, 255])
+        }
+    });
+    let mut encoded = Vec::new();
+    image
+        .write_to(&mut std::io::Cursor::new(&mut encoded), format)
+        .unwrap();
+    encoded
+}
+
+#[tokio::test]
+async fn uploaded_image_is_stored_and_shown() {
+    let mut h = TestHarness::new(|t| vec![t.device(DEVICE, suffix::STREAM)]).await;
+
+    let resp = h
+        .http
+        .post(format!(
+            "http://{}/api/images?filter=nearest&name=halves&device={DEVICE}",
+            h.addr
+        ))
+        .body(two_tone_png())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 201);
+    let info: ImageInfo = resp.json().await.unwrap();
+    assert_eq!(info.name.as_deref(), Some("halves"));
+    assert_eq!(
+        (info.width, info.height),
+        (2 * LED_PANEL_WIDTH as u32, 2 * LED_PANEL_HEIGHT as u32)
+    );
+
+    let expected: Vec<u8> = (0..LED_PANEL_HEIGHT)
+        .flat_map(|_| 0..LED_PANEL_WIDTH)
+        .flat_map(|x| {
+            if x < LED_PANEL_WIDTH / 2 {
+                [255, 0, 0]
+            } else {
+                [0, 0, 255]
+            }
+        })
+        .collect();
+    let payload = h
+        .expect_mqtt_on_topic(&h.topics.device(DEVICE, suffix::STREAM), T)
+        .await;
+    let mut display = vec![0u8; LED_BUFFER_SIZE];
+    StreamFrame::parse(&payload)
+        .unwrap()
+        .apply(&mut display)
+        .unwrap();
+    assert_eq!(display, expected);
+
+    let images: Vec<ImageInfo> = h
+        .http
+        .get(format!("http://{}/api/images", h.addr))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(images, vec![info.clone()]);
+
+    // The preview is the converted image
+    let resp = h
+        .http
+        .get(format!("http://{}/api/images/{}", h.addr, info.id))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let preview = image::load_from_memory(&resp.bytes().await.unwrap()).unwrap();
+    assert_eq!(preview.to_rgb8().into_raw(), expected);
+
+    let resp = h
+        .http
+        .post(format!(
+            "http://{}/api/images/{}/show?device={DEVICE}",
+            h.addr, info.id
+        ))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 202);
+    h.expect_mqtt_on_topic(&h.topics.device(DEVICE, suffix::STREAM), T)
+        .await;
+
+    let url = format!("http://{}/api/images/{}", h.addr, info.id);
+    assert_eq!(h.http.delete(&url).send().await.unwrap().status(), 204);
+    assert_eq!(h.http.get(&url).send().await.unwrap().status(), 404);
+}
+
+/// Needs no broker.
+#[tokio::test]
+async fn webp_image_is_stored() {
+    let addr = serve_without_broker(Database::open_in_memory().unwrap()).await;
+    let http = reqwest::Client::new();
+    let resp = http
+        .post(format!("http://{addr}/api/images?filter=nearest"))
+        // Lossless, so the colours come through exactly
+        .body(two_tone(image::ImageFormat::WebP))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 201);
+    let info: ImageInfo = resp.json().await.unwrap();
+    assert_eq!(
+        (info.width, info.height),
+        (2 * LED_PANEL_WIDTH as u32, 2 * LED_PANEL_HEIGHT as u32)
+    );
+
+    let resp = http
+        .get(format!("http://{addr}/api/images/{}", info.id))
+        .send()
+        .await
+        .unwrap();
+    let preview = image::load_from_memory(&resp.bytes().await.unwrap())
+        .unwrap()
+        .to_rgb8();
+    assert_eq!(preview.get_pixel(0, 0).0, [255, 0, 0]);
+    assert_eq!(
+        preview.get_pixel(LED_PANEL_WIDTH as u32 - 1, 0).0,
+        [0, 0, 255]
+    );
+}
+
+#[tokio::test]
+async fn bad_images_are_rejected() {
+    let h = TestHarness::new(|_| vec![]).await;
+    let post = |query: &str, body: Vec<u8>| {
+        h.http
+            .post(format!("http://{}/api/images{query}", h.addr))
+            .body(body)
+            .send()
+    };
+
+    let resp = post("", b"not an image".to_vec()).await.unwrap();
+    assert_eq!(resp.status(), 415);
+    let mut truncated = two_tone_png();
+    truncated.truncate(64);
+    let resp = post("", truncated).await.unwrap();
+    assert_eq!(resp.status(), 400);
+    let resp = post("?bits=9", two_tone_png()).await.unwrap();
+    assert_eq!(resp.status(), 400);
+    let resp = post("?device=a&group=b", two_tone_png()).await.unwrap();
+    assert_eq!(resp.status(), 400);
+
+    // Nothing was stored
+    let images: Vec<ImageInfo> = h
+        .http
+        .get(format!("http://{}/api/images", h.addr))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert!(images.is_empty());
+}
+
+const RED: [u8; 3] = [255, 0, 0];
+const BLUE: [u8; 3] = [0, 0, 255];
+
+/// A panel-sized, looping GIF: red for 200 ms, then blue drawn over the right half for
+/// 500 ms.
+fn two_frame_gif() -> Vec<u8> {
+    let (width, height) = (LED_PANEL_WIDTH as u16, LED_PANEL_HEIGHT as u16);
+    let mut gif = b"GIF89a".to_vec();
+    gif.extend(width.to_le_bytes());
+    gif.extend(height.to_le_bytes());
+    // A global colour table of two entries
+    gif.extend([0x80, 0, 0]);
+    gif.extend(RED);
+    gif.extend(BLUE);
+    // Loop forever
+    gif.extend(b"\x21\xff\x0bNETSCAPE2.0\x03\x01\x00\x00\x00");
+
+    let mut image = |left: u16, width: u16, index: u8, delay_cs: u16| {
+        // Graphic control: keep the frame when done
+        gif.extend([0x21, 0xf9, 4, 0x04]);
+        gif.extend(delay_cs.to_le_bytes());
+        gif.extend([0, 0]);
+
+        gif.push(0x2c);
+        for n in [left, 0, width, height] {
+            gif.extend(n.to_le_bytes());
+        }
+        gif.extend([0, 2]);
+        let indices = vec![index; width as usize * height as usize];
+        let compressed = weezl::encode::Encoder::new(weezl::BitOrder::Lsb, 2)
+            .encode(&indices)
+            .unwrap();
+        for block in compressed.chunks(255) {
+            gif.push(block.len() as u8);
+            gif.extend(block);
+        }
+        gif.push(0);
+    };
+    image(0, width, 0, 20);
+    image(width / 2, width / 2, 1, 50);
+    gif.push(0x3b);
+    gif
+}
+
+/// A panel-sized APNG played twice: red for 250 ms, then blue for 750 ms.
+fn two_frame_apng() -> Vec<u8> {
+    let mut apng = Vec::new();
+    let mut encoder = png::Encoder::new(&mut apng, LED_PANEL_WIDTH as u32, LED_PANEL_HEIGHT as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_animated(2, 2).unwrap();
+    let mut writer = encoder.write_header().unwrap();
+    for (color, delay) in [(RED, 1), (BLUE, 3)] {
+        writer.set_frame_delay(delay, 4).unwrap();
+        writer
+            .write_image_data(&color.repeat(LED_PANEL_NUM_LEDS))
+            .unwrap();
+    }
+    writer.finish().unwrap();
+    apng
+}
+
+fn halves(left: [u8; 3], right: [u8; 3]) -> Vec<u8> {
+    (0..LED_PANEL_HEIGHT)
+        .flat_map(|_| 0..LED_PANEL_WIDTH)
+        .flat_map(|x| if x < LED_PANEL_WIDTH / 2 { left } else { right })
+        .collect()
+}
+
+#[tokio::test]
+async fn gif_is_played_with_its_timing() {
+    let mut h = TestHarness::new(|t| vec![t.device(DEVICE, suffix::STREAM)]).await;
+
+    let resp = h
+        .http
+        .post(format!(
+            "http://{}/api/animations?filter=nearest&device={DEVICE}",
+            h.addr
+        ))
+        .body(two_frame_gif())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 201);
+    let info: AnimationInfo = resp.json().await.unwrap();
+    assert_eq!(info.delays_ms, vec![200, 500]);
+    assert_eq!(info.plays, None);
+
+    // The second frame is drawn over the first, and then it starts again
+    assert_eq!(h.expect_frame().await, halves(RED, RED));
+    let shown = std::time::Instant::now();
+    assert_eq!(h.expect_frame().await, halves(RED, BLUE));
+    assert!(shown.elapsed() >= Duration::from_millis(150));
+    assert_eq!(h.expect_frame().await, halves(RED, RED));
+
+    let stop = format!("http://{}/api/animations/stop?device={DEVICE}", h.addr);
+    assert_eq!(h.http.post(&stop).send().await.unwrap().status(), 204);
+    assert_eq!(h.http.post(&stop).send().await.unwrap().status(), 404);
+}
+
+#[tokio::test]
+async fn apng_is_stored_and_played() {
+    let mut h = TestHarness::new(|t| vec![t.device(DEVICE, suffix::STREAM)]).await;
+
+    let resp = h
+        .http
+        .post(format!("http://{}/api/animations?name=flash", h.addr))
+        .body(two_frame_apng())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 201);
+    let info: AnimationInfo = resp.json().await.unwrap();
+    assert_eq!(info.name.as_deref(), Some("flash"));
+    assert_eq!(info.delays_ms, vec![250, 750]);
+    assert_eq!(info.plays, Some(2));
+
+    let animations: Vec<AnimationInfo> = h
+        .http
+        .get(format!("http://{}/api/animations", h.addr))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(animations, vec![info.clone()]);
+
+    let resp = h
+        .http
+        .post(format!(
+            "http://{}/api/animations/{}/play?device={DEVICE}",
+            h.addr, info.id
+        ))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 202);
+    assert_eq!(h.expect_frame().await, halves(RED, RED));
+    assert_eq!(h.expect_frame().await, halves(BLUE, BLUE));
+
+    let url = format!("http://{}/api/animations/{}", h.addr, info.id);
+    assert_eq!(h.http.delete(&url).send().await.unwrap().status(), 204);
+    assert_eq!(h.http.get(&url).send().await.unwrap().status(), 404);
+}
+
+#[tokio::test]
+async fn bad_animations_are_rejected() {
+    let h = TestHarness::new(|_| vec![]).await;
+    let post = |body: Vec<u8>| {
+        h.http
+            .post(format!("http://{}/api/animations", h.addr))
+            .body(body)
+            .send()
+    };
+
+    assert_eq!(
+        post(b"not an animation".to_vec()).await.unwrap().status(),
+        415
+    );
+    // A still PNG is an image
+    assert_eq!(post(two_tone_png()).await.unwrap().status(), 400);
+    let mut truncated = two_frame_gif();
+    truncated.truncate(30);
+    assert_eq!(post(truncated).await.unwrap().status(), 400);
+}
+
+/// Needs no broker.
+#[test]
+fn gif_frames_must_fit_the_canvas() {
+    let frames = |gif: &[u8]| {
+        let mut frames = 0;
+        backend::gif::decode(gif, |_, _| frames += 1).map(|_| frames)
+    };
+    assert_eq!(frames(&two_frame_gif()), Ok(2));
+
+    // A 2x2 canvas with one frame of 2x2 black, at `left`, `width` and `height`
+    let gif = |left: u16, width: u16, height: u16| {
+        let mut gif = b"GIF89a\x02\x00\x02\x00\x80\x00\x00".to_vec();
+        gif.extend(RED);
+        gif.extend(BLUE);
+        gif.push(0x2c);
+        for n in [left, 0, width, height] {
+            gif.extend(n.to_le_bytes());
+        }
+        gif.extend([0, 2]);
+        let compressed = weezl::encode::Encoder::new(weezl::BitOrder::Lsb, 2)
+            .encode(&[0; 4])
+            .unwrap();
+        gif.push(compressed.len() as u8);
+        gif.extend(compressed);
+        gif.extend([0, 0x3b]);
+        gif
+    };
+    assert_eq!(frames(&gif(0, 2, 2)), Ok(1));
+    // Off the edge of the canvas
+    assert_eq!(
+        frames(&gif(1, 2, 2)),
+        Err(backend::gif::GifError::BadImageData)
+    );
+    // Would need 4 GiB to decompress
+    assert_eq!(
+        frames(&gif(0, u16::MAX, u16::MAX)),
+        Err(backend::gif::GifError::BadImageData)
+    );
+}
+
+/// The guest's sprite sheet and Aseprite export, as `POST /api/animations/aseprite` takes them.
+fn anim_0002_upload(sheet: serde_json::Value) -> serde_json::Value {
+    use base64::Engine;
+    let png = include_bytes!("../../guest/assets/anim-0002.png");
+    serde_json::json!({
+        "image": base64::engine::general_purpose::STANDARD.encode(png),
+        "sheet": sheet,
+    })
+}
+
+/// Frame `index` of the guest's sprite sheet: 16x16 frames, one above the other.
+fn anim_0002_frame(index: u32) -> Vec<u8> {
+    let sheet =
+        image::load_from_memory(include_bytes!("../../guest/assets/anim-0002.png")).unwrap();
+    sheet.crop_imm(0, index * 16, 16, 16).to_rgb8().into_raw()
+}
+
+#[tokio::test]
+async fn aseprite_tag_is_played() {
+    let mut h = TestHarness::new(|t| vec![t.device(DEVICE, suffix::STREAM)]).await;
+
+    let sheet: serde_json::Value =
+        serde_json::from_str(include_str!("../../guest/assets/anim-0002.json")).unwrap();
+    let resp = h
+        .http
+        .post(format!(
+            "http://{}/api/animations/aseprite?tag=Loop&filter=nearest&device={DEVICE}",
+            h.addr
+        ))
+        .json(&anim_0002_upload(sheet))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 201);
+    let info: AnimationInfo = resp.json().await.unwrap();
+    assert_eq!(info.delays_ms, vec![250, 250, 500]);
+    assert_eq!(info.plays, None);
+
+    for index in [3, 4, 5, 3] {
+        assert_eq!(h.expect_frame().await, anim_0002_frame(index));
+    }
+    let stop = format!("http://{}/api/animations/stop?device={DEVICE}", h.addr);
+    assert_eq!(h.http.post(&stop).send().await.unwrap().status(), 204);
+}
+
+#[tokio::test]
+async fn aseprite_directions_and_bad_tags() {
+    let h = TestHarness::new(|_| vec![]).await;
+
+    // The array layout, with a tag played there and back twice
+    let frame = |index: u32, duration: u32| {
+        serde_json::json!({
+            "frame": {"x": 0, "y": index * 16, "w": 16, "h": 16},
+            "duration": duration,
+        })
+    };
+    let sheet = serde_json::json!({
+        "frames": [frame(0, 100), frame(1, 200), frame(2, 300)],
+        "meta": {"frameTags": [
+            {"name": "bounce", "from": 0, "to": 2, "direction": "pingpong", "repeat": "2"},
+            {"name": "back", "from": 0, "to": 2, "direction": "reverse"},
+        ]},
+    });
+    let post = |tag: &str| {
+        h.http
+            .post(format!(
+                "http://{}/api/animations/aseprite?tag={tag}",
+                h.addr
+            ))
+            .json(&anim_0002_upload(sheet.clone()))
+            .send()
+    };
+
+    let resp = post("bounce").await.unwrap();
+    assert_eq!(resp.status(), 201);
+    let info: AnimationInfo = resp.json().await.unwrap();
+    assert_eq!(info.delays_ms, vec![100, 200, 300, 200]);
+    assert_eq!(info.plays, Some(2));
+
+    let info: AnimationInfo = post("back").await.unwrap().json().await.unwrap();
+    assert_eq!(info.delays_ms, vec![300, 200, 100]);
+
+    let resp = post("missing").await.unwrap();
+    assert_eq!(resp.status(), 400);
+    assert!(resp.text().await.unwrap().contains("bounce"));
+}
+
+#[tokio::test]
+async fn library_is_searched_and_tagged() {
+    let h = TestHarness::new(|_| vec![]).await;
+    let library = format!("http://{}/api/library", h.addr);
+    let search = |query: &str| {
+        let url = format!("{library}{query}");
+        let http = h.http.clone();
+        async move {
+            let found: Vec<Content> = http.get(url).send().await.unwrap().json().await.unwrap();
+            found
+                .into_iter()
+                .map(|c| c.name.unwrap_or_default())
+                .collect::<Vec<_>>()
+        }
+    };
+
+    let image: ImageInfo = h
+        .http
+        .post(format!("http://{}/api/images?name=halves", h.addr))
+        .body(two_tone_png())
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let animation: AnimationInfo = h
+        .http
+        .post(format!("http://{}/api/animations?name=flash", h.addr))
+        .body(two_frame_gif())
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    let resp = h
+        .http
+        .patch(format!("{library}/{}", image.id))
+        .json(&serde_json::json!({"tags": ["red", "favourite"]}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let content: Content = resp.json().await.unwrap();
+    assert_eq!(content.kind, Kind::Image);
+    assert_eq!(content.name.as_deref(), Some("halves"));
+    assert_eq!(
+        content.tags.into_iter().collect::<Vec<_>>(),
+        vec!["favourite", "red"]
+    );
+
+    assert_eq!(search("").await, vec!["halves", "flash"]);
+    assert_eq!(search("?kind=animation").await, vec!["flash"]);
+    assert_eq!(search("?tag=red").await, vec!["halves"]);
+    // By name, or by tag
+    assert_eq!(search("?q=LAS").await, vec!["flash"]);
+    assert_eq!(search("?q=fav").await, vec!["halves"]);
+    assert!(search("?q=%25").await.is_empty());
+
+    let resp = h
+        .http
+        .patch(format!("{library}/{}", animation.id))
+        .json(&serde_json::json!({"name": "blink"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let info: AnimationInfo = h
+        .http
+        .get(format!("http://{}/api/animations/{}", h.addr, animation.id))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(info.name.as_deref(), Some("blink"));
+    assert_eq!(info.delays_ms, animation.delays_ms);
+
+    let url = format!("{library}/{}", image.id);
+    assert_eq!(h.http.delete(&url).send().await.unwrap().status(), 204);
+    assert_eq!(h.http.get(&url).send().await.unwrap().status(), 404);
+    let resp = h
+        .http
+        .get(format!("http://{}/api/images/{}", h.addr, image.id))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 404);
+}
+
+/// Needs no broker.
+#[tokio::test]
+async fn database_outlives_the_backend() {
+    let path = std::env::temp_dir().join(format!("esp32-wasmi-led-{}.db", uuid_short()));
+    {
+        let db = Database::open(&path).unwrap();
+        db.insert(
+            "one",
+            Kind::Image,
+            Some("first"),
+            &serde_json::json!({"width": 1, "height": 2}),
+            &[1, 2, 3],
+        )
+        .unwrap();
+        let update = serde_json::from_value(serde_json::json!({"tags": ["kept"]})).unwrap();
+        db.update("one", &update).unwrap().unwrap();
+        db.set_device_groups(DEVICE, &["kitchen".to_string()].into())
+            .unwrap();
+    }
+
+    let db = Database::open(&path).unwrap();
+    assert_eq!(db.version().unwrap(), backend::db::MIGRATIONS.len());
+    let (content, data) = db.get_with_data(Kind::Image, "one").unwrap().unwrap();
+    assert_eq!(content.name.as_deref(), Some("first"));
+    assert!(content.tags.contains("kept"));
+    assert_eq!(content.meta["height"], 2);
+    assert_eq!(data, vec![1, 2, 3]);
+    assert!(db.get_with_data(Kind::Animation, "one").unwrap().is_none());
+
+    // Devices keep their groups, and are known before they report in
+    let (client, _eventloop) =
+        AsyncClient::new(rumqttc::MqttOptions::new("unused", "localhost", 1), 1);
+    let state = create_state(client, Topics::default(), db);
+    let devices = state.devices.read().unwrap();
+    let device = devices.get(DEVICE).unwrap();
+    assert!(!device.online);
+    assert!(device.groups.contains("kitchen"));
+    drop(devices);
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn playlists_are_played_with_transitions() {
+    let mut h = TestHarness::new(|t| vec![t.device(DEVICE, suffix::STREAM)]).await;
+    let playlists = format!("http://{}/api/playlists", h.addr);
+    let color = |[r, g, b]: [u8; 3]| serde_json::json!({"r": r, "g": g, "b": b});
+
+    for (bad, reason) in [
+        (serde_json::json!([]), "no items"),
+        (
+            serde_json::json!([{"type": "image", "id": "gone", "duration_ms": 1000}]),
+            "no image gone",
+        ),
+        (
+            serde_json::json!([{"type": "guest", "duration_ms": 1000,
+                                "transition": {"type": "wipe", "ms": 2000}}]),
+            "longer",
+        ),
+    ] {
+        let resp = h
+            .http
+            .post(&playlists)
+            .json(&serde_json::json!({"items": bad}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 400);
+        assert!(resp.text().await.unwrap().contains(reason));
+    }
+
+    let resp = h
+        .http
+        .post(&playlists)
+        .json(&serde_json::json!({"name": "red and blue", "items": [
+            {"type": "color", "color": color(RED), "duration_ms": 300},
+            {"type": "color", "color": color(BLUE), "duration_ms": 1000,
+             "transition": {"type": "fade", "ms": 400}},
+        ]}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 201);
+    let playlist: Playlist = resp.json().await.unwrap();
+    assert_eq!(playlist.items[1].transition, Transition::Fade { ms: 400 });
+
+    let url = format!("{playlists}/{}", playlist.id);
+    let resp = h
+        .http
+        .post(format!("{url}/play?device={DEVICE}"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 202);
+
+    let red = halves(RED, RED);
+    let blue = halves(BLUE, BLUE);
+    assert_eq!(h.expect_frame().await, red);
+    // Fading through purple, then blue, then straight back to red
+    let mut fading = false;
+    let mut frame = h.expect_frame().await;
+    while frame == red {
+        frame = h.expect_frame().await;
+    }
+    while frame != blue {
+        let [r, g, b] = [frame[0], frame[1], frame[2]];
+        assert!(r < 255 && g == 0 && b > 0, "{r} {g} {b}");
+        assert!(frame.as_chunks::<3>().0.iter().all(|p| *p == [r, g, b]));
+        fading = true;
+        frame = h.expect_frame().await;
+    }
+    assert!(fading);
+    while frame == blue {
+        frame = h.expect_frame().await;
+    }
+    assert_eq!(frame, red);
+
+    // Changed while it plays
+    let green = [0, 255, 0];
+    let resp = h
+        .http
+        .put(&url)
+        .json(&serde_json::json!({"items": [
+            {"type": "color", "color": color(green), "duration_ms": 1000},
+        ]}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    while frame != halves(green, green) {
+        frame = h.expect_frame().await;
+    }
+    let listed: Vec<Playlist> = h
+        .http
+        .get(&playlists)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0].id, playlist.id);
+    assert_eq!(listed[0].name, None);
+
+    assert_eq!(h.http.delete(&url).send().await.unwrap().status(), 204);
+    assert_eq!(h.http.delete(&url).send().await.unwrap().status(), 404);
+    let stop = format!("{playlists}/stop?device={DEVICE}");
+    assert_eq!(h.http.post(&stop).send().await.unwrap().status(), 404);
+}
+
+#[test]
+fn cron_expressions_and_transitions() {
+    let at = |date: &str| NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M").unwrap();
+    let cron = |expression: &str| expression.parse::<Cron>().unwrap();
+
+    for bad in [
+        "* * * *",
+        "60 * * * *",
+        "* 7-6 * * *",
+        "*/0 * * * *",
+        "* * * foo *",
+    ] {
+        assert!(bad.parse::<Cron>().is_err(), "{bad}");
+    }
+
+    // 2026-10-19 is a Monday
+    let now = at("2026-10-19 08:30");
+    assert_eq!(cron("0 7 * * *").latest(now), Some(at("2026-10-19 07:00")));
+    assert_eq!(cron("0 9 * * *").latest(now), Some(at("2026-10-18 09:00")));
+    assert_eq!(cron("30 8 * * *").latest(now), Some(at("2026-10-19 08:30")));
+    assert_eq!(
+        cron("*/20 8-9 * * *").latest(now),
+        Some(at("2026-10-19 08:20"))
+    );
+    assert_eq!(
+        cron("5/20 * * * *").latest(now),
+        Some(at("2026-10-19 08:25"))
+    );
+    assert_eq!(
+        cron("0 12 * * sat,7").latest(now),
+        Some(at("2026-10-18 12:00"))
+    );
+    assert_eq!(
+        cron("0 0 1 jan *").latest(now),
+        Some(at("2026-01-01 00:00"))
+    );
+    assert_eq!(cron("0 0 29 2 *").latest(now), Some(at("2024-02-29 00:00")));
+    // Either day will do when both are given
+    assert_eq!(
+        cron("0 0 1 * mon").latest(now),
+        Some(at("2026-10-19 00:00"))
+    );
+    assert_eq!(cron("0 0 31 * *").latest(now), Some(at("2026-08-31 00:00")));
+    assert_eq!(cron("0 0 31 2 *").latest(now), None);
+    assert!(cron("* * * * 1-5").matches(now));
+    assert!(!cron("* * * * sat-sun").matches(now));
+
+    let red = [255, 0, 0].repeat(LED_PANEL_NUM_LEDS);
+    let blue = [0, 0, 255].repeat(LED_PANEL_NUM_LEDS);
+    let fade = Transition::Fade { ms: 1000 };
+    assert_eq!(playlists::blend(fade, &red, &blue, 0.0), red);
+    assert_eq!(
+        &playlists::blend(fade, &red, &blue, 0.5)[..3],
+        [128, 0, 128]
+    );
+    let wiped = playlists::blend(Transition::Wipe { ms: 1000 }, &red, &blue, 0.25);
+    let column = |x: usize| &wiped[x * 3..x * 3 + 3];
+    assert_eq!(column(LED_PANEL_WIDTH / 4 - 1), [0, 0, 255]);
+    assert_eq!(column(LED_PANEL_WIDTH / 4), [255, 0, 0]);
+}
+
+#[derive(Debug)]
+struct MockClock(std::sync::Mutex<NaiveDateTime>);
+
+impl Clock for MockClock {
+    fn now(&self) -> NaiveDateTime {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// Needs no broker.
+#[tokio::test(start_paused = true)]
+async fn scheduler_follows_the_clock() {
+    let at = |date: &str| NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M").unwrap();
+    let clock = Arc::new(MockClock(at("2026-10-19 12:00").into()));
+    let (client, _eventloop) =
+        AsyncClient::new(rumqttc::MqttOptions::new("unused", "localhost", 1), 100);
+    let mut state = create_state(
+        client,
+        Topics::default(),
+        Database::open_in_memory().unwrap(),
+    );
+    state.clock = clock.clone();
+
+    let show = |id: &str| serde_json::json!({"type": "playlist", "id": id});
+    for id in ["clock", "animations"] {
+        let playlist: Playlist = serde_json::from_value(serde_json::json!({
+            "id": id,
+            "items": [{"type": "clock", "duration_ms": 60000}],
+        }))
+        .unwrap();
+        state
+            .db
+            .put_record(Records::Playlists, id, &playlist)
+            .unwrap();
+    }
+    let schedule: Schedule = serde_json::from_value(serde_json::json!({
+        "id": "kitchen",
+        "target": {"Device": DEVICE},
+        "entries": [
+            {"cron": "0 7 * * *", "show": show("clock")},
+            {"cron": "0 9 * * *", "show": show("animations")},
+            {"cron": "30 22 * * *", "show": {"type": "off"}},
+        ],
+    }))
+    .unwrap();
+    let target = schedule.target.clone();
+
+    // Pure: what shows when, and only changes are due
+    let showing = |time: &str| schedule.active(at(time)).unwrap().0.show.clone();
+    let animations = Show::Playlist {
+        id: "animations".into(),
+    };
+    assert_eq!(showing("2026-10-19 06:59"), Show::Off);
+    assert_eq!(showing("2026-10-19 12:00"), animations);
+    assert_eq!(showing("2026-10-19 23:00"), Show::Off);
+    let mut scheduler = Scheduler::default();
+    let schedules = [schedule.clone()];
+    assert_eq!(
+        scheduler.due(&schedules, at("2026-10-19 12:00")),
+        vec![(target.clone(), animations)]
+    );
+    assert!(scheduler.due(&schedules, at("2026-10-19 22:29")).is_empty());
+    assert_eq!(scheduler.due(&schedules, at("2026-10-19 22:30")).len(), 1);
+    // Forgotten while it's gone, so due again when it's back
+    assert!(scheduler.due(&[], at("2026-10-19 22:31")).is_empty());
+    assert_eq!(scheduler.due(&schedules, at("2026-10-19 22:32")).len(), 1);
+
+    // Running, starting at noon
+    state
+        .db
+        .put_record(Records::Schedules, &schedule.id, &schedule)
+        .unwrap();
+    let scheduler = spawn_scheduler(state.clone());
+    let playing_at = |time: &str| {
+        *clock.0.lock().unwrap() = at(time);
+        let state = state.clone();
+        let target = target.clone();
+        async move {
+            tokio::time::sleep(SCHEDULER_INTERVAL * 2).await;
+            state.players.playing(&target)
+        }
+    };
+    assert_eq!(
+        playing_at("2026-10-19 12:00").await.as_deref(),
+        Some("animations")
+    );
+    assert_eq!(playing_at("2026-10-19 22:30").await, None);
+    assert_eq!(playing_at("2026-10-20 06:59").await, None);
+    assert_eq!(
+        playing_at("2026-10-20 07:00").await.as_deref(),
+        Some("clock")
+    );
+    // Stopped by hand, it stays stopped until the next change
+    state.players.stop(&target);
+    assert_eq!(playing_at("2026-10-20 08:00").await, None);
+    assert_eq!(
+        playing_at("2026-10-20 09:00").await.as_deref(),
+        Some("animations")
+    );
+    scheduler.abort();
+}
+
+/// A guest module as the host expects one, with a manifest and `data_len` bytes of data, so
+/// that it can be made to span several deploy chunks.
+fn guest_wat(manifest: &str, data_len: usize) -> String {
+    format!(
+        r#"(module
+            (memory (export "memory") 1)
+            (data (i32.const 0) "{}")
+            (func (export "init"))
+            (func (export "update") (param i64 i64 i32) (result i32) i32.const 0)
+            (@custom "esp32-wasmi-led.manifest" "{}"))"#,
+        "x".repeat(data_len),
+        manifest.replace('"', "\\\""),
+    )
+}
+
+#[test]
+fn guest_modules_are_validated() {
+    let check = |wat: &str| guests::validate(&wat::parse_str(wat).unwrap());
+
+    let manifest = r#"{"name": "sparkle", "version": "0.3", "author": "someone"}"#;
+    let validated = check(&guest_wat(manifest, 0)).unwrap();
+    assert_eq!(validated.memory_pages, 1);
+    assert_eq!(validated.manifest.name.as_deref(), Some("sparkle"));
+    assert_eq!(validated.manifest.version.as_deref(), Some("0.3"));
+    assert_eq!(validated.manifest.description, None);
+
+    // The manifest is optional, but must be JSON if it's there
+    let bare = r#"(module
+        (memory (export "memory") 0 1)
+        (func (export "init"))
+        (func (export "update") (param i64 i64 i32) (result i32) i32.const 0))"#;
+    assert_eq!(check(bare).unwrap().manifest, guests::Manifest::default());
+    assert!(matches!(
+        check(&guest_wat("not json", 0)),
+        Err(GuestError::BadManifest(_))
+    ));
+
+    assert!(matches!(
+        guests::validate(b"not wasm"),
+        Err(GuestError::Invalid(_))
+    ));
+    assert!(matches!(
+        guests::validate(&vec![0; MAX_GUEST_LEN + 1]),
+        Err(GuestError::TooLarge(_))
+    ));
+
+    let missing = r#"(module (memory (export "memory") 1) (func (export "init")))"#;
+    assert!(matches!(
+        check(missing),
+        Err(GuestError::MissingExport("update"))
+    ));
+    let wrong_type = r#"(module
+        (memory (export "memory") 1)
+        (func (export "init"))
+        (func (export "update") (param i32) (result i32) i32.const 0))"#;
+    assert!(matches!(
+        check(wrong_type),
+        Err(GuestError::WrongExport("update"))
+    ));
+    // No room for the host's page
+    let fixed_memory = r#"(module
+        (memory (export "memory") 1 1)
+        (func (export "init"))
+        (func (export "update") (param i64 i64 i32) (result i32) i32.const 0))"#;
+    assert!(matches!(
+        check(fixed_memory),
+        Err(GuestError::WrongExport("memory"))
+    ));
+    let large_memory = r#"(module
+        (memory (export "memory") 4)
+        (func (export "init"))
+        (func (export "update") (param i64 i64 i32) (result i32) i32.const 0))"#;
+    assert!(matches!(
+        check(large_memory),
+        Err(GuestError::TooMuchMemory(5))
+    ));
+    let imports = r#"(module
+        (import "env" "random" (func (result i32)))
+        (memory (export "memory") 1)
+        (func (export "init"))
+        (func (export "update") (param i64 i64 i32) (result i32) i32.const 0))"#;
+    match check(imports) {
+        Err(GuestError::UnknownImport { module, name }) => {
+            assert_eq!((module.as_str(), name.as_str()), ("env", "random"))
+        }
+        other => panic!("expected an unknown import, got {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn guests_are_versioned_and_deployed() {
+    let mut h = TestHarness::new(|t| vec![t.device(DEVICE, suffix::GUEST)]).await;
+    let (http, addr) = (h.http.clone(), h.addr);
+    let post = |query: &str, body: Vec<u8>| {
+        http.post(format!("http://{addr}/api/guests{query}"))
+            .body(body)
+            .send()
+    };
+
+    // Named by its manifest, then by the query, which takes precedence
+    let first = wat::parse_str(guest_wat(r#"{"name": "sparkle"}"#, 0)).unwrap();
+    let resp = post("", first.clone()).await.unwrap();
+    assert_eq!(resp.status(), 201);
+    let first_info: GuestInfo = resp.json().await.unwrap();
+    assert_eq!(
+        (first_info.name.as_str(), first_info.version),
+        ("sparkle", 1)
+    );
+    assert_eq!(first_info.len, first.len());
+
+    // Larger than a chunk, to be deployed in several
+    let second = wat::parse_str(guest_wat("{}", 3000)).unwrap();
+    let resp = post("?name=sparkle", second.clone()).await.unwrap();
+    assert_eq!(resp.status(), 201);
+    let second_info: GuestInfo = resp.json().await.unwrap();
+    assert_eq!(second_info.version, 2);
+    let resp = post("?name=other", first.clone()).await.unwrap();
+    assert_eq!(resp.json::<GuestInfo>().await.unwrap().version, 1);
+
+    // Refused modules aren't kept
+    assert_eq!(post("", b"not wasm".to_vec()).await.unwrap().status(), 422);
+    assert_eq!(post("", second.clone()).await.unwrap().status(), 400);
+    let too_long = "x".repeat(MAX_GUEST_NAME_LEN + 1);
+    let resp = post(&format!("?name={too_long}"), first.clone()).await;
+    assert_eq!(resp.unwrap().status(), 400);
+
+    let guests: Vec<GuestInfo> = h
+        .http_get("/api/guests?name=sparkle")
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(guests, vec![first_info.clone(), second_info.clone()]);
+    let guests: Vec<GuestInfo> = h.http_get("/api/guests").await.json().await.unwrap();
+    assert_eq!(guests.len(), 3);
+    let wasm = h
+        .http_get(&format!("/api/guests/{}/wasm", second_info.id))
+        .await
+        .bytes()
+        .await
+        .unwrap();
+    assert_eq!(wasm.to_vec(), second);
+
+    let resp = h
+        .http
+        .post(format!(
+            "http://{}/api/guests/{}/deploy?device={DEVICE}",
+            h.addr, second_info.id
+        ))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 202);
+    let deployment: guests::Deployment = resp.json().await.unwrap();
+    assert_eq!(deployment.device_ids, vec![DEVICE.to_string()]);
+
+    // The device puts the module back together from the chunks
+    let topic = h.topics.device(DEVICE, suffix::GUEST);
+    let mut received = Vec::new();
+    loop {
+        let payload = h.expect_mqtt_on_topic(&topic, T).await;
+        let chunk = GuestChunk::parse(&payload).unwrap();
+        assert_eq!(chunk.transfer, deployment.transfer);
+        assert_eq!(chunk.offset as usize, received.len());
+        assert_eq!(chunk.total as usize, second.len());
+        received.extend_from_slice(chunk.data);
+        if chunk.is_last() {
+            break;
+        }
+    }
+    assert_eq!(received, second);
+    assert!(second.len() > MAX_GUEST_CHUNK_DATA_LEN);
+
+    let url = format!("http://{}/api/guests/{}", h.addr, first_info.id);
+    assert_eq!(h.http.delete(&url).send().await.unwrap().status(), 204);
+    assert_eq!(h.http.get(&url).send().await.unwrap().status(), 404);
+    // Versions aren't reused
+    let resp = post("?name=sparkle", first).await.unwrap();
+    assert_eq!(resp.json::<GuestInfo>().await.unwrap().version, 3);
+}
+
+/// A guest that shows its `counter`, the low byte of its `ticks`, and how often `update` has
+/// been called since it was instantiated, in its first pixel.
+const COUNTING_GUEST: &str = r#"(module
+    (memory (export "memory") 1)
+    (global $calls (mut i32) (i32.const 0))
+    (func (export "init"))
+    (func (export "update") (param $ticks i64) (param $counter i64) (param $buffer i32)
+        (result i32)
+        (global.set $calls (i32.add (global.get $calls) (i32.const 1)))
+        (i32.store8 (local.get $buffer) (i32.wrap_i64 (local.get $counter)))
+        (i32.store8 offset=1 (local.get $buffer) (i32.wrap_i64 (local.get $ticks)))
+        (i32.store8 offset=2 (local.get $buffer) (global.get $calls))
+        (local.get $buffer)))"#;
+
+/// `(ms, counter, first pixel)` of a preview frame.
+fn preview_frame(msg: ServerMsg) -> (u64, u64, [u8; 3]) {
+    match msg {
+        ServerMsg::Frame {
+            ms,
+            counter,
+            pixels,
+            ..
+        } => {
+            assert_eq!(pixels.len(), LED_BUFFER_SIZE);
+            (ms, counter, pixels[..3].try_into().unwrap())
+        }
+        other => panic!("expected a frame, got {other:?}"),
+    }
+}
+
+#[test]
+fn guest_is_simulated_frame_by_frame() {
+    let wasm: Arc<[u8]> = wat::parse_str(COUNTING_GUEST).unwrap().into();
+    let mut simulation = Simulation::new("counting".to_string(), wasm, 10).unwrap();
+    let mut step = || preview_frame(simulation.step().unwrap());
+    // 256 ticks a second
+    assert_eq!(step(), (0, 0, [0, 0, 1]));
+    assert_eq!(step(), (100, 1, [1, 25, 2]));
+    simulation.speed = 2.0;
+    assert_eq!(
+        preview_frame(simulation.step().unwrap()),
+        (200, 2, [2, 51, 3])
+    );
+
+    // Forward, the frames in between are run but not shown
+    let frame = simulation.seek(1000).unwrap();
+    assert_eq!(preview_frame(frame), (1000, 9, [9, 0, 10]));
+    // Back, the guest is run again from the start
+    let frame = simulation.seek(250).unwrap();
+    assert_eq!(preview_frame(frame), (250, 2, [2, 64, 3]));
+
+    // Guests that never return, or draw outside their memory, fail
+    let looping = r#"(module
+        (memory (export "memory") 1)
+        (func (export "init"))
+        (func (export "update") (param i64 i64 i32) (result i32) (loop (br 0)) i32.const 0))"#;
+    let wasm: Arc<[u8]> = wat::parse_str(looping).unwrap().into();
+    let mut simulation = Simulation::new("looping".to_string(), wasm, 10).unwrap();
+    assert!(simulation.step().is_err());
+    let outside = r#"(module
+        (memory (export "memory") 1)
+        (func (export "init"))
+        (func (export "update") (param i64 i64 i32) (result i32) i32.const 0x20000))"#;
+    let wasm: Arc<[u8]> = wat::parse_str(outside).unwrap().into();
+    let mut simulation = Simulation::new("outside".to_string(), wasm, 10).unwrap();
+    assert!(simulation.step().is_err());
+}
+
+#[tokio::test]
+async fn guest_preview_is_streamed_over_websocket() {
+    let h = TestHarness::new(|_| vec![]).await;
+    let resp = h
+        .http
+        .post(format!("http://{}/api/guests?name=counting", h.addr))
+        .body(wat::parse_str(COUNTING_GUEST).unwrap())
+        .send()
+        .await
+        .unwrap();
+    let guest: GuestInfo = resp.json().await.unwrap();
+
+    let mut ws = h.connect_ws().await;
+    let control = |control| ClientMsg::Simulate { control };
+    TestHarness::ws_send(
+        &mut ws,
+        &control(SimulatorControl::Start {
+            guest_id: guest.id.clone(),
+            fps: Some(50),
+        }),
+    )
+    .await;
+    for (counter, ms) in [(0, 0), (1, 20), (2, 40)] {
+        let (frame_ms, frame_counter, _) = preview_frame(TestHarness::ws_recv(&mut ws, T).await);
+        assert_eq!((frame_counter, frame_ms), (counter, ms));
+    }
+
+    // Paused, a seek still shows the frame there
+    TestHarness::ws_send(&mut ws, &control(SimulatorControl::Pause)).await;
+    TestHarness::ws_send(&mut ws, &control(SimulatorControl::Seek { ms: 1000 })).await;
+    let frame = loop {
+        let frame = preview_frame(TestHarness::ws_recv(&mut ws, T).await);
+        if frame.0 == 1000 {
+            break frame;
+        }
+    };
+    assert_eq!(frame.1, 50);
+    assert!(
+        timeout(Duration::from_millis(200), ws.next())
+            .await
+            .is_err(),
+        "paused preview sent a frame"
+    );
+
+    TestHarness::ws_send(&mut ws, &control(SimulatorControl::Speed { speed: 0.0 })).await;
+    assert!(matches!(
+        TestHarness::ws_recv(&mut ws, T).await,
+        ServerMsg::SimulatorError { .. }
+    ));
+    TestHarness::ws_send(&mut ws, &control(SimulatorControl::Stop)).await;
+    TestHarness::ws_send(&mut ws, &control(SimulatorControl::Play)).await;
+    assert!(matches!(
+        TestHarness::ws_recv(&mut ws, T).await,
+        ServerMsg::SimulatorError { .. }
+    ));
+    TestHarness::ws_send(
+        &mut ws,
+        &control(SimulatorControl::Start {
+            guest_id: "unknown".to_string(),
+            fps: None,
+        }),
+    )
+    .await;
+    match TestHarness::ws_recv(&mut ws, T).await {
+        ServerMsg::SimulatorError { error } => assert!(error.contains("unknown")),
+        other => panic!("expected an error, got {other:?}"),
+    }
+}
+
+#[test]
+fn configuration_is_layered() {
+    let path = std::env::temp_dir().join(format!("esp32-wasmi-led-{}.toml", uuid_short()));
+    std::fs::write(
+        &path,
+        r#"
+            [mqtt]
+            host = "file-host"
+            port = 1884
+            username = "backend"
+            password = "secret"
+            topic_prefix = "from-file"
+
+            [wled]
+            port = 21325
+
+            [log]
+            level = "debug"
+        "#,
+    )
+    .unwrap();
+    let env: HashMap<&str, &str> = [
+        ("CONFIG_FILE", path.to_str().unwrap()),
+        ("MQTT_HOST", "env-host"),
+        ("BIND_ADDR", "127.0.0.1:3001"),
+        ("DATABASE_PATH", ""),
+    ]
+    .into();
+    let var = |name: &str| env.get(name).map(ToString::to_string);
+    let args = |args: &[&str]| args.iter().map(ToString::to_string).collect::<Vec<_>>();
+
+    // Flags override the environment, which overrides the file
+    let config = Config::load(
+        args(&["--mqtt-host", "flag-host", "--bind=[::1]:3002"]),
+        var,
+    )
+    .unwrap();
+    assert_eq!(config.broker.host, "flag-host");
+    assert_eq!(config.broker.port, 1884);
+    assert_eq!(
+        config.broker.credentials,
+        Some(("backend".to_string(), "secret".to_string()))
+    );
+    assert_eq!(config.topic_prefix, "from-file");
+    assert_eq!(config.wled_port, 21325);
+    assert_eq!(config.bind, "[::1]:3002".parse().unwrap());
+    assert_eq!(config.log_level, LevelFilter::DEBUG);
+    // Empty is unset
+    assert_eq!(config.database_path, Path::new(backend::db::DEFAULT_PATH));
+    assert_eq!(config.client_id, config::DEFAULT_CLIENT_ID);
+    let config = Config::load(args(&[]), var).unwrap();
+    assert_eq!(config.broker.host, "env-host");
+    assert_eq!(config.bind, "127.0.0.1:3001".parse().unwrap());
+
+    // Errors say where the bad setting was given
+    let error = |args: &[&str], env: &[(&str, &str)]| {
+        let env: HashMap<&str, &str> = env.iter().copied().collect();
+        Config::load(args.iter().map(ToString::to_string), |name| {
+            env.get(name).map(ToString::to_string)
+        })
+        .unwrap_err()
+        .to_string()
+    };
+    assert_eq!(
+        error(&[], &[("MQTT_PORT", "x")]),
+        r#"MQTT_PORT "x" is not a port number"#
+    );
+    assert_eq!(
+        error(&["--log-level", "loud"], &[]),
+        r#"--log-level "loud" is not a log level"#
+    );
+    assert_eq!(
+        error(&["--topic-prefix=a/#"], &[]),
+        r#"--topic-prefix "a/#" is not a topic prefix, without wildcards or a trailing /"#
+    );
+    assert_eq!(error(&["--bind"], &[]), "--bind needs a value");
+    assert_eq!(
+        error(&[], &[("WLED_ROLE", "boss")]),
+        r#"WLED_ROLE "boss" is not viewer, painter or admin"#
+    );
+    assert_eq!(
+        error(&["--mqtt-password", "x"], &[]),
+        "unknown option --mqtt-password, see --help"
+    );
+    assert_eq!(
+        error(&[], &[("MQTT_PASSWORD", "x")]),
+        "the MQTT username and password must be given together"
+    );
+    std::fs::write(&path, "[mqtt]\nhots = \"typo\"\n").unwrap();
+    let in_file = error(&["--config", path.to_str().unwrap()], &[]);
+    assert!(in_file.ends_with("unknown setting mqtt.hots"), "{in_file}");
+    std::fs::write(&path, "[mqtt]\nport = \"x\"\n").unwrap();
+    let in_file = error(&["--config", path.to_str().unwrap()], &[]);
+    assert!(in_file.starts_with("mqtt.port in "), "{in_file}");
+
+    std::fs::remove_file(&path).unwrap();
+    let missing = error(&["--config", path.to_str().unwrap()], &[]);
+    assert!(missing.starts_with("can't read"), "{missing}");
+}
+
+/// Needs no broker.
+#[tokio::test]
+async fn wled_api_may_be_opened_to_callers_without_credentials() {
+    let (client, _eventloop) =
+        AsyncClient::new(rumqttc::MqttOptions::new("unused", "localhost", 1), 10);
+    let mut state = create_state(
+        client,
+        Topics::default(),
+        Database::open_in_memory().unwrap(),
+    );
+    state.wled_role = Some(Role::Painter);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, build_router(state)).await.unwrap();
+    });
+    let http = reqwest::Client::new();
+    let url = |path: &str| format!("http://{addr}{path}");
+
+    let resp = http
+        .post(url("/api/users"))
+        .json(&serde_json::json!({"name": "alice", "password": "correct horse", "role": "admin"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 201);
+
+    // As a stock WLED app would, with no way to log in
+    let resp = http
+        .post(url("/json/state"))
+        .json(&serde_json::json!({"on": true, "bri": 128}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let wled: serde_json::Value = http
+        .get(url("/json/state"))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(wled["bri"], 128);
+
+    // Only the WLED API
+    let resp = http.get(url("/api/devices")).send().await.unwrap();
+    assert_eq!(resp.status(), 401);
+}
+
+/// Needs no broker.
+#[tokio::test]
+async fn accounts_tokens_and_roles_are_checked() {
+    let (client, _eventloop) =
+        AsyncClient::new(rumqttc::MqttOptions::new("unused", "localhost", 1), 10);
+    let state = create_state(
+        client,
+        Topics::default(),
+        Database::open_in_memory().unwrap(),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, build_router(state)).await.unwrap();
+    });
+    let http = reqwest::Client::new();
+    let url = |path: &str| format!("http://{addr}{path}");
+    let me = |auth: (&'static str, String)| {
+        let request = http.get(url("/api/me")).header(auth.0, auth.1);
+        async move { request.send().await.unwrap() }
+    };
+    let login = |name: &str, password: &str| {
+        let request = http
+            .post(url("/api/login"))
+            .json(&serde_json::json!({"name": name, "password": password}));
+        async move {
+            let resp = request.send().await.unwrap();
+            let cookie = resp
+                .headers()
+                .get("set-cookie")
+                .map(|c| c.to_str().unwrap().to_string());
+            (resp.status(), cookie)
+        }
+    };
+    let session = |set_cookie: Option<String>| {
+        let set_cookie = set_cookie.unwrap();
+        assert!(set_cookie.contains("HttpOnly"), "{set_cookie}");
+        ("cookie", set_cookie.split(';').next().unwrap().to_string())
+    };
+
+    // Open until there's a user, who's an admin whatever they asked for
+    let caller: Caller = me(("x-none", String::new())).await.json().await.unwrap();
+    assert_eq!(caller.user, None);
+    assert_eq!(caller.role, Role::Admin);
+    let resp = http
+        .post(url("/api/users"))
+        .json(&serde_json::json!({"name": "alice", "password": "correct horse", "role": "viewer"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 201);
+    let resp = http.get(url("/api/devices")).send().await.unwrap();
+    assert_eq!(resp.status(), 401);
+
+    assert_eq!(
+        login("alice", "wrong horse").await,
+        (401.try_into().unwrap(), None)
+    );
+    assert_eq!(login("nobody", "correct horse").await.0, 401);
+    let (status, set_cookie) = login("alice", "correct horse").await;
+    assert_eq!(status, 200);
+    let alice = session(set_cookie);
+    let caller: Caller = me(alice.clone()).await.json().await.unwrap();
+    assert_eq!(caller.user.as_deref(), Some("alice"));
+    assert_eq!(caller.role, Role::Admin);
+
+    for (name, role) in [("bob", "painter"), ("carol", "viewer")] {
+        let resp = http
+            .post(url("/api/users"))
+            .header(alice.0, &alice.1)
+            .json(&serde_json::json!({"name": name, "password": "password", "role": role}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 201);
+    }
+    let resp = http
+        .post(url("/api/users"))
+        .header(alice.0, &alice.1)
+        .json(&serde_json::json!({"name": "dave", "password": "short", "role": "viewer"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 400);
+
+    // A viewer may look, but not change anything
+    let carol = session(login("carol", "password").await.1);
+    let resp = http
+        .get(url("/api/devices"))
+        .header(carol.0, &carol.1)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let resp = http
+        .put(url(&format!("/api/devices/{DEVICE}/groups")))
+        .header(carol.0, &carol.1)
+        .json(&serde_json::json!(["kitchen"]))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 403);
+    let resp = http
+        .get(url("/api/users"))
+        .header(carol.0, &carol.1)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 403);
+
+    // Nor over the WebSocket
+    let ws_url = format!("ws://{addr}/api/ws");
+    let mut request = tungstenite::client::IntoClientRequest::into_client_request(&ws_url).unwrap();
+    request
+        .headers_mut()
+        .insert("cookie", carol.1.parse().unwrap());
+    let (mut ws, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+    let publish = ClientMsg::Publish {
+        payload: "hello".to_string(),
+    };
+    ws.send(tungstenite::Message::Text(
+        serde_json::to_string(&publish).unwrap().into(),
+    ))
+    .await
+    .unwrap();
+    let reply = timeout(Duration::from_secs(5), ws.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    let reply: ServerMsg = serde_json::from_str(reply.to_text().unwrap()).unwrap();
+    assert!(matches!(reply, ServerMsg::Forbidden { .. }), "{reply:?}");
+    assert!(tokio_tungstenite::connect_async(&ws_url).await.is_err());
+
+    // Tokens are scoped to at most their user's role, and shown once
+    let token = |session: &(&'static str, String), role: &str| {
+        let request = http
+            .post(url("/api/tokens"))
+            .header(session.0, &session.1)
+            .json(&serde_json::json!({"name": "script", "role": role}));
+        async move { request.send().await.unwrap() }
+    };
+    assert_eq!(token(&carol, "painter").await.status(), 403);
+    let bob = session(login("bob", "password").await.1);
+    let resp = token(&bob, "painter").await;
+    assert_eq!(resp.status(), 201);
+    let bobs: NewToken = resp.json().await.unwrap();
+    assert!(bobs.token.starts_with(TOKEN_PREFIX));
+    let bearer = ("authorization", format!("Bearer {}", bobs.token));
+    let caller: Caller = me(bearer.clone()).await.json().await.unwrap();
+    assert_eq!(caller.user.as_deref(), Some("bob"));
+    assert_eq!(caller.role, Role::Painter);
+    let tokens: Vec<serde_json::Value> = http
+        .get(url("/api/tokens"))
+        .header(carol.0, &carol.1)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert!(tokens.is_empty());
+
+    // A token acts with no more than its user's current role
+    let resp = http
+        .patch(url("/api/users/bob"))
+        .header(alice.0, &alice.1)
+        .json(&serde_json::json!({"role": "viewer"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let caller: Caller = me(bearer.clone()).await.json().await.unwrap();
+    assert_eq!(caller.role, Role::Viewer);
+    let resp = http
+        .delete(url(&format!("/api/tokens/{}", bobs.info.id)))
+        .header(alice.0, &alice.1)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 204);
+    assert_eq!(me(bearer).await.status(), 401);
+
+    // There's always an admin
+    let resp = http
+        .delete(url("/api/users/alice"))
+        .header(alice.0, &alice.1)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 409);
+
+    // A new password, or logging out, ends sessions
+    let resp = http
+        .patch(url("/api/users/carol"))
+        .header(alice.0, &alice.1)
+        .json(&serde_json::json!({"password": "new password"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(me(carol).await.status(), 401);
+    // Her open WebSocket too
+    ws.send(tungstenite::Message::Text(
+        serde_json::to_string(&publish).unwrap().into(),
+    ))
+    .await
+    .unwrap();
+    let reply = timeout(Duration::from_secs(5), ws.next()).await.unwrap();
+    assert!(
+        matches!(reply, Some(Ok(tungstenite::Message::Close(_))) | None),
+        "{reply:?}"
+    );
+    let resp = http
+        .post(url("/api/logout"))
+        .header(alice.0, &alice.1)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 204);
+    assert_eq!(me(alice).await.status(), 401);
+}
@@ -0,0 +1,109 @@
+//! Art-Net `ArtDmx` packets.
+//!
+//! ```text
+//! offset  field
+//!   0     ID "Art-Net\0"
+//!   8     OpCode (u16 LE, 0x5000 = ArtDmx)
+//!  10     protocol version (u16 BE, 14)
+//!  12     sequence, physical port
+//!  14     SubUni (sub-net and universe), Net - together a 15-bit port address
+//!  16     length (u16 BE, even, 2-512)
+//!  18     channel values
+//! ```
+
+use crate::{DmxError, DmxPacket, UNIVERSE_SIZE};
+
+const ID: &[u8; 8] = b"Art-Net\0";
+const OP_DMX: u16 = 0x5000;
+const MIN_PROTOCOL_VERSION: u16 = 14;
+
+pub fn is_artnet(bytes: &[u8]) -> bool {
+    bytes.starts_with(ID)
+}
+
+pub fn parse(bytes: &[u8]) -> Result<DmxPacket<'_>, DmxError> {
+    if !is_artnet(bytes) {
+        return Err(DmxError::UnknownProtocol);
+    }
+    let [_, _, _, _, _, _, _, _, op_lo, op_hi, rest @ ..] = bytes else {
+        return Err(DmxError::InvalidLength);
+    };
+    // ArtPoll, ArtSync etc.
+    if u16::from_le_bytes([*op_lo, *op_hi]) != OP_DMX {
+        return Err(DmxError::NotDmxData);
+    }
+
+    let [
+        ver_hi,
+        ver_lo,
+        sequence,
+        _physical,
+        sub_uni,
+        net,
+        len_hi,
+        len_lo,
+        data @ ..,
+    ] = rest
+    else {
+        return Err(DmxError::InvalidLength);
+    };
+    if u16::from_be_bytes([*ver_hi, *ver_lo]) < MIN_PROTOCOL_VERSION || net & 0x80 != 0 {
+        return Err(DmxError::InvalidHeader);
+    }
+
+    // Senders may pad the packet, but never short it
+    let len = u16::from_be_bytes([*len_hi, *len_lo]) as usize;
+    if !(2..=UNIVERSE_SIZE).contains(&len) || data.len() < len {
+        return Err(DmxError::InvalidLength);
+    }
+
+    Ok(DmxPacket {
+        universe: u16::from_le_bytes([*sub_uni, *net]),
+        sequence: *sequence,
+        data: &data[..len],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER_LEN: usize = 18;
+
+    // QLC+ output: port address 0, 512 channels
+    const QLC_U0: &[u8] = include_bytes!("../testdata/artnet_dmx_u0.bin");
+    // QLC+ ArtPoll
+    const QLC_POLL: &[u8] = include_bytes!("../testdata/artnet_poll.bin");
+
+    #[test]
+    fn test_parse_capture() {
+        let packet = parse(QLC_U0).unwrap();
+        assert_eq!(packet.universe, 0);
+        assert_eq!(packet.sequence, 0x07);
+        assert_eq!(packet.data.len(), 512);
+        assert_eq!(&packet.data[..4], &[10, 20, 30, 40]);
+        assert_eq!(QLC_U0.len(), HEADER_LEN + 512);
+    }
+
+    #[test]
+    fn test_port_address() {
+        let mut packet = QLC_U0.to_vec();
+        packet[14] = 0x23; // sub-net 2, universe 3
+        packet[15] = 0x01; // net 1
+        assert_eq!(parse(&packet).unwrap().universe, 0x0123);
+    }
+
+    #[test]
+    fn test_non_dmx_and_malformed() {
+        assert_eq!(parse(QLC_POLL), Err(DmxError::NotDmxData));
+        assert_eq!(
+            parse(&QLC_U0[..HEADER_LEN - 1]),
+            Err(DmxError::InvalidLength)
+        );
+        assert_eq!(parse(&QLC_U0[..100]), Err(DmxError::InvalidLength));
+
+        let mut old_version = QLC_U0.to_vec();
+        old_version[11] = 13;
+        assert_eq!(parse(&old_version), Err(DmxError::InvalidHeader));
+    }
+}
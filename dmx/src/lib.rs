@@ -0,0 +1,98 @@
+#![cfg_attr(not(test), no_std)]
+
+//! Receivers for DMX-over-IP lighting protocols - E1.31 (sACN) and Art-Net - and the mapping
+//! of DMX channels onto the LED panel.
+//!
+//! Only DMX data packets are decoded. Everything else (sACN sync and discovery, ArtPoll, ...)
+//! is reported as [`DmxError::NotDmxData`] so that callers can ignore it quietly.
+
+pub mod artnet;
+pub mod mapping;
+pub mod sacn;
+
+pub use mapping::DmxMapping;
+
+/// Number of channels in a DMX universe.
+pub const UNIVERSE_SIZE: usize = 512;
+
+/// UDP port for E1.31 (sACN).
+pub const SACN_PORT: u16 = 5568;
+
+/// UDP port for Art-Net.
+pub const ARTNET_PORT: u16 = 6454;
+
+/// The DMX data of one universe, borrowed from a received packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DmxPacket<'a> {
+    /// sACN universe (1-63999) or Art-Net port address (0-32767)
+    pub universe: u16,
+    /// Wrapping sequence number. Art-Net uses 0 to mean "not sequenced".
+    pub sequence: u8,
+    /// Channel values, starting at channel 1. May be shorter than a full universe.
+    pub data: &'a [u8],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DmxError {
+    /// Not an sACN or Art-Net packet
+    UnknownProtocol,
+    /// A well-formed packet that doesn't carry DMX levels
+    NotDmxData,
+    /// Truncated, or a length field that disagrees with the packet
+    InvalidLength,
+    /// A header field has an unexpected value
+    InvalidHeader,
+}
+
+impl<'a> DmxPacket<'a> {
+    /// Parse an sACN or Art-Net packet, telling them apart by their identifiers.
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, DmxError> {
+        if sacn::is_sacn(bytes) {
+            sacn::parse(bytes)
+        } else if artnet::is_artnet(bytes) {
+            artnet::parse(bytes)
+        } else {
+            Err(DmxError::UnknownProtocol)
+        }
+    }
+}
+
+/// Whether a packet with sequence number `seq` should be used, given the last one used
+/// (E1.31 section 6.7.2): packets up to 20 behind the last are out of order and discarded,
+/// anything else is taken as newer - including a source that has restarted.
+pub fn sequence_is_newer(seq: u8, last: u8) -> bool {
+    let diff = seq.wrapping_sub(last) as i8;
+    !(-20 < diff && diff <= 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_detects_protocol() {
+        let sacn = DmxPacket::parse(include_bytes!("../testdata/sacn_xlights_u1.bin")).unwrap();
+        assert_eq!(sacn.universe, 1);
+
+        let artnet = DmxPacket::parse(include_bytes!("../testdata/artnet_dmx_u0.bin")).unwrap();
+        assert_eq!(artnet.universe, 0);
+
+        assert_eq!(
+            DmxPacket::parse(b"GET / HTTP/1.1\r\n"),
+            Err(DmxError::UnknownProtocol)
+        );
+        assert_eq!(DmxPacket::parse(&[]), Err(DmxError::UnknownProtocol));
+    }
+
+    #[test]
+    fn test_sequence_is_newer() {
+        assert!(sequence_is_newer(1, 0));
+        assert!(sequence_is_newer(0, 255));
+        assert!(!sequence_is_newer(5, 5));
+        assert!(!sequence_is_newer(250, 5));
+        // A big jump backwards is a restarted source
+        assert!(sequence_is_newer(0, 100));
+    }
+}
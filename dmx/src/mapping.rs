@@ -0,0 +1,149 @@
+//! Mapping of DMX channels onto the panel's RGB pixel buffer.
+//!
+//! The panel's bytes are treated as one run of consecutive channels, starting at
+//! `start_channel` of `universe` and continuing into the following universes. By default
+//! 510 channels of each universe are used, so that a pixel never straddles two universes
+//! (xLights and most pixel controllers do the same).
+
+use serde::{Deserialize, Serialize};
+
+use crate::UNIVERSE_SIZE;
+
+/// Channels used per universe, by default - 170 RGB pixels.
+pub const DEFAULT_CHANNELS_PER_UNIVERSE: u16 = 510;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DmxMapping {
+    /// Universe holding the first pixel. sACN numbers universes from 1, Art-Net from 0.
+    pub universe: u16,
+    /// 1-based channel of the first pixel's red component within `universe`
+    pub start_channel: u16,
+    /// Channels used in each universe before moving on to the next
+    #[serde(default = "default_channels_per_universe")]
+    pub channels_per_universe: u16,
+}
+
+fn default_channels_per_universe() -> u16 {
+    DEFAULT_CHANNELS_PER_UNIVERSE
+}
+
+impl Default for DmxMapping {
+    fn default() -> Self {
+        Self::new(1, 1)
+    }
+}
+
+impl DmxMapping {
+    pub const fn new(universe: u16, start_channel: u16) -> Self {
+        Self {
+            universe,
+            start_channel,
+            channels_per_universe: DEFAULT_CHANNELS_PER_UNIVERSE,
+        }
+    }
+
+    fn channels_per_universe(&self) -> usize {
+        (self.channels_per_universe as usize).clamp(1, UNIVERSE_SIZE)
+    }
+
+    fn start_offset(&self) -> usize {
+        self.start_channel.saturating_sub(1) as usize
+    }
+
+    /// The universes needed to fill a `frame_len` byte frame.
+    pub fn universes(&self, frame_len: usize) -> core::ops::RangeInclusive<u16> {
+        let last_channel = self.start_offset() + frame_len.max(1) - 1;
+        let count = last_channel / self.channels_per_universe();
+        self.universe..=self.universe.saturating_add(count as u16)
+    }
+
+    /// Copy the channels of `universe` that belong to the panel into `frame`, returning
+    /// whether anything was copied.
+    pub fn apply(&self, universe: u16, data: &[u8], frame: &mut [u8]) -> bool {
+        let Some(index) = universe.checked_sub(self.universe) else {
+            return false;
+        };
+        let per_universe = self.channels_per_universe();
+
+        // Channel numbers below are 0-based and counted from channel 1 of `self.universe`
+        let first = index as usize * per_universe;
+        let end = first + data.len().min(per_universe);
+        let start = self.start_offset();
+
+        let lo = first.max(start);
+        let hi = end.min(start + frame.len());
+        if lo >= hi {
+            return false;
+        }
+        frame[lo - start..hi - start].copy_from_slice(&data[lo - first..hi - first]);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME_LEN: usize = 16 * 16 * 3;
+
+    fn universe_of(value: u8) -> [u8; UNIVERSE_SIZE] {
+        [value; UNIVERSE_SIZE]
+    }
+
+    #[test]
+    fn test_default_mapping_spans_two_universes() {
+        let mapping = DmxMapping::default();
+        assert_eq!(mapping.universes(FRAME_LEN), 1..=2);
+
+        let mut frame = [0u8; FRAME_LEN];
+        assert!(!mapping.apply(0, &universe_of(9), &mut frame));
+        assert!(mapping.apply(1, &universe_of(1), &mut frame));
+        assert!(mapping.apply(2, &universe_of(2), &mut frame));
+        assert!(!mapping.apply(3, &universe_of(3), &mut frame));
+
+        // Pixel 170 (the 171st) is the first in universe 2, channels 511 and 512 are unused
+        assert!(frame[..510].iter().all(|&b| b == 1));
+        assert!(frame[510..].iter().all(|&b| b == 2));
+    }
+
+    #[test]
+    fn test_start_channel_offsets_the_panel() {
+        let mapping = DmxMapping::new(5, 4);
+        let mut frame = [0u8; FRAME_LEN];
+        let mut data = [0u8; UNIVERSE_SIZE];
+        data[3..6].copy_from_slice(&[7, 8, 9]);
+
+        assert!(mapping.apply(5, &data, &mut frame));
+        assert_eq!(&frame[..3], &[7, 8, 9]);
+        assert_eq!(mapping.universes(FRAME_LEN), 5..=6);
+    }
+
+    #[test]
+    fn test_short_universe_only_updates_its_channels() {
+        let mapping = DmxMapping::default();
+        let mut frame = [0xffu8; FRAME_LEN];
+        assert!(mapping.apply(1, &[1, 2, 3], &mut frame));
+        assert_eq!(&frame[..4], &[1, 2, 3, 0xff]);
+    }
+
+    #[test]
+    fn test_full_universes() {
+        let mapping = DmxMapping {
+            channels_per_universe: 512,
+            ..DmxMapping::new(0, 1)
+        };
+        let mut frame = [0u8; FRAME_LEN];
+        mapping.apply(0, &universe_of(1), &mut frame);
+        mapping.apply(1, &universe_of(2), &mut frame);
+        assert_eq!(frame[511], 1);
+        assert_eq!(frame[512], 2);
+    }
+
+    #[test]
+    fn test_deserialise_with_defaults() {
+        let (mapping, _) =
+            serde_json_core::from_str::<DmxMapping>(r#"{"universe":3,"start_channel":1}"#).unwrap();
+        assert_eq!(mapping, DmxMapping::new(3, 1));
+    }
+}
@@ -0,0 +1,168 @@
+//! ANSI E1.31 (Streaming ACN) data packets.
+//!
+//! ```text
+//! offset  field
+//!   0     root layer: preamble size (0x0010), postamble size (0x0000)
+//!   4     ACN packet identifier "ASC-E1.17\0\0\0"
+//!  16     flags & length, vector (4 = E1.31 data), CID (16 bytes)
+//!  38     framing layer: flags & length, vector (2 = DMP data), source name (64 bytes),
+//! 108       priority, sync address (u16), sequence, options, universe (u16)
+//! 115     DMP layer: flags & length, vector (2), address type (0xa1), first address (0),
+//! 121       address increment (1), property value count (u16)
+//! 125     DMX start code, then up to 512 channel values
+//! ```
+//!
+//! All multi-byte fields are big-endian.
+
+use crate::{DmxError, DmxPacket, UNIVERSE_SIZE};
+
+const ACN_PACKET_IDENTIFIER: &[u8; 16] = b"\x00\x10\x00\x00ASC-E1.17\x00\x00\x00";
+
+const VECTOR_ROOT_E131_DATA: u32 = 0x0000_0004;
+const VECTOR_E131_DATA_PACKET: u32 = 0x0000_0002;
+const VECTOR_DMP_SET_PROPERTY: u8 = 0x02;
+const DMP_ADDRESS_TYPE: u8 = 0xa1;
+
+const OPTION_PREVIEW_DATA: u8 = 0x80;
+const OPTION_STREAM_TERMINATED: u8 = 0x40;
+
+const DMX_START_CODE: u8 = 0x00;
+
+const SEQUENCE_OFFSET: usize = 111;
+const OPTIONS_OFFSET: usize = 112;
+const UNIVERSE_OFFSET: usize = 113;
+const PROPERTY_COUNT_OFFSET: usize = 123;
+const START_CODE_OFFSET: usize = 125;
+const DATA_OFFSET: usize = 126;
+
+/// Multicast group that sACN sources send `universe` to (239.255.{hi}.{lo}).
+pub fn multicast_address(universe: u16) -> [u8; 4] {
+    let [hi, lo] = universe.to_be_bytes();
+    [239, 255, hi, lo]
+}
+
+pub fn is_sacn(bytes: &[u8]) -> bool {
+    bytes.starts_with(ACN_PACKET_IDENTIFIER)
+}
+
+pub fn parse(bytes: &[u8]) -> Result<DmxPacket<'_>, DmxError> {
+    if !is_sacn(bytes) {
+        return Err(DmxError::UnknownProtocol);
+    }
+    if bytes.len() < 22 {
+        return Err(DmxError::InvalidLength);
+    }
+    // Extended packets (sync, universe discovery) use another root vector
+    if u32_at(bytes, 18) != VECTOR_ROOT_E131_DATA {
+        return Err(DmxError::NotDmxData);
+    }
+    if bytes.len() < DATA_OFFSET {
+        return Err(DmxError::InvalidLength);
+    }
+    if u32_at(bytes, 40) != VECTOR_E131_DATA_PACKET
+        || bytes[117] != VECTOR_DMP_SET_PROPERTY
+        || bytes[118] != DMP_ADDRESS_TYPE
+        || u16_at(bytes, 119) != 0
+        || u16_at(bytes, 121) != 1
+    {
+        return Err(DmxError::InvalidHeader);
+    }
+
+    // Each layer's length runs from its flags & length field to the end of the packet
+    for offset in [16, 38, 115] {
+        let len = (u16_at(bytes, offset) & 0x0fff) as usize;
+        if offset + len != bytes.len() {
+            return Err(DmxError::InvalidLength);
+        }
+    }
+
+    let property_count = u16_at(bytes, PROPERTY_COUNT_OFFSET) as usize;
+    if property_count == 0
+        || property_count > UNIVERSE_SIZE + 1
+        || START_CODE_OFFSET + property_count != bytes.len()
+    {
+        return Err(DmxError::InvalidLength);
+    }
+
+    let options = bytes[OPTIONS_OFFSET];
+    if options & (OPTION_PREVIEW_DATA | OPTION_STREAM_TERMINATED) != 0
+        || bytes[START_CODE_OFFSET] != DMX_START_CODE
+    {
+        return Err(DmxError::NotDmxData);
+    }
+
+    Ok(DmxPacket {
+        universe: u16_at(bytes, UNIVERSE_OFFSET),
+        sequence: bytes[SEQUENCE_OFFSET],
+        data: &bytes[DATA_OFFSET..],
+    })
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // xLights output: universe 1, 510 channels (170 pixels), priority 100
+    const XLIGHTS_U1: &[u8] = include_bytes!("../testdata/sacn_xlights_u1.bin");
+    // xLights universe sync packet (root vector 8)
+    const XLIGHTS_SYNC: &[u8] = include_bytes!("../testdata/sacn_xlights_sync.bin");
+
+    #[test]
+    fn test_parse_xlights_capture() {
+        let packet = parse(XLIGHTS_U1).unwrap();
+        assert_eq!(packet.universe, 1);
+        assert_eq!(packet.sequence, 0x2a);
+        assert_eq!(packet.data.len(), 510);
+        assert_eq!(&packet.data[..6], &[255, 0, 0, 0, 255, 0]);
+    }
+
+    #[test]
+    fn test_non_data_packets_are_ignored() {
+        assert_eq!(parse(XLIGHTS_SYNC), Err(DmxError::NotDmxData));
+
+        let mut terminated = XLIGHTS_U1.to_vec();
+        terminated[OPTIONS_OFFSET] |= OPTION_STREAM_TERMINATED;
+        assert_eq!(parse(&terminated), Err(DmxError::NotDmxData));
+
+        let mut preview = XLIGHTS_U1.to_vec();
+        preview[OPTIONS_OFFSET] |= OPTION_PREVIEW_DATA;
+        assert_eq!(parse(&preview), Err(DmxError::NotDmxData));
+
+        // Alternate start codes (e.g. 0xdd per-channel priority) aren't levels
+        let mut priority = XLIGHTS_U1.to_vec();
+        priority[START_CODE_OFFSET] = 0xdd;
+        assert_eq!(parse(&priority), Err(DmxError::NotDmxData));
+    }
+
+    #[test]
+    fn test_truncated_packets_are_rejected() {
+        assert_eq!(
+            parse(&XLIGHTS_U1[..XLIGHTS_U1.len() - 1]),
+            Err(DmxError::InvalidLength)
+        );
+        assert_eq!(parse(&XLIGHTS_U1[..64]), Err(DmxError::InvalidLength));
+
+        let mut bad_dmp = XLIGHTS_U1.to_vec();
+        bad_dmp[118] = 0;
+        assert_eq!(parse(&bad_dmp), Err(DmxError::InvalidHeader));
+    }
+
+    #[test]
+    fn test_multicast_address() {
+        assert_eq!(multicast_address(1), [239, 255, 0, 1]);
+        assert_eq!(multicast_address(0x1234), [239, 255, 0x12, 0x34]);
+    }
+}
@@ -1,9 +1,16 @@
+use common::{BYTES_PER_LED, LED_PANEL_HEIGHT, LED_PANEL_WIDTH};
 use eframe::egui;
 use futures::channel::mpsc;
 use futures::{SinkExt, StreamExt};
 use gloo_net::websocket::{Message as WsMessage, futures::WebSocket};
+use protocol::config::BrokerAddress;
+use protocol::presence::DeviceInfo;
+use protocol::telemetry::Telemetry;
+use protocol::{Command, DeviceConfig, Mode};
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
 use uuid::Uuid;
-use web_common::{ClientMsg, LastMessage, ServerMsg};
+use web_common::{ClientMsg, LastMessage, Login, ServerMsg, SimulatorControl, Target};
 
 // Channel messages internal to the frontend
 enum ToBackend {
@@ -17,6 +24,37 @@ enum IncomingEvent {
     Msg(ServerMsg),
 }
 
+// What we know about a device, from its device topics
+#[derive(Default)]
+struct DeviceView {
+    online: bool,
+    info: Option<DeviceInfo>,
+    telemetry: Option<Telemetry>,
+    config: Option<DeviceConfig>,
+}
+
+// A stored guest, run by the backend
+struct Preview {
+    guest_id: String,
+    playing: bool,
+    speed: f32,
+    // Guest time and pixels of the latest frame
+    frame: Option<(u64, Vec<u8>)>,
+    error: Option<String>,
+}
+
+impl Default for Preview {
+    fn default() -> Self {
+        Self {
+            guest_id: String::new(),
+            playing: false,
+            speed: 1.0,
+            frame: None,
+            error: None,
+        }
+    }
+}
+
 pub struct PrototypeApp {
     // Outbound channel: UI thread → WebSocket send task
     ws_tx: mpsc::UnboundedSender<ToBackend>,
@@ -30,11 +68,22 @@ pub struct PrototypeApp {
     last_fetched: Option<LastMessage>,
     live_messages: Vec<String>,
     ping_responses: Vec<String>,
+    command_results: Vec<String>,
+    devices: BTreeMap<String, DeviceView>,
+    // Which device(s) pings and commands go to
+    target: Target,
+    preview: Preview,
     fetch_error: Option<String>,
+    login: Login,
+    login_error: Option<String>,
 
     // Internal Fetch results channel
     fetch_tx: mpsc::UnboundedSender<Result<LastMessage, String>>,
     fetch_rx: mpsc::UnboundedReceiver<Result<LastMessage, String>>,
+
+    // Failed logins (a successful one reloads the page)
+    login_tx: mpsc::UnboundedSender<String>,
+    login_rx: mpsc::UnboundedReceiver<String>,
 }
 
 impl PrototypeApp {
@@ -56,6 +105,7 @@ impl PrototypeApp {
             wasm_bindgen_futures::spawn_local(ws_task(ws_rx, incoming_tx, ctx));
 
             let (fetch_tx, fetch_rx) = mpsc::unbounded();
+            let (login_tx, login_rx) = mpsc::unbounded();
 
             Self {
                 ws_tx,
@@ -65,15 +115,24 @@ impl PrototypeApp {
                 last_fetched: None,
                 live_messages: Vec::new(),
                 ping_responses: Vec::new(),
+                command_results: Vec::new(),
+                devices: BTreeMap::new(),
+                target: Target::default(),
+                preview: Preview::default(),
                 fetch_error: None,
+                login: Login::default(),
+                login_error: None,
                 fetch_tx,
                 fetch_rx,
+                login_tx,
+                login_rx,
             }
         }
 
         #[cfg(not(target_arch = "wasm32"))]
         {
             let (fetch_tx, fetch_rx) = mpsc::unbounded();
+            let (login_tx, login_rx) = mpsc::unbounded();
 
             Self {
                 ws_tx,
@@ -83,9 +142,17 @@ impl PrototypeApp {
                 last_fetched: None,
                 live_messages: Vec::new(),
                 ping_responses: Vec::new(),
+                command_results: Vec::new(),
+                devices: BTreeMap::new(),
+                target: Target::default(),
+                preview: Preview::default(),
                 fetch_error: None,
+                login: Login::default(),
+                login_error: None,
                 fetch_tx,
                 fetch_rx,
+                login_tx,
+                login_rx,
             }
         }
     }
@@ -108,17 +175,65 @@ impl eframe::App for PrototypeApp {
                             }
                         }
                         ServerMsg::PingResponse {
+                            device_id,
                             correlation_id,
                             device_reply,
                         } => {
                             self.ping_responses
-                                .push(format!("[{correlation_id}] {device_reply}"));
+                                .push(format!("{device_id} [{correlation_id}] {device_reply}"));
+                        }
+                        ServerMsg::CommandSent { device_id, id } => {
+                            self.command_results.push(format!("{device_id} #{id} sent"));
+                        }
+                        ServerMsg::CommandAck {
+                            device_id,
+                            id,
+                            error,
+                        } => {
+                            self.command_results.push(match error {
+                                None => format!("{device_id} #{id} ok"),
+                                Some(e) => format!("{device_id} #{id} rejected: {e:?}"),
+                            });
+                            // Keep last 10
+                            if self.command_results.len() > 10 {
+                                self.command_results.remove(0);
+                            }
+                        }
+                        ServerMsg::Telemetry {
+                            device_id,
+                            telemetry,
+                        } => {
+                            self.devices.entry(device_id).or_default().telemetry = Some(telemetry);
+                        }
+                        ServerMsg::Presence { device_id, online } => {
+                            self.devices.entry(device_id).or_default().online = online;
+                        }
+                        ServerMsg::DeviceInfo { info } => {
+                            let device_id = info.device_id.to_string();
+                            self.devices.entry(device_id).or_default().info = Some(info);
+                        }
+                        ServerMsg::DeviceConfig { device_id, config } => {
+                            self.devices.entry(device_id).or_default().config = Some(*config);
+                        }
+                        ServerMsg::Frame { ms, pixels, .. } => {
+                            self.preview.frame = Some((ms, pixels));
+                        }
+                        ServerMsg::SimulatorError { error } => {
+                            self.preview.playing = false;
+                            self.preview.error = Some(error);
+                        }
+                        ServerMsg::Forbidden { action } => {
+                            self.command_results.push(format!("Not allowed: {action}"));
                         }
                     }
                 }
             }
         }
 
+        while let Ok(error) = self.login_rx.try_recv() {
+            self.login_error = Some(error);
+        }
+
         // Drain fetch results, if any:
         while let Ok(result) = self.fetch_rx.try_recv() {
             match result {
@@ -146,6 +261,57 @@ impl eframe::App for PrototypeApp {
                 ui.colored_label(color, text);
             });
 
+            // Once the backend has users, the WebSocket needs a login
+            if !self.connected {
+                ui.horizontal(|ui| {
+                    ui.label("Name:");
+                    ui.text_edit_singleline(&mut self.login.name);
+                    ui.label("Password:");
+                    ui.add(egui::TextEdit::singleline(&mut self.login.password).password(true));
+                    if ui.button("Log in").clicked() {
+                        #[cfg(target_arch = "wasm32")]
+                        log_in(self.login.clone(), self.login_tx.clone(), ctx.clone());
+                    }
+                });
+                if let Some(ref err) = self.login_error {
+                    ui.colored_label(egui::Color32::RED, err);
+                }
+            }
+
+            for (device_id, device) in &self.devices {
+                let (color, state) = if device.online {
+                    (egui::Color32::GREEN, "online")
+                } else {
+                    (egui::Color32::GRAY, "offline")
+                };
+                let size = device
+                    .info
+                    .as_ref()
+                    .map_or(String::new(), |i| format!(" ({}x{})", i.width, i.height));
+                ui.colored_label(color, format!("● Device {device_id}{size} {state}"));
+            }
+
+            // Target for pings and commands:
+            ui.horizontal(|ui| {
+                ui.label("Target:");
+                let label = match &self.target {
+                    Target::Device(device_id) => device_id.clone(),
+                    Target::Group(_) => "All devices".to_string(),
+                };
+                egui::ComboBox::from_id_salt("target")
+                    .selected_text(label)
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut self.target, Target::default(), "All devices");
+                        for device_id in self.devices.keys() {
+                            ui.selectable_value(
+                                &mut self.target,
+                                Target::Device(device_id.clone()),
+                                device_id,
+                            );
+                        }
+                    });
+            });
+
             ui.separator();
 
             // Real-time send to backend:
@@ -203,17 +369,178 @@ impl eframe::App for PrototypeApp {
                 ui.label("Ping Device (Request-Response over MQTT)");
                 if ui.button("Ping Device").clicked() {
                     let correlation_id = Uuid::new_v4().to_string();
-                    let msg = ClientMsg::PingDevice { correlation_id };
+                    let msg = ClientMsg::PingDevice {
+                        correlation_id,
+                        target: self.target.clone(),
+                    };
                     let _ = self.ws_tx.unbounded_send(ToBackend::Send(msg));
                 }
                 for resp in &self.ping_responses {
                     ui.label(resp);
                 }
             });
+
+            ui.separator();
+
+            // Device commands, via the backend:
+            ui.group(|ui| {
+                ui.label("Device Mode (WebSocket → MQTT command)");
+                ui.horizontal(|ui| {
+                    for mode in Mode::ALL {
+                        if ui.button(format!("{mode:?}")).clicked() {
+                            let msg = ClientMsg::Command {
+                                target: self.target.clone(),
+                                command: Box::new(Command::SetMode(mode)),
+                            };
+                            let _ = self.ws_tx.unbounded_send(ToBackend::Send(msg));
+                        }
+                    }
+                    if ui.button("Get Config").clicked() {
+                        let msg = ClientMsg::Command {
+                            target: self.target.clone(),
+                            command: Box::new(Command::GetConfig),
+                        };
+                        let _ = self.ws_tx.unbounded_send(ToBackend::Send(msg));
+                    }
+                });
+                for result in &self.command_results {
+                    ui.label(result);
+                }
+            });
+
+            ui.separator();
+
+            // Preview of a stored guest, run by the backend:
+            ui.group(|ui| {
+                ui.label("Guest Preview (simulated by the backend)");
+                let mut control = None;
+                ui.horizontal(|ui| {
+                    ui.label("Guest id:");
+                    ui.text_edit_singleline(&mut self.preview.guest_id);
+                    if ui.button("Start").clicked() {
+                        control = Some(SimulatorControl::Start {
+                            guest_id: self.preview.guest_id.clone(),
+                            fps: None,
+                        });
+                        self.preview.playing = true;
+                        self.preview.error = None;
+                    }
+                });
+                ui.horizontal(|ui| {
+                    if self.preview.playing {
+                        if ui.button("Pause").clicked() {
+                            control = Some(SimulatorControl::Pause);
+                            self.preview.playing = false;
+                        }
+                    } else if ui.button("Play").clicked() {
+                        control = Some(SimulatorControl::Play);
+                        self.preview.playing = true;
+                    }
+                    if ui.button("Restart").clicked() {
+                        control = Some(SimulatorControl::Seek { ms: 0 });
+                    }
+                    if ui.button("Stop").clicked() {
+                        control = Some(SimulatorControl::Stop);
+                        self.preview.playing = false;
+                        self.preview.frame = None;
+                    }
+                    let speed = egui::Slider::new(&mut self.preview.speed, 0.25..=4.0)
+                        .logarithmic(true)
+                        .text("speed");
+                    if ui.add(speed).changed() {
+                        control = Some(SimulatorControl::Speed {
+                            speed: self.preview.speed,
+                        });
+                    }
+                });
+                if let Some(control) = control {
+                    let msg = ClientMsg::Simulate { control };
+                    let _ = self.ws_tx.unbounded_send(ToBackend::Send(msg));
+                }
+                if let Some((ms, pixels)) = &self.preview.frame {
+                    ui.label(format!("{:.1} s", *ms as f32 / 1000.0));
+                    draw_frame(ui, pixels);
+                }
+                if let Some(error) = &self.preview.error {
+                    ui.colored_label(egui::Color32::RED, error);
+                }
+            });
+
+            ui.separator();
+
+            // Latest telemetry from each device:
+            ui.group(|ui| {
+                ui.label("Device Status (MQTT telemetry)");
+                if self.devices.is_empty() {
+                    ui.label("No devices yet");
+                }
+                for (device_id, device) in &self.devices {
+                    ui.strong(device_id);
+                    if let Some(c) = &device.config {
+                        let network = c.static_ip.map_or("DHCP".to_string(), |ip| {
+                            format!("{}/{}", Ipv4Addr::from(ip.address), ip.prefix_len)
+                        });
+                        let broker = match c.mqtt.broker_address() {
+                            BrokerAddress::Host(host) => host.to_string(),
+                            BrokerAddress::Ip(ip) => Ipv4Addr::from(ip).to_string(),
+                            BrokerAddress::Discover => "(discovered)".to_string(),
+                        };
+                        ui.label(format!(
+                            "Config: WiFi {}, {network}, broker {broker}:{}, brightness {}",
+                            c.wifi.ssid, c.mqtt.port, c.brightness
+                        ));
+                    }
+                    let Some(t) = &device.telemetry else {
+                        ui.label("No telemetry yet");
+                        continue;
+                    };
+                    ui.label(format!(
+                        "Firmware {} (protocol v{}), up {} s",
+                        t.firmware_version, t.protocol_version, t.uptime_s
+                    ));
+                    ui.label(format!(
+                        "Mode: {:?}, guest: {}",
+                        t.mode,
+                        t.guest.as_deref().unwrap_or("none")
+                    ));
+                    ui.label(format!(
+                        "{} FPS, frame time {}/{}/{} µs (min/avg/max)",
+                        t.frames.fps, t.frames.min_us, t.frames.avg_us, t.frames.max_us
+                    ));
+                    ui.label(format!(
+                        "Heap: {} used, {} free; guest memory: {} bytes",
+                        t.heap.used, t.heap.free, t.guest_memory_bytes
+                    ));
+                    ui.label(format!("Power: ~{} mW", t.power_mw));
+                    ui.label(format!(
+                        "IP: {}, RSSI: {}",
+                        t.ip.as_deref().unwrap_or("-"),
+                        t.rssi_dbm.map_or("-".into(), |r| format!("{r} dBm"))
+                    ));
+                }
+            });
         });
     }
 }
 
+// Draw a frame of row-major RGB pixels, as the panel would show it
+fn draw_frame(ui: &mut egui::Ui, pixels: &[u8]) {
+    const LED_SIZE: f32 = 12.0;
+    let size = egui::vec2(
+        LED_PANEL_WIDTH as f32 * LED_SIZE,
+        LED_PANEL_HEIGHT as f32 * LED_SIZE,
+    );
+    let (response, painter) = ui.allocate_painter(size, egui::Sense::hover());
+    painter.rect_filled(response.rect, 0.0, egui::Color32::BLACK);
+    for (i, led) in pixels.as_chunks::<BYTES_PER_LED>().0.iter().enumerate() {
+        let (x, y) = (i % LED_PANEL_WIDTH, i / LED_PANEL_WIDTH);
+        let center = response.rect.min
+            + egui::vec2((x as f32 + 0.5) * LED_SIZE, (y as f32 + 0.5) * LED_SIZE);
+        let color = egui::Color32::from_rgb(led[0], led[1], led[2]);
+        painter.circle_filled(center, LED_SIZE * 0.4, color);
+    }
+}
+
 // WebSocket management task
 // (Runs as a spawned future in the browser)
 #[cfg(target_arch = "wasm32")]
@@ -289,3 +616,27 @@ fn fetch_last_message(
         ctx.request_repaint();
     });
 }
+
+// Log in, then reload so the WebSocket reconnects with the session cookie
+#[cfg(target_arch = "wasm32")]
+fn log_in(login: Login, mut tx: mpsc::UnboundedSender<String>, ctx: egui::Context) {
+    wasm_bindgen_futures::spawn_local(async move {
+        let resp = match gloo_net::http::Request::post("/api/login").json(&login) {
+            Ok(request) => request.send().await,
+            Err(e) => Err(e),
+        };
+        let error = match resp {
+            Ok(r) if r.ok() => {
+                let _ = web_sys::window().unwrap().location().reload();
+                return;
+            }
+            Ok(r) => r
+                .text()
+                .await
+                .unwrap_or_else(|_| format!("HTTP {}", r.status())),
+            Err(e) => e.to_string(),
+        };
+        let _ = tx.send(error).await;
+        ctx.request_repaint();
+    });
+}
@@ -0,0 +1,236 @@
+//! MQTT connection supervision, independent of the transport and the MQTT client.
+//!
+//! [`Connection`] tracks whether we are connecting, connected or backing off, and how long to
+//! wait before the next attempt. [`KeepAlive`] decides when to ping the broker and when a silent
+//! broker should be given up on - a half-open TCP connection otherwise goes unnoticed, since
+//! QoS 0 publishes succeed as long as the socket accepts them.
+//!
+//! Neither does any I/O or reads a clock: the caller reports what happened, with times in
+//! milliseconds from any monotonic source.
+
+/// Exponential backoff between connection attempts, doubling from `initial_ms` up to `max_ms`.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial_ms: u32,
+    max_ms: u32,
+    next_ms: u32,
+}
+
+impl Backoff {
+    pub const fn new(initial_ms: u32, max_ms: u32) -> Self {
+        Self {
+            initial_ms,
+            max_ms,
+            next_ms: initial_ms,
+        }
+    }
+
+    /// The delay before the next attempt, doubling the one after.
+    pub fn next_delay(&mut self) -> u32 {
+        let delay = self.next_ms;
+        self.next_ms = self.next_ms.saturating_mul(2).min(self.max_ms);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.next_ms = self.initial_ms;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum State {
+    /// Opening the transport, connecting, subscribing and publishing the birth message.
+    /// `attempt` counts from 1 since the last established connection.
+    Connecting { attempt: u32 },
+    /// Subscribed and announced
+    Connected,
+    /// Waiting `delay_ms` before connecting again
+    Backoff { delay_ms: u32 },
+}
+
+/// The connection lifecycle: `Connecting` → `Connected` → (lost) → `Backoff` → `Connecting` …
+///
+/// A failed attempt also backs off. The backoff resets once a connection is established, so the
+/// first reconnect after a drop is quick.
+#[derive(Debug, Clone)]
+pub struct Connection {
+    state: State,
+    attempt: u32,
+    backoff: Backoff,
+}
+
+impl Connection {
+    pub const fn new(backoff: Backoff) -> Self {
+        Self {
+            state: State::Connecting { attempt: 1 },
+            attempt: 1,
+            backoff,
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// The session is set up: subscribed, and the birth message published.
+    pub fn established(&mut self) {
+        self.state = State::Connected;
+        self.attempt = 0;
+        self.backoff.reset();
+    }
+
+    /// The attempt failed, or the connection was lost. Returns the delay before the next attempt.
+    pub fn failed(&mut self) -> u32 {
+        let delay_ms = self.backoff.next_delay();
+        self.state = State::Backoff { delay_ms };
+        delay_ms
+    }
+
+    /// The backoff delay has passed; start the next attempt.
+    pub fn retry(&mut self) {
+        self.attempt += 1;
+        self.state = State::Connecting {
+            attempt: self.attempt,
+        };
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum KeepAliveAction {
+    None,
+    /// Send a PINGREQ
+    Ping,
+    /// Nothing from the broker within the timeout after a ping; drop the connection
+    Dead,
+}
+
+/// Keep-alive for one connection.
+///
+/// Pings when nothing has been sent for `interval_ms` (so the broker keeps us), or nothing has
+/// been received for `interval_ms` (to check the broker is still there). The connection is dead
+/// if nothing at all is received within `timeout_ms` of a ping.
+#[derive(Debug, Clone)]
+pub struct KeepAlive {
+    interval_ms: u64,
+    timeout_ms: u64,
+    last_sent_ms: u64,
+    last_received_ms: u64,
+    ping_sent_ms: Option<u64>,
+}
+
+impl KeepAlive {
+    pub const fn new(interval_ms: u64, timeout_ms: u64, now_ms: u64) -> Self {
+        Self {
+            interval_ms,
+            timeout_ms,
+            last_sent_ms: now_ms,
+            last_received_ms: now_ms,
+            ping_sent_ms: None,
+        }
+    }
+
+    /// A packet (including a ping) was sent.
+    pub fn sent(&mut self, now_ms: u64) {
+        self.last_sent_ms = now_ms;
+    }
+
+    /// A ping was sent.
+    pub fn pinged(&mut self, now_ms: u64) {
+        self.sent(now_ms);
+        self.ping_sent_ms = Some(now_ms);
+    }
+
+    /// A packet (of any kind) was received, so the broker is alive.
+    pub fn received(&mut self, now_ms: u64) {
+        self.last_received_ms = now_ms;
+        self.ping_sent_ms = None;
+    }
+
+    pub fn poll(&self, now_ms: u64) -> KeepAliveAction {
+        match self.ping_sent_ms {
+            Some(pinged) if now_ms.saturating_sub(pinged) >= self.timeout_ms => {
+                KeepAliveAction::Dead
+            }
+            Some(_) => KeepAliveAction::None,
+            None if now_ms >= self.last_sent_ms + self.interval_ms
+                || now_ms >= self.last_received_ms + self.interval_ms =>
+            {
+                KeepAliveAction::Ping
+            }
+            None => KeepAliveAction::None,
+        }
+    }
+
+    /// When [`poll`](Self::poll) next needs calling, if nothing is sent or received before.
+    pub fn deadline_ms(&self) -> u64 {
+        match self.ping_sent_ms {
+            Some(pinged) => pinged + self.timeout_ms,
+            None => self.last_sent_ms.min(self.last_received_ms) + self.interval_ms,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_doubles_up_to_max() {
+        let mut backoff = Backoff::new(500, 3000);
+        let delays: Vec<u32> = (0..5).map(|_| backoff.next_delay()).collect();
+        assert_eq!(delays, [500, 1000, 2000, 3000, 3000]);
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), 500);
+    }
+
+    #[test]
+    fn test_connection_lifecycle() {
+        let mut conn = Connection::new(Backoff::new(1000, 8000));
+        assert_eq!(conn.state(), State::Connecting { attempt: 1 });
+
+        // Broker absent at boot: keep trying, backing off
+        assert_eq!(conn.failed(), 1000);
+        assert_eq!(conn.state(), State::Backoff { delay_ms: 1000 });
+        conn.retry();
+        assert_eq!(conn.state(), State::Connecting { attempt: 2 });
+        assert_eq!(conn.failed(), 2000);
+        conn.retry();
+        conn.established();
+        assert_eq!(conn.state(), State::Connected);
+
+        // Lost: the first retry is quick again
+        assert_eq!(conn.failed(), 1000);
+        conn.retry();
+        assert_eq!(conn.state(), State::Connecting { attempt: 1 });
+    }
+
+    #[test]
+    fn test_keep_alive_pings_when_idle() {
+        let mut ka = KeepAlive::new(30_000, 10_000, 0);
+        assert_eq!(ka.poll(29_999), KeepAliveAction::None);
+        assert_eq!(ka.deadline_ms(), 30_000);
+
+        // Sending regularly isn't enough - we also need to hear from the broker
+        ka.sent(20_000);
+        assert_eq!(ka.poll(30_000), KeepAliveAction::Ping);
+
+        ka.pinged(30_000);
+        assert_eq!(ka.poll(35_000), KeepAliveAction::None);
+        ka.received(35_000);
+        assert_eq!(ka.poll(59_999), KeepAliveAction::None);
+        assert_eq!(ka.deadline_ms(), 60_000);
+    }
+
+    #[test]
+    fn test_keep_alive_detects_dead_broker() {
+        let mut ka = KeepAlive::new(30_000, 10_000, 0);
+        ka.pinged(30_000);
+        assert_eq!(ka.deadline_ms(), 40_000);
+        // Still sending (e.g. telemetry) doesn't help
+        ka.sent(35_000);
+        assert_eq!(ka.poll(39_999), KeepAliveAction::None);
+        assert_eq!(ka.poll(40_000), KeepAliveAction::Dead);
+    }
+}
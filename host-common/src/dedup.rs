@@ -0,0 +1,85 @@
+//! Recognising redelivered commands.
+//!
+//! Commands arrive with QoS 1, so the broker may deliver one again, e.g. when our `PUBACK` was
+//! lost with the connection. [`RecentCommands`] remembers the ids of the last `N` commands and
+//! how each turned out, so that a repeat is acknowledged again with the same result instead of
+//! being carried out twice. Ids are only unique per sender run (see
+//! [`protocol::CommandEnvelope`]), so commands are told apart by both.
+
+use protocol::CommandError;
+
+/// The outcome of the last `N` commands, by sender and id.
+#[derive(Debug, Clone)]
+pub struct RecentCommands<const N: usize> {
+    entries: heapless::Deque<((u32, u32), Result<(), CommandError>), N>,
+}
+
+impl<const N: usize> RecentCommands<N> {
+    pub const fn new() -> Self {
+        Self {
+            entries: heapless::Deque::new(),
+        }
+    }
+
+    /// The result of command `id` from `sender`, if it was carried out recently.
+    pub fn get(&self, sender: u32, id: u32) -> Option<Result<(), CommandError>> {
+        self.entries
+            .iter()
+            .find(|(seen, _)| *seen == (sender, id))
+            .map(|(_, result)| *result)
+    }
+
+    /// Remember that command `id` from `sender` was carried out, forgetting the oldest if full.
+    pub fn insert(&mut self, sender: u32, id: u32, result: Result<(), CommandError>) {
+        if self.entries.is_full() {
+            self.entries.pop_front();
+        }
+        // Can't fail, there's room
+        let _ = self.entries.push_back(((sender, id), result));
+    }
+}
+
+impl<const N: usize> Default for RecentCommands<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repeats_are_recognised() {
+        let mut recent = RecentCommands::<4>::new();
+        assert_eq!(recent.get(9, 1), None);
+
+        recent.insert(9, 1, Ok(()));
+        recent.insert(9, 2, Err(CommandError::Busy));
+        assert_eq!(recent.get(9, 1), Some(Ok(())));
+        assert_eq!(recent.get(9, 2), Some(Err(CommandError::Busy)));
+        assert_eq!(recent.get(9, 3), None);
+    }
+
+    #[test]
+    fn test_restarted_sender_is_not_repeating() {
+        let mut recent = RecentCommands::<4>::new();
+        recent.insert(9, 1, Ok(()));
+        // Another run of the sender may use the same ids for other commands
+        assert_eq!(recent.get(10, 1), None);
+        recent.insert(10, 1, Err(CommandError::WrongMode));
+        assert_eq!(recent.get(9, 1), Some(Ok(())));
+        assert_eq!(recent.get(10, 1), Some(Err(CommandError::WrongMode)));
+    }
+
+    #[test]
+    fn test_oldest_is_forgotten() {
+        let mut recent = RecentCommands::<2>::new();
+        recent.insert(9, 1, Ok(()));
+        recent.insert(9, 2, Ok(()));
+        recent.insert(9, 3, Err(CommandError::OutOfRange));
+        assert_eq!(recent.get(9, 1), None);
+        assert_eq!(recent.get(9, 2), Some(Ok(())));
+        assert_eq!(recent.get(9, 3), Some(Err(CommandError::OutOfRange)));
+    }
+}
@@ -0,0 +1,290 @@
+// Drawing primitives over a row-major RGB pixel buffer (top-left origin, 3 bytes per pixel).
+//
+// Coordinates are signed so that shapes may start or extend off-panel; anything outside the
+// canvas is clipped.
+
+use crate::font::{GLYPH_HEIGHT, GLYPH_WIDTH, glyph};
+
+const BYTES_PER_PIXEL: usize = 3;
+
+pub type Color = (u8, u8, u8);
+
+pub struct Canvas<'a> {
+    buf: &'a mut [u8],
+    width: usize,
+    height: usize,
+}
+
+impl<'a> Canvas<'a> {
+    /// Panics if `buf` is smaller than `width * height * 3` bytes.
+    pub fn new(buf: &'a mut [u8], width: usize, height: usize) -> Self {
+        let len = width * height * BYTES_PER_PIXEL;
+        assert!(buf.len() >= len, "pixel buffer too small for canvas");
+        Self {
+            buf: &mut buf[..len],
+            width,
+            height,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    fn offset(&self, x: i32, y: i32) -> Option<usize> {
+        if x < 0 || y < 0 || x as usize >= self.width || y as usize >= self.height {
+            return None;
+        }
+        Some((y as usize * self.width + x as usize) * BYTES_PER_PIXEL)
+    }
+
+    pub fn pixel(&self, x: i32, y: i32) -> Option<Color> {
+        let o = self.offset(x, y)?;
+        Some((self.buf[o], self.buf[o + 1], self.buf[o + 2]))
+    }
+
+    pub fn set_pixel(&mut self, x: i32, y: i32, (r, g, b): Color) {
+        if let Some(o) = self.offset(x, y) {
+            self.buf[o..o + BYTES_PER_PIXEL].copy_from_slice(&[r, g, b]);
+        }
+    }
+
+    pub fn fill(&mut self, (r, g, b): Color) {
+        for px in self.buf.as_chunks_mut::<BYTES_PER_PIXEL>().0 {
+            *px = [r, g, b];
+        }
+    }
+
+    pub fn fill_rect(&mut self, x: i32, y: i32, width: u32, height: u32, color: Color) {
+        // Clip to the canvas before iterating, so huge rectangles stay cheap.
+        let x0 = x.max(0);
+        let y0 = y.max(0);
+        let x1 = x.saturating_add_unsigned(width).min(self.width as i32);
+        let y1 = y.saturating_add_unsigned(height).min(self.height as i32);
+
+        for py in y0..y1 {
+            for px in x0..x1 {
+                self.set_pixel(px, py, color);
+            }
+        }
+    }
+
+    /// Bresenham line, inclusive of both end points.
+    pub fn draw_line(&mut self, (x0, y0): (i32, i32), (x1, y1): (i32, i32), color: Color) {
+        let dx = (x1 - x0).abs();
+        let dy = -(y1 - y0).abs();
+        let sx = if x0 < x1 { 1 } else { -1 };
+        let sy = if y0 < y1 { 1 } else { -1 };
+        let mut err = dx + dy;
+        let (mut x, mut y) = (x0, y0);
+
+        loop {
+            self.set_pixel(x, y, color);
+            if x == x1 && y == y1 {
+                break;
+            }
+            let e2 = 2 * err;
+            if e2 >= dy {
+                err += dy;
+                x += sx;
+            }
+            if e2 <= dx {
+                err += dx;
+                y += sy;
+            }
+        }
+    }
+
+    /// Draw `text` in the built-in 3x5 font, with `(x, y)` at the top-left of the first glyph.
+    /// A `'\n'` starts a new line below.
+    pub fn draw_text(&mut self, (x, y): (i32, i32), text: &str, color: Color) {
+        const ADVANCE_X: i32 = GLYPH_WIDTH as i32 + 1;
+        const ADVANCE_Y: i32 = GLYPH_HEIGHT as i32 + 1;
+
+        let (mut cx, mut cy) = (x, y);
+        for c in text.chars() {
+            if c == '\n' {
+                cx = x;
+                cy += ADVANCE_Y;
+                continue;
+            }
+            for (row, bits) in glyph(c).iter().enumerate() {
+                for col in 0..GLYPH_WIDTH {
+                    if bits & (1 << (GLYPH_WIDTH - 1 - col)) != 0 {
+                        self.set_pixel(cx + col as i32, cy + row as i32, color);
+                    }
+                }
+            }
+            cx += ADVANCE_X;
+        }
+    }
+
+    /// Copy a `width * height` block of row-major RGB `data` to `(x, y)`.
+    /// Pixels beyond the end of `data` are left untouched.
+    pub fn blit(&mut self, (x, y): (i32, i32), width: usize, height: usize, data: &[u8]) {
+        for (i, &[r, g, b]) in data
+            .as_chunks::<BYTES_PER_PIXEL>()
+            .0
+            .iter()
+            .take(width * height)
+            .enumerate()
+        {
+            let px_x = x + (i % width) as i32;
+            let px_y = y + (i / width) as i32;
+            self.set_pixel(px_x, px_y, (r, g, b));
+        }
+    }
+
+    /// Shift the whole canvas by `(dx, dy)`. Positive `dx` moves content right, positive `dy`
+    /// moves it down. Vacated pixels wrap around from the opposite edge, or are cleared to black.
+    pub fn scroll(&mut self, dx: i32, dy: i32, wrap: bool) {
+        let (w, h) = (self.width as i32, self.height as i32);
+        if w == 0 || h == 0 {
+            return;
+        }
+        if !wrap && (dx.abs() >= w || dy.abs() >= h) {
+            self.fill((0, 0, 0));
+            return;
+        }
+
+        let row_len = self.width * BYTES_PER_PIXEL;
+        let shift_x = dx.rem_euclid(w) as usize * BYTES_PER_PIXEL;
+        let shift_y = dy.rem_euclid(h) as usize * row_len;
+
+        for row in self.buf.chunks_exact_mut(row_len) {
+            row.rotate_right(shift_x);
+        }
+        self.buf.rotate_right(shift_y);
+
+        if !wrap {
+            let black = (0, 0, 0);
+            if dx > 0 {
+                self.fill_rect(0, 0, dx as u32, h as u32, black);
+            } else if dx < 0 {
+                self.fill_rect(w + dx, 0, dx.unsigned_abs(), h as u32, black);
+            }
+            if dy > 0 {
+                self.fill_rect(0, 0, w as u32, dy as u32, black);
+            } else if dy < 0 {
+                self.fill_rect(0, h + dy, w as u32, dy.unsigned_abs(), black);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const W: usize = 8;
+    const H: usize = 6;
+    const RED: Color = (255, 0, 0);
+    const BLACK: Color = (0, 0, 0);
+
+    fn lit(canvas: &Canvas) -> Vec<(i32, i32)> {
+        let mut v = Vec::new();
+        for y in 0..canvas.height() as i32 {
+            for x in 0..canvas.width() as i32 {
+                if canvas.pixel(x, y) != Some(BLACK) {
+                    v.push((x, y));
+                }
+            }
+        }
+        v
+    }
+
+    #[test]
+    fn test_set_pixel_clips() {
+        let mut buf = [0u8; W * H * 3];
+        let mut c = Canvas::new(&mut buf, W, H);
+        c.set_pixel(-1, 0, RED);
+        c.set_pixel(W as i32, 0, RED);
+        c.set_pixel(0, H as i32, RED);
+        c.set_pixel(1, 2, RED);
+        assert_eq!(lit(&c), vec![(1, 2)]);
+        assert_eq!(&buf[(2 * W + 1) * 3..][..3], &[255, 0, 0]);
+    }
+
+    #[test]
+    fn test_fill_rect_clips() {
+        let mut buf = [0u8; W * H * 3];
+        let mut c = Canvas::new(&mut buf, W, H);
+        c.fill_rect(-2, -2, 4, 3, RED);
+        assert_eq!(lit(&c), vec![(0, 0), (1, 0)]);
+
+        c.fill((0, 0, 0));
+        c.fill_rect(6, 4, 100, 100, RED);
+        assert_eq!(lit(&c), vec![(6, 4), (7, 4), (6, 5), (7, 5)]);
+    }
+
+    #[test]
+    fn test_draw_line() {
+        let mut buf = [0u8; W * H * 3];
+        let mut c = Canvas::new(&mut buf, W, H);
+        c.draw_line((3, 3), (0, 0), RED);
+        assert_eq!(lit(&c), vec![(0, 0), (1, 1), (2, 2), (3, 3)]);
+
+        c.fill(BLACK);
+        c.draw_line((0, 5), (7, 5), RED);
+        assert_eq!(lit(&c).len(), W);
+
+        c.fill(BLACK);
+        c.draw_line((2, 2), (2, 2), RED);
+        assert_eq!(lit(&c), vec![(2, 2)]);
+    }
+
+    #[test]
+    fn test_draw_text() {
+        let mut buf = [0u8; W * H * 3];
+        let mut c = Canvas::new(&mut buf, W, H);
+        c.draw_text((0, 0), "1-", RED);
+        #[rustfmt::skip]
+        assert_eq!(
+            lit(&c),
+            vec![
+                (1, 0),
+                (0, 1), (1, 1),
+                (1, 2), (4, 2), (5, 2), (6, 2),
+                (1, 3),
+                (0, 4), (1, 4), (2, 4),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_blit_partial_and_clipped() {
+        let mut buf = [0u8; W * H * 3];
+        let mut c = Canvas::new(&mut buf, W, H);
+        let data = [1, 1, 1, 2, 2, 2, 3, 3, 3, 4, 4, 4];
+        c.blit((7, 4), 2, 2, &data);
+        assert_eq!(c.pixel(7, 4), Some((1, 1, 1)));
+        assert_eq!(c.pixel(7, 5), Some((3, 3, 3)));
+        assert_eq!(lit(&c), vec![(7, 4), (7, 5)]);
+    }
+
+    #[test]
+    fn test_scroll() {
+        let mut buf = [0u8; W * H * 3];
+        let mut c = Canvas::new(&mut buf, W, H);
+        c.set_pixel(0, 0, RED);
+        c.set_pixel(7, 5, (0, 255, 0));
+
+        c.scroll(1, 1, true);
+        assert_eq!(c.pixel(1, 1), Some(RED));
+        assert_eq!(c.pixel(0, 0), Some((0, 255, 0)));
+
+        c.scroll(-1, -1, false);
+        assert_eq!(lit(&c), vec![(0, 0)]);
+
+        c.scroll(0, -1, false);
+        assert!(lit(&c).is_empty());
+
+        c.set_pixel(3, 3, RED);
+        c.scroll(W as i32, 0, false);
+        assert!(lit(&c).is_empty());
+    }
+}
@@ -0,0 +1,184 @@
+//! Putting WASM guest modules back together from the chunks they're sent in (see
+//! [`protocol::guest`]).
+//!
+//! Chunks arrive with QoS 1, so the broker may deliver one again: a chunk already received is
+//! ignored. Any other chunk that isn't where the transfer is up to abandons it, and a chunk of
+//! another transfer starts afresh. The module is allocated in full when its transfer starts,
+//! and the previous one freed first.
+
+use alloc::vec::Vec;
+use protocol::guest::GuestChunk;
+
+/// Why a chunk was dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TransferError {
+    /// Not at the offset expected, so the transfer was abandoned
+    OutOfOrder { transfer: u32, offset: u32 },
+    /// No room on the heap for a module this large
+    OutOfMemory(u32),
+}
+
+/// The transfer under way, if any.
+#[derive(Debug, Default)]
+pub struct Assembler {
+    transfer: Option<Transfer>,
+}
+
+#[derive(Debug)]
+struct Transfer {
+    id: u32,
+    total: u32,
+    /// Bytes of the module received so far, in order
+    received: usize,
+    /// Taken once complete
+    module: Vec<u8>,
+    complete: bool,
+}
+
+impl Assembler {
+    pub const fn new() -> Self {
+        Self { transfer: None }
+    }
+
+    /// Add `chunk` to its transfer, returning the module once all of it has arrived.
+    pub fn receive(&mut self, chunk: &GuestChunk<'_>) -> Result<Option<Vec<u8>>, TransferError> {
+        let transfer = match &mut self.transfer {
+            Some(transfer) if transfer.id == chunk.transfer && transfer.total == chunk.total => {
+                transfer
+            }
+            _ => {
+                self.transfer = None;
+                if chunk.offset != 0 {
+                    return Err(TransferError::OutOfOrder {
+                        transfer: chunk.transfer,
+                        offset: chunk.offset,
+                    });
+                }
+                let mut module = Vec::new();
+                module
+                    .try_reserve_exact(chunk.total as usize)
+                    .map_err(|_| TransferError::OutOfMemory(chunk.total))?;
+                self.transfer.insert(Transfer {
+                    id: chunk.transfer,
+                    total: chunk.total,
+                    received: 0,
+                    module,
+                    complete: false,
+                })
+            }
+        };
+
+        // Can't overflow, `GuestChunk::parse` checked it ends within the module
+        let (offset, end) = (
+            chunk.offset as usize,
+            chunk.offset as usize + chunk.data.len(),
+        );
+        if transfer.complete || (offset < transfer.received && end <= transfer.received) {
+            // Redelivered
+            return Ok(None);
+        }
+        if offset != transfer.received {
+            self.transfer = None;
+            return Err(TransferError::OutOfOrder {
+                transfer: chunk.transfer,
+                offset: chunk.offset,
+            });
+        }
+
+        transfer.module.extend_from_slice(chunk.data);
+        transfer.received = end;
+        if !chunk.is_last() {
+            return Ok(None);
+        }
+        transfer.complete = true;
+        Ok(Some(core::mem::take(&mut transfer.module)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(transfer: u32, offset: u32, total: u32, data: &[u8]) -> Vec<u8> {
+        let mut payload = GuestChunk::header(transfer, offset, total).to_vec();
+        payload.extend_from_slice(data);
+        payload
+    }
+
+    fn receive(
+        assembler: &mut Assembler,
+        payload: &[u8],
+    ) -> Result<Option<Vec<u8>>, TransferError> {
+        assembler.receive(&GuestChunk::parse(payload).unwrap())
+    }
+
+    #[test]
+    fn test_chunks_make_the_module() {
+        let mut assembler = Assembler::new();
+        assert_eq!(
+            receive(&mut assembler, &payload(7, 0, 5, &[1, 2])),
+            Ok(None)
+        );
+        assert_eq!(
+            receive(&mut assembler, &payload(7, 2, 5, &[3, 4])),
+            Ok(None)
+        );
+        assert_eq!(
+            receive(&mut assembler, &payload(7, 4, 5, &[5])),
+            Ok(Some(vec![1, 2, 3, 4, 5]))
+        );
+    }
+
+    #[test]
+    fn test_redelivered_chunks_are_ignored() {
+        let mut assembler = Assembler::new();
+        let first = payload(7, 0, 4, &[1, 2]);
+        let last = payload(7, 2, 4, &[3, 4]);
+        receive(&mut assembler, &first).unwrap();
+        assert_eq!(receive(&mut assembler, &first), Ok(None));
+        assert_eq!(receive(&mut assembler, &last), Ok(Some(vec![1, 2, 3, 4])));
+        // Not loaded twice
+        assert_eq!(receive(&mut assembler, &last), Ok(None));
+    }
+
+    #[test]
+    fn test_gap_abandons_the_transfer() {
+        let mut assembler = Assembler::new();
+        receive(&mut assembler, &payload(7, 0, 6, &[1, 2])).unwrap();
+        assert_eq!(
+            receive(&mut assembler, &payload(7, 4, 6, &[5, 6])),
+            Err(TransferError::OutOfOrder {
+                transfer: 7,
+                offset: 4
+            })
+        );
+        // The missing chunk is too late now
+        assert_eq!(
+            receive(&mut assembler, &payload(7, 2, 6, &[3, 4])),
+            Err(TransferError::OutOfOrder {
+                transfer: 7,
+                offset: 2
+            })
+        );
+    }
+
+    #[test]
+    fn test_new_transfer_starts_afresh() {
+        let mut assembler = Assembler::new();
+        receive(&mut assembler, &payload(7, 0, 4, &[1, 2])).unwrap();
+        assert_eq!(receive(&mut assembler, &payload(8, 0, 2, &[9])), Ok(None));
+        assert_eq!(
+            receive(&mut assembler, &payload(8, 1, 2, &[8])),
+            Ok(Some(vec![9, 8]))
+        );
+        // The rest of the old one is no use
+        assert_eq!(
+            receive(&mut assembler, &payload(7, 2, 4, &[3, 4])),
+            Err(TransferError::OutOfOrder {
+                transfer: 7,
+                offset: 2
+            })
+        );
+    }
+}
@@ -1,14 +1,41 @@
 #![cfg_attr(not(test), no_std)]
 
+// Deployed guest modules are put back together on the heap
+extern crate alloc;
+
+pub mod connection;
+pub mod dedup;
+pub mod draw;
+mod font;
+pub mod guest;
+pub mod power;
+#[cfg(feature = "mqtt")]
+pub mod session;
+pub mod stats;
+pub mod store;
+
+use protocol::config::{Corner, PanelLayout};
+
 #[inline(always)]
 pub fn serpentine_index(x: usize, y: usize, width: usize, height: usize) -> usize {
-    let py = height - 1 - y; // flip: framebuffer top-left → physical bottom-left
-    if py.is_multiple_of(2) {
-        // Even physical rows (0, 2, ...): left to right
-        py * width + x
+    panel_index(x, y, width, height, PanelLayout::default())
+}
+
+/// The strip index of framebuffer pixel (x, y), for a panel wired as `layout`.
+#[inline(always)]
+pub fn panel_index(x: usize, y: usize, width: usize, height: usize, layout: PanelLayout) -> usize {
+    // Physical row and column, counted from the first LED's corner
+    let (px, py) = match layout.first_led {
+        Corner::BottomLeft => (x, height - 1 - y),
+        Corner::BottomRight => (width - 1 - x, height - 1 - y),
+        Corner::TopLeft => (x, y),
+        Corner::TopRight => (width - 1 - x, y),
+    };
+    if layout.serpentine && !py.is_multiple_of(2) {
+        // Odd physical rows (1, 3, ...) run back the other way
+        py * width + (width - 1 - px)
     } else {
-        // Odd physical rows (1, 3, ...): right to left
-        py * width + (width - 1 - x)
+        py * width + px
     }
 }
 
@@ -26,4 +53,23 @@ mod tests {
         assert_eq!(serpentine_index(0, 15, 16, 16), 0);
         assert_eq!(serpentine_index(15, 15, 16, 16), 15);
     }
+
+    #[test]
+    fn test_panel_index_layouts() {
+        let layout = |first_led, serpentine| PanelLayout {
+            first_led,
+            serpentine,
+        };
+        // 4x2: the first LED's corner is index 0, and the second row starts above/below it
+        // unless serpentine
+        let top_left = layout(Corner::TopLeft, false);
+        assert_eq!(panel_index(0, 0, 4, 2, top_left), 0);
+        assert_eq!(panel_index(0, 1, 4, 2, top_left), 4);
+        let top_right = layout(Corner::TopRight, true);
+        assert_eq!(panel_index(3, 0, 4, 2, top_right), 0);
+        assert_eq!(panel_index(3, 1, 4, 2, top_right), 7);
+        let bottom_right = layout(Corner::BottomRight, false);
+        assert_eq!(panel_index(3, 1, 4, 2, bottom_right), 0);
+        assert_eq!(panel_index(0, 0, 4, 2, bottom_right), 7);
+    }
 }
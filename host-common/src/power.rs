@@ -0,0 +1,33 @@
+//! Power draw estimate for a WS2812B panel, from the values written to it.
+//!
+//! Each colour channel draws roughly 20 mA at full duty, linearly with its value, and each LED
+//! draws about 1 mA when dark. It's a rough figure, but good enough to size a supply or spot a
+//! frame that would brown one out.
+
+/// Current per colour channel at full duty, in µA
+pub const CHANNEL_MAX_UA: u32 = 20_000;
+/// Current per LED when dark, in µA
+pub const LED_IDLE_UA: u32 = 1_000;
+pub const SUPPLY_MV: u32 = 5_000;
+
+/// Estimated power in mW for `num_leds` LEDs whose channel values (after gamma and brightness)
+/// add up to `channel_sum`.
+pub fn estimate_mw(channel_sum: u32, num_leds: u32) -> u32 {
+    let ua =
+        channel_sum as u64 * CHANNEL_MAX_UA as u64 / 255 + num_leds as u64 * LED_IDLE_UA as u64;
+    (ua * SUPPLY_MV as u64 / 1_000_000) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate_mw() {
+        // 256 LEDs: dark, then full white
+        assert_eq!(estimate_mw(0, 256), 1280);
+        assert_eq!(estimate_mw(256 * 3 * 255, 256), 78_080);
+        // One channel at half: 10 mA + 1 mA idle
+        assert_eq!(estimate_mw(127, 1), 54);
+    }
+}
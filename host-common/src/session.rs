@@ -0,0 +1,726 @@
+//! The device's MQTT session, over any transport implementing the `embedded-io-async` traits.
+//!
+//! [`run`] connects to the broker and keeps reconnecting, with backoff, whenever the connection
+//! fails or is lost. Each session subscribes and announces the device afresh. If the broker kept
+//! the session, it also delivers the commands sent while we were away. The session then
+//! publishes telemetry and handles incoming messages until something fails, including the
+//! chunks of WASM guest modules, which it puts back together for the device to load.
+//!
+//! What's particular to the device - reaching the broker, carrying out commands, showing frames
+//! and reporting telemetry - is left to a [`Transport`] and a [`Handler`], so that the session
+//! also runs natively, against a real broker in the tests.
+
+use alloc::vec::Vec;
+use core::convert::Infallible;
+
+use embassy_futures::select::{Either3, select3};
+use embassy_time::{Duration, Instant, Ticker, Timer};
+use embedded_io_async::{Read, Write};
+use protocol::config::{DeviceConfig, MAX_CONFIG_LEN, MqttConfig};
+use protocol::envelope::{EnvelopeHeader, ReplyTopic};
+use protocol::guest::GuestChunk;
+use protocol::ping::{PingRequest, PingResponse};
+use protocol::presence::{self, DeviceInfo};
+use protocol::telemetry::{MAX_TELEMETRY_LEN, Telemetry};
+use protocol::topics::suffix::{
+    BLIT, COMMAND, COMMAND_ACK, CONFIG, GUEST, INFO, PING_REQUEST, PING_RESPONSE, STATUS, STREAM,
+    TELEMETRY,
+};
+use protocol::topics::{DEFAULT_PREFIX, device_topic, parse_device_topic};
+use protocol::{BinaryBlit, Command, CommandAck, CommandEnvelope, CommandError, PROTOCOL_VERSION};
+use rust_mqtt::client::event::{Event, Suback};
+use rust_mqtt::client::options::{PublicationOptions, RetainHandling, SubscriptionOptions};
+use rust_mqtt::types::{QoS, TopicName};
+use rust_mqtt::{
+    Bytes,
+    buffer::BumpBuffer,
+    client::{
+        Client,
+        options::{ConnectOptions, WillOptions},
+    },
+    config::{KeepAlive, SessionExpiryInterval},
+    types::{MqttBinary, MqttString},
+};
+
+use crate::connection::{Backoff, Connection, KeepAliveAction, State};
+use crate::dedup::RecentCommands;
+use crate::guest::Assembler;
+
+/// Logs with `defmt` if enabled. Otherwise the arguments are only type-checked, never formatted.
+macro_rules! log {
+    ($level:ident, $($arg:tt)*) => {{
+        #[cfg(feature = "defmt")]
+        defmt::$level!($($arg)*);
+        #[cfg(not(feature = "defmt"))]
+        let _ = core::format_args!($($arg)*);
+    }};
+}
+
+const TELEMETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Requested from the broker, and how often we ping it when the connection is otherwise idle
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+/// How long the broker has to answer a ping before we give up on the connection
+const PING_TIMEOUT: Duration = Duration::from_secs(10);
+
+const RECONNECT_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_MAX: Duration = Duration::from_secs(60);
+
+/// How long the broker keeps our session - subscriptions and undelivered QoS 1 commands - after
+/// we drop off. Long enough for a WiFi glitch or a restart, short enough not to replay commands
+/// that have long stopped mattering.
+const SESSION_EXPIRY_S: u32 = 300;
+
+/// Commands remembered for de-duplication
+const RECENT_COMMANDS: usize = 16;
+
+/// Opens connections to the broker.
+pub trait Transport {
+    type Connection<'a>: Read + Write
+    where
+        Self: 'a;
+
+    /// A new connection to the broker, or `None` if it can't be reached this time. Called
+    /// again, after a backoff, once the connection fails.
+    fn connect(&mut self) -> impl Future<Output = Option<Self::Connection<'_>>>;
+}
+
+/// Carries out what arrives, and reports on the device.
+pub trait Handler {
+    /// Carry out an acknowledged, validated command. Unlike [`dispatch`](Self::dispatch), this
+    /// never waits: a full queue is reported as [`CommandError::Busy`] for the sender to retry.
+    /// The config asked for by [`Command::GetConfig`] is published by the session.
+    fn execute(&mut self, command: Command) -> Result<(), CommandError>;
+
+    /// Carry out a bare command, which isn't acknowledged.
+    fn dispatch(&mut self, command: Command) -> impl Future<Output = ()>;
+
+    /// A frame published on [`STREAM`], at up to 60 FPS.
+    fn stream(&mut self, frame: &[u8]);
+
+    /// A blit published on [`BLIT`].
+    fn blit(&mut self, blit: BinaryBlit<'_>) -> impl Future<Output = ()>;
+
+    /// A guest module deployed on [`GUEST`], once all of it has arrived, to replace the running
+    /// guest. One that can't be run should leave the running guest be.
+    fn load_guest(&mut self, module: Vec<u8>);
+
+    /// A report for [`TELEMETRY`], covering the time since the previous one.
+    fn telemetry(&mut self) -> Telemetry;
+
+    /// The current config, which is published without its passwords.
+    fn config(&self) -> DeviceConfig;
+}
+
+/// Who the device is, and how it logs in.
+pub struct Options<'a> {
+    /// Announced on [`INFO`]. Its `device_id` also names our topics and MQTT client id.
+    pub info: DeviceInfo,
+    /// Only the credentials are used, the [`Transport`] finds the broker
+    pub mqtt: &'a MqttConfig,
+    /// Sent back in ping responses
+    pub pong: &'a str,
+}
+
+/// Why a session ended.
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+enum SessionError {
+    Connect,
+    Subscribe,
+    Publish,
+    Receive,
+    /// The broker stopped answering pings
+    KeepAlive,
+}
+
+/// Run MQTT sessions over connections from `transport`, one after another, forever. `buf` must
+/// hold the largest inbound publish, at least a full guest chunk.
+pub async fn run<T: Transport, H: Handler>(
+    transport: &mut T,
+    handler: &mut H,
+    options: &Options<'_>,
+    buf: &mut [u8],
+) {
+    let mut connection = Connection::new(Backoff::new(
+        RECONNECT_MIN.as_millis() as u32,
+        RECONNECT_MAX.as_millis() as u32,
+    ));
+    // Outlives sessions, as a command may be redelivered in the next session
+    let mut recent = RecentCommands::<RECENT_COMMANDS>::new();
+    // Also outlives sessions, as the rest of a transfer is queued for us while we're away
+    let mut guest = Assembler::new();
+
+    loop {
+        if let State::Backoff { delay_ms } = connection.state() {
+            Timer::after_millis(delay_ms as u64).await;
+            connection.retry();
+        }
+        if let State::Connecting { attempt } = connection.state() {
+            log!(info, "Connecting to MQTT broker, attempt {}", attempt);
+        }
+
+        let Some(net) = transport.connect().await else {
+            let delay_ms = connection.failed();
+            log!(
+                warn,
+                "No connection to the broker, retrying in {} ms",
+                delay_ms
+            );
+            continue;
+        };
+
+        let Err(e) = session(
+            net,
+            buf,
+            options,
+            &mut connection,
+            &mut recent,
+            &mut guest,
+            handler,
+        )
+        .await;
+        let delay_ms = connection.failed();
+        log!(
+            warn,
+            "MQTT session ended: {:?}, reconnecting in {} ms",
+            e,
+            delay_ms
+        );
+    }
+}
+
+/// One MQTT session over `net`: connect, subscribe, announce, then publish telemetry and handle
+/// incoming messages until something fails.
+async fn session<N: Read + Write, H: Handler>(
+    net: N,
+    buf: &mut [u8],
+    options: &Options<'_>,
+    connection: &mut Connection,
+    recent: &mut RecentCommands<RECENT_COMMANDS>,
+    guest: &mut Assembler,
+    handler: &mut H,
+) -> Result<Infallible, SessionError> {
+    let mut buffer = BumpBuffer::new(buf);
+
+    let mut client: MqttClient<'_, N> = Client::new(&mut buffer);
+
+    let device_id = &options.info.device_id;
+    let mqtt = options.mqtt;
+    let client_id = presence::client_id(device_id);
+    let status_topic = device_topic(device_id, STATUS);
+
+    let connect_options = ConnectOptions {
+        // Resume the session, to receive the commands queued for us
+        clean_start: false,
+        session_expiry_interval: SessionExpiryInterval::Seconds(SESSION_EXPIRY_S),
+        keep_alive: KeepAlive::Seconds(KEEP_ALIVE.as_secs() as u16),
+        user_name: (!mqtt.username.is_empty())
+            .then(|| MqttString::from_slice(mqtt.username.as_str()).unwrap()),
+        password: (!mqtt.password.is_empty())
+            .then(|| MqttBinary::from_slice(mqtt.password.as_str().as_bytes()).unwrap()),
+        // The broker marks us offline if we drop off without disconnecting
+        will: Some(WillOptions {
+            will_qos: QoS::AtLeastOnce,
+            will_retain: true,
+            will_topic: MqttString::from_slice(status_topic.as_str()).unwrap(),
+            will_payload: MqttBinary::try_from(presence::OFFLINE).unwrap(),
+            will_delay_interval: 0,
+            is_payload_utf8: true,
+            message_expiry_interval: None,
+            content_type: Some(MqttString::try_from("text/plain").unwrap()),
+            response_topic: None,
+            correlation_data: None,
+        }),
+    };
+
+    match client
+        .connect(
+            net,
+            &connect_options,
+            Some(MqttString::from_slice(client_id.as_str()).unwrap()),
+        )
+        .await
+    {
+        Ok(c) => log!(info, "Connected to MQTT broker: {:?}", c),
+        Err(e) => {
+            log!(error, "MQTT connect failed: {:?}", e);
+            return Err(SessionError::Connect);
+        }
+    }
+
+    // Subscribe even if the broker kept our session, whose subscriptions may be those of other
+    // firmware. Commands and guest chunks are QoS 1, so that they're queued while we're away;
+    // everything else is only worth having live.
+    for (suffix, qos) in [
+        (PING_REQUEST, QoS::AtMostOnce),
+        (BLIT, QoS::AtMostOnce),
+        (STREAM, QoS::AtMostOnce),
+        (COMMAND, QoS::AtLeastOnce),
+        (GUEST, QoS::AtLeastOnce),
+    ] {
+        let sub_options = SubscriptionOptions {
+            retain_handling: RetainHandling::SendIfNotSubscribedBefore,
+            retain_as_published: true,
+            no_local: false,
+            qos,
+        };
+        let topic = device_topic(device_id, suffix);
+        subscribe(
+            &mut client,
+            &topic,
+            sub_options,
+            options,
+            recent,
+            guest,
+            handler,
+        )
+        .await?;
+    }
+
+    // Birth message, replacing the retained Last Will from any previous connection
+    let status_options = PublicationOptions {
+        retain: true,
+        topic: unsafe {
+            TopicName::new_unchecked(MqttString::from_slice(status_topic.as_str()).unwrap())
+        },
+        qos: QoS::AtMostOnce,
+    };
+    match client
+        .publish(&status_options, Bytes::from(presence::ONLINE.as_bytes()))
+        .await
+    {
+        Ok(_) => log!(
+            info,
+            "Published {} to {}",
+            presence::ONLINE,
+            status_topic.as_str()
+        ),
+        Err(e) => {
+            log!(error, "Failed to publish status: {:?}", e);
+            return Err(SessionError::Publish);
+        }
+    }
+
+    // Announce ourselves, for discovery
+    let info_topic = device_topic(device_id, INFO);
+    let info_options = PublicationOptions {
+        retain: true,
+        topic: unsafe {
+            TopicName::new_unchecked(MqttString::from_slice(info_topic.as_str()).unwrap())
+        },
+        qos: QoS::AtMostOnce,
+    };
+    let info_payload: heapless::Vec<u8, 160> = serde_json_core::to_vec(&options.info).unwrap();
+    if let Err(e) = client
+        .publish(&info_options, Bytes::from(info_payload.as_slice()))
+        .await
+    {
+        log!(error, "Failed to publish device info: {:?}", e);
+        return Err(SessionError::Publish);
+    }
+
+    // Retained, so that subscribers always see the latest report
+    let telemetry_topic = device_topic(device_id, TELEMETRY);
+    let telemetry_options = PublicationOptions {
+        retain: true,
+        topic: unsafe {
+            TopicName::new_unchecked(MqttString::from_slice(telemetry_topic.as_str()).unwrap())
+        },
+        qos: QoS::AtMostOnce,
+    };
+
+    // Report straight away, then every TELEMETRY_INTERVAL
+    publish_telemetry(&mut client, &telemetry_options, handler).await?;
+    let mut ticker = Ticker::every(TELEMETRY_INTERVAL);
+
+    connection.established();
+    let mut keep_alive = crate::connection::KeepAlive::new(
+        KEEP_ALIVE.as_millis(),
+        PING_TIMEOUT.as_millis(),
+        Instant::now().as_millis(),
+    );
+
+    // Main loop: publish periodically + receive incoming messages
+    loop {
+        // Safe to reset here because we've finished processing any
+        // previous poll_body data by this point in the loop.
+        unsafe { client.buffer().reset() };
+
+        let keep_alive_deadline = Instant::from_millis(keep_alive.deadline_ms());
+        match select3(
+            ticker.next(),
+            Timer::at(keep_alive_deadline),
+            client.poll_header(),
+        )
+        .await
+        {
+            // Timer fired — publish telemetry
+            Either3::First(_) => {
+                publish_telemetry(&mut client, &telemetry_options, handler).await?;
+                keep_alive.sent(Instant::now().as_millis());
+            }
+
+            // Ping if idle, or give up on a broker that stopped answering
+            Either3::Second(_) => match keep_alive.poll(Instant::now().as_millis()) {
+                KeepAliveAction::None => {}
+                KeepAliveAction::Ping => {
+                    log!(debug, "Sending PINGREQ");
+                    client.ping().await.map_err(|e| {
+                        log!(error, "Ping failed: {:?}", e);
+                        SessionError::Publish
+                    })?;
+                    keep_alive.pinged(Instant::now().as_millis());
+                }
+                KeepAliveAction::Dead => {
+                    log!(
+                        error,
+                        "No response from broker within {} s of a ping",
+                        PING_TIMEOUT.as_secs()
+                    );
+                    client.abort().await;
+                    return Err(SessionError::KeepAlive);
+                }
+            },
+
+            // Incoming packet header received — read the body
+            Either3::Third(header_result) => {
+                let h = match header_result {
+                    Ok(h) => h,
+                    Err(e) => {
+                        log!(error, "poll_header failed: {:?}", e);
+                        return Err(SessionError::Receive);
+                    }
+                };
+                // Not `info` - stream frames arrive at up to 60 FPS.
+                log!(debug, "Received header {:?}", h.packet_type());
+
+                let event = client.poll_body(h).await;
+                if event.is_ok() {
+                    keep_alive.received(Instant::now().as_millis());
+                }
+                let replies = match event {
+                    Ok(Event::Publish(msg)) => {
+                        let topic = msg.topic.as_ref();
+                        handle_publish(topic, &msg.message, options, recent, guest, handler).await
+                    }
+                    Ok(e) => {
+                        log!(info, "Event: {:?}", e);
+                        Replies::default()
+                    }
+                    Err(e) => {
+                        log!(error, "poll_body failed: {:?}", e);
+                        return Err(SessionError::Receive);
+                    }
+                };
+
+                // The `msg` borrow is released here, so it's safe to publish the replies.
+                if send_replies(&mut client, replies, device_id, handler).await? {
+                    keep_alive.sent(Instant::now().as_millis());
+                }
+            }
+        }
+    }
+}
+
+/// Replies to a publish. Built while its payload borrows the client's buffer, then sent once
+/// it's released (publish needs `&mut client`).
+#[derive(Default)]
+struct Replies {
+    /// A pong or command ack
+    reply: Option<(ReplyTopic, heapless::Vec<u8, 160>)>,
+    /// The reply to `GetConfig`
+    config: bool,
+}
+
+/// Handle a publish on one of our device topics.
+async fn handle_publish<H: Handler>(
+    topic: &str,
+    payload: &[u8],
+    options: &Options<'_>,
+    recent: &mut RecentCommands<RECENT_COMMANDS>,
+    guest: &mut Assembler,
+    handler: &mut H,
+) -> Replies {
+    let device_id = &options.info.device_id;
+    let mut replies = Replies::default();
+    log!(
+        debug,
+        "Received publish on '{}', payload len={}",
+        topic,
+        payload.len()
+    );
+    // We only subscribe to our own device topics
+    let suffix = parse_device_topic(DEFAULT_PREFIX, topic)
+        .filter(|(id, _)| *id == device_id.as_str())
+        .map_or("", |(_, suffix)| suffix);
+
+    if suffix == STREAM {
+        handler.stream(payload);
+    } else if suffix == PING_REQUEST {
+        match serde_json_core::from_slice::<PingRequest>(payload) {
+            Ok((req, _)) => {
+                let resp = PingResponse {
+                    correlation_id: req.correlation_id,
+                    message: heapless::String::try_from(options.pong).unwrap_or_default(),
+                    protocol_version: PROTOCOL_VERSION,
+                };
+                match serde_json_core::to_vec(&resp) {
+                    Ok(p) => {
+                        let topic = device_topic(device_id, PING_RESPONSE);
+                        replies.reply = Some((ReplyTopic::try_from(topic.as_str()).unwrap(), p))
+                    }
+                    Err(_) => log!(warn, "Ping response payload too long"),
+                }
+            }
+            Err(_) => log!(warn, "Failed to parse ping request"),
+        }
+    } else if suffix == GUEST {
+        match GuestChunk::parse(payload).map(|chunk| guest.receive(&chunk)) {
+            Ok(Ok(Some(module))) => {
+                log!(info, "Received guest module, {} bytes", module.len());
+                handler.load_guest(module);
+            }
+            Ok(Ok(None)) => {}
+            Ok(Err(e)) => log!(warn, "Guest chunk dropped: {:?}", e),
+            Err(e) => log!(warn, "Invalid guest chunk: {:?}", e),
+        }
+    } else if suffix == BLIT {
+        match BinaryBlit::parse(payload) {
+            Ok(blit) => handler.blit(blit).await,
+            Err(e) => log!(warn, "Invalid binary blit: {:?}", e),
+        }
+    } else if suffix == COMMAND
+        && let Ok((header, _)) = serde_json_core::from_slice::<EnvelopeHeader>(payload)
+    {
+        let result = if let Some(result) = recent.get(header.sender, header.id) {
+            // Redelivered: acknowledge again, but don't repeat it
+            log!(
+                info,
+                "Repeated command #{}, not carried out again",
+                header.id
+            );
+            result
+        } else {
+            let result = match serde_json_core::from_slice::<CommandEnvelope>(payload) {
+                Ok((envelope, _)) => {
+                    log!(
+                        info,
+                        "Parsed command #{}: {:?}",
+                        header.id,
+                        envelope.command
+                    );
+                    replies.config = envelope.command == Command::GetConfig;
+                    envelope
+                        .command
+                        .validate()
+                        .and_then(|()| handler.execute(envelope.command))
+                }
+                Err(_) => {
+                    log!(warn, "Failed to parse command #{}", header.id);
+                    Err(CommandError::Parse)
+                }
+            };
+            // A `Busy` command wasn't carried out, so a repeat may still be
+            if result != Err(CommandError::Busy) {
+                recent.insert(header.sender, header.id, result);
+            }
+            result
+        };
+        let ack = match result {
+            Ok(()) => CommandAck::ok(header.id),
+            Err(e) => {
+                log!(info, "Rejected command #{}: {:?}", header.id, e);
+                CommandAck::nack(header.id, e)
+            }
+        };
+        let reply_to = header.reply_to.unwrap_or_else(|| {
+            let topic = device_topic(device_id, COMMAND_ACK);
+            ReplyTopic::try_from(topic.as_str()).unwrap()
+        });
+        match serde_json_core::to_vec(&ack) {
+            Ok(p) => replies.reply = Some((reply_to, p)),
+            Err(_) => log!(warn, "Command ack payload too long"),
+        }
+    } else if suffix == COMMAND {
+        // A bare command, without an envelope, isn't acknowledged
+        match serde_json_core::from_slice::<Command>(payload) {
+            Ok((command, _bytes_consumed)) => {
+                log!(info, "Parsed command: {:?}", command);
+                replies.config = command == Command::GetConfig;
+                handler.dispatch(command).await;
+            }
+            Err(_) => {
+                // Log what we received for debugging
+                if let Ok(s) = core::str::from_utf8(payload) {
+                    log!(warn, "Failed to parse: \"{}\"", s);
+                } else {
+                    log!(warn, "Failed to parse non-UTF8 payload");
+                }
+            }
+        }
+    } else {
+        log!(warn, "Publish on unexpected topic: {}", topic);
+    }
+    replies
+}
+
+/// Publish `replies`, returning whether there were any. The config goes first, so it has
+/// arrived by the time `GetConfig` is acknowledged.
+async fn send_replies<N: Read + Write, H: Handler>(
+    client: &mut MqttClient<'_, N>,
+    replies: Replies,
+    device_id: &str,
+    handler: &H,
+) -> Result<bool, SessionError> {
+    if replies.config {
+        publish_config(client, device_id, &handler.config()).await?;
+    }
+    let Some((reply_topic, payload)) = replies.reply else {
+        return Ok(replies.config);
+    };
+    let Ok(reply_topic_name) = MqttString::from_slice(reply_topic.as_str()) else {
+        log!(warn, "Invalid reply topic: {}", reply_topic.as_str());
+        return Ok(replies.config);
+    };
+    let resp_options = PublicationOptions {
+        retain: false,
+        topic: unsafe { TopicName::new_unchecked(reply_topic_name) },
+        qos: QoS::AtMostOnce,
+    };
+    match client
+        .publish(&resp_options, Bytes::from(payload.as_slice()))
+        .await
+    {
+        Ok(_) => log!(info, "Published reply to {}", reply_topic.as_str()),
+        Err(e) => {
+            log!(error, "Failed to publish reply: {:?}", e);
+            return Err(SessionError::Publish);
+        }
+    }
+    Ok(true)
+}
+
+/// One subscription in flight at a time; up to 4 QoS 1 commands from the broker awaiting our
+/// `PUBACK`; we only publish QoS 0.
+type MqttClient<'c, N> = Client<'c, N, BumpBuffer<'c>, 1, 4, 1>;
+
+/// Subscribe to `topic` and wait for the Suback.
+///
+/// Sequential (subscribe -> wait for Suback) keeps the client's MAX_SUBSCRIBES=1
+/// in-flight bound satisfied. Publishes that arrive meanwhile are handled as in the main loop:
+/// when the broker kept our session, the commands it queued arrive straight after connecting,
+/// and the client has already acknowledged them.
+async fn subscribe<N: Read + Write, H: Handler>(
+    client: &mut MqttClient<'_, N>,
+    topic: &str,
+    sub_options: SubscriptionOptions,
+    options: &Options<'_>,
+    recent: &mut RecentCommands<RECENT_COMMANDS>,
+    guest: &mut Assembler,
+    handler: &mut H,
+) -> Result<(), SessionError> {
+    let topic_name = unsafe { TopicName::new_unchecked(MqttString::from_slice(topic).unwrap()) };
+
+    match client.subscribe(topic_name.into(), sub_options).await {
+        Ok(_) => log!(info, "Sent Subscribe ({})", topic),
+        Err(e) => {
+            log!(error, "Failed to subscribe ({}): {:?}", topic, e);
+            return Err(SessionError::Subscribe);
+        }
+    };
+
+    loop {
+        let replies = match client.poll().await {
+            Ok(Event::Suback(Suback {
+                packet_identifier: _,
+                reason_code,
+            })) => {
+                log!(
+                    info,
+                    "Subscribed ({}) with reason code {:?}",
+                    topic,
+                    reason_code
+                );
+                return Ok(());
+            }
+            Ok(Event::Publish(msg)) => {
+                let topic = msg.topic.as_ref();
+                handle_publish(topic, &msg.message, options, recent, guest, handler).await
+            }
+            Ok(e) => {
+                log!(
+                    error,
+                    "Expected Suback ({}) but received event {:?}",
+                    topic,
+                    e
+                );
+                return Err(SessionError::Subscribe);
+            }
+            Err(e) => {
+                log!(error, "Failed to receive Suback ({}) {:?}", topic, e);
+                return Err(SessionError::Subscribe);
+            }
+        };
+        send_replies(client, replies, &options.info.device_id, handler).await?;
+        // The handled publish no longer borrows the buffer
+        unsafe { client.buffer().reset() };
+    }
+}
+
+async fn publish_telemetry<N: Read + Write, H: Handler>(
+    client: &mut MqttClient<'_, N>,
+    options: &PublicationOptions<'_>,
+    handler: &mut H,
+) -> Result<(), SessionError> {
+    let telemetry = handler.telemetry();
+    let payload: heapless::Vec<u8, MAX_TELEMETRY_LEN> = match serde_json_core::to_vec(&telemetry) {
+        Ok(p) => p,
+        Err(_) => {
+            log!(warn, "Telemetry payload too long");
+            return Ok(());
+        }
+    };
+    match client
+        .publish(options, Bytes::from(payload.as_slice()))
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            log!(error, "Telemetry publish failed: {:?}", e);
+            Err(SessionError::Publish)
+        }
+    }
+}
+
+/// Publish `config`, without passwords, in reply to `GetConfig`.
+async fn publish_config<N: Read + Write>(
+    client: &mut MqttClient<'_, N>,
+    device_id: &str,
+    config: &DeviceConfig,
+) -> Result<(), SessionError> {
+    let topic = device_topic(device_id, CONFIG);
+    let options = PublicationOptions {
+        retain: false,
+        topic: unsafe { TopicName::new_unchecked(MqttString::from_slice(topic.as_str()).unwrap()) },
+        qos: QoS::AtMostOnce,
+    };
+    let payload: heapless::Vec<u8, MAX_CONFIG_LEN> =
+        match serde_json_core::to_vec(&config.redacted()) {
+            Ok(p) => p,
+            Err(_) => {
+                log!(warn, "Config payload too long");
+                return Ok(());
+            }
+        };
+    match client
+        .publish(&options, Bytes::from(payload.as_slice()))
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            log!(error, "Config publish failed: {:?}", e);
+            Err(SessionError::Publish)
+        }
+    }
+}
@@ -0,0 +1,99 @@
+//! Frame rate and frame time statistics, recorded by the task writing frames and collected
+//! periodically by another.
+//!
+//! Collecting resets each counter separately, so a frame recorded at the same moment may be
+//! split across two periods. That's fine for reporting.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+pub struct FrameStats {
+    frames: AtomicU32,
+    total_us: AtomicU32,
+    min_us: AtomicU32,
+    max_us: AtomicU32,
+}
+
+/// Summary of the frames recorded over a period.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FrameTimes {
+    pub frames: u32,
+    pub fps: u16,
+    pub min_us: u32,
+    pub avg_us: u32,
+    pub max_us: u32,
+}
+
+impl FrameStats {
+    pub const fn new() -> Self {
+        Self {
+            frames: AtomicU32::new(0),
+            total_us: AtomicU32::new(0),
+            min_us: AtomicU32::new(u32::MAX),
+            max_us: AtomicU32::new(0),
+        }
+    }
+
+    pub fn record(&self, frame_us: u32) {
+        self.frames.fetch_add(1, Ordering::Relaxed);
+        self.total_us.fetch_add(frame_us, Ordering::Relaxed);
+        self.min_us.fetch_min(frame_us, Ordering::Relaxed);
+        self.max_us.fetch_max(frame_us, Ordering::Relaxed);
+    }
+
+    /// Summarise and reset the frames recorded over the last `elapsed_ms`.
+    pub fn take(&self, elapsed_ms: u32) -> FrameTimes {
+        let frames = self.frames.swap(0, Ordering::Relaxed);
+        let total_us = self.total_us.swap(0, Ordering::Relaxed);
+        let min_us = self.min_us.swap(u32::MAX, Ordering::Relaxed);
+        let max_us = self.max_us.swap(0, Ordering::Relaxed);
+
+        if frames == 0 {
+            return FrameTimes::default();
+        }
+        let fps = (frames as u64 * 1000 / elapsed_ms.max(1) as u64).min(u16::MAX as u64);
+        FrameTimes {
+            frames,
+            fps: fps as u16,
+            min_us,
+            avg_us: total_us / frames,
+            max_us,
+        }
+    }
+}
+
+impl Default for FrameStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take_summarises_and_resets() {
+        let stats = FrameStats::new();
+        for us in [1000, 3000, 2000] {
+            stats.record(us);
+        }
+        assert_eq!(
+            stats.take(100),
+            FrameTimes {
+                frames: 3,
+                fps: 30,
+                min_us: 1000,
+                avg_us: 2000,
+                max_us: 3000,
+            }
+        );
+        assert_eq!(stats.take(100), FrameTimes::default());
+    }
+
+    #[test]
+    fn test_no_elapsed_time() {
+        let stats = FrameStats::new();
+        stats.record(10);
+        assert_eq!(stats.take(0).fps, 1000);
+    }
+}
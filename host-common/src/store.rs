@@ -0,0 +1,280 @@
+//! Persistent [`DeviceConfig`] storage, independent of the flash driver.
+//!
+//! The config is stored at the start of an [`embedded_storage::Storage`] as a header followed
+//! by the JSON:
+//!
+//! | bytes | content                                  |
+//! |-------|------------------------------------------|
+//! | 0..4  | [`MAGIC`]                                |
+//! | 4..6  | format [`VERSION`], little-endian        |
+//! | 6..8  | JSON length, little-endian               |
+//! | 8..12 | CRC-32 (IEEE) of the JSON, little-endian |
+//!
+//! Anything that doesn't check out loads as a [`LoadError`], for the caller to fall back to
+//! its defaults. Fields added to [`DeviceConfig`] since a config was saved take their serde
+//! defaults, so a format version bump is only needed for changes serde can't absorb.
+
+use embedded_storage::{ReadStorage, Storage};
+use protocol::config::{DeviceConfig, MAX_CONFIG_LEN};
+
+pub const MAGIC: [u8; 4] = *b"LCFG";
+pub const VERSION: u16 = 1;
+pub const HEADER_LEN: usize = 12;
+
+/// Storage needed for the largest config.
+pub const MAX_STORED_LEN: usize = HEADER_LEN + MAX_CONFIG_LEN;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LoadError {
+    /// Nothing stored, e.g. erased flash or after [`ConfigStore::erase`]
+    Empty,
+    /// Stored by newer firmware
+    UnsupportedVersion(u16),
+    /// Bad length or CRC
+    Corrupt,
+    /// Intact, but not a config this firmware understands
+    Parse,
+    Storage,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SaveError {
+    TooLong,
+    Storage,
+}
+
+pub struct ConfigStore<S> {
+    storage: S,
+}
+
+impl<S: Storage> ConfigStore<S> {
+    pub const fn new(storage: S) -> Self {
+        Self { storage }
+    }
+
+    pub fn load(&mut self) -> Result<DeviceConfig, LoadError> {
+        let mut buf = [0u8; MAX_STORED_LEN];
+        let (header, json) = buf.split_at_mut(HEADER_LEN);
+        self.storage
+            .read(0, header)
+            .map_err(|_| LoadError::Storage)?;
+
+        if header[0..4] != MAGIC {
+            return Err(LoadError::Empty);
+        }
+        let version = u16::from_le_bytes([header[4], header[5]]);
+        if version != VERSION {
+            return Err(LoadError::UnsupportedVersion(version));
+        }
+        let len = u16::from_le_bytes([header[6], header[7]]) as usize;
+        let crc = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+        if len > MAX_CONFIG_LEN {
+            return Err(LoadError::Corrupt);
+        }
+
+        let json = &mut json[..len];
+        self.storage
+            .read(HEADER_LEN as u32, json)
+            .map_err(|_| LoadError::Storage)?;
+        if crc32(json) != crc {
+            return Err(LoadError::Corrupt);
+        }
+        serde_json_core::from_slice::<DeviceConfig>(json)
+            .map(|(config, _)| config)
+            .map_err(|_| LoadError::Parse)
+    }
+
+    pub fn save(&mut self, config: &DeviceConfig) -> Result<(), SaveError> {
+        let mut buf = [0u8; MAX_STORED_LEN];
+        let len = serde_json_core::to_slice(config, &mut buf[HEADER_LEN..])
+            .map_err(|_| SaveError::TooLong)?;
+        let crc = crc32(&buf[HEADER_LEN..][..len]);
+
+        buf[0..4].copy_from_slice(&MAGIC);
+        buf[4..6].copy_from_slice(&VERSION.to_le_bytes());
+        buf[6..8].copy_from_slice(&(len as u16).to_le_bytes());
+        buf[8..12].copy_from_slice(&crc.to_le_bytes());
+
+        self.storage
+            .write(0, &buf[..HEADER_LEN + len])
+            .map_err(|_| SaveError::Storage)
+    }
+
+    /// Forget the stored config, so that the next [`load`](Self::load) is [`LoadError::Empty`].
+    pub fn erase(&mut self) -> Result<(), S::Error> {
+        self.storage.write(0, &[0xff; HEADER_LEN])
+    }
+}
+
+/// CRC-32 (IEEE 802.3), as used by zlib.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+/// Storage in RAM, erased to `0xff` like flash. For tests and the emulator.
+pub struct MemStorage<const N: usize> {
+    pub data: [u8; N],
+}
+
+impl<const N: usize> MemStorage<N> {
+    pub const fn new() -> Self {
+        Self { data: [0xff; N] }
+    }
+}
+
+impl<const N: usize> Default for MemStorage<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct OutOfBounds;
+
+impl<const N: usize> MemStorage<N> {
+    fn range(offset: u32, len: usize) -> Result<core::ops::Range<usize>, OutOfBounds> {
+        let start = offset as usize;
+        let end = start.checked_add(len).filter(|&end| end <= N);
+        end.map(|end| start..end).ok_or(OutOfBounds)
+    }
+}
+
+impl<const N: usize> ReadStorage for MemStorage<N> {
+    type Error = OutOfBounds;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        bytes.copy_from_slice(&self.data[Self::range(offset, bytes.len())?]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        N
+    }
+}
+
+impl<const N: usize> Storage for MemStorage<N> {
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.data[Self::range(offset, bytes.len())?].copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::config::{Corner, MqttConfig, PanelLayout, Secret, WifiConfig};
+
+    fn config() -> DeviceConfig {
+        DeviceConfig {
+            wifi: WifiConfig {
+                ssid: heapless::String::try_from("home").unwrap(),
+                password: Secret(heapless::String::try_from("secret").unwrap()),
+            },
+            static_ip: None,
+            mqtt: MqttConfig {
+                host: heapless::String::new(),
+                broker: Some([192, 168, 1, 201]),
+                port: 1883,
+                username: heapless::String::new(),
+                password: Secret::default(),
+            },
+            brightness: 42,
+            panel: PanelLayout {
+                first_led: Corner::TopLeft,
+                serpentine: false,
+            },
+        }
+    }
+
+    fn store() -> ConfigStore<MemStorage<1024>> {
+        ConfigStore::new(MemStorage::new())
+    }
+
+    /// Store a raw JSON payload with a valid header, as older firmware would have.
+    fn store_json(store: &mut ConfigStore<MemStorage<1024>>, version: u16, json: &str) {
+        let data = &mut store.storage.data;
+        data[0..4].copy_from_slice(&MAGIC);
+        data[4..6].copy_from_slice(&version.to_le_bytes());
+        data[6..8].copy_from_slice(&(json.len() as u16).to_le_bytes());
+        data[8..12].copy_from_slice(&crc32(json.as_bytes()).to_le_bytes());
+        data[HEADER_LEN..][..json.len()].copy_from_slice(json.as_bytes());
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let mut store = store();
+        assert_eq!(store.load(), Err(LoadError::Empty));
+
+        store.save(&config()).unwrap();
+        assert_eq!(store.load(), Ok(config()));
+
+        store.erase().unwrap();
+        assert_eq!(store.load(), Err(LoadError::Empty));
+    }
+
+    #[test]
+    fn test_corruption_is_detected() {
+        let mut store = store();
+        store.save(&config()).unwrap();
+        store.storage.data[HEADER_LEN + 3] ^= 0x01;
+        assert_eq!(store.load(), Err(LoadError::Corrupt));
+
+        store.save(&config()).unwrap();
+        store.storage.data[6..8].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert_eq!(store.load(), Err(LoadError::Corrupt));
+
+        store_json(&mut store, VERSION, r#"{"wifi":"#);
+        assert_eq!(store.load(), Err(LoadError::Parse));
+    }
+
+    #[test]
+    fn test_newer_format_is_not_loaded() {
+        let mut store = store();
+        store.save(&config()).unwrap();
+        store.storage.data[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert_eq!(
+            store.load(),
+            Err(LoadError::UnsupportedVersion(VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn test_missing_fields_take_defaults() {
+        // A config saved before `mqtt.host` existed, without the other defaulted fields
+        let mut store = store();
+        store_json(
+            &mut store,
+            VERSION,
+            r#"{"wifi":{"ssid":"home","password":"secret"},"static_ip":null,
+                "mqtt":{"broker":[192,168,1,201],"port":1883,"username":"","password":""}}"#,
+        );
+        let loaded = store.load().unwrap();
+        assert_eq!(loaded.brightness, protocol::config::DEFAULT_BRIGHTNESS);
+        assert_eq!(loaded.panel, PanelLayout::default());
+        assert_eq!(loaded.wifi, config().wifi);
+        assert_eq!(loaded.mqtt, config().mqtt);
+    }
+
+    #[test]
+    fn test_storage_too_small() {
+        let mut store = ConfigStore::new(MemStorage::<64>::new());
+        assert_eq!(store.save(&config()), Err(SaveError::Storage));
+        assert_eq!(store.load(), Err(LoadError::Empty));
+    }
+}
@@ -0,0 +1,537 @@
+//! Tests of the device's MQTT session, run natively against a real broker.
+//!
+//! **Prerequisites**: as for the backend's integration tests, a Mosquitto (or compatible) MQTT
+//! broker, by default on localhost:1883 with `allow_anonymous true`. `MQTT_HOST`, `MQTT_PORT`,
+//! `MQTT_USERNAME` and `MQTT_PASSWORD` select another broker and credentials. The device has no
+//! TLS, so neither do these tests, and it speaks MQTT 5, as do they.
+//!
+//! Run with:
+//!   cargo test -p host-common --features mqtt --test session -- --nocapture
+//!
+//! or `just test-session`.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Duration;
+
+use embedded_io_adapters::tokio_1::FromTokio;
+use rumqttc::v5::mqttbytes::{QoS, v5::Packet};
+use rumqttc::v5::{AsyncClient, Event, MqttOptions};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+
+use host_common::session::{self, Handler, Options, Transport};
+use protocol::config::{DeviceConfig, MqttConfig, PanelLayout, Secret, WifiConfig};
+use protocol::envelope::ReplyTopic;
+use protocol::guest::{GuestChunk, MAX_GUEST_CHUNK_DATA_LEN};
+use protocol::ping::{PingRequest, PingResponse};
+use protocol::presence::{self, DeviceInfo};
+use protocol::telemetry::{FrameTimes, HeapUsage, Telemetry};
+use protocol::topics::{DEFAULT_PREFIX, device_topic, suffix};
+use protocol::{BinaryBlit, Command, CommandAck, CommandEnvelope, CommandError, Mode};
+
+/// Default timeout used across tests
+const T: Duration = Duration::from_secs(5);
+
+/// The broker to test against, from the environment
+struct Broker {
+    host: String,
+    port: u16,
+    username: String,
+    password: String,
+}
+
+impl Broker {
+    fn from_env() -> Self {
+        let var = |name: &str| std::env::var(name).unwrap_or_default();
+        Self {
+            host: std::env::var("MQTT_HOST").unwrap_or_else(|_| "localhost".to_string()),
+            port: std::env::var("MQTT_PORT")
+                .map(|port| port.parse().expect("bad MQTT_PORT"))
+                .unwrap_or(1883),
+            username: var("MQTT_USERNAME"),
+            password: var("MQTT_PASSWORD"),
+        }
+    }
+
+    /// The device's settings for this broker
+    fn mqtt_config(&self) -> MqttConfig {
+        MqttConfig {
+            host: heapless::String::try_from(self.host.as_str()).unwrap(),
+            broker: None,
+            port: self.port,
+            username: heapless::String::try_from(self.username.as_str()).unwrap(),
+            password: Secret(heapless::String::try_from(self.password.as_str()).unwrap()),
+        }
+    }
+
+    /// A client watching everything on `device_id`'s topics, whose publishes arrive on the
+    /// returned channel.
+    async fn observer(
+        &self,
+        client_id: &str,
+        device_id: &str,
+    ) -> (AsyncClient, mpsc::Receiver<(String, Vec<u8>)>) {
+        let mut opts = MqttOptions::new(client_id, &self.host, self.port);
+        if !self.username.is_empty() {
+            opts.set_credentials(&self.username, &self.password);
+        }
+        let (client, mut eventloop) = AsyncClient::new(opts, 50);
+        client
+            .subscribe(format!("{DEFAULT_PREFIX}/{device_id}/#"), QoS::AtLeastOnce)
+            .await
+            .unwrap();
+
+        let (tx, rx) = mpsc::channel(64);
+        tokio::spawn(async move {
+            loop {
+                match eventloop.poll().await {
+                    Ok(Event::Incoming(Packet::Publish(p))) => {
+                        let _ = tx
+                            .send((
+                                String::from_utf8(p.topic.to_vec()).unwrap(),
+                                p.payload.to_vec(),
+                            ))
+                            .await;
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        eprintln!("observer MQTT error: {e:?}");
+                        tokio::time::sleep(Duration::from_millis(200)).await;
+                    }
+                }
+            }
+        });
+        // Give the broker a moment to process the subscription.
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        (client, rx)
+    }
+}
+
+/// Plain TCP to the broker, as the device has.
+struct Tcp {
+    addr: (String, u16),
+}
+
+impl Transport for Tcp {
+    type Connection<'a> = FromTokio<TcpStream>;
+
+    async fn connect(&mut self) -> Option<FromTokio<TcpStream>> {
+        let stream = TcpStream::connect((self.addr.0.as_str(), self.addr.1)).await;
+        stream.ok().map(FromTokio::new)
+    }
+}
+
+/// A device that remembers the commands it carried out, and the guests it was sent.
+struct Recorder {
+    executed: Rc<RefCell<Vec<Command>>>,
+    guests: Rc<RefCell<Vec<Vec<u8>>>>,
+}
+
+impl Handler for Recorder {
+    fn execute(&mut self, command: Command) -> Result<(), CommandError> {
+        if let Command::DirectCommand(_) = command {
+            return Err(CommandError::WrongMode);
+        }
+        self.executed.borrow_mut().push(command);
+        Ok(())
+    }
+
+    async fn dispatch(&mut self, command: Command) {
+        self.executed.borrow_mut().push(command);
+    }
+
+    fn stream(&mut self, _frame: &[u8]) {}
+
+    async fn blit(&mut self, _blit: BinaryBlit<'_>) {}
+
+    fn load_guest(&mut self, module: Vec<u8>) {
+        self.guests.borrow_mut().push(module);
+    }
+
+    fn telemetry(&mut self) -> Telemetry {
+        Telemetry {
+            protocol_version: protocol::PROTOCOL_VERSION,
+            firmware_version: heapless::String::try_from("test").unwrap(),
+            uptime_s: 0,
+            mode: Mode::Direct,
+            guest: None,
+            frames: FrameTimes::default(),
+            heap: HeapUsage::default(),
+            guest_memory_bytes: 0,
+            power_mw: 0,
+            rssi_dbm: None,
+            ip: None,
+        }
+    }
+
+    fn config(&self) -> DeviceConfig {
+        DeviceConfig {
+            wifi: WifiConfig {
+                ssid: heapless::String::try_from("test").unwrap(),
+                password: Secret(heapless::String::try_from("wifi secret").unwrap()),
+            },
+            static_ip: None,
+            mqtt: Broker::from_env().mqtt_config(),
+            brightness: 100,
+            panel: PanelLayout::default(),
+        }
+    }
+}
+
+/// A simulated device, with a unique id so that parallel tests don't interfere with each other.
+struct Device {
+    id: String,
+    broker: Broker,
+    executed: Rc<RefCell<Vec<Command>>>,
+    guests: Rc<RefCell<Vec<Vec<u8>>>>,
+}
+
+impl Device {
+    fn new() -> Self {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .subsec_nanos();
+        let pid = std::process::id();
+        let mac = [
+            0x7e,
+            (pid >> 8) as u8,
+            pid as u8,
+            (nanos >> 16) as u8,
+            (nanos >> 8) as u8,
+            nanos as u8,
+        ];
+        Self {
+            id: presence::device_id(mac).to_string(),
+            broker: Broker::from_env(),
+            executed: Rc::default(),
+            guests: Rc::default(),
+        }
+    }
+
+    fn topic(&self, suffix: &str) -> String {
+        device_topic(&self.id, suffix).to_string()
+    }
+
+    /// Run the session until `test` completes.
+    async fn run_while(&self, test: impl Future<Output = ()>) {
+        let mqtt = self.broker.mqtt_config();
+        let options = Options {
+            info: DeviceInfo {
+                device_id: heapless::String::try_from(self.id.as_str()).unwrap(),
+                firmware_version: heapless::String::try_from("test").unwrap(),
+                protocol_version: protocol::PROTOCOL_VERSION,
+                width: 16,
+                height: 16,
+            },
+            mqtt: &mqtt,
+            pong: "pong from the tests",
+        };
+        let mut transport = Tcp {
+            addr: (self.broker.host.clone(), self.broker.port),
+        };
+        let mut handler = Recorder {
+            executed: self.executed.clone(),
+            guests: self.guests.clone(),
+        };
+        let mut buf = [0u8; 2048];
+        tokio::select! {
+            () = session::run(&mut transport, &mut handler, &options, &mut buf) => {
+                unreachable!("the session never ends")
+            }
+            () = test => {}
+        }
+    }
+}
+
+/// Wait for a publish on `topic`, discarding others.
+async fn expect_on_topic(rx: &mut mpsc::Receiver<(String, Vec<u8>)>, topic: &str) -> Vec<u8> {
+    timeout(T, async {
+        loop {
+            let (t, payload) = rx.recv().await.expect("observer channel closed");
+            if t == topic {
+                return payload;
+            }
+        }
+    })
+    .await
+    .unwrap_or_else(|_| panic!("timed out waiting for MQTT on topic '{topic}'"))
+}
+
+/// Publish a command envelope, as the backend would.
+async fn send_command(client: &AsyncClient, device: &Device, envelope: &CommandEnvelope) {
+    client
+        .publish(
+            device.topic(suffix::COMMAND),
+            QoS::AtLeastOnce,
+            false,
+            serde_json::to_vec(envelope).unwrap(),
+        )
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn device_announces_itself_and_answers_pings() {
+    let device = Device::new();
+    let (client, mut rx) = device
+        .broker
+        .observer(&format!("test-observer-{}", device.id), &device.id)
+        .await;
+
+    device
+        .run_while(async {
+            let status = expect_on_topic(&mut rx, &device.topic(suffix::STATUS)).await;
+            assert_eq!(status, presence::ONLINE.as_bytes());
+            let info = expect_on_topic(&mut rx, &device.topic(suffix::INFO)).await;
+            let info: DeviceInfo = serde_json::from_slice(&info).unwrap();
+            assert_eq!(info.device_id.as_str(), device.id);
+            let telemetry = expect_on_topic(&mut rx, &device.topic(suffix::TELEMETRY)).await;
+            let telemetry: Telemetry = serde_json::from_slice(&telemetry).unwrap();
+            assert_eq!(telemetry.firmware_version.as_str(), "test");
+
+            let request = PingRequest {
+                correlation_id: heapless::String::try_from("abc").unwrap(),
+                message: heapless::String::try_from("ping").unwrap(),
+            };
+            client
+                .publish(
+                    device.topic(suffix::PING_REQUEST),
+                    QoS::AtMostOnce,
+                    false,
+                    serde_json::to_vec(&request).unwrap(),
+                )
+                .await
+                .unwrap();
+            let pong = expect_on_topic(&mut rx, &device.topic(suffix::PING_RESPONSE)).await;
+            let pong: PingResponse = serde_json::from_slice(&pong).unwrap();
+            assert_eq!(pong.correlation_id.as_str(), "abc");
+            assert_eq!(pong.message.as_str(), "pong from the tests");
+        })
+        .await;
+}
+
+#[tokio::test]
+async fn commands_are_acknowledged_and_carried_out_once() {
+    let device = Device::new();
+    let (client, mut rx) = device
+        .broker
+        .observer(&format!("test-observer-{}", device.id), &device.id)
+        .await;
+    let ack_topic = device.topic(suffix::COMMAND_ACK);
+
+    device
+        .run_while(async {
+            expect_on_topic(&mut rx, &device.topic(suffix::STATUS)).await;
+
+            // A redelivery is acknowledged again, but not carried out again
+            let mut envelope = CommandEnvelope {
+                sender: 1,
+                id: 7,
+                reply_to: None,
+                command: Command::SetBrightness(10),
+            };
+            for _ in 0..2 {
+                send_command(&client, &device, &envelope).await;
+                let ack = expect_on_topic(&mut rx, &ack_topic).await;
+                let ack: CommandAck = serde_json::from_slice(&ack).unwrap();
+                assert_eq!(ack, CommandAck::ok(7));
+            }
+            assert_eq!(*device.executed.borrow(), [Command::SetBrightness(10)]);
+
+            // The same id from a restarted sender is another command
+            envelope.sender = 2;
+            envelope.command = Command::SetBrightness(20);
+            send_command(&client, &device, &envelope).await;
+            let ack = expect_on_topic(&mut rx, &ack_topic).await;
+            assert_eq!(
+                serde_json::from_slice::<CommandAck>(&ack).unwrap(),
+                CommandAck::ok(7)
+            );
+            assert_eq!(
+                *device.executed.borrow(),
+                [Command::SetBrightness(10), Command::SetBrightness(20)]
+            );
+
+            // Rejected by the handler, acknowledged on the topic asked for
+            let reply_to = format!("test-replies-{}", device.id);
+            let envelope = CommandEnvelope {
+                sender: 1,
+                id: 8,
+                reply_to: Some(ReplyTopic::try_from(reply_to.as_str()).unwrap()),
+                command: Command::DirectCommand(protocol::DirectCommand::Clear),
+            };
+            client.subscribe(&reply_to, QoS::AtLeastOnce).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(250)).await;
+            send_command(&client, &device, &envelope).await;
+            let ack = expect_on_topic(&mut rx, &reply_to).await;
+            let ack: CommandAck = serde_json::from_slice(&ack).unwrap();
+            assert_eq!(ack, CommandAck::nack(8, CommandError::WrongMode));
+
+            // The config is published, without its passwords, before `GetConfig` is acknowledged
+            let envelope = CommandEnvelope {
+                sender: 1,
+                id: 9,
+                reply_to: None,
+                command: Command::GetConfig,
+            };
+            send_command(&client, &device, &envelope).await;
+            let config = expect_on_topic(&mut rx, &device.topic(suffix::CONFIG)).await;
+            let config: DeviceConfig = serde_json::from_slice(&config).unwrap();
+            assert_eq!(config.wifi.ssid.as_str(), "test");
+            assert!(config.wifi.password.is_empty() && config.mqtt.password.is_empty());
+            let ack = expect_on_topic(&mut rx, &ack_topic).await;
+            assert_eq!(
+                serde_json::from_slice::<CommandAck>(&ack).unwrap(),
+                CommandAck::ok(9)
+            );
+        })
+        .await;
+}
+
+#[tokio::test]
+async fn device_reconnects_when_its_connection_is_taken_over() {
+    let device = Device::new();
+    let (client, mut rx) = device
+        .broker
+        .observer(&format!("test-observer-{}", device.id), &device.id)
+        .await;
+    let status_topic = device.topic(suffix::STATUS);
+
+    device
+        .run_while(async {
+            expect_on_topic(&mut rx, &status_topic).await;
+
+            // Connecting with the device's client id disconnects the device
+            let client_id = presence::client_id(&device.id);
+            let mut opts =
+                MqttOptions::new(client_id.as_str(), &device.broker.host, device.broker.port);
+            if !device.broker.username.is_empty() {
+                opts.set_credentials(&device.broker.username, &device.broker.password);
+            }
+            let (_intruder, mut eventloop) = AsyncClient::new(opts, 10);
+            timeout(T, async {
+                while !matches!(
+                    eventloop.poll().await,
+                    Ok(Event::Incoming(Packet::ConnAck(_)))
+                ) {}
+            })
+            .await
+            .expect("intruder not connected");
+            drop(eventloop);
+
+            // It comes back after a backoff, announces itself again and takes commands
+            loop {
+                let status = expect_on_topic(&mut rx, &status_topic).await;
+                if status == presence::ONLINE.as_bytes() {
+                    break;
+                }
+            }
+            let envelope = CommandEnvelope {
+                sender: 1,
+                id: 1,
+                reply_to: None,
+                command: Command::SetMode(Mode::Wasm),
+            };
+            send_command(&client, &device, &envelope).await;
+            let ack = expect_on_topic(&mut rx, &device.topic(suffix::COMMAND_ACK)).await;
+            assert_eq!(
+                serde_json::from_slice::<CommandAck>(&ack).unwrap(),
+                CommandAck::ok(1)
+            );
+            assert_eq!(*device.executed.borrow(), [Command::SetMode(Mode::Wasm)]);
+        })
+        .await;
+}
+
+#[tokio::test]
+async fn commands_sent_while_offline_are_carried_out_on_reconnecting() {
+    let device = Device::new();
+    let (client, mut rx) = device
+        .broker
+        .observer(&format!("test-observer-{}", device.id), &device.id)
+        .await;
+    let status_topic = device.topic(suffix::STATUS);
+
+    // Connect once, so that the broker keeps a session with our subscriptions
+    device
+        .run_while(async {
+            expect_on_topic(&mut rx, &status_topic).await;
+        })
+        .await;
+
+    // Queued by the broker, then delivered as soon as the device is back - while it's still
+    // subscribing
+    let envelope = CommandEnvelope {
+        sender: 1,
+        id: 1,
+        reply_to: None,
+        command: Command::SetBrightness(30),
+    };
+    send_command(&client, &device, &envelope).await;
+    tokio::time::sleep(Duration::from_millis(250)).await;
+
+    device
+        .run_while(async {
+            let ack = expect_on_topic(&mut rx, &device.topic(suffix::COMMAND_ACK)).await;
+            assert_eq!(
+                serde_json::from_slice::<CommandAck>(&ack).unwrap(),
+                CommandAck::ok(1)
+            );
+        })
+        .await;
+    assert_eq!(*device.executed.borrow(), [Command::SetBrightness(30)]);
+}
+
+#[tokio::test]
+async fn deployed_guest_is_put_back_together() {
+    let device = Device::new();
+    let (client, mut rx) = device
+        .broker
+        .observer(&format!("test-observer-{}", device.id), &device.id)
+        .await;
+    let module: Vec<u8> = (0..2 * MAX_GUEST_CHUNK_DATA_LEN + 100)
+        .map(|i| i as u8)
+        .collect();
+
+    device
+        .run_while(async {
+            // Subscribed once it's online
+            expect_on_topic(&mut rx, &device.topic(suffix::STATUS)).await;
+
+            // In chunks, as the backend deploys it, with the first one delivered twice
+            let chunks: Vec<Vec<u8>> = module
+                .chunks(MAX_GUEST_CHUNK_DATA_LEN)
+                .enumerate()
+                .map(|(i, data)| {
+                    let offset = (i * MAX_GUEST_CHUNK_DATA_LEN) as u32;
+                    let mut payload = GuestChunk::header(9, offset, module.len() as u32).to_vec();
+                    payload.extend_from_slice(data);
+                    payload
+                })
+                .collect();
+            for chunk in [&chunks[0], &chunks[0], &chunks[1], &chunks[2]] {
+                client
+                    .publish(
+                        device.topic(suffix::GUEST),
+                        QoS::AtLeastOnce,
+                        false,
+                        chunk.clone(),
+                    )
+                    .await
+                    .unwrap();
+            }
+
+            timeout(T, async {
+                while device.guests.borrow().is_empty() {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                }
+            })
+            .await
+            .expect("timed out waiting for the guest");
+        })
+        .await;
+    assert_eq!(*device.guests.borrow(), [module]);
+}
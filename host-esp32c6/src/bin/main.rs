@@ -12,15 +12,20 @@ use embassy_net::StackResources;
 use embassy_time::{Duration, Timer};
 #[allow(unused_imports)]
 use esp_backtrace as _;
+use esp_bootloader_esp_idf::partitions::PARTITION_TABLE_MAX_LEN;
 use esp_hal::clock::CpuClock;
 use esp_hal::rng::Rng;
 use esp_hal::timer::timg::TimerGroup;
 use esp_radio::Controller;
+use esp_storage::FlashStorage;
+use host_esp32c6::config::{self, config_task};
 use host_esp32c6::direct::direct_task;
+use host_esp32c6::dmx::dmx_task;
 use host_esp32c6::led::led_task;
 use host_esp32c6::log;
 use host_esp32c6::mqtt::mqtt_task;
-use host_esp32c6::net::{connection, net_task};
+use host_esp32c6::net::{connection, net_task, stack_config};
+use host_esp32c6::stream::stream_task;
 use host_esp32c6::wasm::wasm_task;
 use host_esp32c6::{MODE, Mode};
 
@@ -56,6 +61,14 @@ async fn main(spawner: embassy_executor::Spawner) -> ! {
         esp_hal::interrupt::software::SoftwareInterruptControl::new(peripherals.SW_INTERRUPT);
     esp_rtos::start(timg0.timer0, sw_interrupt.software_interrupt0);
 
+    let flash = mk_static!(FlashStorage<'static>, FlashStorage::new(peripherals.FLASH));
+    let partition_table = mk_static!(
+        [u8; PARTITION_TABLE_MAX_LEN],
+        [0u8; PARTITION_TABLE_MAX_LEN]
+    );
+    let mut store = config::open_store(flash, partition_table);
+    let device_config = config::init(store.as_mut());
+
     log!("🛜 Initialising WiFi...");
     let esp_radio_ctrl = &*mk_static!(Controller<'static>, esp_radio::init().unwrap());
 
@@ -64,22 +77,8 @@ async fn main(spawner: embassy_executor::Spawner) -> ! {
 
     let wifi_interface = interfaces.sta;
 
-    // DHCP
-    //let config = embassy_net::Config::dhcpv4(Default::default());
-
-    // Static IP
-    let config = embassy_net::Config::ipv4_static(embassy_net::StaticConfigV4 {
-        address: embassy_net::Ipv4Cidr::new(embassy_net::Ipv4Address::new(192, 168, 1, 242), 24),
-        gateway: Some(embassy_net::Ipv4Address::new(192, 168, 1, 1)),
-        dns_servers: {
-            let mut dns = heapless::Vec::<embassy_net::Ipv4Address, 3>::new();
-            dns.push(embassy_net::Ipv4Address::new(192, 168, 1, 1))
-                .unwrap();
-            dns
-        },
-        // dns_servers: heapless::Vec::from_slice(&[embassy_net::Ipv4Address::new(192, 168, 1, 1)])
-        //     .unwrap(),
-    });
+    // Static IP, or DHCP, from the config
+    let config = stack_config(device_config.static_ip);
 
     let rng = Rng::new();
     let seed = (rng.random() as u64) << 32 | rng.random() as u64;
@@ -88,13 +87,16 @@ async fn main(spawner: embassy_executor::Spawner) -> ! {
     let (stack, runner) = embassy_net::new(
         wifi_interface,
         config,
-        mk_static!(StackResources<3>, StackResources::<3>::new()),
+        // DHCP, DNS, broker discovery, MQTT, sACN and Art-Net
+        mk_static!(StackResources<7>, StackResources::<7>::new()),
         seed,
     );
 
     MODE.sender().send(Mode::Wasm);
 
-    spawner.spawn(connection(controller)).ok();
+    spawner
+        .spawn(connection(controller, device_config.wifi.clone()))
+        .ok();
     spawner.spawn(net_task(runner)).ok();
 
     loop {
@@ -113,13 +115,22 @@ async fn main(spawner: embassy_executor::Spawner) -> ! {
         Timer::after(Duration::from_millis(500)).await;
     }
 
-    spawner.spawn(mqtt_task(stack)).ok();
+    spawner.spawn(config_task(store)).ok();
+    spawner
+        .spawn(mqtt_task(stack, device_config.mqtt.clone()))
+        .ok();
+    spawner.spawn(dmx_task(stack)).ok();
 
     spawner.spawn(wasm_task()).ok();
     spawner.spawn(direct_task()).ok();
+    spawner.spawn(stream_task()).ok();
 
     spawner
-        .spawn(led_task(peripherals.GPIO10.into(), peripherals.RMT))
+        .spawn(led_task(
+            peripherals.GPIO10.into(),
+            peripherals.RMT,
+            device_config.panel,
+        ))
         .ok();
 
     loop {
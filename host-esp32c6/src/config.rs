@@ -0,0 +1,163 @@
+//! The device configuration: loaded from flash at startup, falling back to the compile-time
+//! defaults, and updated by `SetConfig` / `ResetConfig` (see [`protocol::config`]).
+//!
+//! Updates change the [`current`] config straight away and are saved by [`config_task`], so
+//! that acknowledging a command never waits for flash. Tasks that read the config once at
+//! startup pick up changes on the next restart.
+//!
+//! It's kept in a partition of its own, [`PARTITION_LABEL`], which the partition table the
+//! runner flashes (`partitions.csv`) adds to the usual ones.
+
+use crate::{BRIGHTNESS, log};
+use core::cell::RefCell;
+use core::sync::atomic::Ordering;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use esp_bootloader_esp_idf::partitions::{self, FlashRegion, PARTITION_TABLE_MAX_LEN};
+use esp_storage::FlashStorage;
+use host_common::store::{ConfigStore, LoadError};
+use protocol::config::{
+    DEFAULT_BRIGHTNESS, DeviceConfig, MqttConfig, PanelLayout, Secret, WifiConfig,
+};
+
+pub type Store = ConfigStore<FlashRegion<'static, FlashStorage<'static>>>;
+
+/// The config's own data partition, as in `partitions.csv`, so that nothing else's format is
+/// written over
+pub const PARTITION_LABEL: &str = "config";
+/// Data subtypes from 0x40 are for the application's use
+pub const PARTITION_SUBTYPE: u8 = 0x40;
+const DATA_PARTITION_TYPE: u8 = 1;
+
+/// The firmware's built-in configuration.
+pub fn defaults() -> DeviceConfig {
+    DeviceConfig {
+        wifi: WifiConfig {
+            ssid: heapless::String::try_from(env!("WIFI_SSID")).unwrap(),
+            password: Secret(heapless::String::try_from(env!("WIFI_PASSWORD")).unwrap()),
+        },
+        // DHCP
+        static_ip: None,
+        mqtt: MqttConfig {
+            // Discovered unless built with MQTT_HOST
+            host: heapless::String::try_from(option_env!("MQTT_HOST").unwrap_or_default()).unwrap(),
+            broker: None,
+            port: 1883,
+            // As made by mosquitto/setup.sh unless built with others
+            username: heapless::String::try_from(
+                option_env!("MQTT_USERNAME").unwrap_or("testUser"),
+            )
+            .unwrap(),
+            password: Secret(
+                heapless::String::try_from(option_env!("MQTT_PASSWORD").unwrap_or("testPass"))
+                    .unwrap(),
+            ),
+        },
+        brightness: DEFAULT_BRIGHTNESS,
+        panel: PanelLayout::default(),
+    }
+}
+
+static CONFIG: Mutex<CriticalSectionRawMutex, RefCell<Option<DeviceConfig>>> =
+    Mutex::new(RefCell::new(None));
+
+enum Persist {
+    Save,
+    Erase,
+}
+
+// Only the latest update needs saving
+static PERSIST: Signal<CriticalSectionRawMutex, Persist> = Signal::new();
+
+/// Open the config store in the [`PARTITION_LABEL`] partition.
+pub fn open_store(
+    flash: &'static mut FlashStorage<'static>,
+    table: &'static mut [u8; PARTITION_TABLE_MAX_LEN],
+) -> Option<Store> {
+    let partitions = match partitions::read_partition_table(&mut *flash, table) {
+        Ok(partitions) => partitions,
+        Err(e) => {
+            defmt::error!("Failed to read the partition table: {:?}", e);
+            return None;
+        }
+    };
+    // By the raw type, as `find_partition` only knows ESP-IDF's own subtypes, and panics on
+    // others
+    let config = partitions.iter().find(|partition| {
+        partition.raw_type() == DATA_PARTITION_TYPE
+            && partition.raw_subtype() == PARTITION_SUBTYPE
+            && partition.label_as_str() == PARTITION_LABEL
+    });
+    match config {
+        Some(config) => Some(ConfigStore::new(config.as_embedded_storage(flash))),
+        None => {
+            defmt::error!("No `{}` partition for the config", PARTITION_LABEL);
+            None
+        }
+    }
+}
+
+/// Load the stored config, or the defaults, and make it [`current`]. Call once at startup.
+pub fn init(store: Option<&mut Store>) -> DeviceConfig {
+    let config = match store.map(|store| store.load()) {
+        Some(Ok(config)) => {
+            log!("Loaded config: {:?}", config);
+            config
+        }
+        Some(Err(LoadError::Empty)) => {
+            log!("No stored config, using defaults");
+            defaults()
+        }
+        Some(Err(e)) => {
+            defmt::warn!("Stored config not loaded ({:?}), using defaults", e);
+            defaults()
+        }
+        None => defaults(),
+    };
+    BRIGHTNESS.store(config.brightness, Ordering::Relaxed);
+    CONFIG.lock(|c| c.replace(Some(config.clone())));
+    config
+}
+
+/// The config as last set, which may not have taken effect yet.
+pub fn current() -> DeviceConfig {
+    CONFIG.lock(|c| c.borrow().clone()).unwrap_or_else(defaults)
+}
+
+/// Handle `SetConfig`: the brightness applies now, and the config is saved.
+pub fn set(mut config: DeviceConfig) {
+    config.merge_secrets(&current());
+    BRIGHTNESS.store(config.brightness, Ordering::Relaxed);
+    CONFIG.lock(|c| c.replace(Some(config)));
+    PERSIST.signal(Persist::Save);
+}
+
+/// Handle `ResetConfig`: forget the stored config, so the defaults apply from the next restart.
+pub fn reset() {
+    CONFIG.lock(|c| c.replace(Some(defaults())));
+    PERSIST.signal(Persist::Erase);
+}
+
+/// Saves config changes to flash.
+#[embassy_executor::task]
+pub async fn config_task(mut store: Option<Store>) {
+    log!("🌱 Start config task...");
+    loop {
+        let persist = PERSIST.wait().await;
+        let Some(store) = store.as_mut() else {
+            defmt::warn!("No config storage, change not saved");
+            continue;
+        };
+        match persist {
+            Persist::Save => match store.save(&current()) {
+                Ok(()) => log!("Config saved"),
+                Err(e) => defmt::error!("Config not saved: {:?}", e),
+            },
+            Persist::Erase => match store.erase() {
+                Ok(()) => log!("Config reset to defaults"),
+                Err(e) => defmt::error!("Config not reset: {:?}", e),
+            },
+        }
+    }
+}
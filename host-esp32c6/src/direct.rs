@@ -1,10 +1,12 @@
 use crate::{
-    DIRECT_CMD, DirectCommand, FRAME_CONSUMED, FRAME_LEN, FRAME_PTR, FRAME_READY, HOST_BUFFER_PTR,
+    DIRECT_BLIT, DIRECT_CMD, FRAME_CONSUMED, FRAME_LEN, FRAME_PTR, FRAME_READY, HOST_BUFFER_PTR,
     MODE, Mode, log,
 };
-use common::{LED_BUFFER_SIZE, set_all, set_color};
+use common::{LED_BUFFER_SIZE, LED_PANEL_HEIGHT, LED_PANEL_WIDTH};
 use core::sync::atomic::Ordering;
-use embassy_futures::select::{Either, select};
+use embassy_futures::select::{Either3, select3};
+use host_common::draw::Canvas;
+use protocol::DirectCommand;
 
 #[embassy_executor::task]
 pub async fn direct_task() {
@@ -16,56 +18,136 @@ pub async fn direct_task() {
     log!("🔁 Direct entering main loop...");
     loop {
         let active = current_mode == Mode::Direct;
+        let event = select3(
+            receiver.changed(),
+            DIRECT_CMD.receive(),
+            DIRECT_BLIT.receive(),
+        )
+        .await;
+
+        // Loaded after waiting, as a deployed guest replaces the buffer along with its memory
         let host_pixel_ptr = HOST_BUFFER_PTR.load(Ordering::Acquire) as *mut u8;
         let host_pixel_ptr = (!host_pixel_ptr.is_null()).then_some(host_pixel_ptr);
 
-        match select(receiver.changed(), DIRECT_CMD.receive()).await {
-            Either::First(mode) => {
+        match event {
+            Either3::First(mode) => {
                 current_mode = mode;
 
                 log!("Direct mode: host_pixel_ptr {:?}", host_pixel_ptr);
+                continue;
             }
-            Either::Second(cmd) => {
-                if let Some(host_pixel_ptr) = host_pixel_ptr {
-                    match cmd {
-                        DirectCommand::SetPixel { point, color } => {
-                            log!("SetPixel: {:?}, {:?}", point, color);
+            Either3::Second(cmd) => {
+                let Some(host_pixel_ptr) = host_pixel_ptr else {
+                    // host pointer isn't valid (yet)
+                    continue;
+                };
+                log!("{:?}", cmd);
 
-                            if active {
-                                // SAFETY: host_pixel_ptr points to a valid pixel buffer
-                                unsafe {
-                                    set_color(
-                                        host_pixel_ptr,
-                                        (point.x.into(), point.y.into()),
-                                        (color.r, color.g, color.b),
-                                    )
-                                };
-                            }
-                        }
-                        DirectCommand::SetAll { color } => {
-                            log!("SetAll: {:?}", color);
+                if active {
+                    // SAFETY: host_pixel_ptr points to a valid pixel buffer, and led_task only
+                    // reads it after FRAME_READY is signalled below.
+                    let mut canvas = unsafe { host_canvas(host_pixel_ptr) };
+                    apply_command(&mut canvas, &cmd);
+                }
+            }
+            Either3::Third(blit) => {
+                let Some(host_pixel_ptr) = host_pixel_ptr else {
+                    continue;
+                };
+                log!(
+                    "Binary blit: {:?} {}x{}",
+                    blit.origin,
+                    blit.width,
+                    blit.height
+                );
 
-                            if active {
-                                // SAFETY: host_pixel_ptr points to a valid pixel buffer
-                                unsafe { set_all(host_pixel_ptr, (color.r, color.g, color.b)) };
-                            }
-                        }
-                    }
+                if active {
+                    // SAFETY: as above
+                    let mut canvas = unsafe { host_canvas(host_pixel_ptr) };
+                    canvas.blit(
+                        (blit.origin.x.into(), blit.origin.y.into()),
+                        blit.width.into(),
+                        blit.height.into(),
+                        &blit.data,
+                    );
+                }
+            }
+        }
 
-                    if active {
-                        // Publish the host buffer pointer — safe because led_task won't read
-                        // until signalled, and we block until it's done.
-                        FRAME_PTR.store(host_pixel_ptr as usize, Ordering::Release);
-                        FRAME_LEN.store(LED_BUFFER_SIZE, Ordering::Release);
+        if active && let Some(host_pixel_ptr) = host_pixel_ptr {
+            // Publish the host buffer pointer — safe because led_task won't read
+            // until signalled, and we block until it's done.
+            FRAME_PTR.store(host_pixel_ptr as usize, Ordering::Release);
+            FRAME_LEN.store(LED_BUFFER_SIZE, Ordering::Release);
 
-                        FRAME_READY.signal(());
-                        FRAME_CONSUMED.wait().await;
-                    }
-                } else {
-                    // host pointer isn't valid (yet)
-                    continue;
-                }
+            FRAME_READY.signal(());
+            FRAME_CONSUMED.wait().await;
+        }
+    }
+}
+
+/// # SAFETY
+/// `ptr` must point to a valid, writeable [u8; LED_BUFFER_SIZE] that nothing else is accessing.
+unsafe fn host_canvas(ptr: *mut u8) -> Canvas<'static> {
+    let buf = unsafe { core::slice::from_raw_parts_mut(ptr, LED_BUFFER_SIZE) };
+    Canvas::new(buf, LED_PANEL_WIDTH, LED_PANEL_HEIGHT)
+}
+
+fn apply_command(canvas: &mut Canvas, cmd: &DirectCommand) {
+    match cmd {
+        DirectCommand::SetPixel { point, color } => {
+            canvas.set_pixel(point.x.into(), point.y.into(), (*color).into());
+        }
+        DirectCommand::SetAll { color } => canvas.fill((*color).into()),
+        DirectCommand::Clear => canvas.fill((0, 0, 0)),
+        DirectCommand::SetPixels { pixels } => {
+            for pixel in pixels {
+                canvas.set_pixel(
+                    pixel.point.x.into(),
+                    pixel.point.y.into(),
+                    pixel.color.into(),
+                );
+            }
+        }
+        DirectCommand::FillRect {
+            origin,
+            width,
+            height,
+            color,
+        } => canvas.fill_rect(
+            origin.x.into(),
+            origin.y.into(),
+            (*width).into(),
+            (*height).into(),
+            (*color).into(),
+        ),
+        DirectCommand::DrawLine { from, to, color } => canvas.draw_line(
+            (from.x.into(), from.y.into()),
+            (to.x.into(), to.y.into()),
+            (*color).into(),
+        ),
+        DirectCommand::DrawText {
+            origin,
+            text,
+            color,
+        } => canvas.draw_text((origin.x.into(), origin.y.into()), text, (*color).into()),
+        DirectCommand::Blit {
+            origin,
+            width,
+            height,
+            ..
+        } => {
+            let mut data = [0u8; LED_BUFFER_SIZE];
+            match cmd.decode_blit_data(&mut data) {
+                Ok(len) => canvas.blit(
+                    (origin.x.into(), origin.y.into()),
+                    (*width).into(),
+                    (*height).into(),
+                    &data[..len],
+                ),
+                Err(e) => defmt::warn!("Invalid blit: {:?}", e),
             }
         }
+        DirectCommand::Scroll { dx, dy, wrap } => canvas.scroll((*dx).into(), (*dy).into(), *wrap),
     }
 }
@@ -0,0 +1,110 @@
+//! Finding the MQTT broker: by address, by hostname (DNS, or mDNS for `.local` names), or by
+//! DNS-SD discovery of `_mqtt._tcp` (see [`protocol::mdns`]).
+
+use crate::log;
+use embassy_net::dns::DnsQueryType;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{IpAddress, IpEndpoint, Ipv4Address, Stack};
+use embassy_time::{Duration, with_timeout};
+use protocol::config::{BrokerAddress, MqttConfig};
+use protocol::mdns::{self, MDNS_ADDR, MDNS_PORT, MQTT_SERVICE, TYPE_PTR};
+
+const DISCOVERY_ATTEMPTS: usize = 3;
+/// How long to wait for answers to each discovery query
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The broker's address and port, or `None` if it couldn't be found this time.
+pub async fn resolve_broker(stack: Stack<'static>, mqtt: &MqttConfig) -> Option<IpEndpoint> {
+    match mqtt.broker_address() {
+        BrokerAddress::Ip([a, b, c, d]) => Some(IpEndpoint::new(
+            IpAddress::Ipv4(Ipv4Address::new(a, b, c, d)),
+            mqtt.port,
+        )),
+        BrokerAddress::Host(host) => {
+            let address = resolve_host(stack, host).await?;
+            log!(
+                "Resolved broker {} to {}",
+                host,
+                defmt::Display2Format(&address)
+            );
+            Some(IpEndpoint::new(address, mqtt.port))
+        }
+        BrokerAddress::Discover => discover(stack).await,
+    }
+}
+
+async fn resolve_host(stack: Stack<'static>, host: &str) -> Option<IpAddress> {
+    match stack.dns_query(host, DnsQueryType::A).await {
+        Ok(addresses) => addresses.first().copied(),
+        Err(e) => {
+            defmt::warn!("Failed to resolve {}: {:?}", host, e);
+            None
+        }
+    }
+}
+
+/// Ask for `_mqtt._tcp` instances, taking the first answer.
+async fn discover(stack: Stack<'static>) -> Option<IpEndpoint> {
+    let mut rx_meta = [PacketMetadata::EMPTY; 2];
+    let mut rx_buffer = [0u8; 512];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = [0u8; 64];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    // An ephemeral port, so responders answer us directly
+    if let Err(e) = socket.bind(0) {
+        defmt::warn!("mDNS socket bind failed: {:?}", e);
+        return None;
+    }
+
+    let [a, b, c, d] = MDNS_ADDR;
+    let group = IpEndpoint::new(IpAddress::Ipv4(Ipv4Address::new(a, b, c, d)), MDNS_PORT);
+    let mut query = [0u8; 64];
+    let mut packet = [0u8; 512];
+
+    for attempt in 1..=DISCOVERY_ATTEMPTS {
+        log!("Discovering MQTT broker, attempt {}", attempt);
+        let id = attempt as u16;
+        // Always fits
+        let len = mdns::query(&mut query, id, MQTT_SERVICE, TYPE_PTR).unwrap();
+        if let Err(e) = socket.send_to(&query[..len], group).await {
+            defmt::warn!("mDNS query not sent: {:?}", e);
+            continue;
+        }
+
+        let answer = with_timeout(DISCOVERY_TIMEOUT, async {
+            loop {
+                let Ok((n, _)) = socket.recv_from(&mut packet).await else {
+                    continue;
+                };
+                match mdns::find_service(&packet[..n], MQTT_SERVICE) {
+                    Ok(Some(instance)) => return instance,
+                    Ok(None) => {}
+                    Err(e) => defmt::debug!("Ignoring mDNS packet: {:?}", e),
+                }
+            }
+        })
+        .await;
+
+        if let Ok(instance) = answer {
+            let address = match instance.address {
+                Some([a, b, c, d]) => IpAddress::Ipv4(Ipv4Address::new(a, b, c, d)),
+                None => resolve_host(stack, &instance.host).await?,
+            };
+            log!(
+                "Discovered MQTT broker {} at {}:{}",
+                instance.host.as_str(),
+                defmt::Display2Format(&address),
+                instance.port
+            );
+            return Some(IpEndpoint::new(address, instance.port));
+        }
+    }
+    defmt::warn!("No MQTT broker found");
+    None
+}
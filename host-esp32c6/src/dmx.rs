@@ -0,0 +1,153 @@
+//! E1.31 (sACN) and Art-Net receiver, for lighting desks and sequencers such as xLights.
+//!
+//! Packets are decoded and mapped onto the panel by the `dmx` crate. Received universes are
+//! only shown while in `Mode::Dmx`.
+
+use crate::{DMX_MAPPING, FRAME_CONSUMED, FRAME_LEN, FRAME_PTR, FRAME_READY, MODE, Mode, log};
+use common::LED_BUFFER_SIZE;
+use core::sync::atomic::Ordering;
+use dmx::{ARTNET_PORT, DmxError, DmxMapping, DmxPacket, SACN_PORT, sacn, sequence_is_newer};
+use embassy_futures::select::{Either, Either3, select, select3};
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{Ipv4Address, Stack};
+
+// Largest sACN data packet is 638 bytes, Art-Net 530
+const MAX_PACKET_LEN: usize = 640;
+
+// Universes we track sequence numbers for - the panel needs 2 with the default mapping
+const MAX_TRACKED_UNIVERSES: usize = 4;
+
+#[embassy_executor::task]
+pub async fn dmx_task(stack: Stack<'static>) {
+    log!("🌱 Start DMX task...");
+
+    let mut sacn_rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut sacn_rx_buffer = [0u8; 4 * MAX_PACKET_LEN];
+    let mut sacn_tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut sacn_tx_buffer = [0u8; 64];
+    let mut sacn_socket = UdpSocket::new(
+        stack,
+        &mut sacn_rx_meta,
+        &mut sacn_rx_buffer,
+        &mut sacn_tx_meta,
+        &mut sacn_tx_buffer,
+    );
+
+    let mut artnet_rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut artnet_rx_buffer = [0u8; 4 * MAX_PACKET_LEN];
+    let mut artnet_tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut artnet_tx_buffer = [0u8; 64];
+    let mut artnet_socket = UdpSocket::new(
+        stack,
+        &mut artnet_rx_meta,
+        &mut artnet_rx_buffer,
+        &mut artnet_tx_meta,
+        &mut artnet_tx_buffer,
+    );
+
+    if let Err(e) = sacn_socket.bind(SACN_PORT) {
+        defmt::error!("Failed to bind sACN port: {:?}", defmt::Debug2Format(&e));
+        return;
+    }
+    if let Err(e) = artnet_socket.bind(ARTNET_PORT) {
+        defmt::error!("Failed to bind Art-Net port: {:?}", defmt::Debug2Format(&e));
+        return;
+    }
+
+    let mut mapping = DmxMapping::default();
+    join_universes(stack, &mapping);
+
+    let mut frame = [0u8; LED_BUFFER_SIZE];
+    let mut last_seq: [Option<u8>; MAX_TRACKED_UNIVERSES] = [None; MAX_TRACKED_UNIVERSES];
+    let mut sacn_buf = [0u8; MAX_PACKET_LEN];
+    let mut artnet_buf = [0u8; MAX_PACKET_LEN];
+
+    let mut current_mode = Mode::default();
+    let mut receiver = MODE.receiver().unwrap();
+
+    log!("🔁 DMX entering main loop...");
+    loop {
+        let packets = select(
+            sacn_socket.recv_from(&mut sacn_buf),
+            artnet_socket.recv_from(&mut artnet_buf),
+        );
+
+        let bytes = match select3(receiver.changed(), DMX_MAPPING.wait(), packets).await {
+            Either3::First(mode) => {
+                current_mode = mode;
+                continue;
+            }
+            Either3::Second(new_mapping) => {
+                log!("DMX mapping: {:?}", new_mapping);
+                leave_universes(stack, &mapping);
+                mapping = new_mapping;
+                join_universes(stack, &mapping);
+                last_seq = [None; MAX_TRACKED_UNIVERSES];
+                continue;
+            }
+            Either3::Third(Either::First(Ok((n, _)))) => &sacn_buf[..n],
+            Either3::Third(Either::Second(Ok((n, _)))) => &artnet_buf[..n],
+            Either3::Third(Either::First(Err(e)) | Either::Second(Err(e))) => {
+                defmt::warn!("DMX receive failed: {:?}", defmt::Debug2Format(&e));
+                continue;
+            }
+        };
+
+        let packet = match DmxPacket::parse(bytes) {
+            Ok(p) => p,
+            Err(DmxError::NotDmxData) => continue,
+            Err(e) => {
+                defmt::debug!("Invalid DMX packet: {:?}", e);
+                continue;
+            }
+        };
+
+        // Drop out-of-order packets. Art-Net sequence 0 means the sender doesn't sequence.
+        if let Some(last) = packet
+            .universe
+            .checked_sub(mapping.universe)
+            .and_then(|i| last_seq.get_mut(i as usize))
+            && packet.sequence != 0
+        {
+            if let Some(prev) = *last
+                && !sequence_is_newer(packet.sequence, prev)
+            {
+                continue;
+            }
+            *last = Some(packet.sequence);
+        }
+
+        if !mapping.apply(packet.universe, packet.data, &mut frame) || current_mode != Mode::Dmx {
+            continue;
+        }
+
+        // Publish our frame buffer — safe because led_task won't read until signalled,
+        // and we block until it's done.
+        FRAME_PTR.store(frame.as_ptr() as usize, Ordering::Release);
+        FRAME_LEN.store(LED_BUFFER_SIZE, Ordering::Release);
+
+        FRAME_READY.signal(());
+        FRAME_CONSUMED.wait().await;
+    }
+}
+
+// sACN sources usually multicast; Art-Net is broadcast or unicast and needs nothing extra.
+fn join_universes(stack: Stack<'static>, mapping: &DmxMapping) {
+    for universe in mapping.universes(LED_BUFFER_SIZE) {
+        let [a, b, c, d] = sacn::multicast_address(universe);
+        if let Err(e) = stack.join_multicast_group(Ipv4Address::new(a, b, c, d)) {
+            defmt::warn!(
+                "Failed to join sACN universe {}: {:?}",
+                universe,
+                defmt::Debug2Format(&e)
+            );
+        }
+    }
+}
+
+fn leave_universes(stack: Stack<'static>, mapping: &DmxMapping) {
+    for universe in mapping.universes(LED_BUFFER_SIZE) {
+        let [a, b, c, d] = sacn::multicast_address(universe);
+        let _ = stack.leave_multicast_group(Ipv4Address::new(a, b, c, d));
+    }
+}
@@ -1,22 +1,26 @@
-use crate::{FRAME_CONSUMED, FRAME_LEN, FRAME_PTR, FRAME_READY, log};
+use crate::{
+    BRIGHTNESS, FRAME_CONSUMED, FRAME_LEN, FRAME_PTR, FRAME_READY, FRAME_STATS, POWER_MW, log,
+};
 use common::{LED_PANEL_HEIGHT, LED_PANEL_NUM_LEDS, LED_PANEL_WIDTH};
 use core::sync::atomic::Ordering;
+use embassy_time::Instant;
 use esp_hal::rmt::Rmt;
 use esp_hal_smartled::{RmtSmartLeds, Ws2812Timing, buffer_size, color_order};
-use host_common::serpentine_index;
+use host_common::{panel_index, power};
+use protocol::config::PanelLayout;
 use smart_leds::SmartLedsWrite;
 use smart_leds::{RGB8, brightness, gamma};
 
-const BRIGHTNESS: u8 = 100;
-
 #[embassy_executor::task]
 pub async fn led_task(
     gpio: esp_hal::gpio::AnyPin<'static>,
     rmt: esp_hal::peripherals::RMT<'static>,
+    layout: PanelLayout,
 ) {
     log!("🌱 Start LED task...");
 
-    // LED panel is a strip of 256 WS2812B LEDs arranged in a 16x16 grid, in a serpentine pattern.
+    // LED panel is a strip of 256 WS2812B LEDs arranged in a 16x16 grid, by default in a
+    // serpentine pattern (`layout` can say otherwise):
     //
     // The first strip LED is at the panel's bottom left corner, then the sequence goes right,
     // then up a row, then goes left, then up a row, and so on in a serpentine pattern.
@@ -69,6 +73,7 @@ pub async fn led_task(
     log!("🔁 LED task waiting for frames...");
     loop {
         FRAME_READY.wait().await;
+        let start = Instant::now();
 
         let ptr = FRAME_PTR.load(Ordering::Acquire);
         let len = FRAME_LEN.load(Ordering::Acquire);
@@ -80,7 +85,7 @@ pub async fn led_task(
         for y in 0..LED_PANEL_HEIGHT {
             for x in 0..LED_PANEL_WIDTH {
                 let src = (y * LED_PANEL_WIDTH + x) * 3usize;
-                let dst = serpentine_index(x, y, LED_PANEL_WIDTH, LED_PANEL_HEIGHT);
+                let dst = panel_index(x, y, LED_PANEL_WIDTH, LED_PANEL_HEIGHT, layout);
                 data[dst] = RGB8 {
                     r: pixels[src],
                     g: pixels[src + 1],
@@ -89,12 +94,24 @@ pub async fn led_task(
             }
         }
 
+        let level = BRIGHTNESS.load(Ordering::Relaxed);
+
+        // Sum what is actually written, after gamma and brightness, for the power estimate
+        let mut channel_sum = 0u32;
+        let colors = brightness(gamma(data.iter().cloned()), level).inspect(|c| {
+            channel_sum += c.r as u32 + c.g as u32 + c.b as u32;
+        });
+
         // Disable interrupts to avoid glitches
         critical_section::with(|_| {
-            led.write(brightness(gamma(data.iter().cloned()), BRIGHTNESS))
-                .expect("Should write to LED");
+            led.write(colors).expect("Should write to LED");
         });
 
         FRAME_CONSUMED.signal(());
+        POWER_MW.store(
+            power::estimate_mw(channel_sum, LED_PANEL_NUM_LEDS as u32),
+            Ordering::Relaxed,
+        );
+        FRAME_STATS.record(start.elapsed().as_micros() as u32);
     }
 }
@@ -1,17 +1,31 @@
 //#![cfg_attr(not(test), no_std)]
 #![no_std]
 
-use core::sync::atomic::AtomicUsize;
+extern crate alloc;
+
+use ::dmx::DmxMapping;
+use alloc::vec::Vec;
+use common::LED_BUFFER_SIZE;
+use core::sync::atomic::{AtomicI8, AtomicU8, AtomicU32, AtomicUsize};
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::channel::Channel;
 use embassy_sync::signal::Signal;
 use embassy_sync::watch::Watch;
-use serde::{Deserialize, Serialize};
+use host_common::stats::FrameStats;
+use protocol::stream::MAX_STREAM_FRAME_LEN;
+use protocol::{DirectCommand, Point};
+
+pub use protocol::Mode;
 
+pub mod config;
 pub mod direct;
+pub mod discovery;
+pub mod dmx;
 pub mod led;
 pub mod mqtt;
 pub mod net;
+pub mod stream;
+pub mod telemetry;
 pub mod wasm;
 
 // buffer provider signals this when a frame is ready in the pixel buffer
@@ -25,13 +39,40 @@ pub(crate) static FRAME_PTR: AtomicUsize = AtomicUsize::new(0);
 pub(crate) static FRAME_LEN: AtomicUsize = AtomicUsize::new(0);
 
 // Broadcast the current mode to all listening tasks
-pub static MODE: Watch<CriticalSectionRawMutex, Mode, 2> = Watch::new();
+pub static MODE: Watch<CriticalSectionRawMutex, Mode, 4> = Watch::new();
 
 // Host pixel buffer pointer, created within WASM guest memory space.
 // Valid after WASM init; backed by wasmi heap memory that outlives all tasks.
 pub(crate) static HOST_BUFFER_PTR: AtomicUsize = AtomicUsize::new(0);
 
 pub(crate) static DIRECT_CMD: Channel<CriticalSectionRawMutex, DirectCommand, 4> = Channel::new();
+pub(crate) static DIRECT_BLIT: Channel<CriticalSectionRawMutex, BlitFrame, 1> = Channel::new();
+
+// Binary stream frames (`protocol::stream`), newest last. Kept short so a slow consumer
+// displays recent frames rather than working through a backlog.
+pub(crate) static STREAM_FRAMES: Channel<CriticalSectionRawMutex, StreamPacket, 2> = Channel::new();
+
+// Global brightness applied by led_task (0-255), set by `Command::SetBrightness` and initialised
+// from the config
+pub(crate) static BRIGHTNESS: AtomicU8 = AtomicU8::new(protocol::config::DEFAULT_BRIGHTNESS);
+
+// Set by `Command::SetDmxMapping`, picked up by dmx_task
+pub(crate) static DMX_MAPPING: Signal<CriticalSectionRawMutex, DmxMapping> = Signal::new();
+
+// Frames written by led_task, reported in telemetry
+pub(crate) static FRAME_STATS: FrameStats = FrameStats::new();
+
+// A guest module deployed over MQTT, picked up by wasm_task. Only the latest is kept.
+pub(crate) static GUEST_MODULE: Signal<CriticalSectionRawMutex, Vec<u8>> = Signal::new();
+
+// Guest linear memory size in bytes, 0 until the guest is loaded
+pub(crate) static GUEST_MEMORY_BYTES: AtomicU32 = AtomicU32::new(0);
+
+// Estimated panel power draw in mW for the latest frame, updated by the LED task
+pub(crate) static POWER_MW: AtomicU32 = AtomicU32::new(0);
+
+// Access point RSSI in dBm, sampled by the connection task. 0 when not connected.
+pub(crate) static WIFI_RSSI: AtomicI8 = AtomicI8::new(0);
 
 // A macro that calls defmt::info!() as well as println!()
 #[macro_export]
@@ -42,35 +83,12 @@ macro_rules! log {
     }};
 }
 
-#[derive(Serialize, Deserialize, defmt::Format, Debug, Clone, PartialEq, Default)]
-pub enum Mode {
-    Direct,
-    #[default]
-    Wasm,
-}
-
-#[derive(Serialize, Deserialize, defmt::Format, Debug)]
-pub(crate) struct Rgb {
-    r: u8,
-    g: u8,
-    b: u8,
-}
-
-/// Top-left is {x: 0, y: 0}, bottom right is {x: NUM_X - 1, y: NUM_Y - 1}
-#[derive(Serialize, Deserialize, defmt::Format, Debug)]
-pub(crate) struct Point {
-    x: u8,
-    y: u8,
-}
-
-#[derive(Serialize, Deserialize, defmt::Format, Debug)]
-pub(crate) enum DirectCommand {
-    SetPixel { point: Point, color: Rgb },
-    SetAll { color: Rgb },
-}
+pub(crate) type StreamPacket = heapless::Vec<u8, MAX_STREAM_FRAME_LEN>;
 
-#[derive(Serialize, Deserialize, defmt::Format, Debug)]
-pub(crate) enum Command {
-    SetMode(Mode),
-    DirectCommand(DirectCommand),
+/// An owned copy of a [`protocol::BinaryBlit`], queued for `direct_task`.
+pub(crate) struct BlitFrame {
+    origin: Point,
+    width: u8,
+    height: u8,
+    data: heapless::Vec<u8, LED_BUFFER_SIZE>,
 }
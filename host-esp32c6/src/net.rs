@@ -1,15 +1,17 @@
-use crate::log;
-use embassy_net::Runner;
+use crate::{WIFI_RSSI, log};
+use core::sync::atomic::Ordering;
+use embassy_futures::select::{Either, select};
+use embassy_net::{Ipv4Address, Ipv4Cidr, Runner};
 use embassy_time::{Duration, Timer};
 use esp_radio::wifi::{
     ClientConfig, ModeConfig, ScanConfig, WifiController, WifiDevice, WifiEvent, WifiStaState,
 };
+use protocol::config::{StaticIp, WifiConfig};
 
-const SSID: &str = env!("WIFI_SSID");
-const PASSWORD: &str = env!("WIFI_PASSWORD");
+const RSSI_INTERVAL: Duration = Duration::from_secs(5);
 
 #[embassy_executor::task]
-pub async fn connection(mut controller: WifiController<'static>) {
+pub async fn connection(mut controller: WifiController<'static>, wifi: WifiConfig) {
     log!("🌱 Start connection task...");
     log!(
         "Device capabilities: {:?}",
@@ -17,16 +19,25 @@ pub async fn connection(mut controller: WifiController<'static>) {
     );
     loop {
         if esp_radio::wifi::sta_state() == WifiStaState::Connected {
-            // wait until we're no longer connected
-            controller.wait_for_event(WifiEvent::StaDisconnected).await;
+            // wait until we're no longer connected, sampling the signal strength meanwhile
+            while esp_radio::wifi::sta_state() == WifiStaState::Connected {
+                if let Ok(rssi) = controller.rssi() {
+                    WIFI_RSSI.store(rssi.clamp(i8::MIN.into(), -1) as i8, Ordering::Relaxed);
+                }
+                let disconnected = controller.wait_for_event(WifiEvent::StaDisconnected);
+                if let Either::First(_) = select(disconnected, Timer::after(RSSI_INTERVAL)).await {
+                    break;
+                }
+            }
+            WIFI_RSSI.store(0, Ordering::Relaxed);
             log!("💀 WiFi disconnected");
             Timer::after(Duration::from_millis(5000)).await
         }
         if !matches!(controller.is_started(), Ok(true)) {
             let client_config = ModeConfig::Client(
                 ClientConfig::default()
-                    .with_ssid(SSID.into())
-                    .with_password(PASSWORD.into()),
+                    .with_ssid(wifi.ssid.as_str().into())
+                    .with_password(wifi.password.as_str().into()),
             );
             controller.set_config(&client_config).unwrap();
             log!("Starting wifi");
@@ -58,6 +69,23 @@ pub async fn connection(mut controller: WifiController<'static>) {
     }
 }
 
+/// The network stack config for `static_ip`, or DHCP.
+pub fn stack_config(static_ip: Option<StaticIp>) -> embassy_net::Config {
+    let Some(ip) = static_ip else {
+        return embassy_net::Config::dhcpv4(Default::default());
+    };
+    let [a, b, c, d] = ip.address;
+    let [ga, gb, gc, gd] = ip.gateway;
+    let [da, db, dc, dd] = ip.dns;
+    let mut dns_servers = heapless::Vec::<Ipv4Address, 3>::new();
+    let _ = dns_servers.push(Ipv4Address::new(da, db, dc, dd));
+    embassy_net::Config::ipv4_static(embassy_net::StaticConfigV4 {
+        address: Ipv4Cidr::new(Ipv4Address::new(a, b, c, d), ip.prefix_len),
+        gateway: Some(Ipv4Address::new(ga, gb, gc, gd)),
+        dns_servers,
+    })
+}
+
 #[embassy_executor::task]
 pub async fn net_task(mut runner: Runner<'static, WifiDevice<'static>>) {
     runner.run().await
@@ -0,0 +1,90 @@
+use crate::{FRAME_CONSUMED, FRAME_LEN, FRAME_PTR, FRAME_READY, MODE, Mode, STREAM_FRAMES, log};
+use common::LED_BUFFER_SIZE;
+use core::sync::atomic::Ordering;
+use embassy_futures::select::{Either3, select3};
+use embassy_time::{Duration, Instant, Timer};
+use protocol::stream::StreamFrame;
+
+// Fall back to the previous mode if no frame arrives for this long
+const STREAM_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[embassy_executor::task]
+pub async fn stream_task() {
+    log!("🌱 Start Stream task...");
+
+    // The displayed frame, which deltas are applied to
+    let mut frame = [0u8; LED_BUFFER_SIZE];
+    let mut last_seq: Option<u32> = None;
+    let mut last_frame_at = Instant::now();
+
+    let mut current_mode = Mode::default();
+    let mut previous_mode = Mode::default();
+    let mut receiver = MODE.receiver().unwrap();
+
+    log!("🔁 Stream entering main loop...");
+    loop {
+        let timeout = async {
+            if current_mode == Mode::Stream {
+                Timer::at(last_frame_at + STREAM_TIMEOUT).await
+            } else {
+                core::future::pending().await
+            }
+        };
+
+        match select3(receiver.changed(), STREAM_FRAMES.receive(), timeout).await {
+            Either3::First(mode) => {
+                if mode == Mode::Stream {
+                    // Give an explicit SetMode(Stream) a full timeout to start streaming
+                    last_frame_at = Instant::now();
+                } else {
+                    previous_mode = mode.clone();
+                    last_seq = None;
+                }
+                current_mode = mode;
+            }
+            Either3::Second(packet) => {
+                let incoming = match StreamFrame::parse(&packet) {
+                    Ok(f) => f,
+                    Err(e) => {
+                        defmt::warn!("Invalid stream frame: {:?}", e);
+                        continue;
+                    }
+                };
+                if !incoming.follows(last_seq) {
+                    defmt::debug!(
+                        "Dropping stream frame {} after {:?}",
+                        incoming.seq,
+                        last_seq
+                    );
+                    continue;
+                }
+                if let Err(e) = incoming.apply(&mut frame) {
+                    defmt::warn!("Invalid stream frame {}: {:?}", incoming.seq, e);
+                    continue;
+                }
+                last_seq = Some(incoming.seq);
+                last_frame_at = Instant::now();
+
+                if current_mode != Mode::Stream {
+                    log!("Stream started, will return to {:?}", previous_mode);
+                    current_mode = Mode::Stream;
+                    MODE.sender().send(Mode::Stream);
+                }
+
+                // Publish our frame buffer — safe because led_task won't read until signalled,
+                // and we block until it's done.
+                FRAME_PTR.store(frame.as_ptr() as usize, Ordering::Release);
+                FRAME_LEN.store(LED_BUFFER_SIZE, Ordering::Release);
+
+                FRAME_READY.signal(());
+                FRAME_CONSUMED.wait().await;
+            }
+            Either3::Third(()) => {
+                log!("Stream timed out, returning to {:?}", previous_mode);
+                last_seq = None;
+                current_mode = previous_mode.clone();
+                MODE.sender().send(previous_mode.clone());
+            }
+        }
+    }
+}
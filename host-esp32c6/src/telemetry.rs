@@ -0,0 +1,70 @@
+//! Device status reports, published by `mqtt_task` on [`protocol::topics::suffix::TELEMETRY`].
+
+use crate::wasm::GUEST_NAME;
+use crate::{FRAME_STATS, GUEST_MEMORY_BYTES, MODE, POWER_MW, WIFI_RSSI};
+use core::fmt::Write;
+use core::sync::atomic::Ordering;
+use embassy_net::Stack;
+use embassy_time::Instant;
+use protocol::PROTOCOL_VERSION;
+use protocol::telemetry::{FrameTimes, HeapUsage, Telemetry};
+
+/// Collects a [`Telemetry`] report. Frame statistics cover the time since the previous report.
+pub struct Reporter {
+    last_report: Instant,
+}
+
+impl Reporter {
+    pub fn new() -> Self {
+        Self {
+            last_report: Instant::now(),
+        }
+    }
+
+    pub fn report(&mut self, stack: Stack<'_>) -> Telemetry {
+        let now = Instant::now();
+        let elapsed_ms = (now - self.last_report).as_millis() as u32;
+        self.last_report = now;
+
+        let frames = FRAME_STATS.take(elapsed_ms);
+        let guest_memory_bytes = GUEST_MEMORY_BYTES.load(Ordering::Relaxed);
+        let rssi = WIFI_RSSI.load(Ordering::Relaxed);
+
+        let ip = stack.config_v4().map(|config| {
+            let mut ip = heapless::String::new();
+            // A dotted quad always fits
+            let _ = write!(ip, "{}", config.address.address());
+            ip
+        });
+
+        Telemetry {
+            protocol_version: PROTOCOL_VERSION,
+            firmware_version: heapless::String::try_from(env!("CARGO_PKG_VERSION"))
+                .unwrap_or_default(),
+            uptime_s: now.as_secs() as u32,
+            mode: MODE.try_get().unwrap_or_default(),
+            guest: (guest_memory_bytes != 0)
+                .then(|| heapless::String::try_from(GUEST_NAME).unwrap()),
+            frames: FrameTimes {
+                fps: frames.fps,
+                min_us: frames.min_us,
+                avg_us: frames.avg_us,
+                max_us: frames.max_us,
+            },
+            heap: HeapUsage {
+                used: esp_alloc::HEAP.used() as u32,
+                free: esp_alloc::HEAP.free() as u32,
+            },
+            guest_memory_bytes,
+            power_mw: POWER_MW.load(Ordering::Relaxed),
+            rssi_dbm: (rssi != 0).then_some(rssi),
+            ip,
+        }
+    }
+}
+
+impl Default for Reporter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
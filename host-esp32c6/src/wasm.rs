@@ -1,19 +1,39 @@
-use crate::{FRAME_CONSUMED, FRAME_LEN, FRAME_PTR, FRAME_READY, HOST_BUFFER_PTR, MODE, Mode, log};
+use crate::{
+    FRAME_CONSUMED, FRAME_LEN, FRAME_PTR, FRAME_READY, GUEST_MEMORY_BYTES, GUEST_MODULE,
+    HOST_BUFFER_PTR, MODE, Mode, log,
+};
 use common::LED_BUFFER_SIZE;
 use core::sync::atomic::Ordering;
-use embassy_futures::select::{Either, select};
+use embassy_futures::select::{Either3, select3};
 use embassy_time::{Duration, Timer};
 use esp_hal::time::Instant;
 use wasmi::{Engine, Linker, Memory, Module, Store, TypedFunc};
 
 const TICKS_PER_SECOND: u64 = 256;
 
+/// Name of the guest built into the firmware
+pub const GUEST_NAME: &str = "guest";
+
+/// Run until another guest is deployed, and again if that one won't start
+const BUILT_IN_GUEST: &[u8] =
+    include_bytes!("../../target/wasm32-unknown-unknown/release/guest.wasm");
+
 pub struct AppState {
     start_time: Instant,
     ticks: u64,
     counter: u64,
 }
 
+impl AppState {
+    fn new() -> Self {
+        Self {
+            start_time: Instant::now(),
+            ticks: 0,
+            counter: 0,
+        }
+    }
+}
+
 pub struct GuestState {
     _engine: Engine,
     store: Store<()>,
@@ -26,76 +46,93 @@ pub struct GuestState {
     update: TypedFunc<(u64, u64, u32), u32>,
 }
 
-#[embassy_executor::task]
-pub async fn wasm_task() {
-    log!("🌱 Start WASM task...");
+impl GuestState {
+    /// Instantiate `module`, give it the host's page of memory, and call its `init`. The host
+    /// buffer is published for the other tasks.
+    fn new(engine: Engine, module: &Module) -> Result<Self, wasmi::Error> {
+        log!("⚙️ Initialising WASMI store...");
+        let mut store = Store::new(&engine, ());
+        log!("⚙️ Initialising WASMI linker...");
+        let linker = Linker::<()>::new(&engine);
+
+        log!("⚙️ Instantiating WASMI instance...");
+        let instance = linker.instantiate_and_start(&mut store, module)?;
+
+        let memory = instance
+            .get_memory(&store, "memory")
+            .ok_or_else(|| wasmi::Error::new("no `memory` export"))?;
+
+        let host_buffer_offset = memory.data(&store).len() as u32;
+
+        // Grow guest memory by 1 page (64KiB) to give some space for the host buffer
+        memory.grow(&mut store, 1)?;
+        log!(
+            "⚙️ Guest memory size: 0x{:04x} bytes @ offset 0x{:04x}",
+            memory.data(&store).len(),
+            host_buffer_offset
+        );
+
+        if host_buffer_offset as usize + LED_BUFFER_SIZE > memory.data_size(&store) {
+            return Err(wasmi::Error::new("Not enough memory for host pixel buffer"));
+        }
 
-    let wasm_bytes = include_bytes!("../../target/wasm32-unknown-unknown/release/guest.wasm");
+        let update_func = instance.get_typed_func::<(u64, u64, u32), u32>(&mut store, "update")?;
+        let init_func = instance.get_typed_func::<(), ()>(&mut store, "init")?;
+
+        log!("🧳 Calling guest 'init' function...");
+        init_func.call(&mut store, ())?;
+
+        // Store the host buffer pointer for sharing between tasks
+        let host_buffer_ptr = memory.data(&store).as_ptr() as usize + host_buffer_offset as usize;
+        HOST_BUFFER_PTR.store(host_buffer_ptr, Ordering::Release);
+        GUEST_MEMORY_BYTES.store(memory.data_size(&store) as u32, Ordering::Relaxed);
+
+        Ok(Self {
+            _engine: engine,
+            store,
+            _linker: linker,
+            memory,
+            host_buffer_offset,
+            _init: init_func,
+            update: update_func,
+        })
+    }
+}
+
+/// Compile `wasm`, which checks it's a valid module.
+fn compile(wasm: &[u8]) -> Result<(Engine, Module), wasmi::Error> {
     log!("⚙️ Initialising WASMI engine...");
     let engine = Engine::default();
     log!("⚙️ Initialising WASMI module...");
-    let module = Module::new(&engine, wasm_bytes).expect("Failed to create module");
-    log!("⚙️ Initialising WASMI store...");
-    let mut store = Store::new(&engine, ());
-    log!("⚙️ Initialising WASMI linker...");
-    let linker = Linker::<()>::new(&engine);
-
-    log!("⚙️ Instantiating WASMI instance...");
-    let instance = linker
-        .instantiate_and_start(&mut store, &module)
-        .expect("Failed to instantiate module");
-
-    let memory = instance
-        .get_memory(&store, "memory")
-        .expect("Failed to get guest memory");
-
-    let host_buffer_offset = memory.data(&store).len() as u32;
-
-    // Grow guest memory by 1 page (64KiB) to give some space for the host buffer
-    memory.grow(&mut store, 1).expect("Failed to grow memory");
-    log!(
-        "⚙️ Guest memory size: 0x{:04x} bytes @ offset 0x{:04x}",
-        memory.data(&store).len(),
-        host_buffer_offset
-    );
-
-    assert!(
-        host_buffer_offset as usize + LED_BUFFER_SIZE <= (memory.data_size(&store)),
-        "Not enough memory for host pixel buffer"
-    );
-
-    // Store the host buffer pointer for sharing between tasks
-    let host_buffer_ptr = memory.data(&store).as_ptr() as usize + host_buffer_offset as usize;
-    HOST_BUFFER_PTR.store(host_buffer_ptr, Ordering::Release);
-
-    let update_func = instance
-        .get_typed_func::<(u64, u64, u32), u32>(&mut store, "update")
-        .expect("Failed to get 'update' function");
-
-    let init_func = instance
-        .get_typed_func::<(), ()>(&mut store, "init")
-        .expect("Failed to get 'init' function");
-
-    log!("🧳 Calling guest 'init' function...");
-    init_func
-        .call(&mut store, ())
-        .expect("Failed to call guest 'init' function");
-
-    let mut guest_state = GuestState {
-        _engine: engine,
-        store,
-        _linker: linker,
-        memory,
-        host_buffer_offset,
-        _init: init_func,
-        update: update_func,
-    };
-
-    let mut app_state = AppState {
-        start_time: Instant::now(),
-        ticks: 0,
-        counter: 0,
-    };
+    let module = Module::new(&engine, wasm)?;
+    Ok((engine, module))
+}
+
+/// The built-in guest, which always runs.
+fn built_in_guest() -> GuestState {
+    let (engine, module) = compile(BUILT_IN_GUEST).expect("Failed to create module");
+    GuestState::new(engine, &module).expect("Failed to start the built-in guest")
+}
+
+/// Drop `guest` and start the one from `next`. The host buffer is in the running guest's
+/// memory, so this waits until the LED task has taken any frame drawn in it; from then on
+/// nothing else runs until `next` has published the new guest's buffer.
+async fn replace_guest(guest: GuestState, next: impl FnOnce() -> GuestState) -> GuestState {
+    while FRAME_READY.signaled() {
+        Timer::after(Duration::from_millis(1)).await;
+    }
+    drop(guest);
+    let guest = next();
+    log!("🧳 Guest replaced");
+    guest
+}
+
+#[embassy_executor::task]
+pub async fn wasm_task() {
+    log!("🌱 Start WASM task...");
+
+    let mut guest_state = built_in_guest();
+    let mut app_state = AppState::new();
 
     let mut current_mode = Mode::default();
 
@@ -104,11 +141,38 @@ pub async fn wasm_task() {
     log!("🔁 WASMI entering main loop...");
 
     loop {
-        match select(receiver.changed(), Timer::after(Duration::from_millis(1))).await {
-            Either::First(mode) => {
+        match select3(
+            receiver.changed(),
+            GUEST_MODULE.wait(),
+            Timer::after(Duration::from_millis(1)),
+        )
+        .await
+        {
+            Either3::First(mode) => {
                 current_mode = mode;
             }
-            Either::Second(_) => {
+            Either3::Second(wasm) => {
+                // Compiled while the running guest is still there, so that one which doesn't
+                // even compile leaves it be
+                let (engine, module) = match compile(&wasm) {
+                    Ok(compiled) => compiled,
+                    Err(e) => {
+                        defmt::warn!("Deployed guest is invalid: {:?}", defmt::Debug2Format(&e));
+                        continue;
+                    }
+                };
+                drop(wasm);
+
+                guest_state = replace_guest(guest_state, || {
+                    GuestState::new(engine, &module).unwrap_or_else(|e| {
+                        defmt::warn!("Deployed guest won't start: {:?}", defmt::Debug2Format(&e));
+                        built_in_guest()
+                    })
+                })
+                .await;
+                app_state = AppState::new();
+            }
+            Either3::Third(_) => {
                 if current_mode != Mode::Wasm {
                     continue;
                 }
@@ -116,17 +180,34 @@ pub async fn wasm_task() {
                 let elapsed = Instant::now() - app_state.start_time;
                 app_state.ticks = elapsed.as_millis() * TICKS_PER_SECOND / 1000;
 
-                let pixel_buffer = guest_state
-                    .update
-                    .call(
-                        &mut guest_state.store,
-                        (
-                            app_state.ticks,
-                            app_state.counter,
-                            guest_state.host_buffer_offset,
-                        ),
-                    )
-                    .expect("Failed to call 'update' function");
+                let result = guest_state.update.call(
+                    &mut guest_state.store,
+                    (
+                        app_state.ticks,
+                        app_state.counter,
+                        guest_state.host_buffer_offset,
+                    ),
+                );
+                let memory_len = guest_state.memory.data_size(&guest_state.store);
+                let pixel_buffer = match result {
+                    Ok(offset)
+                        if (offset as usize)
+                            .checked_add(LED_BUFFER_SIZE)
+                            .is_some_and(|end| end <= memory_len) =>
+                    {
+                        offset
+                    }
+                    // A deployed guest may be broken
+                    result => {
+                        defmt::warn!(
+                            "Guest 'update' failed, restarting the built-in guest: {:?}",
+                            defmt::Debug2Format(&result)
+                        );
+                        guest_state = replace_guest(guest_state, built_in_guest).await;
+                        app_state = AppState::new();
+                        continue;
+                    }
+                };
 
                 // Check mode wasn't changed while guest was executing
                 if let Some(mode) = receiver.try_changed() {
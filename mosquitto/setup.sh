@@ -0,0 +1,55 @@
+#!/usr/bin/env bash
+# Generate what mosquitto.conf needs, if missing:
+#
+# - config/certs: a CA, and a server certificate it issued for localhost, this host (also as
+#   `.local`), the compose service name `mqtt`, and any extra names or IP addresses given as
+#   arguments. Clients pin the broker by trusting only config/certs/ca.crt.
+# - config/passwd: the user MQTT_USERNAME (default testUser) with MQTT_PASSWORD (default
+#   testPass), as built into the device firmware.
+#
+# Delete either to regenerate it.
+set -euo pipefail
+
+cd "$(dirname "$0")/config"
+username="${MQTT_USERNAME:-testUser}"
+password="${MQTT_PASSWORD:-testPass}"
+days=825
+
+if [ ! -f certs/ca.crt ]; then
+    mkdir -p certs
+    host="$(hostname -s)"
+    san="DNS:localhost,DNS:mqtt,DNS:${host},DNS:${host}.local,IP:127.0.0.1"
+    for name in "$@"; do
+        if [[ "$name" =~ ^[0-9.]+$ ]]; then
+            san="${san},IP:${name}"
+        else
+            san="${san},DNS:${name}"
+        fi
+    done
+
+    openssl req -x509 -newkey rsa:2048 -nodes -days "$days" \
+        -keyout certs/ca.key -out certs/ca.crt \
+        -subj "/CN=esp32-wasmi-led MQTT CA"
+    openssl req -newkey rsa:2048 -nodes \
+        -keyout certs/server.key -out certs/server.csr \
+        -subj "/CN=${host}"
+    openssl x509 -req -days "$days" -in certs/server.csr \
+        -CA certs/ca.crt -CAkey certs/ca.key -CAcreateserial \
+        -extfile <(printf "subjectAltName=%s\nextendedKeyUsage=serverAuth\n" "$san") \
+        -out certs/server.crt
+    rm certs/server.csr
+    # Readable by the broker's user in the container
+    chmod 644 certs/server.key
+    echo "Generated certificates for ${san}"
+fi
+
+if [ ! -f passwd ]; then
+    touch passwd
+    if command -v mosquitto_passwd >/dev/null; then
+        mosquitto_passwd -b passwd "$username" "$password"
+    else
+        docker run --rm -v "$PWD:/mosquitto/config" eclipse-mosquitto \
+            mosquitto_passwd -b /mosquitto/config/passwd "$username" "$password"
+    fi
+    echo "Generated passwd for ${username}"
+fi
@@ -0,0 +1,160 @@
+//! Frame blits: copying a rectangle of RGB pixels into the host pixel buffer.
+//!
+//! A blit arrives either as JSON ([`DirectCommand::Blit`](crate::DirectCommand::Blit), base64
+//! pixel data) or as a raw binary payload ([`BinaryBlit`]):
+//!
+//! ```text
+//! [x: u8][y: u8][width: u8][height: u8][width * height * 3 bytes of row-major RGB]
+//! ```
+
+use crate::{DirectCommand, Point};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+
+/// Length of the `[x, y, width, height]` header of a [`BinaryBlit`].
+pub const BINARY_BLIT_HEADER_LEN: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum BlitError {
+    /// Payload is shorter than the 4 byte binary header
+    MissingHeader,
+    /// The pixel data is not valid base64
+    InvalidBase64,
+    /// The pixel data length doesn't match `width * height * 3`
+    LengthMismatch { expected: usize, actual: usize },
+    /// The output buffer is too small for the decoded pixel data
+    BufferTooSmall,
+}
+
+/// A borrowed, binary-encoded blit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BinaryBlit<'a> {
+    pub origin: Point,
+    pub width: u8,
+    pub height: u8,
+    /// `width * height` row-major RGB triples
+    pub data: &'a [u8],
+}
+
+impl<'a> BinaryBlit<'a> {
+    pub fn parse(payload: &'a [u8]) -> Result<Self, BlitError> {
+        let [x, y, width, height, data @ ..] = payload else {
+            return Err(BlitError::MissingHeader);
+        };
+        check_len(*width, *height, data.len())?;
+
+        Ok(Self {
+            origin: Point::new(*x, *y),
+            width: *width,
+            height: *height,
+            data,
+        })
+    }
+
+    /// The header bytes to prefix to `data` when publishing a binary blit.
+    pub fn header(origin: Point, width: u8, height: u8) -> [u8; BINARY_BLIT_HEADER_LEN] {
+        [origin.x, origin.y, width, height]
+    }
+}
+
+impl DirectCommand {
+    /// Decode the base64 pixel data of a [`DirectCommand::Blit`] into `out`, returning the
+    /// number of bytes written. Returns `Ok(0)` for any other command.
+    pub fn decode_blit_data(&self, out: &mut [u8]) -> Result<usize, BlitError> {
+        let DirectCommand::Blit {
+            width,
+            height,
+            data,
+            ..
+        } = self
+        else {
+            return Ok(0);
+        };
+
+        let len = match STANDARD.decode_slice(data.as_bytes(), out) {
+            Ok(len) => len,
+            Err(base64::DecodeSliceError::OutputSliceTooSmall) => {
+                return Err(BlitError::BufferTooSmall);
+            }
+            Err(base64::DecodeSliceError::DecodeError(_)) => return Err(BlitError::InvalidBase64),
+        };
+        check_len(*width, *height, len)?;
+        Ok(len)
+    }
+}
+
+fn check_len(width: u8, height: u8, actual: usize) -> Result<(), BlitError> {
+    let expected = width as usize * height as usize * common::BYTES_PER_LED;
+    if actual != expected {
+        return Err(BlitError::LengthMismatch { expected, actual });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_binary_blit_parse() {
+        let mut payload = BinaryBlit::header(Point::new(2, 3), 2, 1).to_vec();
+        payload.extend_from_slice(&[1, 2, 3, 4, 5, 6]);
+
+        let blit = BinaryBlit::parse(&payload).unwrap();
+        assert_eq!(blit.origin, Point::new(2, 3));
+        assert_eq!((blit.width, blit.height), (2, 1));
+        assert_eq!(blit.data, &[1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn test_binary_blit_errors() {
+        assert_eq!(BinaryBlit::parse(&[0, 0, 1]), Err(BlitError::MissingHeader));
+        assert_eq!(
+            BinaryBlit::parse(&[0, 0, 1, 1, 255, 255]),
+            Err(BlitError::LengthMismatch {
+                expected: 3,
+                actual: 2
+            })
+        );
+    }
+
+    #[test]
+    fn test_decode_blit_data() {
+        // 2x1 pixels: [1,2,3], [4,5,6]
+        let cmd = DirectCommand::Blit {
+            origin: Point::new(0, 0),
+            width: 2,
+            height: 1,
+            data: heapless::String::try_from("AQIDBAUG").unwrap(),
+        };
+        let mut out = [0u8; common::LED_BUFFER_SIZE];
+        assert_eq!(cmd.decode_blit_data(&mut out), Ok(6));
+        assert_eq!(&out[..6], &[1, 2, 3, 4, 5, 6]);
+
+        let cmd = DirectCommand::Blit {
+            origin: Point::new(0, 0),
+            width: 4,
+            height: 1,
+            data: heapless::String::try_from("AQIDBAUG").unwrap(),
+        };
+        assert_eq!(
+            cmd.decode_blit_data(&mut out),
+            Err(BlitError::LengthMismatch {
+                expected: 12,
+                actual: 6
+            })
+        );
+
+        let cmd = DirectCommand::Blit {
+            origin: Point::new(0, 0),
+            width: 1,
+            height: 1,
+            data: heapless::String::try_from("!!!!").unwrap(),
+        };
+        assert_eq!(
+            cmd.decode_blit_data(&mut out),
+            Err(BlitError::InvalidBase64)
+        );
+    }
+}
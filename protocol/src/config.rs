@@ -0,0 +1,290 @@
+//! Device configuration, stored on the device and managed with [`Command::GetConfig`],
+//! [`Command::SetConfig`] and [`Command::ResetConfig`].
+//!
+//! The firmware's compile-time values are the defaults, used until a configuration is stored
+//! and again after a reset. Passwords are never sent back: [`DeviceConfig::redacted`] blanks
+//! them, and an empty password in `SetConfig` keeps the stored one (see
+//! [`DeviceConfig::merge_secrets`]).
+//!
+//! [`Command::GetConfig`]: crate::Command::GetConfig
+//! [`Command::SetConfig`]: crate::Command::SetConfig
+//! [`Command::ResetConfig`]: crate::Command::ResetConfig
+
+use serde::{Deserialize, Serialize};
+
+use crate::CommandError;
+
+pub const MAX_SSID_LEN: usize = 32;
+pub const MAX_WIFI_PASSWORD_LEN: usize = 64;
+pub const MAX_MQTT_USERNAME_LEN: usize = 32;
+pub const MAX_MQTT_PASSWORD_LEN: usize = 64;
+pub const MAX_HOSTNAME_LEN: usize = 64;
+
+/// Longest JSON a [`DeviceConfig`] serialises to.
+pub const MAX_CONFIG_LEN: usize = 640;
+
+/// An IPv4 address, `[192,168,1,1]`
+pub type Ipv4 = [u8; 4];
+
+/// A password. Shown as `***` in debug output, so that it stays out of the logs.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Default)]
+#[serde(transparent)]
+pub struct Secret<const N: usize>(pub heapless::String<N>);
+
+impl<const N: usize> Secret<N> {
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl<const N: usize> core::fmt::Debug for Secret<N> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("***")
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl<const N: usize> defmt::Format for Secret<N> {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "***")
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct WifiConfig {
+    pub ssid: heapless::String<MAX_SSID_LEN>,
+    pub password: Secret<MAX_WIFI_PASSWORD_LEN>,
+}
+
+/// A fixed address instead of DHCP.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct StaticIp {
+    pub address: Ipv4,
+    /// e.g. 24 for 255.255.255.0
+    pub prefix_len: u8,
+    pub gateway: Ipv4,
+    pub dns: Ipv4,
+}
+
+/// Where the broker is, see [`MqttConfig::broker_address`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum BrokerAddress<'a> {
+    /// Resolved by DNS, or mDNS for `.local` names
+    Host(&'a str),
+    Ip(Ipv4),
+    /// Found by DNS-SD, see [`mdns`](crate::mdns)
+    Discover,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MqttConfig {
+    /// Broker hostname, used in preference to `broker`
+    #[serde(default)]
+    pub host: heapless::String<MAX_HOSTNAME_LEN>,
+    /// Broker address. The broker is discovered if neither this nor `host` is set.
+    #[serde(default)]
+    pub broker: Option<Ipv4>,
+    /// Unless discovered, which gives the port too
+    pub port: u16,
+    /// No credentials are sent if empty
+    pub username: heapless::String<MAX_MQTT_USERNAME_LEN>,
+    pub password: Secret<MAX_MQTT_PASSWORD_LEN>,
+}
+
+/// The panel corner wired to the first LED of the strip.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Corner {
+    #[default]
+    BottomLeft,
+    BottomRight,
+    TopLeft,
+    TopRight,
+}
+
+/// How the LED strip is laid out across the panel. The strip runs along rows, starting at
+/// `first_led`; in a serpentine layout every other row runs back the other way.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PanelLayout {
+    pub first_led: Corner,
+    pub serpentine: bool,
+}
+
+impl Default for PanelLayout {
+    /// The 16x16 WS2812B panel this project was built on
+    fn default() -> Self {
+        Self {
+            first_led: Corner::BottomLeft,
+            serpentine: true,
+        }
+    }
+}
+
+pub const DEFAULT_BRIGHTNESS: u8 = 100;
+
+fn default_brightness() -> u8 {
+    DEFAULT_BRIGHTNESS
+}
+
+/// Everything the device needs to know that isn't in the firmware.
+///
+/// Fields added after the first release must have a `#[serde(default)]`, so that
+/// configurations stored by older firmware still load.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DeviceConfig {
+    pub wifi: WifiConfig,
+    /// DHCP if `None`
+    pub static_ip: Option<StaticIp>,
+    pub mqtt: MqttConfig,
+    /// Brightness at startup
+    #[serde(default = "default_brightness")]
+    pub brightness: u8,
+    #[serde(default)]
+    pub panel: PanelLayout,
+}
+
+impl MqttConfig {
+    pub fn broker_address(&self) -> BrokerAddress<'_> {
+        match (self.host.as_str(), self.broker) {
+            ("", Some(ip)) => BrokerAddress::Ip(ip),
+            ("", None) => BrokerAddress::Discover,
+            (host, _) => BrokerAddress::Host(host),
+        }
+    }
+}
+
+impl DeviceConfig {
+    /// A copy without the passwords, for sending off the device.
+    pub fn redacted(&self) -> Self {
+        let mut config = self.clone();
+        config.wifi.password = Secret::default();
+        config.mqtt.password = Secret::default();
+        config
+    }
+
+    /// Keep `stored`'s passwords where this config's are empty, as they are when a redacted
+    /// config is edited and sent back.
+    pub fn merge_secrets(&mut self, stored: &DeviceConfig) {
+        if self.wifi.password.is_empty() {
+            self.wifi.password = stored.wifi.password.clone();
+        }
+        if self.mqtt.password.is_empty() {
+            self.mqtt.password = stored.mqtt.password.clone();
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), CommandError> {
+        let prefix_ok = self
+            .static_ip
+            .is_none_or(|ip| (1..=32).contains(&ip.prefix_len));
+        if self.wifi.ssid.is_empty() || self.mqtt.port == 0 || !prefix_ok {
+            return Err(CommandError::OutOfRange);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example() -> DeviceConfig {
+        DeviceConfig {
+            wifi: WifiConfig {
+                ssid: heapless::String::try_from("home").unwrap(),
+                password: Secret(heapless::String::try_from("wifi-secret").unwrap()),
+            },
+            static_ip: Some(StaticIp {
+                address: [192, 168, 1, 242],
+                prefix_len: 24,
+                gateway: [192, 168, 1, 1],
+                dns: [192, 168, 1, 1],
+            }),
+            mqtt: MqttConfig {
+                host: heapless::String::new(),
+                broker: Some([192, 168, 1, 201]),
+                port: 1883,
+                username: heapless::String::try_from("testUser").unwrap(),
+                password: Secret(heapless::String::try_from("testPass").unwrap()),
+            },
+            brightness: 100,
+            panel: PanelLayout::default(),
+        }
+    }
+
+    #[test]
+    fn test_longest_config_fits() {
+        let mut config = example();
+        config.wifi.ssid = heapless::String::try_from("s".repeat(MAX_SSID_LEN).as_str()).unwrap();
+        config.wifi.password.0 =
+            heapless::String::try_from("p".repeat(MAX_WIFI_PASSWORD_LEN).as_str()).unwrap();
+        config.mqtt.username =
+            heapless::String::try_from("u".repeat(MAX_MQTT_USERNAME_LEN).as_str()).unwrap();
+        config.mqtt.password.0 =
+            heapless::String::try_from("p".repeat(MAX_MQTT_PASSWORD_LEN).as_str()).unwrap();
+        config.mqtt.host =
+            heapless::String::try_from("h".repeat(MAX_HOSTNAME_LEN).as_str()).unwrap();
+        config.static_ip.as_mut().unwrap().address = [255; 4];
+        config.panel.first_led = Corner::BottomRight;
+        config.panel.serpentine = false;
+
+        let mut buf = [0u8; MAX_CONFIG_LEN];
+        let n = serde_json_core::to_slice(&config, &mut buf).unwrap();
+        assert_eq!(
+            serde_json_core::from_slice::<DeviceConfig>(&buf[..n])
+                .unwrap()
+                .0,
+            config
+        );
+    }
+
+    #[test]
+    fn test_passwords_stay_private() {
+        let config = example();
+        let redacted = config.redacted();
+        assert!(redacted.wifi.password.is_empty() && redacted.mqtt.password.is_empty());
+        assert!(!format!("{config:?}").contains("secret"));
+
+        // Sending a redacted config back keeps the passwords; a new one replaces them
+        let mut update = redacted.clone();
+        update.mqtt.password.0 = heapless::String::try_from("new").unwrap();
+        update.merge_secrets(&config);
+        assert_eq!(update.wifi.password, config.wifi.password);
+        assert_eq!(update.mqtt.password.as_str(), "new");
+    }
+
+    #[test]
+    fn test_broker_address() {
+        let mut mqtt = example().mqtt;
+        assert_eq!(mqtt.broker_address(), BrokerAddress::Ip([192, 168, 1, 201]));
+        mqtt.host = heapless::String::try_from("broker.local").unwrap();
+        assert_eq!(mqtt.broker_address(), BrokerAddress::Host("broker.local"));
+        mqtt.host.clear();
+        mqtt.broker = None;
+        assert_eq!(mqtt.broker_address(), BrokerAddress::Discover);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(example().validate(), Ok(()));
+        let mut config = example();
+        config.wifi.ssid.clear();
+        assert_eq!(config.validate(), Err(CommandError::OutOfRange));
+        let mut config = example();
+        config.static_ip.as_mut().unwrap().prefix_len = 33;
+        assert_eq!(config.validate(), Err(CommandError::OutOfRange));
+        let mut config = example();
+        config.static_ip = None;
+        assert_eq!(config.validate(), Ok(()));
+    }
+}
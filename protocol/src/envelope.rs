@@ -0,0 +1,229 @@
+//! Acknowledged commands.
+//!
+//! A [`CommandEnvelope`] wraps a [`Command`] with an `id`. The device replies to every
+//! envelope with a [`CommandAck`] carrying the same `id`, on the envelope's `reply_to` topic
+//! or on the device's [`COMMAND_ACK`](crate::topics::suffix::COMMAND_ACK) topic if it has none.
+//! Bare commands on [`COMMAND`](crate::topics::suffix::COMMAND) are still accepted, but never
+//! acknowledged.
+//!
+//! Envelopes are sent with QoS 1, so one may be delivered more than once. The device carries
+//! out each `id` from a `sender` only once, and acknowledges repeats with the original result -
+//! so a sender must never reuse an `id` for a different command. A sender picks a new random
+//! `sender` each time it starts, so its ids need only be unique until it restarts.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Command, DirectCommand, Point, blit::BlitError};
+
+pub const MAX_REPLY_TOPIC_LEN: usize = 64;
+
+pub type ReplyTopic = heapless::String<MAX_REPLY_TOPIC_LEN>;
+
+/// `{"sender":1234,"id":7,"reply_to":"my/replies","command":{"SetMode":"Direct"}}`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CommandEnvelope {
+    /// Random for each run of the sender. 0 if the sender doesn't set one.
+    #[serde(default)]
+    pub sender: u32,
+    /// Unique to this command; a repeat of a recent `id` from the same `sender` is taken to be
+    /// a redelivery
+    pub id: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<ReplyTopic>,
+    pub command: Command,
+}
+
+/// The addressing fields of a [`CommandEnvelope`], so that an envelope whose command doesn't
+/// parse can still be answered.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct EnvelopeHeader {
+    #[serde(default)]
+    pub sender: u32,
+    pub id: u32,
+    #[serde(default)]
+    pub reply_to: Option<ReplyTopic>,
+}
+
+/// Why the device rejected a command.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CommandError {
+    /// The command didn't deserialise, or its data is malformed
+    Parse,
+    /// A value is outside what the device supports, e.g. a pixel off the panel
+    OutOfRange,
+    /// The command only applies in another [`Mode`](crate::Mode)
+    WrongMode,
+    /// The device couldn't queue the command - try again
+    Busy,
+}
+
+/// `{"id":7}` on success, `{"id":7,"error":"WrongMode"}` on failure.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CommandAck {
+    pub id: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<CommandError>,
+}
+
+impl CommandAck {
+    pub const fn ok(id: u32) -> Self {
+        Self { id, error: None }
+    }
+
+    pub const fn nack(id: u32, error: CommandError) -> Self {
+        Self {
+            id,
+            error: Some(error),
+        }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+impl Command {
+    /// Check that the command's values are ones the device can act on. This doesn't depend on
+    /// the device's state, so [`CommandError::WrongMode`] and [`CommandError::Busy`] are left to
+    /// the device.
+    pub fn validate(&self) -> Result<(), CommandError> {
+        match self {
+            Command::DirectCommand(cmd) => cmd.validate(),
+            Command::SetDmxMapping(mapping) => {
+                let channels = 1..=dmx::UNIVERSE_SIZE as u16;
+                if channels.contains(&mapping.start_channel)
+                    && channels.contains(&mapping.channels_per_universe)
+                {
+                    Ok(())
+                } else {
+                    Err(CommandError::OutOfRange)
+                }
+            }
+            Command::SetConfig(config) => config.validate(),
+            Command::SetMode(_)
+            | Command::SetBrightness(_)
+            | Command::GetConfig
+            | Command::ResetConfig => Ok(()),
+        }
+    }
+}
+
+impl DirectCommand {
+    fn validate(&self) -> Result<(), CommandError> {
+        let on_panel = |p: &Point| {
+            (p.x as usize) < common::LED_PANEL_WIDTH && (p.y as usize) < common::LED_PANEL_HEIGHT
+        };
+        match self {
+            DirectCommand::SetPixel { point, .. } if !on_panel(point) => {
+                Err(CommandError::OutOfRange)
+            }
+            DirectCommand::SetPixels { pixels } if !pixels.iter().all(|p| on_panel(&p.point)) => {
+                Err(CommandError::OutOfRange)
+            }
+            DirectCommand::Blit { .. } => {
+                let mut data = [0u8; common::LED_BUFFER_SIZE];
+                match self.decode_blit_data(&mut data) {
+                    Ok(_) => Ok(()),
+                    Err(BlitError::InvalidBase64) => Err(CommandError::Parse),
+                    Err(_) => Err(CommandError::OutOfRange),
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DmxMapping, Mode, Rgb};
+
+    #[test]
+    fn test_envelope_round_trip() {
+        let envelope = CommandEnvelope {
+            sender: 1234,
+            id: 7,
+            reply_to: Some("my/replies".try_into().unwrap()),
+            command: Command::SetMode(Mode::Direct),
+        };
+        let json = serde_json::to_string(&envelope).unwrap();
+        assert_eq!(
+            json,
+            r#"{"sender":1234,"id":7,"reply_to":"my/replies","command":{"SetMode":"Direct"}}"#
+        );
+        let (parsed, _) = serde_json_core::from_str::<CommandEnvelope>(&json).unwrap();
+        assert_eq!(parsed, envelope);
+    }
+
+    #[test]
+    fn test_header_of_unparseable_envelope() {
+        let json = r#"{"id":3,"command":{"SetMode":"Sideways"}}"#;
+        assert!(serde_json_core::from_str::<CommandEnvelope>(json).is_err());
+        let (header, _) = serde_json_core::from_str::<EnvelopeHeader>(json).unwrap();
+        assert_eq!(
+            header,
+            EnvelopeHeader {
+                sender: 0,
+                id: 3,
+                reply_to: None
+            }
+        );
+    }
+
+    #[test]
+    fn test_bare_command_has_no_header() {
+        assert!(serde_json_core::from_str::<EnvelopeHeader>(r#"{"SetBrightness":9}"#).is_err());
+    }
+
+    #[test]
+    fn test_ack_json() {
+        let mut buf = [0u8; 64];
+        let n = serde_json_core::to_slice(&CommandAck::ok(1), &mut buf).unwrap();
+        assert_eq!(&buf[..n], br#"{"id":1}"#);
+
+        let nack = CommandAck::nack(2, CommandError::WrongMode);
+        let n = serde_json_core::to_slice(&nack, &mut buf).unwrap();
+        assert_eq!(&buf[..n], br#"{"id":2,"error":"WrongMode"}"#);
+        assert_eq!(
+            serde_json::from_slice::<CommandAck>(&buf[..n]).unwrap(),
+            nack
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let pixel = |x, y| {
+            Command::DirectCommand(DirectCommand::SetPixel {
+                point: Point::new(x, y),
+                color: Rgb::BLACK,
+            })
+        };
+        assert_eq!(pixel(15, 15).validate(), Ok(()));
+        assert_eq!(pixel(16, 0).validate(), Err(CommandError::OutOfRange));
+
+        let blit = |width, data: &str| {
+            Command::DirectCommand(DirectCommand::Blit {
+                origin: Point::new(0, 0),
+                width,
+                height: 1,
+                data: data.try_into().unwrap(),
+            })
+        };
+        assert_eq!(blit(1, "AAAA").validate(), Ok(()));
+        assert_eq!(blit(2, "AAAA").validate(), Err(CommandError::OutOfRange));
+        assert_eq!(blit(1, "!!!!").validate(), Err(CommandError::Parse));
+
+        assert_eq!(
+            Command::SetDmxMapping(DmxMapping::new(1, 0)).validate(),
+            Err(CommandError::OutOfRange)
+        );
+        assert_eq!(
+            Command::SetDmxMapping(DmxMapping::new(1, 512)).validate(),
+            Ok(())
+        );
+    }
+}
@@ -0,0 +1,147 @@
+//! WASM guest modules sent to a device, on [`GUEST`](crate::topics::suffix::GUEST), in chunks
+//! small enough for the device's MQTT buffers. Each MQTT payload is one chunk:
+//!
+//! ```text
+//! [transfer: u32 LE][offset: u32 LE][total: u32 LE][data]
+//! ```
+//!
+//! A transfer is one module, `total` bytes long, sent in order with QoS 1. A chunk of a new
+//! transfer starts it afresh, and one already received is ignored; any other chunk that isn't at
+//! the offset expected abandons the transfer. Once all `total` bytes have arrived, the module
+//! replaces the running guest if it starts. The device goes back to the guest it was built with
+//! if it fails, and when it restarts.
+//!
+//! The limits here are what the device can run: a module is checked against them before it's
+//! sent.
+
+/// Length of the `[transfer, offset, total]` header of a [`GuestChunk`].
+pub const GUEST_CHUNK_HEADER_LEN: usize = 12;
+
+/// Most module bytes in a chunk
+pub const MAX_GUEST_CHUNK_DATA_LEN: usize = 1024;
+
+/// Largest module a device takes
+pub const MAX_GUEST_LEN: usize = 64 * 1024;
+
+/// Most 64 KiB pages of linear memory a guest may have, counting the page the host adds for its
+/// pixel buffer
+pub const MAX_GUEST_MEMORY_PAGES: u64 = 3;
+
+/// The host functions a guest may import, as `(module, name)`. None yet.
+pub const HOST_IMPORTS: &[(&str, &str)] = &[];
+
+/// Name of the custom section a guest may describe itself in, as JSON: its `name`, `version`,
+/// `description` and `author`, all optional.
+pub const MANIFEST_SECTION: &str = "esp32-wasmi-led.manifest";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum GuestChunkError {
+    /// Payload is shorter than the 12 byte header
+    MissingHeader,
+    /// The module is larger than [`MAX_GUEST_LEN`]
+    TooLarge(u32),
+    /// The data goes past the end of the module, or is larger than a chunk may be
+    BadLength,
+}
+
+/// A borrowed chunk of a module.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GuestChunk<'a> {
+    pub transfer: u32,
+    /// Of `data` in the module
+    pub offset: u32,
+    /// Of the module
+    pub total: u32,
+    pub data: &'a [u8],
+}
+
+impl<'a> GuestChunk<'a> {
+    pub fn parse(payload: &'a [u8]) -> Result<Self, GuestChunkError> {
+        let Some((header, data)) = payload.split_first_chunk::<GUEST_CHUNK_HEADER_LEN>() else {
+            return Err(GuestChunkError::MissingHeader);
+        };
+        let field = |i: usize| u32::from_le_bytes(header[i * 4..i * 4 + 4].try_into().unwrap());
+        let (transfer, offset, total) = (field(0), field(1), field(2));
+        if total as usize > MAX_GUEST_LEN {
+            return Err(GuestChunkError::TooLarge(total));
+        }
+        // `usize` is 32 bits on the device, so the end could wrap
+        let end = (offset as usize).checked_add(data.len());
+        if data.len() > MAX_GUEST_CHUNK_DATA_LEN || end.is_none_or(|end| end > total as usize) {
+            return Err(GuestChunkError::BadLength);
+        }
+
+        Ok(Self {
+            transfer,
+            offset,
+            total,
+            data,
+        })
+    }
+
+    /// Whether this is the chunk that completes the module.
+    pub fn is_last(&self) -> bool {
+        (self.offset as usize).checked_add(self.data.len()) == Some(self.total as usize)
+    }
+
+    /// The header bytes to prefix to a chunk's data when publishing it.
+    pub fn header(transfer: u32, offset: u32, total: u32) -> [u8; GUEST_CHUNK_HEADER_LEN] {
+        let mut header = [0; GUEST_CHUNK_HEADER_LEN];
+        for (i, field) in [transfer, offset, total].into_iter().enumerate() {
+            header[i * 4..i * 4 + 4].copy_from_slice(&field.to_le_bytes());
+        }
+        header
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_guest_chunk_parse() {
+        let mut payload = GuestChunk::header(7, 4, 6).to_vec();
+        payload.extend_from_slice(&[1, 2]);
+
+        let chunk = GuestChunk::parse(&payload).unwrap();
+        assert_eq!((chunk.transfer, chunk.offset, chunk.total), (7, 4, 6));
+        assert_eq!(chunk.data, &[1, 2]);
+        assert!(chunk.is_last());
+
+        payload.truncate(GUEST_CHUNK_HEADER_LEN + 1);
+        assert!(!GuestChunk::parse(&payload).unwrap().is_last());
+    }
+
+    #[test]
+    fn test_guest_chunk_errors() {
+        assert_eq!(
+            GuestChunk::parse(&[0; GUEST_CHUNK_HEADER_LEN - 1]),
+            Err(GuestChunkError::MissingHeader)
+        );
+
+        let too_large = MAX_GUEST_LEN as u32 + 1;
+        assert_eq!(
+            GuestChunk::parse(&GuestChunk::header(1, 0, too_large)),
+            Err(GuestChunkError::TooLarge(too_large))
+        );
+
+        let mut past_end = GuestChunk::header(1, 5, 6).to_vec();
+        past_end.extend_from_slice(&[1, 2]);
+        assert_eq!(
+            GuestChunk::parse(&past_end),
+            Err(GuestChunkError::BadLength)
+        );
+
+        let mut wraps = GuestChunk::header(1, u32::MAX, 6).to_vec();
+        wraps.extend_from_slice(&[1, 2]);
+        assert_eq!(GuestChunk::parse(&wraps), Err(GuestChunkError::BadLength));
+
+        let mut too_long = GuestChunk::header(1, 0, MAX_GUEST_LEN as u32).to_vec();
+        too_long.resize(GUEST_CHUNK_HEADER_LEN + MAX_GUEST_CHUNK_DATA_LEN + 1, 0);
+        assert_eq!(
+            GuestChunk::parse(&too_long),
+            Err(GuestChunkError::BadLength)
+        );
+    }
+}
@@ -0,0 +1,382 @@
+#![cfg_attr(not(test), no_std)]
+
+//! Message formats and MQTT topics shared between the LED matrix host and its controllers -
+//! the device, backend, frontend and emulator.
+//!
+//! Everything here must deserialise with `serde_json_core` on the device, so payloads are
+//! externally-tagged enums and plain structs, and variable-length fields are bounded
+//! `heapless` containers.
+
+use serde::{Deserialize, Serialize};
+
+pub mod blit;
+pub mod config;
+pub mod envelope;
+pub mod guest;
+pub mod mdns;
+pub mod ping;
+pub mod presence;
+pub mod stream;
+pub mod telemetry;
+pub mod topics;
+
+pub use blit::{BINARY_BLIT_HEADER_LEN, BinaryBlit, BlitError};
+pub use config::DeviceConfig;
+pub use dmx::DmxMapping;
+pub use envelope::{CommandAck, CommandEnvelope, CommandError};
+
+/// Version of the messages in this crate. Bump it when a change isn't backwards compatible.
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// Maximum number of pixels in a single [`DirectCommand::SetPixels`] batch.
+pub const MAX_PIXELS_PER_BATCH: usize = 32;
+
+/// Maximum length (in bytes) of the text in a [`DirectCommand::DrawText`].
+pub const MAX_TEXT_LEN: usize = 32;
+
+/// Capacity of the base64 `data` in a [`DirectCommand::Blit`] - enough for a full frame.
+pub const MAX_BLIT_BASE64_LEN: usize = common::LED_BUFFER_SIZE.div_ceil(3) * 4;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Rgb {
+    pub const BLACK: Rgb = Rgb::new(0, 0, 0);
+
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b }
+    }
+}
+
+impl From<Rgb> for (u8, u8, u8) {
+    fn from(c: Rgb) -> Self {
+        (c.r, c.g, c.b)
+    }
+}
+
+/// Top-left is {x: 0, y: 0}, bottom right is {x: NUM_X - 1, y: NUM_Y - 1}
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Point {
+    pub x: u8,
+    pub y: u8,
+}
+
+impl Point {
+    pub const fn new(x: u8, y: u8) -> Self {
+        Self { x, y }
+    }
+}
+
+/// A single entry in a [`DirectCommand::SetPixels`] batch.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Pixel {
+    pub point: Point,
+    pub color: Rgb,
+}
+
+/// What the device is displaying.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Mode {
+    /// Drawn by [`DirectCommand`]s and binary blits
+    Direct,
+    /// Rendered by the WASM guest
+    #[default]
+    Wasm,
+    /// Entered automatically when [`stream`] frames arrive
+    Stream,
+    /// sACN / Art-Net universes
+    Dmx,
+}
+
+impl Mode {
+    pub const ALL: [Mode; 4] = [Mode::Direct, Mode::Wasm, Mode::Stream, Mode::Dmx];
+}
+
+/// Control messages on [`topics::suffix::COMMAND`], either bare or wrapped in a [`CommandEnvelope`] to
+/// be acknowledged.
+#[allow(clippy::large_enum_variant)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Command {
+    /// `{"SetMode":"Direct"}`
+    SetMode(Mode),
+    /// `{"DirectCommand":"Clear"}`
+    DirectCommand(DirectCommand),
+    /// `{"SetDmxMapping":{"universe":1,"start_channel":1}}`
+    SetDmxMapping(DmxMapping),
+    /// `{"SetBrightness":128}` - takes effect from the next frame
+    SetBrightness(u8),
+    /// `"GetConfig"` - the device publishes its [`DeviceConfig`], without passwords, on
+    /// [`topics::suffix::CONFIG`]
+    GetConfig,
+    /// `{"SetConfig":{..}}` - store a new [`DeviceConfig`]. An empty password keeps the stored
+    /// one. Brightness applies straight away; everything else on the next restart.
+    SetConfig(DeviceConfig),
+    /// `"ResetConfig"` - back to the firmware's defaults, on the next restart
+    ResetConfig,
+}
+
+/// Drawing operations applied to the host pixel buffer while in `Mode::Direct`.
+///
+/// Anything drawn outside the panel is clipped, except that [`Command::validate`] rejects
+/// `SetPixel` and `SetPixels` points that are entirely off the panel.
+// No heap on the device to box the large variants into; queues are sized for the largest anyway.
+#[allow(clippy::large_enum_variant)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DirectCommand {
+    /// `{"SetPixel":{"point":{"x":1,"y":2},"color":{"r":255,"g":0,"b":0}}}`
+    SetPixel { point: Point, color: Rgb },
+
+    /// `{"SetAll":{"color":{"r":0,"g":0,"b":64}}}`
+    SetAll { color: Rgb },
+
+    /// `"Clear"` - same as `SetAll` with black.
+    Clear,
+
+    /// `{"SetPixels":{"pixels":[{"point":{..},"color":{..}}, ...]}}`
+    SetPixels {
+        pixels: heapless::Vec<Pixel, MAX_PIXELS_PER_BATCH>,
+    },
+
+    /// `{"FillRect":{"origin":{"x":0,"y":0},"width":4,"height":2,"color":{..}}}`
+    FillRect {
+        origin: Point,
+        width: u8,
+        height: u8,
+        color: Rgb,
+    },
+
+    /// `{"DrawLine":{"from":{"x":0,"y":0},"to":{"x":15,"y":15},"color":{..}}}` - inclusive of
+    /// both end points.
+    DrawLine { from: Point, to: Point, color: Rgb },
+
+    /// `{"DrawText":{"origin":{"x":0,"y":0},"text":"HI","color":{..}}}` - `origin` is the
+    /// top-left of the first glyph. Glyphs are 3x5 pixels with a 1 pixel gap.
+    DrawText {
+        origin: Point,
+        text: heapless::String<MAX_TEXT_LEN>,
+        color: Rgb,
+    },
+
+    /// `{"Blit":{"origin":{..},"width":16,"height":16,"data":"<base64>"}}` - `data` is
+    /// `width * height` row-major RGB triples, base64 encoded (standard alphabet, padded).
+    ///
+    /// For binary payloads, see [`BinaryBlit`].
+    Blit {
+        origin: Point,
+        width: u8,
+        height: u8,
+        data: heapless::String<MAX_BLIT_BASE64_LEN>,
+    },
+
+    /// `{"Scroll":{"dx":-1,"dy":0,"wrap":true}}` - shift the whole buffer. Vacated pixels are
+    /// filled from the opposite edge if `wrap` is set, otherwise cleared to black.
+    Scroll { dx: i8, dy: i8, wrap: bool },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(json: &str) -> DirectCommand {
+        serde_json_core::from_str::<DirectCommand>(json).unwrap().0
+    }
+
+    #[test]
+    fn test_parse_existing_commands() {
+        assert_eq!(
+            parse(r#"{"SetPixel":{"point":{"x":1,"y":2},"color":{"r":3,"g":4,"b":5}}}"#),
+            DirectCommand::SetPixel {
+                point: Point::new(1, 2),
+                color: Rgb::new(3, 4, 5)
+            }
+        );
+        assert_eq!(
+            parse(r#"{"SetAll":{"color":{"r":0,"g":0,"b":64}}}"#),
+            DirectCommand::SetAll {
+                color: Rgb::new(0, 0, 64)
+            }
+        );
+        assert_eq!(parse(r#""Clear""#), DirectCommand::Clear);
+    }
+
+    #[test]
+    fn test_parse_drawing_commands() {
+        let cmd = parse(
+            r#"{"SetPixels":{"pixels":[
+                {"point":{"x":0,"y":0},"color":{"r":255,"g":0,"b":0}},
+                {"point":{"x":15,"y":15},"color":{"r":0,"g":0,"b":255}}
+            ]}}"#,
+        );
+        let DirectCommand::SetPixels { pixels } = cmd else {
+            panic!("expected SetPixels, got {cmd:?}");
+        };
+        assert_eq!(pixels.len(), 2);
+        assert_eq!(pixels[1].point, Point::new(15, 15));
+
+        assert_eq!(
+            parse(
+                r#"{"FillRect":{"origin":{"x":2,"y":3},"width":4,"height":5,"color":{"r":1,"g":1,"b":1}}}"#
+            ),
+            DirectCommand::FillRect {
+                origin: Point::new(2, 3),
+                width: 4,
+                height: 5,
+                color: Rgb::new(1, 1, 1)
+            }
+        );
+        assert_eq!(
+            parse(
+                r#"{"DrawLine":{"from":{"x":0,"y":0},"to":{"x":15,"y":7},"color":{"r":9,"g":9,"b":9}}}"#
+            ),
+            DirectCommand::DrawLine {
+                from: Point::new(0, 0),
+                to: Point::new(15, 7),
+                color: Rgb::new(9, 9, 9)
+            }
+        );
+        assert_eq!(
+            parse(
+                r#"{"DrawText":{"origin":{"x":1,"y":1},"text":"12:34","color":{"r":0,"g":255,"b":0}}}"#
+            ),
+            DirectCommand::DrawText {
+                origin: Point::new(1, 1),
+                text: heapless::String::try_from("12:34").unwrap(),
+                color: Rgb::new(0, 255, 0)
+            }
+        );
+        assert_eq!(
+            parse(r#"{"Scroll":{"dx":-1,"dy":2,"wrap":true}}"#),
+            DirectCommand::Scroll {
+                dx: -1,
+                dy: 2,
+                wrap: true
+            }
+        );
+    }
+
+    #[test]
+    fn test_full_frame_blit_fits() {
+        // A full frame of base64 must fit within the heapless bounds on the device.
+        let data = "A".repeat(MAX_BLIT_BASE64_LEN);
+        let json = format!(
+            r#"{{"Blit":{{"origin":{{"x":0,"y":0}},"width":16,"height":16,"data":"{data}"}}}}"#
+        );
+        let DirectCommand::Blit { data: parsed, .. } = parse(&json) else {
+            panic!("expected Blit");
+        };
+        assert_eq!(parsed.len(), MAX_BLIT_BASE64_LEN);
+    }
+
+    #[test]
+    fn test_oversized_payloads_are_rejected() {
+        let pixel = r#"{"point":{"x":0,"y":0},"color":{"r":0,"g":0,"b":0}}"#;
+        let pixels = [pixel; MAX_PIXELS_PER_BATCH + 1].join(",");
+        let json = format!(r#"{{"SetPixels":{{"pixels":[{pixels}]}}}}"#);
+        assert!(serde_json_core::from_str::<DirectCommand>(&json).is_err());
+
+        let text = "X".repeat(MAX_TEXT_LEN + 1);
+        let json = format!(
+            r#"{{"DrawText":{{"origin":{{"x":0,"y":0}},"text":"{text}","color":{{"r":0,"g":0,"b":0}}}}}}"#
+        );
+        assert!(serde_json_core::from_str::<DirectCommand>(&json).is_err());
+    }
+
+    /// Serialise with `serde_json` (backend) and parse with `serde_json_core` (device), and
+    /// the other way around.
+    fn assert_roundtrip<T>(value: &T)
+    where
+        T: Serialize + for<'de> Deserialize<'de> + PartialEq + core::fmt::Debug,
+    {
+        let json = serde_json::to_string(value).unwrap();
+        let (parsed, _) = serde_json_core::from_str::<T>(&json).unwrap();
+        assert_eq!(&parsed, value, "std -> core: {json}");
+
+        let mut buf = [0u8; 2048];
+        let n = serde_json_core::to_slice(value, &mut buf).unwrap();
+        let parsed: T = serde_json::from_slice(&buf[..n]).unwrap();
+        assert_eq!(&parsed, value, "core -> std");
+    }
+
+    #[test]
+    fn test_roundtrip_std_and_core() {
+        for mode in Mode::ALL {
+            assert_roundtrip(&Command::SetMode(mode));
+        }
+        assert_roundtrip(&Command::SetBrightness(42));
+        assert_roundtrip(&Command::GetConfig);
+        assert_roundtrip(&Command::ResetConfig);
+        assert_roundtrip(&Command::SetDmxMapping(DmxMapping::new(2, 4)));
+        assert_roundtrip(&Command::DirectCommand(DirectCommand::Clear));
+        assert_roundtrip(&Command::DirectCommand(DirectCommand::DrawText {
+            origin: Point::new(1, 2),
+            text: heapless::String::try_from("Hi!").unwrap(),
+            color: Rgb::new(1, 2, 3),
+        }));
+        assert_roundtrip(&Command::DirectCommand(DirectCommand::Blit {
+            origin: Point::new(0, 0),
+            width: 16,
+            height: 16,
+            data: heapless::String::try_from("A".repeat(MAX_BLIT_BASE64_LEN).as_str()).unwrap(),
+        }));
+        assert_roundtrip(&Command::DirectCommand(DirectCommand::Scroll {
+            dx: -3,
+            dy: 1,
+            wrap: false,
+        }));
+
+        assert_roundtrip(&ping::PingRequest {
+            correlation_id: heapless::String::try_from("abc-123").unwrap(),
+            message: heapless::String::try_from("ping").unwrap(),
+        });
+        assert_roundtrip(&ping::PingResponse {
+            correlation_id: heapless::String::try_from("abc-123").unwrap(),
+            message: heapless::String::try_from("pong").unwrap(),
+            protocol_version: PROTOCOL_VERSION,
+        });
+    }
+
+    #[test]
+    fn test_command_json_format() {
+        assert_eq!(
+            serde_json::to_string(&Command::SetMode(Mode::Direct)).unwrap(),
+            r#"{"SetMode":"Direct"}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&Command::SetBrightness(0)).unwrap(),
+            r#"{"SetBrightness":0}"#
+        );
+    }
+
+    #[test]
+    fn test_ping_response_without_version() {
+        // Devices from before PROTOCOL_VERSION
+        let (resp, _) = serde_json_core::from_str::<ping::PingResponse>(
+            r#"{"correlation_id":"x","message":"pong from host-esp32c6"}"#,
+        )
+        .unwrap();
+        assert_eq!(resp.protocol_version, 0);
+    }
+
+    #[test]
+    fn test_serialise_matches_device_format() {
+        let cmd = DirectCommand::FillRect {
+            origin: Point::new(1, 2),
+            width: 3,
+            height: 4,
+            color: Rgb::new(5, 6, 7),
+        };
+        let mut buf = [0u8; 128];
+        let n = serde_json_core::to_slice(&cmd, &mut buf).unwrap();
+        assert_eq!(parse(core::str::from_utf8(&buf[..n]).unwrap()), cmd);
+    }
+}
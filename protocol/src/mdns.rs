@@ -0,0 +1,595 @@
+//! Just enough mDNS / DNS-SD (RFC 6762, RFC 6763) for the device to discover the MQTT broker,
+//! and for the backend to advertise it.
+//!
+//! The device sends a "legacy unicast" query - from an ephemeral port, to [`MDNS_ADDR`] - for
+//! [`MQTT_SERVICE`], and responders answer it directly, with the service's `SRV` record and the
+//! host's `A` record ([`find_service`]). Hostnames, including `.local` ones, are resolved by the
+//! network stack instead.
+//!
+//! [`Message`] writes messages without name compression; [`Reader`] reads them with it.
+
+use core::fmt::Write;
+
+pub const MDNS_ADDR: [u8; 4] = [224, 0, 0, 251];
+pub const MDNS_PORT: u16 = 5353;
+
+/// DNS-SD service type of MQTT brokers
+pub const MQTT_SERVICE: &str = "_mqtt._tcp.local";
+/// DNS-SD service type of web servers, such as the backend's UI
+pub const HTTP_SERVICE: &str = "_http._tcp.local";
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_SRV: u16 = 33;
+pub const TYPE_ANY: u16 = 255;
+
+const CLASS_IN: u16 = 1;
+/// In a question: please answer by unicast. In a record: replaces any cached records.
+const CLASS_TOP_BIT: u16 = 0x8000;
+
+/// Header flags of a response from the authority for the name
+pub const FLAGS_RESPONSE: u16 = 0x8400;
+
+pub const HEADER_LEN: usize = 12;
+pub const MAX_NAME_LEN: usize = 255;
+const MAX_LABEL_LEN: usize = 63;
+// Compression pointers followed while reading a name, more means a loop
+const MAX_POINTERS: usize = 16;
+
+/// A name as read from a message, dot separated without the trailing dot.
+pub type Name = heapless::String<MAX_NAME_LEN>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The message ends early, or a count or length is wrong
+    Truncated,
+    /// A name is malformed or too long
+    BadName,
+    /// The buffer being written is full
+    BufferFull,
+}
+
+/// DNS names are case-insensitive.
+pub fn name_eq(a: &str, b: &str) -> bool {
+    a.eq_ignore_ascii_case(b)
+}
+
+/// Whether `name` is an instance of `service`, e.g. `Broker._mqtt._tcp.local`.
+pub fn is_instance_of(name: &str, service: &str) -> bool {
+    // Compared as bytes, as `name` comes off the network and may not split at a char boundary
+    let (name, service) = (name.as_bytes(), service.as_bytes());
+    name.len() > service.len() + 1
+        && name[name.len() - service.len()..].eq_ignore_ascii_case(service)
+        && name[name.len() - service.len() - 1] == b'.'
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RData<'a> {
+    A([u8; 4]),
+    Ptr(&'a str),
+    Srv {
+        port: u16,
+        target: &'a str,
+    },
+    /// No key/value pairs
+    EmptyTxt,
+}
+
+impl RData<'_> {
+    pub fn rtype(&self) -> u16 {
+        match self {
+            RData::A(_) => TYPE_A,
+            RData::Ptr(_) => TYPE_PTR,
+            RData::Srv { .. } => TYPE_SRV,
+            RData::EmptyTxt => TYPE_TXT,
+        }
+    }
+}
+
+/// A record to write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Record<'a> {
+    pub name: &'a str,
+    pub ttl: u32,
+    /// Set on records only this host answers for, so that caches replace rather than add
+    pub cache_flush: bool,
+    pub data: RData<'a>,
+}
+
+/// Writes a message into a buffer. Add the questions, answers and additional records in that
+/// order.
+pub struct Message<'b> {
+    buf: &'b mut [u8],
+    len: usize,
+    // questions, answers, authority, additional
+    counts: [u16; 4],
+}
+
+impl<'b> Message<'b> {
+    pub fn new(buf: &'b mut [u8], id: u16, flags: u16) -> Result<Self, Error> {
+        let mut message = Self {
+            buf,
+            len: 0,
+            counts: [0; 4],
+        };
+        message.put(&id.to_be_bytes())?;
+        message.put(&flags.to_be_bytes())?;
+        message.put(&[0; 8])?;
+        Ok(message)
+    }
+
+    fn put(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        let end = self.len + bytes.len();
+        self.buf
+            .get_mut(self.len..end)
+            .ok_or(Error::BufferFull)?
+            .copy_from_slice(bytes);
+        self.len = end;
+        Ok(())
+    }
+
+    fn put_name(&mut self, name: &str) -> Result<(), Error> {
+        if name.len() > MAX_NAME_LEN {
+            return Err(Error::BadName);
+        }
+        for label in name.split('.') {
+            if label.is_empty() || label.len() > MAX_LABEL_LEN {
+                return Err(Error::BadName);
+            }
+            self.put(&[label.len() as u8])?;
+            self.put(label.as_bytes())?;
+        }
+        self.put(&[0])
+    }
+
+    /// `unicast_response` asks responders to reply directly rather than to the group.
+    pub fn question(
+        &mut self,
+        name: &str,
+        qtype: u16,
+        unicast_response: bool,
+    ) -> Result<(), Error> {
+        self.put_name(name)?;
+        self.put(&qtype.to_be_bytes())?;
+        let class = if unicast_response {
+            CLASS_IN | CLASS_TOP_BIT
+        } else {
+            CLASS_IN
+        };
+        self.put(&class.to_be_bytes())?;
+        self.counts[0] += 1;
+        Ok(())
+    }
+
+    fn record(&mut self, record: &Record, section: usize) -> Result<(), Error> {
+        self.put_name(record.name)?;
+        self.put(&record.data.rtype().to_be_bytes())?;
+        let class = if record.cache_flush {
+            CLASS_IN | CLASS_TOP_BIT
+        } else {
+            CLASS_IN
+        };
+        self.put(&class.to_be_bytes())?;
+        self.put(&record.ttl.to_be_bytes())?;
+
+        // Length, filled in once the data is written
+        let len_at = self.len;
+        self.put(&[0, 0])?;
+        match record.data {
+            RData::A(address) => self.put(&address)?,
+            RData::Ptr(name) => self.put_name(name)?,
+            RData::Srv { port, target } => {
+                // Priority and weight
+                self.put(&[0, 0, 0, 0])?;
+                self.put(&port.to_be_bytes())?;
+                self.put_name(target)?;
+            }
+            RData::EmptyTxt => self.put(&[0])?,
+        }
+        let data_len = (self.len - len_at - 2) as u16;
+        self.buf[len_at..len_at + 2].copy_from_slice(&data_len.to_be_bytes());
+
+        self.counts[section] += 1;
+        Ok(())
+    }
+
+    pub fn answer(&mut self, record: &Record) -> Result<(), Error> {
+        self.record(record, 1)
+    }
+
+    pub fn additional(&mut self, record: &Record) -> Result<(), Error> {
+        self.record(record, 3)
+    }
+
+    /// The message length.
+    pub fn finish(self) -> usize {
+        for (i, count) in self.counts.iter().enumerate() {
+            self.buf[4 + 2 * i..6 + 2 * i].copy_from_slice(&count.to_be_bytes());
+        }
+        self.len
+    }
+}
+
+/// A legacy unicast query for `name`, asking for a unicast reply.
+pub fn query(buf: &mut [u8], id: u16, name: &str, qtype: u16) -> Result<usize, Error> {
+    let mut message = Message::new(buf, id, 0)?;
+    message.question(name, qtype, true)?;
+    Ok(message.finish())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Question {
+    pub name: Name,
+    pub qtype: u16,
+    pub unicast_response: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParsedRData {
+    A([u8; 4]),
+    Ptr(Name),
+    Srv { port: u16, target: Name },
+    Other,
+}
+
+/// A record read from a message, from any section.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParsedRecord {
+    pub name: Name,
+    pub rtype: u16,
+    pub ttl: u32,
+    pub data: ParsedRData,
+}
+
+/// Reads a message: the questions, then the records of every section.
+pub struct Reader<'p> {
+    packet: &'p [u8],
+    offset: usize,
+    questions: u16,
+    records: u16,
+}
+
+impl<'p> Reader<'p> {
+    pub fn new(packet: &'p [u8]) -> Result<Self, Error> {
+        if packet.len() < HEADER_LEN {
+            return Err(Error::Truncated);
+        }
+        let count = |i: usize| u16::from_be_bytes([packet[4 + 2 * i], packet[5 + 2 * i]]);
+        Ok(Self {
+            packet,
+            offset: HEADER_LEN,
+            questions: count(0),
+            records: count(1).saturating_add(count(2)).saturating_add(count(3)),
+        })
+    }
+
+    pub fn id(&self) -> u16 {
+        u16::from_be_bytes([self.packet[0], self.packet[1]])
+    }
+
+    pub fn is_response(&self) -> bool {
+        self.packet[2] & 0x80 != 0
+    }
+
+    fn u16_at(&self, offset: usize) -> Result<u16, Error> {
+        let bytes = self
+            .packet
+            .get(offset..offset + 2)
+            .ok_or(Error::Truncated)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32_at(&self, offset: usize) -> Result<u32, Error> {
+        let bytes = self
+            .packet
+            .get(offset..offset + 4)
+            .ok_or(Error::Truncated)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// Read the name at `offset`, returning the offset after it.
+    fn read_name(&self, mut offset: usize, name: &mut Name) -> Result<usize, Error> {
+        name.clear();
+        let mut end = None;
+        let mut pointers = 0;
+        loop {
+            let len = *self.packet.get(offset).ok_or(Error::Truncated)? as usize;
+            match len {
+                0 => return Ok(end.unwrap_or(offset + 1)),
+                l if l & 0xc0 == 0xc0 => {
+                    pointers += 1;
+                    if pointers > MAX_POINTERS {
+                        return Err(Error::BadName);
+                    }
+                    let target = self.u16_at(offset)? as usize & 0x3fff;
+                    end.get_or_insert(offset + 2);
+                    offset = target;
+                }
+                l if l > MAX_LABEL_LEN => return Err(Error::BadName),
+                l => {
+                    let label = self
+                        .packet
+                        .get(offset + 1..offset + 1 + l)
+                        .ok_or(Error::Truncated)?;
+                    let label = core::str::from_utf8(label).map_err(|_| Error::BadName)?;
+                    let separator = if name.is_empty() { "" } else { "." };
+                    write!(name, "{separator}{label}").map_err(|_| Error::BadName)?;
+                    offset += 1 + l;
+                }
+            }
+        }
+    }
+
+    /// The next question, `None` after the last one.
+    pub fn question(&mut self) -> Option<Result<Question, Error>> {
+        if self.questions == 0 {
+            return None;
+        }
+        self.questions -= 1;
+        let mut read = || {
+            let mut name = Name::new();
+            let offset = self.read_name(self.offset, &mut name)?;
+            let qtype = self.u16_at(offset)?;
+            let class = self.u16_at(offset + 2)?;
+            self.offset = offset + 4;
+            Ok(Question {
+                name,
+                qtype,
+                unicast_response: class & CLASS_TOP_BIT != 0,
+            })
+        };
+        Some(read().inspect_err(|_| self.questions = 0))
+    }
+
+    /// The next record, `None` after the last one. Any questions not yet read are skipped.
+    pub fn record(&mut self) -> Option<Result<ParsedRecord, Error>> {
+        while self.questions > 0 {
+            if let Some(Err(e)) = self.question() {
+                return Some(Err(e));
+            }
+        }
+        if self.records == 0 {
+            return None;
+        }
+        self.records -= 1;
+        let mut read = || {
+            let mut name = Name::new();
+            let offset = self.read_name(self.offset, &mut name)?;
+            let rtype = self.u16_at(offset)?;
+            let ttl = self.u32_at(offset + 4)?;
+            let data_len = self.u16_at(offset + 8)? as usize;
+            let data_at = offset + 10;
+            let data = self
+                .packet
+                .get(data_at..data_at + data_len)
+                .ok_or(Error::Truncated)?;
+            let data = match rtype {
+                TYPE_A => ParsedRData::A(data.try_into().map_err(|_| Error::Truncated)?),
+                TYPE_PTR => {
+                    let mut target = Name::new();
+                    self.read_name(data_at, &mut target)?;
+                    ParsedRData::Ptr(target)
+                }
+                TYPE_SRV => {
+                    let port = self.u16_at(data_at + 4)?;
+                    let mut target = Name::new();
+                    self.read_name(data_at + 6, &mut target)?;
+                    ParsedRData::Srv { port, target }
+                }
+                _ => ParsedRData::Other,
+            };
+            self.offset = data_at + data_len;
+            Ok(ParsedRecord {
+                name,
+                rtype,
+                ttl,
+                data,
+            })
+        };
+        Some(read().inspect_err(|_| self.records = 0))
+    }
+}
+
+/// An instance of a service, from its `SRV` record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceInstance {
+    pub host: Name,
+    pub port: u16,
+    /// From the host's `A` record, if the response included it
+    pub address: Option<[u8; 4]>,
+}
+
+/// The first instance of `service` in a response.
+pub fn find_service(packet: &[u8], service: &str) -> Result<Option<ServiceInstance>, Error> {
+    let mut reader = Reader::new(packet)?;
+    let mut found = None;
+    while let Some(record) = reader.record() {
+        let record = record?;
+        if let ParsedRData::Srv { port, target } = record.data
+            && is_instance_of(&record.name, service)
+        {
+            found = Some(ServiceInstance {
+                host: target,
+                port,
+                address: None,
+            });
+            break;
+        }
+    }
+    let Some(mut instance) = found else {
+        return Ok(None);
+    };
+    instance.address = find_address(packet, &instance.host)?;
+    Ok(Some(instance))
+}
+
+/// The address of `host` in a response.
+pub fn find_address(packet: &[u8], host: &str) -> Result<Option<[u8; 4]>, Error> {
+    let mut reader = Reader::new(packet)?;
+    while let Some(record) = reader.record() {
+        let record = record?;
+        if let ParsedRData::A(address) = record.data
+            && name_eq(&record.name, host)
+        {
+            return Ok(Some(address));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INSTANCE: &str = "esp32-wasmi-led._mqtt._tcp.local";
+    const HOST: &str = "broker.local";
+
+    /// A response as the backend sends it.
+    fn response(buf: &mut [u8]) -> usize {
+        let mut message = Message::new(buf, 0x1234, FLAGS_RESPONSE).unwrap();
+        message.question(MQTT_SERVICE, TYPE_PTR, false).unwrap();
+        message
+            .answer(&Record {
+                name: MQTT_SERVICE,
+                ttl: 10,
+                cache_flush: false,
+                data: RData::Ptr(INSTANCE),
+            })
+            .unwrap();
+        for data in [
+            RData::Srv {
+                port: 1883,
+                target: HOST,
+            },
+            RData::EmptyTxt,
+        ] {
+            message
+                .additional(&Record {
+                    name: INSTANCE,
+                    ttl: 10,
+                    cache_flush: true,
+                    data,
+                })
+                .unwrap();
+        }
+        message
+            .additional(&Record {
+                name: HOST,
+                ttl: 10,
+                cache_flush: true,
+                data: RData::A([192, 168, 1, 201]),
+            })
+            .unwrap();
+        message.finish()
+    }
+
+    #[test]
+    fn test_query_round_trip() {
+        let mut buf = [0u8; 64];
+        let n = query(&mut buf, 7, MQTT_SERVICE, TYPE_PTR).unwrap();
+        let mut reader = Reader::new(&buf[..n]).unwrap();
+        assert_eq!(reader.id(), 7);
+        assert!(!reader.is_response());
+        let question = reader.question().unwrap().unwrap();
+        assert_eq!(question.name, MQTT_SERVICE);
+        assert_eq!(question.qtype, TYPE_PTR);
+        assert!(question.unicast_response);
+        assert!(reader.question().is_none());
+        assert!(reader.record().is_none());
+    }
+
+    #[test]
+    fn test_find_service() {
+        let mut buf = [0u8; 512];
+        let n = response(&mut buf);
+        let reader = Reader::new(&buf[..n]).unwrap();
+        assert!(reader.is_response());
+        assert_eq!(
+            find_service(&buf[..n], MQTT_SERVICE),
+            Ok(Some(ServiceInstance {
+                host: Name::try_from(HOST).unwrap(),
+                port: 1883,
+                address: Some([192, 168, 1, 201]),
+            }))
+        );
+        assert_eq!(find_service(&buf[..n], HTTP_SERVICE), Ok(None));
+        assert_eq!(
+            find_address(&buf[..n], "BROKER.local"),
+            Ok(Some([192, 168, 1, 201]))
+        );
+    }
+
+    #[test]
+    fn test_compressed_names() {
+        // A response with one SRV record, compressed the way most responders do:
+        // name = "b" + pointer to "_mqtt._tcp.local" in the question, target = "h" + pointer
+        // to "local"
+        let mut packet = [0u8; 12].to_vec();
+        packet[2] = 0x84;
+        packet[5] = 1; // 1 question
+        packet[7] = 1; // 1 answer
+        packet.extend_from_slice(b"\x05_mqtt\x04_tcp\x05local\x00\x00\x0c\x00\x01");
+        packet.extend_from_slice(b"\x01b\xc0\x0c"); // b._mqtt._tcp.local
+        packet.extend_from_slice(&[0, 33, 0, 1, 0, 0, 0, 120, 0, 10]);
+        packet.extend_from_slice(&[0, 0, 0, 0, 0x07, 0x5b]); // port 1883
+        packet.extend_from_slice(b"\x01h\xc0\x17"); // h.local
+
+        let mut reader = Reader::new(&packet).unwrap();
+        let record = reader.record().unwrap().unwrap();
+        assert_eq!(record.name, "b._mqtt._tcp.local");
+        assert_eq!(record.ttl, 120);
+        assert_eq!(
+            record.data,
+            ParsedRData::Srv {
+                port: 1883,
+                target: Name::try_from("h.local").unwrap()
+            }
+        );
+        assert!(reader.record().is_none());
+    }
+
+    #[test]
+    fn test_malformed_messages() {
+        assert_eq!(Reader::new(&[0; 4]).err(), Some(Error::Truncated));
+
+        // A pointer to itself
+        let mut packet = [0u8; 12].to_vec();
+        packet[5] = 1;
+        packet.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1]);
+        let mut reader = Reader::new(&packet).unwrap();
+        assert_eq!(reader.question(), Some(Err(Error::BadName)));
+        assert!(reader.question().is_none());
+
+        // More records than there are
+        let mut buf = [0u8; 512];
+        let n = response(&mut buf);
+        buf[11] += 1;
+        assert_eq!(
+            find_address(&buf[..n], "missing.local"),
+            Err(Error::Truncated)
+        );
+
+        let mut buf = [0u8; 16];
+        assert_eq!(
+            query(&mut buf, 1, MQTT_SERVICE, TYPE_PTR),
+            Err(Error::BufferFull)
+        );
+        assert_eq!(
+            query(&mut [0u8; 64], 1, "bad..name", TYPE_A),
+            Err(Error::BadName)
+        );
+    }
+
+    #[test]
+    fn test_is_instance_of() {
+        assert!(is_instance_of(INSTANCE, MQTT_SERVICE));
+        assert!(is_instance_of("X._MQTT._tcp.local", MQTT_SERVICE));
+        assert!(!is_instance_of(MQTT_SERVICE, MQTT_SERVICE));
+        assert!(!is_instance_of("x_mqtt._tcp.local", MQTT_SERVICE));
+        assert!(is_instance_of("Brücke._mqtt._tcp.local", MQTT_SERVICE));
+        // Where the service would start is inside the `é`
+        assert!(!is_instance_of("xéaaaaaaaaaaaaaaa", MQTT_SERVICE));
+    }
+}
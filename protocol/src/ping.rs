@@ -0,0 +1,27 @@
+//! Request-response check that the device is alive, correlated by an ID chosen by the sender.
+
+use serde::{Deserialize, Serialize};
+
+pub const MAX_CORRELATION_ID_LEN: usize = 64;
+pub const MAX_PING_MESSAGE_LEN: usize = 32;
+
+/// Published on the device's [`PING_REQUEST`](crate::topics::suffix::PING_REQUEST) topic.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PingRequest {
+    pub correlation_id: heapless::String<MAX_CORRELATION_ID_LEN>,
+    pub message: heapless::String<MAX_PING_MESSAGE_LEN>,
+}
+
+/// Published by the device on its [`PING_RESPONSE`](crate::topics::suffix::PING_RESPONSE)
+/// topic, echoing the request's `correlation_id`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PingResponse {
+    pub correlation_id: heapless::String<MAX_CORRELATION_ID_LEN>,
+    pub message: heapless::String<MAX_PING_MESSAGE_LEN>,
+    /// [`PROTOCOL_VERSION`](crate::PROTOCOL_VERSION) of the responder, 0 if it predates
+    /// versioning
+    #[serde(default)]
+    pub protocol_version: u16,
+}
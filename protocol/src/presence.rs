@@ -0,0 +1,80 @@
+//! Device presence and discovery.
+//!
+//! Each device has a retained [`STATUS`](crate::topics::suffix::STATUS) topic. The device
+//! publishes [`ONLINE`] once connected, and registers [`OFFLINE`] as its Last Will, so the
+//! broker publishes it if the device drops off. It also announces itself with a retained
+//! [`DeviceInfo`] on [`INFO`](crate::topics::suffix::INFO), so that controllers can discover
+//! it. The device id is the lowercase hex WiFi MAC address, which also makes the MQTT client id
+//! unique.
+
+use core::fmt::Write;
+
+use serde::{Deserialize, Serialize};
+
+pub const ONLINE: &str = "online";
+pub const OFFLINE: &str = "offline";
+
+pub const DEVICE_ID_LEN: usize = 12;
+pub type DeviceId = heapless::String<DEVICE_ID_LEN>;
+
+/// MQTT client ids are this followed by the device id.
+pub const CLIENT_ID_PREFIX: &str = "esp32-wasmi-led-";
+pub const MAX_CLIENT_ID_LEN: usize = CLIENT_ID_PREFIX.len() + DEVICE_ID_LEN;
+
+/// `aabbccddeeff` for MAC `AA:BB:CC:DD:EE:FF`
+pub fn device_id(mac: [u8; 6]) -> DeviceId {
+    let mut id = DeviceId::new();
+    for byte in mac {
+        // Always fits: 2 characters per byte
+        let _ = write!(id, "{byte:02x}");
+    }
+    id
+}
+
+pub fn client_id(device_id: &str) -> heapless::String<MAX_CLIENT_ID_LEN> {
+    let mut id = heapless::String::new();
+    let _ = write!(id, "{CLIENT_ID_PREFIX}{device_id}");
+    id
+}
+
+/// `Some(true)` for [`ONLINE`], `Some(false)` for [`OFFLINE`].
+pub fn parse_status(payload: &[u8]) -> Option<bool> {
+    match payload {
+        p if p == ONLINE.as_bytes() => Some(true),
+        p if p == OFFLINE.as_bytes() => Some(false),
+        _ => None,
+    }
+}
+
+pub const MAX_FIRMWARE_VERSION_LEN: usize = 16;
+
+/// `{"device_id":"404cca01abff","firmware_version":"0.1.0","protocol_version":1,"width":16,"height":16}`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DeviceInfo {
+    pub device_id: DeviceId,
+    pub firmware_version: heapless::String<MAX_FIRMWARE_VERSION_LEN>,
+    pub protocol_version: u16,
+    /// Panel size in pixels
+    pub width: u8,
+    pub height: u8,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ids() {
+        let id = device_id([0x40, 0x4c, 0xca, 0x01, 0xAB, 0xff]);
+        assert_eq!(id, "404cca01abff");
+        assert_eq!(client_id(&id), "esp32-wasmi-led-404cca01abff");
+    }
+
+    #[test]
+    fn test_parse_status() {
+        assert_eq!(parse_status(b"online"), Some(true));
+        assert_eq!(parse_status(b"offline"), Some(false));
+        assert_eq!(parse_status(b"Online"), None);
+    }
+}
@@ -0,0 +1,356 @@
+//! Binary realtime frame streaming, for PC-driven content at 30-60 FPS.
+//!
+//! Each MQTT payload is one frame:
+//!
+//! ```text
+//! [encoding: u8][seq: u32 LE][payload]
+//! ```
+//!
+//! - `Raw` (0): the payload is a full frame, `LED_BUFFER_SIZE` bytes of row-major RGB.
+//! - `Delta` (1): the payload is a list of runs against the frame with sequence number
+//!   `seq - 1`, each `[start pixel: u16 LE][count: u8][count * 3 bytes of RGB]`.
+//!
+//! Receivers display the newest frame and drop stale ones. A delta can only be applied on top
+//! of its immediate predecessor, so after a lost frame deltas are dropped until the next raw
+//! frame. A raw frame with sequence number 0 always (re)starts the stream.
+
+use common::{BYTES_PER_LED, LED_BUFFER_SIZE, LED_PANEL_NUM_LEDS};
+
+pub const STREAM_HEADER_LEN: usize = 5;
+
+/// Largest frame a sender will produce - senders fall back to `Raw` if a delta would be bigger.
+pub const MAX_STREAM_FRAME_LEN: usize = STREAM_HEADER_LEN + LED_BUFFER_SIZE;
+
+const RUN_HEADER_LEN: usize = 3;
+const MAX_RUN_LEN: usize = u8::MAX as usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FrameEncoding {
+    Raw = 0,
+    Delta = 1,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum StreamError {
+    /// Shorter than the frame header
+    MissingHeader,
+    /// Unknown encoding byte
+    UnknownEncoding(u8),
+    /// A raw frame that isn't exactly one panel
+    WrongFrameSize(usize),
+    /// A delta run that is truncated or extends past the end of the panel
+    InvalidRun,
+    /// The output buffer is too small for the encoded frame
+    BufferTooSmall,
+}
+
+/// A borrowed, parsed stream frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamFrame<'a> {
+    pub encoding: FrameEncoding,
+    pub seq: u32,
+    pub payload: &'a [u8],
+}
+
+impl<'a> StreamFrame<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, StreamError> {
+        let [encoding, s0, s1, s2, s3, payload @ ..] = bytes else {
+            return Err(StreamError::MissingHeader);
+        };
+        let encoding = match encoding {
+            0 => FrameEncoding::Raw,
+            1 => FrameEncoding::Delta,
+            other => return Err(StreamError::UnknownEncoding(*other)),
+        };
+        if encoding == FrameEncoding::Raw && payload.len() != LED_BUFFER_SIZE {
+            return Err(StreamError::WrongFrameSize(payload.len()));
+        }
+
+        Ok(Self {
+            encoding,
+            seq: u32::from_le_bytes([*s0, *s1, *s2, *s3]),
+            payload,
+        })
+    }
+
+    /// Whether this frame should be displayed, given the sequence number of the last frame
+    /// that was displayed (`None` at the start of a stream).
+    pub fn follows(&self, last_seq: Option<u32>) -> bool {
+        match (self.encoding, last_seq) {
+            (FrameEncoding::Raw, _) if self.seq == 0 => true,
+            (FrameEncoding::Raw, None) => true,
+            (FrameEncoding::Raw, Some(last)) => is_newer(self.seq, last),
+            (FrameEncoding::Delta, Some(last)) => self.seq == last.wrapping_add(1),
+            (FrameEncoding::Delta, None) => false,
+        }
+    }
+
+    /// Apply this frame to `frame` (a full panel buffer holding the previous frame, for deltas).
+    ///
+    /// A delta is validated in full before anything is written, so on error `frame` is
+    /// unchanged.
+    pub fn apply(&self, frame: &mut [u8]) -> Result<(), StreamError> {
+        if frame.len() < LED_BUFFER_SIZE {
+            return Err(StreamError::BufferTooSmall);
+        }
+        match self.encoding {
+            FrameEncoding::Raw => frame[..LED_BUFFER_SIZE].copy_from_slice(self.payload),
+            FrameEncoding::Delta => {
+                for run in runs(self.payload) {
+                    run?;
+                }
+                for run in runs(self.payload) {
+                    let (start, data) = run?;
+                    frame[start..start + data.len()].copy_from_slice(data);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Iterate over the `(byte offset, rgb data)` runs of a delta payload.
+fn runs(mut payload: &[u8]) -> impl Iterator<Item = Result<(usize, &[u8]), StreamError>> {
+    core::iter::from_fn(move || {
+        if payload.is_empty() {
+            return None;
+        }
+        let [lo, hi, count, rest @ ..] = payload else {
+            payload = &[];
+            return Some(Err(StreamError::InvalidRun));
+        };
+        let start = u16::from_le_bytes([*lo, *hi]) as usize;
+        let count = *count as usize;
+        let len = count * BYTES_PER_LED;
+        if start + count > LED_PANEL_NUM_LEDS || rest.len() < len {
+            payload = &[];
+            return Some(Err(StreamError::InvalidRun));
+        }
+        let (data, rest) = rest.split_at(len);
+        payload = rest;
+        Some(Ok((start * BYTES_PER_LED, data)))
+    })
+}
+
+/// Serial number comparison (RFC 1982), so the stream survives `seq` wrapping around.
+pub fn is_newer(seq: u32, last: u32) -> bool {
+    seq != last && seq.wrapping_sub(last) < (1 << 31)
+}
+
+fn write_header(out: &mut [u8], encoding: FrameEncoding, seq: u32) -> Result<(), StreamError> {
+    if out.len() < STREAM_HEADER_LEN {
+        return Err(StreamError::BufferTooSmall);
+    }
+    out[0] = encoding as u8;
+    out[1..STREAM_HEADER_LEN].copy_from_slice(&seq.to_le_bytes());
+    Ok(())
+}
+
+/// Encode `frame` as a raw stream frame into `out`, returning the encoded length.
+pub fn encode_raw(seq: u32, frame: &[u8], out: &mut [u8]) -> Result<usize, StreamError> {
+    if frame.len() != LED_BUFFER_SIZE {
+        return Err(StreamError::WrongFrameSize(frame.len()));
+    }
+    let len = STREAM_HEADER_LEN + LED_BUFFER_SIZE;
+    if out.len() < len {
+        return Err(StreamError::BufferTooSmall);
+    }
+    write_header(out, FrameEncoding::Raw, seq)?;
+    out[STREAM_HEADER_LEN..len].copy_from_slice(frame);
+    Ok(len)
+}
+
+/// Encode the changes from `prev` to `next` as a delta stream frame into `out`, returning the
+/// encoded length. Fails with [`StreamError::BufferTooSmall`] if the delta doesn't fit, in
+/// which case the caller should send a raw frame instead.
+pub fn encode_delta(
+    seq: u32,
+    prev: &[u8],
+    next: &[u8],
+    out: &mut [u8],
+) -> Result<usize, StreamError> {
+    for f in [prev, next] {
+        if f.len() != LED_BUFFER_SIZE {
+            return Err(StreamError::WrongFrameSize(f.len()));
+        }
+    }
+    write_header(out, FrameEncoding::Delta, seq)?;
+
+    let changed = |i: usize| {
+        let o = i * BYTES_PER_LED;
+        prev[o..o + BYTES_PER_LED] != next[o..o + BYTES_PER_LED]
+    };
+
+    let mut len = STREAM_HEADER_LEN;
+    let mut i = 0;
+    while i < LED_PANEL_NUM_LEDS {
+        if !changed(i) {
+            i += 1;
+            continue;
+        }
+
+        // Extend the run over changed pixels, and over single unchanged pixels between them -
+        // resending one pixel is no bigger than a new run header.
+        let start = i;
+        let mut end = i + 1;
+        while end < LED_PANEL_NUM_LEDS && end - start < MAX_RUN_LEN {
+            if changed(end) {
+                end += 1;
+            } else if end + 1 < LED_PANEL_NUM_LEDS
+                && end + 1 - start < MAX_RUN_LEN
+                && changed(end + 1)
+            {
+                end += 2;
+            } else {
+                break;
+            }
+        }
+
+        let data = &next[start * BYTES_PER_LED..end * BYTES_PER_LED];
+        let run_len = RUN_HEADER_LEN + data.len();
+        if out.len() < len + run_len {
+            return Err(StreamError::BufferTooSmall);
+        }
+        out[len..len + 2].copy_from_slice(&(start as u16).to_le_bytes());
+        out[len + 2] = (end - start) as u8;
+        out[len + RUN_HEADER_LEN..len + run_len].copy_from_slice(data);
+        len += run_len;
+        i = end;
+    }
+
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame_with(pixels: &[(usize, [u8; 3])]) -> Vec<u8> {
+        let mut f = vec![0u8; LED_BUFFER_SIZE];
+        for (i, rgb) in pixels {
+            f[i * 3..i * 3 + 3].copy_from_slice(rgb);
+        }
+        f
+    }
+
+    #[test]
+    fn test_raw_roundtrip() {
+        let next = frame_with(&[(0, [1, 2, 3]), (255, [4, 5, 6])]);
+        let mut out = [0u8; MAX_STREAM_FRAME_LEN];
+        let len = encode_raw(7, &next, &mut out).unwrap();
+        assert_eq!(len, MAX_STREAM_FRAME_LEN);
+
+        let frame = StreamFrame::parse(&out[..len]).unwrap();
+        assert_eq!(frame.encoding, FrameEncoding::Raw);
+        assert_eq!(frame.seq, 7);
+
+        let mut display = vec![0xAA; LED_BUFFER_SIZE];
+        frame.apply(&mut display).unwrap();
+        assert_eq!(display, next);
+    }
+
+    #[test]
+    fn test_delta_roundtrip() {
+        let prev = frame_with(&[(10, [9, 9, 9])]);
+        // 20 and 22 are merged into one run across the unchanged 21
+        let next = frame_with(&[(0, [1, 1, 1]), (20, [2, 2, 2]), (22, [3, 3, 3])]);
+
+        let mut out = [0u8; MAX_STREAM_FRAME_LEN];
+        let len = encode_delta(8, &prev, &next, &mut out).unwrap();
+        // 3 runs: [0], [10], [20..=22]
+        assert_eq!(len, STREAM_HEADER_LEN + 3 * RUN_HEADER_LEN + 5 * 3);
+
+        let frame = StreamFrame::parse(&out[..len]).unwrap();
+        assert_eq!(frame.encoding, FrameEncoding::Delta);
+        assert_eq!(frame.seq, 8);
+
+        let mut display = prev.clone();
+        frame.apply(&mut display).unwrap();
+        assert_eq!(display, next);
+    }
+
+    #[test]
+    fn test_delta_of_everything_falls_back() {
+        let prev = frame_with(&[]);
+        let next = vec![1u8; LED_BUFFER_SIZE];
+        let mut out = [0u8; MAX_STREAM_FRAME_LEN];
+        assert_eq!(
+            encode_delta(1, &prev, &next, &mut out),
+            Err(StreamError::BufferTooSmall)
+        );
+
+        // ...but it does fit in a larger buffer, as two runs
+        let mut big = [0u8; 2 * MAX_STREAM_FRAME_LEN];
+        let len = encode_delta(1, &prev, &next, &mut big).unwrap();
+        let mut display = prev.clone();
+        StreamFrame::parse(&big[..len])
+            .unwrap()
+            .apply(&mut display)
+            .unwrap();
+        assert_eq!(display, next);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            StreamFrame::parse(&[0, 0, 0]),
+            Err(StreamError::MissingHeader)
+        );
+        assert_eq!(
+            StreamFrame::parse(&[9, 0, 0, 0, 0]),
+            Err(StreamError::UnknownEncoding(9))
+        );
+        assert_eq!(
+            StreamFrame::parse(&[0, 0, 0, 0, 0, 1, 2, 3]),
+            Err(StreamError::WrongFrameSize(3))
+        );
+    }
+
+    #[test]
+    fn test_invalid_delta_leaves_frame_untouched() {
+        // First run is fine, second runs off the end of the panel
+        let mut bytes = vec![1, 0, 0, 0, 0];
+        bytes.extend_from_slice(&[0, 0, 1, 7, 7, 7]);
+        bytes.extend_from_slice(&[255, 0, 2, 7, 7, 7, 7, 7, 7]);
+        let frame = StreamFrame::parse(&bytes).unwrap();
+
+        let mut display = vec![0u8; LED_BUFFER_SIZE];
+        assert_eq!(frame.apply(&mut display), Err(StreamError::InvalidRun));
+        assert!(display.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_follows() {
+        let raw = |seq| StreamFrame {
+            encoding: FrameEncoding::Raw,
+            seq,
+            payload: &[],
+        };
+        let delta = |seq| StreamFrame {
+            encoding: FrameEncoding::Delta,
+            seq,
+            payload: &[],
+        };
+
+        assert!(raw(5).follows(None));
+        assert!(raw(5).follows(Some(3)));
+        assert!(!raw(3).follows(Some(5)), "stale");
+        assert!(raw(0).follows(Some(5)), "restart");
+
+        assert!(!delta(1).follows(None), "no base frame");
+        assert!(delta(6).follows(Some(5)));
+        assert!(!delta(7).follows(Some(5)), "missed a frame");
+        assert!(delta(0).follows(Some(u32::MAX)));
+    }
+
+    #[test]
+    fn test_is_newer() {
+        assert!(is_newer(2, 1));
+        assert!(!is_newer(1, 2));
+        assert!(!is_newer(5, 5));
+        assert!(is_newer(0, u32::MAX));
+        assert!(!is_newer(u32::MAX, 0));
+    }
+}
@@ -0,0 +1,93 @@
+//! Periodic device status, published retained on
+//! [`TELEMETRY`](crate::topics::suffix::TELEMETRY) so that a newly connected subscriber sees the
+//! latest report straight away.
+
+use serde::{Deserialize, Serialize};
+
+use crate::Mode;
+use crate::presence::MAX_FIRMWARE_VERSION_LEN;
+
+pub const MAX_GUEST_NAME_LEN: usize = 32;
+/// Dotted-quad IPv4 address
+pub const MAX_IP_LEN: usize = 15;
+
+/// Largest serialised [`Telemetry`], for sizing buffers on the device.
+pub const MAX_TELEMETRY_LEN: usize = 512;
+
+/// `{"protocol_version":1,"firmware_version":"0.1.0","uptime_s":42,"mode":"Wasm",...}`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Telemetry {
+    pub protocol_version: u16,
+    pub firmware_version: heapless::String<MAX_FIRMWARE_VERSION_LEN>,
+    pub uptime_s: u32,
+    pub mode: Mode,
+    /// The loaded WASM guest, if any
+    pub guest: Option<heapless::String<MAX_GUEST_NAME_LEN>>,
+    /// Over the period since the previous report
+    pub frames: FrameTimes,
+    pub heap: HeapUsage,
+    /// Size of the guest's linear memory, including the host pixel buffer
+    pub guest_memory_bytes: u32,
+    /// Estimated panel power draw for the latest frame
+    #[serde(default)]
+    pub power_mw: u32,
+    /// Signal strength of the access point, if connected
+    pub rssi_dbm: Option<i8>,
+    pub ip: Option<heapless::String<MAX_IP_LEN>>,
+}
+
+/// Frames written to the panel, and how long each took to write.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FrameTimes {
+    pub fps: u16,
+    pub min_us: u32,
+    pub avg_us: u32,
+    pub max_us: u32,
+}
+
+/// Heap usage in bytes, across all regions.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct HeapUsage {
+    pub used: u32,
+    pub free: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip_fits() {
+        let telemetry = Telemetry {
+            protocol_version: crate::PROTOCOL_VERSION,
+            firmware_version: "0.1.0-longest-ok".try_into().unwrap(),
+            uptime_s: u32::MAX,
+            mode: Mode::Direct,
+            guest: Some("g".repeat(MAX_GUEST_NAME_LEN).as_str().try_into().unwrap()),
+            frames: FrameTimes {
+                fps: u16::MAX,
+                min_us: u32::MAX,
+                avg_us: u32::MAX,
+                max_us: u32::MAX,
+            },
+            heap: HeapUsage {
+                used: u32::MAX,
+                free: u32::MAX,
+            },
+            guest_memory_bytes: u32::MAX,
+            power_mw: u32::MAX,
+            rssi_dbm: Some(i8::MIN),
+            ip: Some("255.255.255.255".try_into().unwrap()),
+        };
+
+        let json: heapless::Vec<u8, MAX_TELEMETRY_LEN> =
+            serde_json_core::to_vec(&telemetry).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<Telemetry>(&json).unwrap(),
+            telemetry
+        );
+    }
+}
@@ -0,0 +1,102 @@
+//! MQTT topic names.
+//!
+//! Device topics are `{prefix}/{device_id}/{suffix}`, with the suffixes in [`suffix`]. The
+//! device always uses [`DEFAULT_PREFIX`] (see [`device_topic`]); the backend can use another
+//! prefix, e.g. to keep concurrent tests apart. The web frontend's demo topics aren't tied to
+//! a device, and are `{prefix}/{suffix}`.
+
+use core::fmt::Write;
+
+use crate::presence::DEVICE_ID_LEN;
+
+pub const DEFAULT_PREFIX: &str = "esp32-wasmi-led";
+
+/// Topic names without the prefix (and device id).
+pub mod suffix {
+    /// JSON [`Command`](crate::Command)s to the device
+    pub const COMMAND: &str = "command";
+    /// [`CommandAck`](crate::CommandAck)s from the device, for envelopes without a `reply_to`
+    pub const COMMAND_ACK: &str = "command/ack";
+    /// Binary blits ([`BinaryBlit`](crate::BinaryBlit)) to the device, drawn in `Mode::Direct`
+    pub const BLIT: &str = "blit";
+    /// Binary realtime frames ([`stream`](crate::stream)) to the device
+    pub const STREAM: &str = "stream";
+    /// Binary chunks of a WASM guest module ([`guest`](crate::guest)) to the device
+    pub const GUEST: &str = "guest";
+    /// [`PingRequest`](crate::ping::PingRequest) to the device
+    pub const PING_REQUEST: &str = "ping/request";
+    /// [`PingResponse`](crate::ping::PingResponse) from the device
+    pub const PING_RESPONSE: &str = "ping/response";
+    /// Retained [`Telemetry`](crate::telemetry::Telemetry) from the device
+    pub const TELEMETRY: &str = "telemetry";
+    /// Retained `online` / `offline` ([`presence`](crate::presence)) for the device
+    pub const STATUS: &str = "status";
+    /// Retained [`DeviceInfo`](crate::presence::DeviceInfo), announcing the device
+    pub const INFO: &str = "info";
+    /// [`DeviceConfig`](crate::DeviceConfig) from the device, in reply to `GetConfig`
+    pub const CONFIG: &str = "config";
+
+    /// Raw payloads published by the web frontend
+    pub const SEND: &str = "send";
+    /// Messages cached by the backend for polling
+    pub const POLL: &str = "poll";
+    /// Messages forwarded live to the web frontend
+    pub const LIVE: &str = "live";
+}
+
+/// Longest topic returned by [`device_topic`].
+pub const MAX_DEVICE_TOPIC_LEN: usize =
+    DEFAULT_PREFIX.len() + 1 + DEVICE_ID_LEN + 1 + suffix::PING_RESPONSE.len();
+
+pub type DeviceTopic = heapless::String<MAX_DEVICE_TOPIC_LEN>;
+
+/// `{DEFAULT_PREFIX}/{device_id}/{suffix}`
+pub fn device_topic(device_id: &str, suffix: &str) -> DeviceTopic {
+    let mut topic = DeviceTopic::new();
+    // Fits for every suffix in `suffix`
+    let _ = write!(topic, "{DEFAULT_PREFIX}/{device_id}/{suffix}");
+    topic
+}
+
+/// Split a device topic under `prefix` into its device id and suffix.
+pub fn parse_device_topic<'t>(prefix: &str, topic: &'t str) -> Option<(&'t str, &'t str)> {
+    let rest = topic.strip_prefix(prefix)?.strip_prefix('/')?;
+    let (device_id, suffix) = rest.split_once('/')?;
+    (!device_id.is_empty() && !suffix.is_empty()).then_some((device_id, suffix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_device_topic_round_trip() {
+        let topic = device_topic("404cca01abff", suffix::PING_RESPONSE);
+        assert_eq!(topic, "esp32-wasmi-led/404cca01abff/ping/response");
+        assert_eq!(topic.len(), MAX_DEVICE_TOPIC_LEN);
+        assert_eq!(
+            parse_device_topic(DEFAULT_PREFIX, &topic),
+            Some(("404cca01abff", suffix::PING_RESPONSE))
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_other_topics() {
+        assert_eq!(
+            parse_device_topic(DEFAULT_PREFIX, "esp32-wasmi-led/send"),
+            None
+        );
+        assert_eq!(
+            parse_device_topic(DEFAULT_PREFIX, "esp32-wasmi-ledx/a/b"),
+            None
+        );
+        assert_eq!(
+            parse_device_topic(DEFAULT_PREFIX, "esp32-wasmi-led//b"),
+            None
+        );
+        assert_eq!(
+            parse_device_topic("test-1", "test-1/abc/command"),
+            Some(("abc", "command"))
+        );
+    }
+}
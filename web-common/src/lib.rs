@@ -1,5 +1,27 @@
+use protocol::presence::DeviceInfo;
+use protocol::telemetry::Telemetry;
+use protocol::{Command, CommandError, DeviceConfig};
 use serde::{Deserialize, Serialize};
 
+/// Every device is in this group.
+pub const ALL_DEVICES: &str = "all";
+
+/// The device(s) a message is for.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Target {
+    /// `{"Device":"404cca01abff"}`
+    Device(String),
+    /// `{"Group":"kitchen"}` - every device in the group, or every known device for
+    /// [`ALL_DEVICES`]
+    Group(String),
+}
+
+impl Default for Target {
+    fn default() -> Self {
+        Target::Group(ALL_DEVICES.to_string())
+    }
+}
+
 // Messages from frontend → backend (over WebSocket):
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")] // easier debugging
@@ -7,8 +29,51 @@ pub enum ClientMsg {
     /// User clicked "Send" — publish this payload to MQTT
     Publish { payload: String },
 
-    /// User clicked "Ping Device" — send a request, expect a correlated reply
-    PingDevice { correlation_id: String },
+    /// User clicked "Ping Device" — send a request, expect a correlated reply from each device
+    PingDevice {
+        correlation_id: String,
+        #[serde(default)]
+        target: Target,
+    },
+
+    /// Send a command to the device(s)
+    Command {
+        #[serde(default)]
+        target: Target,
+        command: Box<Command>,
+    },
+
+    /// Where this client's binary (stream) frames go, all devices until set
+    SetStreamTarget { target: Target },
+
+    /// Control this client's preview of a stored guest, simulated by the backend
+    Simulate { control: SimulatorControl },
+}
+
+/// A guest preview: the backend runs the guest as a device would, and sends this client each
+/// frame as a [`ServerMsg::Frame`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum SimulatorControl {
+    /// Run guest `guest_id` from the start, playing, at `fps` frames per second (the backend's
+    /// default if `None`). Replaces any preview this client had.
+    Start {
+        guest_id: String,
+        fps: Option<u32>,
+    },
+    Play,
+    /// Stop sending frames, keeping the guest where it is
+    Pause,
+    /// Go to `ms` into the guest's run, and send the frame there
+    Seek {
+        ms: u64,
+    },
+    /// How fast guest time passes, 1.0 being real time
+    Speed {
+        speed: f32,
+    },
+    /// End the preview
+    Stop,
 }
 
 // Messages from backend → frontend (over WebSocket):
@@ -20,9 +85,60 @@ pub enum ServerMsg {
 
     /// Response to a PingDevice request
     PingResponse {
+        device_id: String,
         correlation_id: String,
         device_reply: String,
     },
+
+    /// The id a `Command` from this client was sent to a device with
+    CommandSent { device_id: String, id: u32 },
+
+    /// The device acknowledged (`error` is `None`) or rejected a command
+    CommandAck {
+        device_id: String,
+        id: u32,
+        error: Option<CommandError>,
+    },
+
+    /// A device published a status report
+    Telemetry {
+        device_id: String,
+        telemetry: Telemetry,
+    },
+
+    /// A device came online or went offline. Sent for every known device on connecting.
+    Presence { device_id: String, online: bool },
+
+    /// A device announced itself. Sent for every known device on connecting.
+    DeviceInfo { info: DeviceInfo },
+
+    /// A device's configuration (without passwords), in reply to `GetConfig`
+    DeviceConfig {
+        device_id: String,
+        config: Box<DeviceConfig>,
+    },
+
+    /// A frame of this client's guest preview: `counter` frames and `ms` guest time into its
+    /// run, as row-major RGB
+    Frame {
+        guest_id: String,
+        ms: u64,
+        counter: u64,
+        pixels: Vec<u8>,
+    },
+
+    /// This client's guest preview couldn't start, or stopped because the guest failed
+    SimulatorError { error: String },
+
+    /// This client's role doesn't allow what it asked for, e.g. a viewer sending a command
+    Forbidden { action: String },
+}
+
+/// Body of `POST /api/login`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Login {
+    pub name: String,
+    pub password: String,
 }
 
 // HTTP response for the "Fetch" button: